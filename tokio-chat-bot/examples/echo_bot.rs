@@ -0,0 +1,41 @@
+// A bot that repeats everything said in the rooms it's in. Start tokio-chat-server with
+// `--allow-guests`, then run
+//
+//     cargo run --example echo_bot -- lobby another-room
+//
+// and say something in one of those rooms from tokio-chat-client.
+extern crate tokio_core;
+extern crate tokio_chat_bot;
+extern crate tokio_chat_common;
+
+use tokio_core::reactor::Core;
+use tokio_chat_bot::MessageHandler;
+use tokio_chat_common::{ClientMessage, Handshake, ServerMessage, DEFAULT_ROOM};
+
+struct EchoBot;
+
+impl MessageHandler for EchoBot {
+    // Our own messages never get here, so there's no echoing our echoes.
+    fn on_message(&self, msg: &ServerMessage) -> Option<ClientMessage> {
+        match *msg {
+            ServerMessage::Message(_, ref from, ref body) => {
+                Some(ClientMessage::new(format!("{} said: {}", from, body)))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn main() {
+    let mut rooms = std::env::args().skip(1).collect::<Vec<_>>();
+    if rooms.is_empty() {
+        rooms.push(DEFAULT_ROOM.to_string());
+    }
+    let rooms = rooms.iter().map(|room| room.as_str()).collect::<Vec<_>>();
+
+    let addr = "127.0.0.1:12345".parse().unwrap();
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    core.run(tokio_chat_bot::run(EchoBot, &addr, Handshake::new("echo-bot"), &rooms, &handle))
+        .unwrap();
+}
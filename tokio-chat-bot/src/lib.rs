@@ -0,0 +1,176 @@
+//! tokio-chat-bot is a small toolkit for writing bots that talk to tokio-chat-server.
+//!
+//! A bot is anything implementing `ChatBot`. Hand one to `run` along with the rooms it should sit
+//! in, and `run` opens a connection to the server per room, then calls `ChatBot::on_message` for
+//! every `ServerMessage` that arrives on any of them. The `BotClient` passed alongside each
+//! message belongs to the connection it arrived on, so anything the bot sends through it goes
+//! back to the same room.
+//!
+//! Bots that only ever answer what they hear can implement `MessageHandler` instead, whose
+//! `on_message` just returns the reply, if any. See examples/echo_bot.rs for a complete one.
+//!
+//! Like the rest of this project, this is built on futures 0.1, so `on_message` isn't an `async
+//! fn`: it runs to completion before the next message is handled, and anything it sends is
+//! queued and written to the server once it returns.
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_chat_common;
+
+use std::cell::RefCell;
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use tokio_core::io::Io;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use futures::{future, Future, Sink, Stream};
+use futures::sync::mpsc;
+use tokio_chat_common::{Handshake, HandshakeCodec, ClientMessage, ServerMessage,
+                        ClientToServerCodec, DEFAULT_ROOM};
+
+// What a bot's `on_message` returns. Returning an error disconnects the bot from the room the
+// message came from, and `run`'s future fails with it.
+pub type BotResult = Result<(), Box<Error + Send + Sync>>;
+
+// The behavior of a bot. Because one bot may sit in several rooms at once, check
+// `client.room()` if it matters where a message came from.
+pub trait ChatBot {
+    fn on_message(&mut self, msg: &ServerMessage, client: &mut BotClient) -> BotResult;
+}
+
+// A bot that only answers messages, which it can't tell apart by room: whatever `on_message`
+// returns is sent back over the connection the message came from. It isn't passed the bot's own
+// chat messages, so one that answers everything won't go on answering itself forever. Every
+// `MessageHandler` is a `ChatBot`, so `run` takes them as they are.
+pub trait MessageHandler {
+    fn on_message(&self, msg: &ServerMessage) -> Option<ClientMessage>;
+}
+
+impl<H: MessageHandler> ChatBot for H {
+    fn on_message(&mut self, msg: &ServerMessage, client: &mut BotClient) -> BotResult {
+        if let ServerMessage::Message(_, ref from, _) = *msg {
+            if from == client.name() {
+                return Ok(());
+            }
+        }
+        if let Some(reply) = MessageHandler::on_message(self, msg) {
+            client.send(reply);
+        }
+        Ok(())
+    }
+}
+
+// A bot's handle on one of its connections to the server.
+pub struct BotClient {
+    name: String,
+    room: String,
+
+    // Feeds the task writing to the server. `None` once the bot has asked to disconnect, which
+    // ends that task (after it writes everything already queued) and with it the connection.
+    tx: Option<mpsc::UnboundedSender<ClientMessage>>,
+}
+
+impl BotClient {
+    // The name the bot gave in its `Handshake`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // The room this connection is in, as of the last `join_room`.
+    pub fn room(&self) -> &str {
+        &self.room
+    }
+
+    // Queue `msg` to be sent to the server. Messages sent after `disconnect` are dropped.
+    pub fn send(&mut self, msg: ClientMessage) {
+        if let Some(ref tx) = self.tx {
+            // This can only fail if the writing task is gone, in which case so is the connection
+            // and there's nobody to send to anyway.
+            let _ = tx.unbounded_send(msg);
+        }
+    }
+
+    // Move this connection to `room`.
+    pub fn join_room(&mut self, room: &str) {
+        self.room = room.to_string();
+        self.send(ClientMessage::Join(room.to_string()));
+    }
+
+    // Close this connection once everything queued so far has been sent.
+    pub fn disconnect(&mut self) {
+        self.tx = None;
+    }
+}
+
+// Connect `bot` to the server at `addr` once for each of `rooms`, identifying with `handshake`.
+// The returned future runs the bot until all of its connections have closed.
+pub fn run<B>(bot: B,
+              addr: &SocketAddr,
+              handshake: Handshake,
+              rooms: &[&str],
+              handle: &Handle)
+              -> Box<Future<Item = (), Error = io::Error>>
+    where B: ChatBot + 'static
+{
+    // Every connection calls into the same bot. We're single-threaded and `on_message` can't be
+    // reentered, so an `Rc<RefCell<_>>` is all the sharing we need.
+    let bot = Rc::new(RefCell::new(bot));
+    let connections = rooms.iter()
+        .map(|room| connect(bot.clone(), addr, handshake.clone(), room, handle))
+        .collect::<Vec<_>>();
+    Box::new(future::join_all(connections).map(|_| ()))
+}
+
+// Run a single one of `bot`'s connections, sitting in `room`.
+fn connect<B>(bot: Rc<RefCell<B>>,
+              addr: &SocketAddr,
+              handshake: Handshake,
+              room: &str,
+              handle: &Handle)
+              -> Box<Future<Item = (), Error = io::Error>>
+    where B: ChatBot + 'static
+{
+    let room = room.to_string();
+
+    // This is the same dance tokio-chat-client does: send our `Handshake`, then swap codecs.
+    let handshake_sent = TcpStream::connect(addr, handle).and_then(|stream| {
+        let name = handshake.name.clone();
+        stream.framed(HandshakeCodec::new())
+            .send(handshake)
+            .map(|handshake_io| (name, handshake_io.into_inner()))
+    });
+
+    Box::new(handshake_sent.and_then(move |(name, socket)| {
+        let (to_server, from_server) = socket.framed(ClientToServerCodec::new()).split();
+        let (tx, rx) = mpsc::unbounded();
+        let mut client = BotClient {
+            name: name,
+            room: DEFAULT_ROOM.to_string(),
+            tx: Some(tx),
+        };
+
+        // Hold off on joining `room` until the server has said something (it always starts by
+        // announcing our connection). Anything we write before then can get caught up in the
+        // server's handshake framing and lost.
+        let mut pending_room = if room != DEFAULT_ROOM { Some(room) } else { None };
+
+        let reader = from_server.for_each(move |msg| {
+            if let Some(room) = pending_room.take() {
+                client.join_room(&room);
+            }
+            bot.borrow_mut()
+                .on_message(&msg, &mut client)
+                .map_err(io::Error::other)
+        });
+
+        // The writer finishes once `rx` runs dry after a `disconnect`, which (via `select`)
+        // drops the reader and closes the connection.
+        let writer = rx
+            .map_err(|()| unreachable!("rx can't fail"))
+            .fold(to_server, |to_server, msg| to_server.send(msg))
+            .map(|_| ());
+
+        reader.select(writer).map(|_| ()).map_err(|(err, _)| err)
+    }))
+}
@@ -0,0 +1,91 @@
+use tokio_chat_common::{emoji, ClientMessage, RoomConfig, DEFAULT_ROOM};
+
+// What the user meant by a line they typed into the input box.
+pub enum Command {
+    Quit,
+    Send(ClientMessage),
+
+    // Send the file at this path to everyone in the current room.
+    SendFile(String),
+
+    // Change our last message to say this instead.
+    EditLast(String),
+
+    // Make this the topic of the room we're in.
+    SetTopic(String),
+
+    // Time how long the server takes to answer a ping.
+    Ping,
+
+    // Stop showing, or start showing again, what this user says.
+    Ignore(String),
+    Unignore(String),
+}
+
+// Lines starting with `/` are commands; anything else is a chat message. Shortcodes in chat (and
+// edits to it) are expanded into emoji; see `emoji::expand`. On failure, returns a message to show
+// the user.
+pub fn parse(line: &str) -> Result<Command, String> {
+    if !line.starts_with('/') {
+        return Ok(Command::Send(ClientMessage::new(emoji::expand(line))));
+    }
+
+    let (command, args) = match line.find(' ') {
+        Some(i) => (&line[..i], line[i + 1..].trim()),
+        None => (line, ""),
+    };
+    match command {
+        "/quit" => Ok(Command::Quit),
+        "/join" if !args.is_empty() => Ok(Command::Send(ClientMessage::Join(args.to_string()))),
+        "/join" => Err("usage: /join room".to_string()),
+        "/create" if !args.is_empty() => {
+            Ok(Command::Send(ClientMessage::CreateRoom {
+                name: args.to_string(),
+                config: RoomConfig::default(),
+            }))
+        }
+        "/create" => Err("usage: /create room".to_string()),
+        "/info" if !args.is_empty() => {
+            Ok(Command::Send(ClientMessage::GetRoomInfo(args.to_string())))
+        }
+        "/info" => Err("usage: /info room".to_string()),
+        "/leave" => Ok(Command::Send(ClientMessage::Join(DEFAULT_ROOM.to_string()))),
+        "/send" if !args.is_empty() => Ok(Command::SendFile(args.to_string())),
+        "/send" => Err("usage: /send path".to_string()),
+        "/away" => {
+            let status = if args.is_empty() { "away" } else { args };
+            Ok(Command::Send(ClientMessage::SetStatus(Some(status.to_string()))))
+        }
+        "/back" => Ok(Command::Send(ClientMessage::SetStatus(None))),
+        "/who" => Ok(Command::Send(ClientMessage::Who)),
+        "/ping" => Ok(Command::Ping),
+        "/ignore" if !args.is_empty() => Ok(Command::Ignore(args.to_string())),
+        "/ignore" => Err("usage: /ignore user".to_string()),
+        "/unignore" if !args.is_empty() => Ok(Command::Unignore(args.to_string())),
+        "/unignore" => Err("usage: /unignore user".to_string()),
+        "/announce" if !args.is_empty() => {
+            Ok(Command::Send(ClientMessage::AdminAnnounce(args.to_string())))
+        }
+        "/announce" => Err("usage: /announce message".to_string()),
+        "/export" if !args.is_empty() => {
+            Ok(Command::Send(ClientMessage::AdminExport(args.to_string())))
+        }
+        "/export" => Err("usage: /export room".to_string()),
+        "/edit" if !args.is_empty() => Ok(Command::EditLast(emoji::expand(args))),
+        "/edit" => Err("usage: /edit new message".to_string()),
+        "/topic" if !args.is_empty() => Ok(Command::SetTopic(args.to_string())),
+        "/topic" => Err("usage: /topic text".to_string()),
+        "/register" => {
+            match args.find(' ') {
+                Some(i) => {
+                    Ok(Command::Send(ClientMessage::Register {
+                        username: args[..i].to_string(),
+                        password: args[i + 1..].trim().to_string(),
+                    }))
+                }
+                None => Err("usage: /register username password".to_string()),
+            }
+        }
+        _ => Err(format!("unknown command {}", command)),
+    }
+}
@@ -0,0 +1,63 @@
+use std::collections::VecDeque;
+
+// The lines the user has entered, most recent last, for calling back up into the input box with
+// the up and down arrows the way a shell does.
+pub struct InputHistory {
+    lines: VecDeque<String>,
+    max_len: usize,
+
+    // Which line is showing: an index into `lines`, or `lines.len()` for the one being typed.
+    position: usize,
+
+    // What was in the input box when the user started going back, so they can come back to it.
+    draft: String,
+}
+
+impl InputHistory {
+    // Remember up to `max_len` lines, forgetting the oldest ones after that.
+    pub fn new(max_len: usize) -> InputHistory {
+        InputHistory {
+            lines: VecDeque::with_capacity(max_len),
+            max_len: max_len,
+            position: 0,
+            draft: String::new(),
+        }
+    }
+
+    // Remember `line`, which was just entered, and go back to the bottom of the history. Blank
+    // lines and repeats of the line before aren't worth keeping.
+    pub fn push(&mut self, line: &str) {
+        let keep = !line.trim().is_empty() && self.lines.back().map_or(true, |last| last != line);
+        if keep && self.max_len > 0 {
+            if self.lines.len() == self.max_len {
+                self.lines.pop_front();
+            }
+            self.lines.push_back(line.to_string());
+        }
+        self.position = self.lines.len();
+        self.draft.clear();
+    }
+
+    // The line before the one showing, which right now reads `current`, or `None` if we're
+    // already at the oldest.
+    pub fn previous(&mut self, current: &str) -> Option<&str> {
+        if self.position == 0 {
+            return None;
+        }
+        if self.position == self.lines.len() {
+            self.draft = current.to_string();
+        }
+        self.position -= 1;
+        Some(&self.lines[self.position])
+    }
+
+    // The line after the one showing (ending with whatever was being typed before going back), or
+    // `None` if we're already there.
+    pub fn next(&mut self) -> Option<&str> {
+        if self.position == self.lines.len() {
+            return None;
+        }
+        self.position += 1;
+        Some(self.lines.get(self.position).unwrap_or(&self.draft))
+    }
+}
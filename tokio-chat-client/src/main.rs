@@ -1,24 +1,34 @@
 //! A chat client written to talk to tokio-chat-server and present messages in a TUI.
 //!
-//! To run this, first start tokio-chat-server in another window, then run
+//! To run this, first start tokio-chat-server (with `--allow-guests`) in another window, then run
 //!
 //!     cargo run -- your_chat_username
 //!
 //! to connect to the server. If all goes well, you should see a textual chat-like interface
 //! with the message `* your_chat_username connected`, and you should be able to type messages.
 //! Start up another instance of this client (probably with a different username) in another
-//! window to confirm messages are being broadcast to all clients.
+//! window to confirm messages are being broadcast to all clients. Chat scrolls by above the input
+//! line, each message stamped with the (UTC) time it arrived, and who's in the room is listed
+//! down the right-hand side. With `--log FILE`, a transcript of everything sent and received is
+//! appended to FILE, one line of JSON per message.
+//!
+//! Run with `--parse-message` instead of a username, it connects to nothing, and instead reads
+//! messages written the way they're displayed (like `[Join] lobby`) from stdin, one per line, and
+//! prints each one's JSON as it would go over the wire, for checking what a message looks like.
 //!
 //! The 10,000-foot view archiecture of this client is that a thread is spawned to run a tokio
 //! reactor with the client connection to the server, that thread is given a
 //! `std::sync::mpsc::Sender` it can use to send messages back to the GUI thread, and the GUI
 //! thread is given a `futures::mpsc::sync::Sender` to send the user's chat messages to the tokio
-//! thread. See the comments in tokio-chat-server for a description of the client/server protocol.
+//! thread. The connection itself is a `tokio_chat_common::client::Client`, which does the
+//! handshake and framing; this is just the terminal on top of it. See the comments in
+//! tokio-chat-server for a description of the client/server protocol.
 //!
 //! Note that the GUI code below is mostly unannotated except where it comes into contact with
 //! tokio-like things.
 extern crate futures;
 extern crate tokio_core;
+extern crate serde_json;
 extern crate cursive;
 extern crate unicode_width;
 extern crate tokio_chat_common;
@@ -28,24 +38,64 @@ use cursive::direction::Direction;
 use cursive::event::{Event, Key};
 use cursive::theme::Theme;
 use cursive::traits::{Boxable, Identifiable, View};
-use cursive::views::{EditView, LinearLayout};
+use cursive::views::{EditView, LinearLayout, TextView};
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use std::net::SocketAddr;
-use tokio_core::io::Io;
 use tokio_core::reactor::Core;
-use tokio_core::net::TcpStream;
 use futures::{Stream, Sink, Future};
 use futures::sync::mpsc;
-use tokio_chat_common::{Handshake, HandshakeCodec, ClientMessage, ServerMessage,
-                        ClientToServerCodec};
+use tokio_chat_common::{Handshake, ClientMessage, ServerMessage, FileAssembly, MessageId,
+                        DEFAULT_ROOM, capability, offer_file};
+use tokio_chat_common::client::{ClientBuilder, PingTimer};
 
 mod chat_view;
+mod command;
+mod history;
 use self::chat_view::ChatView;
+use self::command::Command;
+use self::history::InputHistory;
+
+// How many entered lines the up arrow can reach back through.
+const INPUT_HISTORY_LEN: usize = 100;
+
+// How many columns the list of who's in the room gets.
+const USER_LIST_WIDTH: usize = 20;
+
+// The id of the last chat message of ours the server broadcast, for `/edit`, or `NO_MESSAGE` if
+// we haven't said anything yet. Set by the tokio thread and read by the GUI thread.
+static LAST_SENT: AtomicU64 = AtomicU64::new(NO_MESSAGE);
+const NO_MESSAGE: MessageId = MessageId::MAX;
+
+// The room we're in, for `/topic`, or empty until the server has welcomed us. Likewise set by the
+// tokio thread and read by the GUI thread.
+static ROOM: Mutex<String> = Mutex::new(String::new());
+
+// Who the user has `/ignore`d, for as long as the client runs. What they say still comes in, and
+// they're still counted in the room, but their chat, edits and status changes aren't shown. The
+// GUI thread changes this, and the tokio thread checks it.
+static IGNORED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+fn is_ignored(user: &str) -> bool {
+    IGNORED.lock().expect("the gui thread panicked").contains(user)
+}
+
+// The `/ping`s that haven't been answered yet. The GUI thread sends them, and the tokio thread
+// hears back.
+static PINGS: Mutex<PingTimer> = Mutex::new(PingTimer::new());
 
 // GuiEventSender is a wrapper around an MPSC Sender (NOTE: This is a `std::sync::mpsc::Sender`,
 // _not_ a `futures::sync::mpsc::Sender`!). This allows us to send closures to be run in the
-// Cursive GUI context.
+// Cursive GUI context. Once the user has quit, there's no GUI left to run them, so they're
+// dropped.
 #[derive(Clone)]
 struct GuiEventSender(std::sync::mpsc::Sender<Box<Fn(&mut Cursive) + Send>>);
 
@@ -53,9 +103,7 @@ impl GuiEventSender {
     fn send<F>(&self, f: F)
         where F: Fn(&mut GuiWrapper) + Send + 'static
     {
-        self.0
-            .send(Box::new(move |cursive| f(&mut GuiWrapper::new(cursive))))
-            .expect("gui is gone?");
+        let _ = self.0.send(Box::new(move |cursive| f(&mut GuiWrapper::new(cursive))));
     }
 }
 
@@ -69,18 +117,43 @@ impl<'a> GuiWrapper<'a> {
     // Build up the Cursive UI. `tx` is a `futures::sync::mpsc::Sender` that we use to send
     // client input to the thread managing the tokio connection to the server.
     fn build_ui(&mut self, tx: mpsc::Sender<ClientMessage>) -> GuiEventSender {
+        let history = Rc::new(RefCell::new(InputHistory::new(INPUT_HISTORY_LEN)));
+        let entered = history.clone();
         self.0.add_layer(LinearLayout::vertical()
-            .child(ChatView::new(500)
-                .with_id("chat")
+            .child(LinearLayout::horizontal()
+                .child(ChatView::new(500)
+                    .with_id("chat")
+                    .full_width())
+                .child(TextView::new("")
+                    .with_id("users")
+                    .fixed_width(USER_LIST_WIDTH))
                 .full_height())
             .child(EditView::new()
                 .on_submit(move |cursive, s| {
                     // This is called whenever the user presses "enter" after entering text.
-                    GuiWrapper::new(cursive).handle_entry_input(s, tx.clone());
+                    GuiWrapper::new(cursive).handle_entry_input(s, tx.clone(), &entered);
                 })
                 .with_id("input")
                 .full_width()));
-        for k in &[Key::Home, Key::End, Key::Up, Key::Down, Key::PageDown, Key::PageUp] {
+
+        // The up and down arrows go back and forth through what we've entered; the input box
+        // handles the rest of the editing keys itself.
+        for &(k, back) in &[(Key::Up, true), (Key::Down, false)] {
+            let history = history.clone();
+            self.0.add_global_callback(Event::Key(k), move |s| {
+                let input = s.find_id::<EditView>("input").unwrap();
+                let mut history = history.borrow_mut();
+                let recalled = if back {
+                    history.previous(&input.get_content())
+                } else {
+                    history.next()
+                };
+                if let Some(line) = recalled {
+                    input.set_content(line);
+                }
+            });
+        }
+        for k in &[Key::Home, Key::End, Key::PageDown, Key::PageUp] {
             let e = Event::Key(*k);
             self.0.add_global_callback(e, move |s| {
                 {
@@ -100,17 +173,100 @@ impl<'a> GuiWrapper<'a> {
         chat.append_content(s, false);
     }
 
-    fn handle_entry_input(&mut self, s: &str, tx: mpsc::Sender<ClientMessage>) {
-        if s == "/quit" {
-            self.0.quit();
-            return;
-        }
+    // Show `users` as who's in `room`.
+    fn set_users(&mut self, room: &str, users: &[String]) {
+        let list = self.0.find_id::<TextView>("users").unwrap();
+        list.set_content(format!("{}\n\n{}", room, users.join("\n")));
+    }
 
-        // Here we `wait` on the send to complete. If this fails, it's because the receiving half
+    // Act on the line `s` the user just entered, and add it to their `history` if it made sense.
+    fn handle_entry_input(&mut self,
+                          s: &str,
+                          tx: mpsc::Sender<ClientMessage>,
+                          history: &RefCell<InputHistory>) {
+        let command = command::parse(s);
+        if command.is_ok() {
+            history.borrow_mut().push(s);
+        }
+        let msgs = match command {
+            Ok(Command::Quit) => {
+                self.0.quit();
+                return;
+            }
+            Ok(Command::Send(msg)) => vec![msg],
+            Ok(Command::Ping) => vec![PINGS.lock().expect("the tokio thread panicked").ping()],
+            Ok(Command::Ignore(user)) => {
+                let notice = format!("* ignoring {}", user);
+                IGNORED.lock().expect("the tokio thread panicked").insert(user);
+                self.append_content(notice);
+                self.clear_entry();
+                return;
+            }
+            Ok(Command::Unignore(user)) => {
+                let notice = if IGNORED.lock().expect("the tokio thread panicked").remove(&user) {
+                    format!("* no longer ignoring {}", user)
+                } else {
+                    format!("! you aren't ignoring {}", user)
+                };
+                self.append_content(notice);
+                self.clear_entry();
+                return;
+            }
+            Ok(Command::EditLast(new_body)) => {
+                match LAST_SENT.load(Ordering::SeqCst) {
+                    NO_MESSAGE => {
+                        self.append_content("! you haven't said anything to edit");
+                        self.clear_entry();
+                        return;
+                    }
+                    id => {
+                        vec![ClientMessage::EditMessage {
+                                 id: id,
+                                 new_body: new_body,
+                             }]
+                    }
+                }
+            }
+            Ok(Command::SetTopic(topic)) => {
+                let room = ROOM.lock().expect("the tokio thread panicked").clone();
+                if room.is_empty() {
+                    self.append_content("! you aren't in a room yet");
+                    self.clear_entry();
+                    return;
+                }
+                vec![ClientMessage::SetTopic {
+                         room: room,
+                         topic: topic,
+                     }]
+            }
+            Ok(Command::SendFile(path)) => {
+                match file_messages(&path) {
+                    Ok((msgs, size)) => {
+                        self.append_content(format!("* sending {} ({} bytes)", path, size));
+                        msgs
+                    }
+                    Err(err) => {
+                        self.append_content(format!("! can't send {}: {}", path, err));
+                        self.clear_entry();
+                        return;
+                    }
+                }
+            }
+            Err(err) => {
+                self.append_content(format!("! {}", err));
+                self.clear_entry();
+                return;
+            }
+        };
+
+        // Here we `wait` on each send to complete. If this fails, it's because the receiving half
         // managed by the tokio thread is gone because we've lost our connection to the server, so
         // just give up and quit altogether.
-        if let Err(_) = tx.send(ClientMessage::new(s)).wait() {
-            self.0.quit();
+        for msg in msgs {
+            if let Err(_) = tx.clone().send(msg).wait() {
+                self.0.quit();
+                return;
+            }
         }
 
         self.clear_entry();
@@ -128,14 +284,127 @@ impl<'a> GuiWrapper<'a> {
     }
 }
 
+// Read the file at `path` and build the messages that send it, along with its size.
+fn file_messages(path: &str) -> std::io::Result<(Vec<ClientMessage>, usize)> {
+    // Transfer ids only have to be unique among our own transfers.
+    static NEXT_TRANSFER_ID: AtomicUsize = AtomicUsize::new(0);
+
+    let data = fs::read(path)?;
+    let name = Path::new(path).file_name().map_or(path.into(), |name| name.to_string_lossy());
+    let transfer_id = NEXT_TRANSFER_ID.fetch_add(1, Ordering::SeqCst) as u64;
+    Ok((offer_file(transfer_id, &name, &data), data.len()))
+}
+
+// Handle a piece of a file someone is sending us: start a new `FileAssembly` for an offer, or add
+// a chunk to one, saving the file to the current directory once it's complete. Returns something
+// to show the user, if there's anything worth mentioning.
+fn receive_file(files: &mut HashMap<(String, u64), FileAssembly>,
+                msg: ServerMessage)
+                -> Option<String> {
+    match msg {
+        ServerMessage::FileOffer { from, transfer_id, name, size, chunk_count } => {
+            let content = match FileAssembly::new(name.clone(), size, chunk_count) {
+                Ok(file) => {
+                    let content = format!("* {} is sending {} ({} bytes)", from, name, size);
+                    files.insert((from.clone(), transfer_id), file);
+                    content
+                }
+                Err(err) => format!("! ignoring {} from {}: {}", name, from, err),
+            };
+            // Empty files don't have any chunks coming, so they're already done.
+            finish_file(files, from, transfer_id).or(Some(content))
+        }
+        ServerMessage::FileChunk { from, transfer_id, index, data } => {
+            let key = (from.clone(), transfer_id);
+            let result = match files.get_mut(&key) {
+                // We didn't see (or turned down) the offer, so there's nothing to add this to.
+                None => return None,
+                Some(file) => {
+                    file.add_chunk(index, data).map_err(|err| (file.name().to_string(), err))
+                }
+            };
+            match result {
+                Ok(()) => finish_file(files, from, transfer_id),
+                Err((name, err)) => {
+                    files.remove(&key);
+                    Some(format!("! gave up receiving {} from {}: {}", name, from, err))
+                }
+            }
+        }
+        _ => None,
+    }
+}
+
+// If the file `from` is sending as `transfer_id` has arrived in full, save it.
+fn finish_file(files: &mut HashMap<(String, u64), FileAssembly>,
+               from: String,
+               transfer_id: u64)
+               -> Option<String> {
+    let key = (from, transfer_id);
+    if !files.get(&key).is_some_and(|file| file.is_complete()) {
+        return None;
+    }
+    let file = files.remove(&key).unwrap();
+    let (from, _) = key;
+
+    // Only ever use the last component of the name we were given, and never overwrite anything.
+    let name = match Path::new(file.name()).file_name() {
+        Some(name) => name.to_owned(),
+        None => {
+            return Some(format!("! not saving file from {} with bad name {}", from, file.name()))
+        }
+    };
+    let saved = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&name)
+        .and_then(|mut f| f.write_all(&file.into_bytes()));
+    Some(match saved {
+        Ok(()) => format!("* saved {} from {}", name.to_string_lossy(), from),
+        Err(err) => format!("! couldn't save {} from {}: {}", name.to_string_lossy(), from, err),
+    })
+}
+
 fn main() {
-    let name = {
+    // Our `Handshake` carries our name, any of the token, password, and login token we were
+    // given, and every capability we know how to use. Alongside it is where to keep a transcript,
+    // if anywhere.
+    let (handshake, log) = {
         let mut args = std::env::args();
-        let usage = format!("usage: {} username", args.nth(0).unwrap());
-        args.nth(0).unwrap_or_else(|| {
+        let usage = format!("usage: {0} username [--token secret] [--password password] \
+                             [--auth-token token] [--log file]\n       {0} --parse-message",
+                            args.nth(0).unwrap());
+        let name = args.nth(0).unwrap_or_else(|| {
             println!("{}", usage);
             std::process::exit(1);
-        })
+        });
+        if name == "--parse-message" {
+            parse_messages();
+            return;
+        }
+        let mut handshake = Handshake::new(name).with_capabilities(capability::ALL);
+        let mut log = None;
+        loop {
+            handshake = match (args.next(), args.next()) {
+                (None, _) => break,
+                (Some(ref flag), Some(value)) if flag == "--token" => handshake.with_token(value),
+                (Some(ref flag), Some(value)) if flag == "--password" => {
+                    handshake.with_password(value)
+                }
+                (Some(ref flag), Some(value)) if flag == "--auth-token" => {
+                    handshake.with_auth_token(value)
+                }
+                (Some(ref flag), Some(value)) if flag == "--log" => {
+                    log = Some(value);
+                    handshake
+                }
+                _ => {
+                    println!("{}", usage);
+                    std::process::exit(1);
+                }
+            }
+        }
+        (handshake, log)
     };
     let mut cursive = Cursive::new();
 
@@ -153,40 +422,234 @@ fn main() {
     let gui_events = GuiWrapper::new(&mut cursive).build_ui(tx);
 
     // Start the tokio thread.
-    thread::spawn(move || run_client(name, gui_events, rx));
+    let tokio = thread::spawn(move || run_client(handshake, log, gui_events, rx));
 
-    // Run the GUI.
+    // Run the GUI. Once the user quits, dropping it drops our end of the channel to the tokio
+    // thread, which then hangs up; waiting for it to finish lets it write out the transcript.
     cursive.run();
+    drop(cursive);
+    let _ = tokio.join();
+}
+
+// Print the wire JSON of each message on stdin, written as it's displayed; client messages are
+// tried first, then server ones.
+fn parse_messages() {
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = line.expect("couldn't read stdin");
+        if line.trim().is_empty() {
+            continue;
+        }
+        let json = match line.parse::<ClientMessage>() {
+            Ok(msg) => serde_json::to_string(&msg),
+            Err(client_err) => {
+                match line.parse::<ServerMessage>() {
+                    Ok(msg) => serde_json::to_string(&msg),
+                    Err(server_err) => {
+                        println!("! not a client message ({}) or a server one ({})",
+                                 client_err,
+                                 server_err);
+                        continue;
+                    }
+                }
+            }
+        };
+        println!("{}", json.expect("messages always serialize"));
+    }
 }
 
-fn run_client(name: String, gui: GuiEventSender, rx: mpsc::Receiver<ClientMessage>) {
-    let addr = "127.0.0.1:12345".parse::<SocketAddr>().unwrap();
+// The time of day now, in UTC, as `HH:MM`.
+fn timestamp() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs());
+    format!("{:02}:{:02}", secs / 3600 % 24, secs / 60 % 60)
+}
 
-    // Create the event loop and initiate the connection to the remote server
+fn run_client(handshake: Handshake,
+              log: Option<String>,
+              gui: GuiEventSender,
+              rx: mpsc::Receiver<ClientMessage>) {
+    // Create the event loop and initiate the connection to the server, which is welcomed (or
+    // refused) before we go any further.
     let mut core = Core::new().unwrap();
     let handle = core.handle();
-    let tcp = TcpStream::connect(&addr, &handle);
-
-    // Once we connect, send a `Handshake` with our name.
-    let handshake = tcp.and_then(|stream| {
-        let handshake_io = stream.framed(HandshakeCodec::new());
-
-        // After sending the handshake, convert the framed stream back into its inner socket.
-        handshake_io.send(Handshake::new(name)).map(|handshake_io| handshake_io.into_inner())
-    });
-
-    // Once we've sent our `Handshake`, start listening for messages from either the server (to
-    // send to the GUI thread) or the GUI thread (to send to the server).
-    let client = handshake.and_then(|socket| {
-        let (to_server, from_server) = socket.framed(ClientToServerCodec::new()).split();
+    let our_name = handshake.name.clone();
+    let mut builder = ClientBuilder::new(handshake);
+    if let Some(log) = log {
+        builder = builder.log(log);
+    }
+    let connecting = builder.connect(&handle);
+
+    // Once we're in, start listening for messages from either the server (to send to the GUI
+    // thread) or the GUI thread (to send to the server).
+    let farewells = gui.clone();
+    let client = connecting.and_then(move |client| {
+        let (to_server, from_server) = client.split();
+        let asker = to_server.clone();
+
+        // Files people are partway through sending us, keyed by sender and transfer id.
+        let mut files = HashMap::new();
+
+        // Who sent each message we've seen, so edits can say whose message changed.
+        let mut authors = HashMap::new();
+
+        // The capabilities the server agreed to in its `Welcome`, shared with the writer below so
+        // it doesn't send anything the server would turn away.
+        let capabilities = Rc::new(RefCell::new(Vec::<String>::new()));
+        let agreed = capabilities.clone();
+        let notices = gui.clone();
+
+        // Who's in our room, for the list beside the chat. We ask the server whenever we arrive
+        // somewhere new, and keep the list up to date from what's announced in the meantime. The
+        // answers to the questions we asked ourselves aren't worth showing in the chat, unlike
+        // the answers to `/who`; `quiet_whos` is how many of those are still on their way.
+        let mut members = BTreeSet::new();
+        let mut quiet_whos = 0;
 
         // For each incoming message...
         let reader = from_server.for_each(move |msg| {
+            // ... keeping track of which room we're in, and who else is there...
+            let room = ROOM.lock().expect("the gui thread panicked").clone();
+            let changed = match msg {
+                ServerMessage::UserJoined(ref user, ref joined, _) if *user == our_name => {
+                    *ROOM.lock().expect("the gui thread panicked") = joined.clone();
+                    members.clear();
+                    asker.send(ClientMessage::Who)?;
+                    quiet_whos += 1;
+                    true
+                }
+                ServerMessage::UserJoined(ref user, ref joined, _) if *joined == room => {
+                    members.insert(user.clone())
+                }
+                ServerMessage::UserLeft(ref user, _) |
+                ServerMessage::UserDisconnected(ref user) => members.remove(user),
+                ServerMessage::Users(ref listed, ref users) if *listed == room => {
+                    members = users.iter().map(|user| user.name.clone()).collect();
+                    true
+                }
+                _ => false,
+            };
+            if changed {
+                let room = ROOM.lock().expect("the gui thread panicked").clone();
+                let users = members.iter().cloned().collect::<Vec<_>>();
+                gui.send(move |g| g.set_users(&room, &users));
+            }
+
             // ... convert it to a string for display in the GUI...
             let content = match msg {
-                ServerMessage::Message(from, msg) => format!("{}: {}", from, msg),
+                // We don't try to reconnect if the connection drops, so there's nothing to do
+                // with a resume token; we just note which capabilities we can use, and that we're
+                // in the default room.
+                ServerMessage::Welcome { capabilities, .. } => {
+                    *agreed.borrow_mut() = capabilities;
+                    *ROOM.lock().expect("the gui thread panicked") = DEFAULT_ROOM.to_string();
+                    asker.send(ClientMessage::Who)?;
+                    quiet_whos += 1;
+                    return Ok(());
+                }
+                msg @ ServerMessage::FileOffer { .. } |
+                msg @ ServerMessage::FileChunk { .. } => {
+                    match receive_file(&mut files, msg) {
+                        Some(content) => content,
+                        None => return Ok(()),
+                    }
+                }
+                // There's no rendering Markdown or HTML in a terminal, so they're shown as they
+                // were written.
+                ServerMessage::Message(id, from, msg) |
+                ServerMessage::FormattedMessage { id, from, body: msg, .. } => {
+                    if from == our_name {
+                        LAST_SENT.store(id, Ordering::SeqCst);
+                    }
+                    let content = format!("[{}] <{}> {}", timestamp(), from, msg);
+                    let ignored = is_ignored(&from);
+                    authors.insert(id, from);
+                    if ignored {
+                        return Ok(());
+                    }
+                    content
+                }
+                ServerMessage::MessageEdited { id, new_body } => {
+                    match authors.get(&id) {
+                        Some(from) if is_ignored(from) => return Ok(()),
+                        Some(from) => {
+                            format!("[{}] <{}> (edited) {}", timestamp(), from, new_body)
+                        }
+                        None => format!("* a message was edited: {}", new_body),
+                    }
+                }
                 ServerMessage::UserConnected(user) => format!("* {} connected", user),
                 ServerMessage::UserDisconnected(user) => format!("* {} disconnected", user),
+                ServerMessage::UserJoined(user, room, None) => {
+                    format!("* {} joined {}", user, room)
+                }
+                ServerMessage::UserJoined(user, room, Some(status)) => {
+                    format!("* {} ({}) joined {}", user, status, room)
+                }
+                ServerMessage::StatusChanged(user, _) if is_ignored(&user) => return Ok(()),
+                ServerMessage::StatusChanged(user, Some(status)) => {
+                    format!("* {} is now {}", user, status)
+                }
+                ServerMessage::StatusChanged(user, None) => format!("* {} is back", user),
+                ServerMessage::Users(..) if quiet_whos > 0 => {
+                    quiet_whos -= 1;
+                    return Ok(());
+                }
+                ServerMessage::Users(room, users) => {
+                    let users = users.into_iter()
+                        .map(|user| match user.status {
+                            Some(status) => format!("{} ({})", user.name, status),
+                            None => user.name,
+                        })
+                        .collect::<Vec<_>>();
+                    format!("* in {}: {}", room, users.join(", "))
+                }
+                ServerMessage::UserLeft(user, room) => format!("* {} left {}", user, room),
+                ServerMessage::TopicChanged { room, ref topic } if topic.is_empty() => {
+                    format!("* {} has no topic", room)
+                }
+                ServerMessage::TopicChanged { room, topic } => {
+                    format!("* topic of {}: {}", room, topic)
+                }
+                ServerMessage::RoomInfo { name, config, member_count, topic } => {
+                    let mut content = format!("* {} has {} members", name, member_count);
+                    if let Some(max_members) = config.max_members {
+                        content.push_str(&format!(" (at most {})", max_members));
+                    }
+                    if config.private {
+                        content.push_str(", and is private");
+                    }
+                    if let Some(topic) = topic {
+                        content.push_str(&format!("; its topic is {}", topic));
+                    }
+                    content
+                }
+                ServerMessage::ServerAnnouncement(text) => format!("*** {} ***", text),
+                ServerMessage::Motd(text) => format!("=== {} ===", text),
+                ServerMessage::Token { token, expires_in_secs } => {
+                    format!("* to log in without your password for the next {} hours, use \
+                             --auth-token {}",
+                            expires_in_secs / 3600,
+                            token)
+                }
+                ServerMessage::Registered(user) => {
+                    format!("* {} is registered; log in with --password from now on", user)
+                }
+                ServerMessage::ExportData { room, messages } => {
+                    let mut content = format!("* {} messages on record in {}",
+                                              messages.len(),
+                                              room);
+                    for msg in messages {
+                        content.push_str(&format!("\n  #{} <{}> {}", msg.id, msg.from, msg.body));
+                    }
+                    content
+                }
+                ServerMessage::Pong(id) => {
+                    match PINGS.lock().expect("the gui thread panicked").pong(id) {
+                        Some(latency) => format!("* pong in {} ms", latency.as_millis()),
+                        None => return Ok(()),
+                    }
+                }
+                ServerMessage::Error(code, detail) => format!("! error ({:?}): {}", code, detail),
             };
 
             // ... and send that string _to_ the GUI.
@@ -195,20 +658,37 @@ fn run_client(name: String, gui: GuiEventSender, rx: mpsc::Receiver<ClientMessag
             Ok(())
         });
 
-        // For each incoming message from the GUI thread, send it along to the server. This
-        // code is identical to the `writer` future in tokio-chat-server, but the `rx` here is
-        // being fed from the GUI thread instead of from other futures.
+        // For each incoming message from the GUI thread, send it along to the server.
+        // Anything that needs a capability the server didn't agree to is dropped instead, with a
+        // notice in the GUI (once per file, rather than for each of its chunks).
         let writer = rx
             .map_err(|()| unreachable!("rx can't fail"))
-            .fold(to_server, |to_server, msg| {
-                to_server.send(msg)
+            .filter(move |msg| {
+                let needed = match msg.capability() {
+                    Some(needed) => needed,
+                    None => return true,
+                };
+                if capabilities.borrow().iter().any(|agreed| agreed == needed) {
+                    return true;
+                }
+                if let ClientMessage::FileChunk { .. } = *msg {
+                    return false;
+                }
+                let notice = format!("! the server doesn't support {}", needed);
+                notices.send(move |g| g.append_content(notice.clone()));
+                false
             })
-            .map(|_| ());
+            .for_each(move |msg| to_server.send(msg));
 
         // Use select to allow either the reading or writing half dropping to drop the other
         // half. The `map` and `map_err` here effectively force this drop.
         reader.select(writer).map(|_| ()).map_err(|(err, _)| err)
     });
 
-    core.run(client).unwrap();
+    // However the connection ended, say so, rather than leaving the user typing into the void.
+    let farewell = match core.run(client) {
+        Ok(()) => "! disconnected from the server".to_string(),
+        Err(err) => format!("! {}", err),
+    };
+    farewells.send(move |g| g.append_content(farewell.clone()));
 }
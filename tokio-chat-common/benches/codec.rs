@@ -0,0 +1,118 @@
+// Encode and decode throughput of the codecs in this crate, for chat messages of a few sizes.
+// Each codec runs in every group, so `cargo bench` reports them side by side, both in messages
+// per second and in bytes (of encoded frame) per second.
+//
+// All of the codecs here speak JSON, so they're what gets compared. A frame's u16 length prefix
+// caps its payload at 64KiB, so the large messages are as big as a single frame allows rather
+// than anything bigger.
+
+extern crate criterion;
+extern crate tokio_core;
+extern crate tokio_chat_common;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use tokio_core::io::{Codec, EasyBuf};
+use tokio_chat_common::{ClientToServerCodec, ServerMessage, ServerToClientCodec, BatchCodec,
+                        LenientJson, StreamingDecoder, ClientMessage};
+
+// Message body sizes, in bytes.
+const SIZES: &[(&str, usize)] = &[("small", 32), ("medium", 4 * 1024), ("large", 60 * 1024)];
+
+fn message(size: usize) -> ServerMessage {
+    ServerMessage::Message(0, "benchmark".to_string(), "x".repeat(size))
+}
+
+// Run `bench` twice under `id`, once counting messages and once counting frame bytes.
+fn throughputs<F>(group: &mut criterion::BenchmarkGroup<criterion::measurement::WallTime>,
+                  id: &str,
+                  frame_len: usize,
+                  mut bench: F)
+    where F: FnMut(&mut criterion::Bencher)
+{
+    group.throughput(Throughput::Elements(1));
+    group.bench_function(BenchmarkId::new("messages", id), &mut bench);
+    group.throughput(Throughput::Bytes(frame_len as u64));
+    group.bench_function(BenchmarkId::new("bytes", id), &mut bench);
+}
+
+fn encode<C: Codec>(b: &mut criterion::Bencher, mut codec: C, msg: &C::Out)
+    where C::Out: Clone
+{
+    let mut buf = Vec::new();
+    b.iter_batched(|| msg.clone(),
+                   |msg| {
+                       buf.clear();
+                       codec.encode(msg, &mut buf).unwrap();
+                   },
+                   BatchSize::SmallInput);
+}
+
+fn decode<T, F>(b: &mut criterion::Bencher, frame: &[u8], mut decode: F)
+    where F: FnMut(&mut EasyBuf) -> Option<T>
+{
+    b.iter_batched(|| EasyBuf::from(frame.to_vec()),
+                   |mut buf| decode(&mut buf).expect("a whole frame"),
+                   BatchSize::SmallInput);
+}
+
+fn encoding(c: &mut Criterion) {
+    for &(name, size) in SIZES {
+        let msg = message(size);
+        let frame_len = frame(&msg).len();
+        let mut group = c.benchmark_group(format!("encode_{}", name));
+
+        throughputs(&mut group, "length-prefixed", frame_len, |b| {
+            encode(b, ServerToClientCodec::new(), &msg)
+        });
+        throughputs(&mut group, "lenient", frame_len, |b| {
+            encode(b, LenientJson::<ClientMessage, ServerMessage>::new(), &msg)
+        });
+        // A batch of one, which is what `BatchEncoder` sends when traffic is light.
+        let batch = vec![msg.clone()];
+        let batch_len = {
+            let mut buf = Vec::new();
+            let mut codec = BatchCodec::<ClientMessage, ServerMessage>::new();
+            codec.encode(batch.clone(), &mut buf).unwrap();
+            buf.len()
+        };
+        throughputs(&mut group, "batch", batch_len, |b| {
+            encode(b, BatchCodec::<ClientMessage, ServerMessage>::new(), &batch)
+        });
+        group.finish();
+    }
+}
+
+fn decoding(c: &mut Criterion) {
+    for &(name, size) in SIZES {
+        let frame = frame(&message(size));
+        let mut group = c.benchmark_group(format!("decode_{}", name));
+
+        throughputs(&mut group, "length-prefixed", frame.len(), |b| {
+            let mut codec = ClientToServerCodec::new();
+            decode(b, &frame, |buf| codec.decode(buf).unwrap())
+        });
+        throughputs(&mut group, "lenient", frame.len(), |b| {
+            let mut codec = LenientJson::<ServerMessage, ClientMessage>::new();
+            decode(b, &frame, |buf| codec.decode(buf).unwrap().map(|msg| msg.unwrap()))
+        });
+        throughputs(&mut group, "streaming", frame.len(), |b| {
+            let mut decoder = StreamingDecoder::<ServerMessage>::new();
+            decode(b, &frame, |buf| decoder.decode(buf).unwrap())
+        });
+        throughputs(&mut group, "batch", frame.len(), |b| {
+            let mut codec = BatchCodec::<ServerMessage, ClientMessage>::new();
+            decode(b, &frame, |buf| codec.decode(buf).unwrap())
+        });
+        group.finish();
+    }
+}
+
+// `msg` encoded as a complete frame.
+fn frame(msg: &ServerMessage) -> Vec<u8> {
+    let mut buf = Vec::new();
+    ServerToClientCodec::new().encode(msg.clone(), &mut buf).unwrap();
+    buf
+}
+
+criterion_group!(benches, encoding, decoding);
+criterion_main!(benches);
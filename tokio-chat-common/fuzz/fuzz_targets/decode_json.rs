@@ -0,0 +1,28 @@
+// Feed arbitrary bytes to `LengthPrefixedJson::decode`. Whatever the bytes are, decoding should
+// produce messages, ask for more input, or fail with an error; it must never panic. Run with
+//
+//     cargo fuzz run decode_json
+//
+// from tokio-chat-common.
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate tokio_core;
+extern crate tokio_chat_common;
+
+use tokio_core::io::{Codec, EasyBuf};
+use tokio_chat_common::{ClientMessage, LengthPrefixedJson, ServerMessage};
+
+fuzz_target!(|data: &[u8]| {
+    let mut codec = LengthPrefixedJson::<ClientMessage, ServerMessage>::new();
+    let mut buf = EasyBuf::from(data.to_vec());
+
+    // The input may hold several frames, so keep going until the codec runs out of complete
+    // frames or gives up. Every `Ok(Some(_))` consumes a frame, so this always terminates.
+    loop {
+        match codec.decode(&mut buf) {
+            Ok(Some(_)) => {}
+            Ok(None) | Err(_) => break,
+        }
+    }
+});
@@ -0,0 +1,33 @@
+// Feed `LengthPrefixedJson::decode` a frame that claims to be enormous, followed by arbitrary
+// bytes, and make sure the max frame length guard turns it away up front: `decode` must fail
+// without waiting for (or consuming) any of the payload. Run with
+//
+//     cargo fuzz run decode_large_frame
+//
+// from tokio-chat-common.
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate tokio_core;
+extern crate tokio_chat_common;
+
+use tokio_core::io::{Codec, EasyBuf};
+use tokio_chat_common::{ClientMessage, LengthPrefixedJson, ServerMessage};
+
+const MAX_FRAME_LEN: usize = 1024;
+
+fuzz_target!(|data: &[u8]| {
+    let mut codec = LengthPrefixedJson::<ClientMessage, ServerMessage>::new()
+        .with_max_frame_len(MAX_FRAME_LEN);
+
+    // A huge big-endian u32 length. Its top two bytes are what the codec reads as the frame's
+    // u16 length prefix, and they're always well over `MAX_FRAME_LEN`.
+    let len = 0xffff_0000u32 | data.len() as u32 & 0xffff;
+    let mut bytes = vec![(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+    bytes.extend_from_slice(data);
+    let total = bytes.len();
+
+    let mut buf = EasyBuf::from(bytes);
+    assert!(codec.decode(&mut buf).is_err(), "oversized frame was not rejected");
+    assert_eq!(buf.len(), total, "oversized frame was partly consumed");
+});
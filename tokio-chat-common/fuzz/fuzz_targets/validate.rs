@@ -0,0 +1,36 @@
+// Feed arbitrary bytes to `LengthPrefixedJson::validate`, and check it against `decode`: it must
+// never panic, and must say a complete frame is there exactly when `decode` doesn't ask for more
+// input. Run with
+//
+//     cargo fuzz run validate
+//
+// from tokio-chat-common.
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate tokio_core;
+extern crate tokio_chat_common;
+
+use tokio_core::io::{Codec, EasyBuf};
+use tokio_chat_common::{ClientMessage, LengthPrefixedJson, ServerMessage};
+
+fuzz_target!(|data: &[u8]| {
+    let mut codec = LengthPrefixedJson::<ClientMessage, ServerMessage>::new();
+    let mut buf = EasyBuf::from(data.to_vec());
+
+    // Walk through the frames the input holds, validating each before decoding it.
+    loop {
+        let validated = codec.validate(&buf);
+        let len_before = buf.len();
+        let decoded = codec.decode(&mut buf);
+        match (validated, decoded) {
+            (Ok(None), Ok(None)) => break,
+            (Ok(Some(len)), Ok(Some(_))) => assert_eq!(len, len_before - buf.len()),
+            // Valid JSON needn't be a message.
+            (Ok(Some(_)), Err(_)) | (Err(_), Err(_)) => break,
+            (validated, decoded) => {
+                panic!("validated as {:?} but decoded as {:?}", validated, decoded)
+            }
+        }
+    }
+});
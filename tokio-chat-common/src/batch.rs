@@ -0,0 +1,283 @@
+use serde::{Serialize, Deserialize};
+use serde_json::{self, Value};
+use tokio_core::io::{Codec, EasyBuf};
+use tokio_core::reactor::{Handle, Timeout};
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend};
+
+use std::collections::VecDeque;
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::time::Duration;
+
+use codec::{check_depth, check_len, decode_frame, encode_frame, frame_len, DEFAULT_MAX_DEPTH,
+            MAX_FRAME_LEN};
+
+// How eagerly a `BatchEncoder` flushes: a batch is sent as soon as it holds `max_batch_size`
+// messages or `max_batch_delay_ms` milliseconds have passed since its first message was queued,
+// whichever comes first.
+#[derive(Debug, Clone, Copy)]
+pub struct BatchConfig {
+    pub max_batch_size: usize,
+    pub max_batch_delay_ms: u64,
+}
+
+impl Default for BatchConfig {
+    fn default() -> BatchConfig {
+        BatchConfig {
+            max_batch_size: 32,
+            max_batch_delay_ms: 10,
+        }
+    }
+}
+
+// `BatchEncoder` wraps a `Sink` of `Vec<T>`s (typically a socket framed with `BatchCodec`) and
+// turns it into a `Sink` of individual `T`s, coalescing messages that are sent close together
+// into a single frame. This saves a socket write (and a length prefix) per message under load.
+//
+// Note that a partial batch is only flushed out of `poll_complete`, which won't report that the
+// sink is flushed until the batch's delay has passed. That means this should be fed with
+// something like `Stream::forward` that keeps calling `start_send` while items are available,
+// rather than `Sink::send`, which waits for a full flush after every single item.
+pub struct BatchEncoder<S, T> {
+    inner: S,
+    config: BatchConfig,
+    handle: Handle,
+    batch: Vec<T>,
+
+    // Fires when the oldest message in `batch` has waited `max_batch_delay_ms`. `None` when
+    // `batch` is empty, or when the deadline has passed but `inner` wasn't ready to take the
+    // batch yet (in which case we hand it over as soon as `inner` can accept it).
+    deadline: Option<Timeout>,
+}
+
+impl<S, T> BatchEncoder<S, T>
+    where S: Sink<SinkItem = Vec<T>>,
+          S::SinkError: From<io::Error>
+{
+    pub fn new(inner: S, config: BatchConfig, handle: &Handle) -> BatchEncoder<S, T> {
+        BatchEncoder {
+            inner: inner,
+            config: config,
+            handle: handle.clone(),
+            batch: Vec::with_capacity(config.max_batch_size),
+            deadline: None,
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn is_full(&self) -> bool {
+        self.batch.len() >= self.config.max_batch_size
+    }
+
+    // Try to hand the current batch to `inner`. If `inner` isn't ready for it, the batch stays
+    // queued and we'll try again on the next `start_send` or `poll_complete`.
+    fn flush_batch(&mut self) -> Result<(), S::SinkError> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+
+        let batch = mem::take(&mut self.batch);
+        match self.inner.start_send(batch)? {
+            AsyncSink::Ready => {
+                self.batch.reserve(self.config.max_batch_size);
+                self.deadline = None;
+            }
+            AsyncSink::NotReady(batch) => self.batch = batch,
+        }
+        Ok(())
+    }
+
+    // Has the current batch waited long enough? Polling the timeout also registers our task to
+    // be woken when it fires.
+    fn deadline_passed(&mut self) -> Result<bool, S::SinkError> {
+        if self.batch.is_empty() {
+            return Ok(false);
+        }
+        let passed = match self.deadline {
+            Some(ref mut deadline) => deadline.poll()?.is_ready(),
+            None => true,
+        };
+        if passed {
+            self.deadline = None;
+        }
+        Ok(passed)
+    }
+}
+
+impl<S, T> Sink for BatchEncoder<S, T>
+    where S: Sink<SinkItem = Vec<T>>,
+          S::SinkError: From<io::Error>
+{
+    type SinkItem = T;
+    type SinkError = S::SinkError;
+
+    fn start_send(&mut self, item: T) -> StartSend<T, S::SinkError> {
+        // If the previous batch filled up but `inner` couldn't take it, try again; if it still
+        // can't, apply backpressure.
+        if self.is_full() {
+            self.flush_batch()?;
+            if self.is_full() {
+                return Ok(AsyncSink::NotReady(item));
+            }
+        }
+
+        // The first message in a batch starts the clock.
+        if self.batch.is_empty() {
+            let delay = Duration::from_millis(self.config.max_batch_delay_ms);
+            self.deadline = Some(Timeout::new(delay, &self.handle)?);
+        }
+
+        self.batch.push(item);
+        if self.is_full() {
+            self.flush_batch()?;
+        }
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), S::SinkError> {
+        if self.is_full() || self.deadline_passed()? {
+            self.flush_batch()?;
+        }
+
+        try_ready!(self.inner.poll_complete());
+
+        // Anything still in `batch` is either waiting on its deadline (which we're registered
+        // to be woken for) or waiting on `inner` (which `inner` will wake us for).
+        if self.batch.is_empty() {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    fn close(&mut self) -> Poll<(), S::SinkError> {
+        // No point waiting out the deadline if we're shutting down.
+        self.flush_batch()?;
+        if !self.batch.is_empty() {
+            try_ready!(self.inner.poll_complete());
+            return Ok(Async::NotReady);
+        }
+        self.inner.close()
+    }
+}
+
+// `BatchCodec` uses the same length-prefixed framing as `LengthPrefixedJson`, but sends
+// `Vec<Out>`s as a single frame holding a JSON array. On the decoding side, a frame whose JSON root
+// is an array is unpacked into its individual messages, and any other frame is decoded as a single
+// message, so a `BatchCodec` can talk to a peer using either `BatchEncoder` or plain
+// `LengthPrefixedJson`. (This does mean `In` itself must not serialize as a JSON array.)
+//
+// Frames are held to the same limits as `LengthPrefixedJson`'s, on their length and their
+// nesting, whichever kind they are. A batch is all or nothing: if any message in it doesn't
+// decode, the frame fails as a whole, and none of the batch is handed out.
+pub struct BatchCodec<In, Out>
+    where In: Serialize + Deserialize,
+          Out: Serialize + Deserialize
+{
+    max_frame_len: usize,
+    max_depth: usize,
+
+    // Messages unpacked from a batch frame that haven't been handed out yet.
+    pending: VecDeque<In>,
+    _out: PhantomData<Out>,
+}
+
+impl<In, Out> BatchCodec<In, Out>
+    where In: Serialize + Deserialize,
+          Out: Serialize + Deserialize
+{
+    pub fn new() -> BatchCodec<In, Out> {
+        BatchCodec {
+            max_frame_len: MAX_FRAME_LEN,
+            max_depth: DEFAULT_MAX_DEPTH,
+            pending: VecDeque::new(),
+            _out: PhantomData,
+        }
+    }
+
+    // See `LengthPrefixedJson::with_max_frame_len`.
+    pub fn with_max_frame_len(mut self, max_frame_len: usize) -> BatchCodec<In, Out> {
+        self.max_frame_len = max_frame_len;
+        self
+    }
+
+    // See `LengthPrefixedJson::with_max_depth`.
+    pub fn with_max_depth(mut self, max_depth: usize) -> BatchCodec<In, Out> {
+        self.max_depth = max_depth;
+        self
+    }
+}
+
+impl<In, Out> Default for BatchCodec<In, Out>
+    where In: Serialize + Deserialize,
+          Out: Serialize + Deserialize
+{
+    fn default() -> BatchCodec<In, Out> {
+        BatchCodec::new()
+    }
+}
+
+// Written out rather than derived, which would insist on `In` and `Out` being `Clone` and copy
+// the messages left over from the last batch. Those belong to the stream this codec was decoding,
+// so a clone starts out with only the settings, ready for a stream of its own.
+impl<In, Out> Clone for BatchCodec<In, Out>
+    where In: Serialize + Deserialize,
+          Out: Serialize + Deserialize
+{
+    fn clone(&self) -> BatchCodec<In, Out> {
+        BatchCodec::new().with_max_frame_len(self.max_frame_len).with_max_depth(self.max_depth)
+    }
+}
+
+impl<In, Out> Codec for BatchCodec<In, Out>
+    where In: Serialize + Deserialize,
+          Out: Serialize + Deserialize
+{
+    type In = In;
+    type Out = Vec<Out>;
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Self::In>> {
+        // Hand out anything left over from the last batch before looking at new bytes.
+        if let Some(msg) = self.pending.pop_front() {
+            return Ok(Some(msg));
+        }
+
+        let invalid = |err| io::Error::new(io::ErrorKind::InvalidData, err);
+        loop {
+            match frame_len(buf) {
+                Some(len) => check_len(len, self.max_frame_len)?,
+                None => return Ok(None),
+            };
+            let msg_buf = match decode_frame(buf) {
+                Some(msg_buf) => msg_buf,
+                None => return Ok(None),
+            };
+
+            let value: Value = check_depth(msg_buf.as_ref(), self.max_depth)
+                .and_then(|()| serde_json::from_slice(msg_buf.as_ref()))
+                .map_err(&invalid)?;
+            match value {
+                Value::Array(values) => {
+                    self.pending = values.into_iter()
+                        .map(serde_json::from_value)
+                        .collect::<Result<_, _>>()
+                        .map_err(&invalid)?;
+                    // An empty batch is legal, if pointless. It doesn't produce anything, so go
+                    // around again in case another frame is already sitting in `buf`.
+                    if let Some(msg) = self.pending.pop_front() {
+                        return Ok(Some(msg));
+                    }
+                }
+                value => return serde_json::from_value(value).map(Some).map_err(&invalid),
+            }
+        }
+    }
+
+    fn encode(&mut self, msgs: Vec<Out>, buf: &mut Vec<u8>) -> io::Result<()> {
+        encode_frame(&msgs, buf)
+    }
+}
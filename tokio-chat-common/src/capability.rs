@@ -0,0 +1,46 @@
+// Optional protocol features, named so that clients and servers can say which ones they support.
+// A client lists its capabilities in its `Handshake`; the server answers in its `Welcome` with the
+// ones they have in common, and from then on neither side sends the other messages that need a
+// capability outside that set. (See `ClientMessage::capability` and `ServerMessage::capability`.)
+// Peers that list nothing get the core protocol: chat, rooms, and user lists.
+
+// Sending and receiving files (`FileOffer` and `FileChunk`).
+pub const FILE_TRANSFER: &str = "file-transfer";
+
+// Setting a status and hearing about others' (`SetStatus` and `StatusChanged`).
+pub const STATUS: &str = "status";
+
+// Operators' announcements (`AdminAnnounce` and `ServerAnnouncement`).
+pub const ANNOUNCEMENTS: &str = "announcements";
+
+// Editing messages after they've been sent (`EditMessage` and `MessageEdited`).
+pub const EDITS: &str = "edits";
+
+// Setting rooms' topics and hearing what they are (`SetTopic` and `TopicChanged`).
+pub const TOPICS: &str = "topics";
+
+// Operators' exports of rooms' recent chat (`AdminExport` and `ExportData`).
+pub const EXPORT: &str = "export";
+
+// Checking the connection is still alive (`Ping` and `Pong`).
+pub const HEARTBEAT: &str = "heartbeat";
+
+// Chat in Markdown or HTML (`FormattedMessage`).
+pub const FORMATTING: &str = "formatting";
+
+// Creating rooms with settings of their own, and asking what a room's are (`CreateRoom`,
+// `GetRoomInfo` and `RoomInfo`).
+pub const ROOMS: &str = "rooms";
+
+// Every capability this version of the protocol knows about.
+pub const ALL: &[&str] = &[FILE_TRANSFER, STATUS, ANNOUNCEMENTS, EDITS, TOPICS, EXPORT,
+                            HEARTBEAT, FORMATTING, ROOMS];
+
+// The capabilities in both `ours` and `theirs`, in the order they appear in `ours`.
+pub fn negotiate<S: AsRef<str>, T: AsRef<str>>(ours: &[S], theirs: &[T]) -> Vec<String> {
+    ours.iter()
+        .map(|capability| capability.as_ref())
+        .filter(|capability| theirs.iter().any(|theirs| theirs.as_ref() == *capability))
+        .map(|capability| capability.to_string())
+        .collect()
+}
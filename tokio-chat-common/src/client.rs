@@ -0,0 +1,627 @@
+// A ready-made connection to tokio-chat-server, for programs that want to chat without doing the
+// handshake and framing themselves. Configure one with a `ClientBuilder`:
+//
+//     let connecting = ClientBuilder::new(Handshake::new("alice"))
+//         .server(addr)
+//         .reconnect(ReconnectConfig::default())
+//         .heartbeat(HeartbeatConfig::default())
+//         .connect(&handle);
+//
+// which resolves to a `Client` once the server has welcomed it (or, for a guest that needs nothing
+// more, `Client::connect(addr, "alice", &handle)` does the same). The connection itself is a task
+// of its own on the reactor, so the `Client` only ever talks to it through channels: `send` queues
+// messages for it to write, and `recv` is the stream of what the server sends, starting with the
+// `Welcome`.
+//
+// With `reconnect`, a connection that drops is opened again, resuming the session with the resume
+// token from the last `Welcome`, so the server replays the chat missed in the meantime; the new
+// `Welcome` comes through `recv` like everything else. With `heartbeat`, the client also pings a
+// server that supports it (see `capability::HEARTBEAT`) now and then, and counts the connection
+// as dropped once it's heard nothing at all for too long. With `log`, everything the client sends
+// and receives is written to a transcript file as it goes.
+
+use std::cell::{Cell, RefCell};
+use std::error;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::{future, Async, Future, Poll, Sink, Stream};
+use futures::future::Loop;
+use futures::sync::mpsc;
+use tokio_core::io::{Codec, Framed, Io};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Interval, Timeout};
+
+use serde_json;
+
+use {capability, rfc3339, ClientMessage, ClientToServerCodec, ErrorCode, Handshake,
+     HandshakeCodec, ServerMessage};
+
+// Where `ClientBuilder` connects unless it's told otherwise: where tokio-chat-server listens by
+// default.
+pub const DEFAULT_SERVER: &str = "127.0.0.1:12345";
+
+// Why a `Client` couldn't connect, or stopped being connected.
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+
+    // The server turned the handshake down, with this error.
+    Refused(ErrorCode, String),
+
+    // The server answered the handshake with something besides a `Welcome` or an `Error`.
+    UnexpectedGreeting(ServerMessage),
+
+    // We heard nothing from the server for longer than the heartbeat allows.
+    TimedOut,
+
+    // The server hung up, or, for `Client::send`, the connection is gone one way or another.
+    Closed,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ClientError::Io(ref err) => write!(f, "{}", err),
+            ClientError::Refused(code, ref detail) => {
+                write!(f, "the server refused us ({:?}): {}", code, detail)
+            }
+            ClientError::UnexpectedGreeting(ref msg) => {
+                write!(f, "the server greeted us with {:?}", msg)
+            }
+            ClientError::TimedOut => write!(f, "the server stopped answering"),
+            ClientError::Closed => write!(f, "the connection is closed"),
+        }
+    }
+}
+
+impl error::Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(err: io::Error) -> ClientError {
+        ClientError::Io(err)
+    }
+}
+
+// How to go about a dropped connection: wait `delay`, then try opening it again, up to `attempts`
+// times in a row before giving up.
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectConfig {
+    pub attempts: u32,
+    pub delay: Duration,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> ReconnectConfig {
+        ReconnectConfig {
+            attempts: 5,
+            delay: Duration::from_secs(1),
+        }
+    }
+}
+
+// Ping the server every `interval`, and count the connection as dropped once nothing at all has
+// come from it for `timeout`.
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> HeartbeatConfig {
+        HeartbeatConfig {
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(90),
+        }
+    }
+}
+
+// Configures a `Client`. Anything left unset keeps its default: `DEFAULT_SERVER`, the standard
+// codec, no reconnecting, no heartbeat and no transcript.
+pub struct ClientBuilder<C> {
+    handshake: Handshake,
+    server: SocketAddr,
+    codec: C,
+    reconnect: Option<ReconnectConfig>,
+    heartbeat: Option<HeartbeatConfig>,
+    log: Option<PathBuf>,
+}
+
+impl ClientBuilder<ClientToServerCodec> {
+    // A client that introduces itself with `handshake`.
+    pub fn new(handshake: Handshake) -> ClientBuilder<ClientToServerCodec> {
+        ClientBuilder {
+            handshake: handshake,
+            server: DEFAULT_SERVER.parse().expect("DEFAULT_SERVER is an address"),
+            codec: ClientToServerCodec::new(),
+            reconnect: None,
+            heartbeat: None,
+            log: None,
+        }
+    }
+}
+
+impl<C> ClientBuilder<C>
+    where C: Codec<In = ServerMessage, Out = ClientMessage> + Clone + 'static
+{
+    pub fn server(mut self, addr: SocketAddr) -> ClientBuilder<C> {
+        self.server = addr;
+        self
+    }
+
+    // Frame messages after the handshake with `codec`, or a clone of it for each reconnection.
+    // (The handshake itself always goes with a `HandshakeCodec`.)
+    pub fn codec<D>(self, codec: D) -> ClientBuilder<D>
+        where D: Codec<In = ServerMessage, Out = ClientMessage> + Clone + 'static
+    {
+        ClientBuilder {
+            handshake: self.handshake,
+            server: self.server,
+            codec: codec,
+            reconnect: self.reconnect,
+            heartbeat: self.heartbeat,
+            log: self.log,
+        }
+    }
+
+    pub fn reconnect(mut self, config: ReconnectConfig) -> ClientBuilder<C> {
+        self.reconnect = Some(config);
+        self
+    }
+
+    // Keep an eye on the connection as `config` says. This adds `capability::HEARTBEAT` to the
+    // handshake, if it isn't there already; a server that doesn't agree to it isn't pinged.
+    pub fn heartbeat(mut self, config: HeartbeatConfig) -> ClientBuilder<C> {
+        if !self.handshake.capabilities.iter().any(|c| c == capability::HEARTBEAT) {
+            self.handshake.capabilities.push(capability::HEARTBEAT.to_string());
+        }
+        self.heartbeat = Some(config);
+        self
+    }
+
+    // Append a transcript of the connection to the file at `path`: one line of JSON for each
+    // message, sent or received, with when it was. (Heartbeat pings and their answers are left
+    // out.) Lines are buffered, and written out once the connection ends, if not before.
+    pub fn log<P: AsRef<Path>>(mut self, path: P) -> ClientBuilder<C> {
+        self.log = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    // Connect and handshake, and once the server has welcomed us, run the connection as a task of
+    // its own on `handle`.
+    pub fn connect(self, handle: &Handle) -> Box<Future<Item = Client, Error = ClientError>> {
+        let server = self.server;
+        self.connect_with(handle, move |handle| Box::new(TcpStream::connect(&server, handle)))
+    }
+
+    // Like `connect`, but over whatever `transport` opens, rather than a TCP connection to the
+    // `server`. It's called each time the connection is opened, reconnections included; see
+    // `testing::MockServer` for one that isn't TCP at all.
+    pub fn connect_with<T, F>(self,
+                              handle: &Handle,
+                              transport: F)
+                              -> Box<Future<Item = Client, Error = ClientError>>
+        where T: Io + 'static,
+              F: Fn(&Handle) -> Box<Future<Item = T, Error = io::Error>> + 'static
+    {
+        let handle = handle.clone();
+        let log = match self.log {
+            Some(ref path) => {
+                match OpenOptions::new().append(true).create(true).open(path) {
+                    Ok(file) => Some(RefCell::new(BufWriter::new(file))),
+                    Err(err) => return Box::new(future::err(err.into())),
+                }
+            }
+            None => None,
+        };
+        let opened = open(transport(&handle), self.handshake.clone(), self.codec.clone());
+        Box::new(opened.map(move |(framed, welcome)| {
+            let (outbound_tx, outbound_rx) = mpsc::unbounded();
+            let (inbound_tx, inbound_rx) = mpsc::unbounded();
+            let connection = Rc::new(Connection {
+                settings: self,
+                transport: Box::new(transport),
+                handle: handle.clone(),
+                outbound: RefCell::new(outbound_rx),
+                inbound: inbound_tx,
+                resume_token: RefCell::new(None),
+                heartbeat_agreed: Cell::new(false),
+                last_heard: Cell::new(Instant::now()),
+                next_ping: Cell::new(0),
+                log: log,
+            });
+            connection.hear(welcome);
+            handle.spawn(run(connection, framed));
+            Client {
+                outbound: Sender(Some(outbound_tx)),
+                inbound: Incoming(inbound_rx),
+            }
+        }))
+    }
+}
+
+// A connection to the server, made by a `ClientBuilder`. Dropping it closes the connection, as
+// `close` does.
+pub struct Client {
+    outbound: Sender,
+    inbound: Incoming,
+}
+
+impl Client {
+    // Connect to the server at `addr` as `nickname`, asking for every capability there is, as a
+    // guest (so the server has to allow them) and without reconnecting. For anything more, use a
+    // `ClientBuilder`.
+    pub fn connect(addr: SocketAddr,
+                   nickname: &str,
+                   handle: &Handle)
+                   -> Box<Future<Item = Client, Error = ClientError>> {
+        ClientBuilder::new(Handshake::new(nickname).with_capabilities(capability::ALL))
+            .server(addr)
+            .connect(handle)
+    }
+
+    // Queue `msg` to be sent to the server. While a dropped connection is being reopened, messages
+    // wait until it's back.
+    pub fn send(&self, msg: ClientMessage) -> Result<(), ClientError> {
+        self.outbound.send(msg)
+    }
+
+    // What the server sends us. The stream ends after `close`, or fails, once, if the connection
+    // drops and can't be reopened.
+    pub fn recv(&mut self) -> &mut Incoming {
+        &mut self.inbound
+    }
+
+    // Hang up, once everything queued so far has been sent.
+    pub fn close(&mut self) {
+        self.outbound = Sender(None);
+    }
+
+    // Separate the sending and receiving halves, so that they can be handed to different parts of
+    // a program. The connection is closed once every clone of the `Sender` is dropped.
+    pub fn split(self) -> (Sender, Incoming) {
+        (self.outbound, self.inbound)
+    }
+}
+
+// The sending half of a `Client`; see `Client::split`.
+#[derive(Clone)]
+pub struct Sender(Option<mpsc::UnboundedSender<ClientMessage>>);
+
+impl Sender {
+    // As `Client::send`.
+    pub fn send(&self, msg: ClientMessage) -> Result<(), ClientError> {
+        match self.0 {
+            Some(ref outbound) => outbound.unbounded_send(msg).map_err(|_| ClientError::Closed),
+            None => Err(ClientError::Closed),
+        }
+    }
+}
+
+// The messages a `Client` receives; see `Client::recv`.
+pub struct Incoming(mpsc::UnboundedReceiver<Result<ServerMessage, ClientError>>);
+
+impl Stream for Incoming {
+    type Item = ServerMessage;
+    type Error = ClientError;
+
+    fn poll(&mut self) -> Poll<Option<ServerMessage>, ClientError> {
+        match self.0.poll().map_err(rx_failed)? {
+            Async::Ready(Some(Ok(msg))) => Ok(Async::Ready(Some(msg))),
+            Async::Ready(Some(Err(err))) => Err(err),
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+// Times how long the server takes to answer `ClientMessage::Ping`s, for showing the user their
+// latency. Each ping is numbered with the time it's sent at, in microseconds since the epoch, so
+// its `Pong` can't be mistaken for the answer to any other ping, overlapping or not, nor for the
+// answer to a `Client`'s heartbeat ping (those are counted up from 0).
+pub struct PingTimer {
+    // Pings that haven't been answered yet, with when they were sent.
+    sent: Vec<(u64, Instant)>,
+}
+
+impl PingTimer {
+    pub const fn new() -> PingTimer {
+        PingTimer { sent: Vec::new() }
+    }
+
+    // A ping to send the server now.
+    pub fn ping(&mut self) -> ClientMessage {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_micros());
+        let mut id = now as u64;
+        // Pings sent within the same microsecond (or while the clock's being turned back) still
+        // need numbers of their own.
+        while self.sent.iter().any(|&(sent, _)| sent == id) {
+            id += 1;
+        }
+        self.sent.push((id, Instant::now()));
+        ClientMessage::Ping(id)
+    }
+
+    // How long the server took to answer the ping numbered `id`, which it just has, with a
+    // `ServerMessage::Pong`. That's `None` for anything besides the first answer to one of ours.
+    pub fn pong(&mut self, id: u64) -> Option<Duration> {
+        let i = self.sent.iter().position(|&(sent, _)| sent == id)?;
+        Some(self.sent.swap_remove(i).1.elapsed())
+    }
+}
+
+impl Default for PingTimer {
+    fn default() -> PingTimer {
+        PingTimer::new()
+    }
+}
+
+// What the task running the connection keeps, across reconnections.
+struct Connection<C, T> {
+    settings: ClientBuilder<C>,
+    transport: Box<Fn(&Handle) -> Box<Future<Item = T, Error = io::Error>>>,
+    handle: Handle,
+
+    // What the `Client` has queued for the server, and where to pass on what comes back.
+    outbound: RefCell<mpsc::UnboundedReceiver<ClientMessage>>,
+    inbound: mpsc::UnboundedSender<Result<ServerMessage, ClientError>>,
+
+    // What the last `Welcome` said: the token to resume with, and whether to ping.
+    resume_token: RefCell<Option<String>>,
+    heartbeat_agreed: Cell<bool>,
+
+    // When the server last sent us anything, and the number for our next heartbeat ping.
+    last_heard: Cell<Instant>,
+    next_ping: Cell<u64>,
+
+    // Where the transcript goes, if we're keeping one.
+    log: Option<RefCell<BufWriter<File>>>,
+}
+
+// A line of a transcript: a message the `Client` sent or received, and when.
+#[derive(Serialize)]
+struct Transcribed<'a> {
+    #[serde(serialize_with = "rfc3339::serialize")]
+    at: SystemTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sent: Option<&'a ClientMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    received: Option<&'a ServerMessage>,
+}
+
+impl<C, T> Connection<C, T> {
+    // Take note of `msg`, which just came from the server, and pass it on to the `Client`. The
+    // answers to our heartbeat pings are just for us.
+    fn hear(&self, msg: ServerMessage) {
+        self.last_heard.set(Instant::now());
+        match msg {
+            ServerMessage::Welcome { ref resume_token, ref capabilities } => {
+                *self.resume_token.borrow_mut() = Some(resume_token.clone());
+                self.heartbeat_agreed.set(capabilities.iter().any(|c| c == capability::HEARTBEAT));
+            }
+            ServerMessage::Pong(id) if id + 1 == self.next_ping.get() => return,
+            _ => {}
+        }
+        self.transcribe(None, Some(&msg));
+        // If the `Client` is gone, the outbound stream ends, and so does the connection.
+        let _ = self.inbound.unbounded_send(Ok(msg));
+    }
+
+    // Add a line to the transcript, if we're keeping one. A transcript that can't be written to
+    // isn't worth dropping the connection over, so any trouble with it is ignored.
+    fn transcribe(&self, sent: Option<&ClientMessage>, received: Option<&ServerMessage>) {
+        let log = match self.log {
+            Some(ref log) => log,
+            None => return,
+        };
+        let line = Transcribed {
+            at: SystemTime::now(),
+            sent: sent,
+            received: received,
+        };
+        if let Ok(mut line) = serde_json::to_vec(&line) {
+            line.push(b'\n');
+            let _ = log.borrow_mut().write_all(&line);
+        }
+    }
+
+    fn flush_log(&self) {
+        if let Some(ref log) = self.log {
+            let _ = log.borrow_mut().flush();
+        }
+    }
+}
+
+// What the writing half of a connection writes: what the `Client` queued, then, once it's closed,
+// nothing more.
+enum Outgoing {
+    Send(ClientMessage),
+    Close,
+}
+
+// The `Client`'s queue, borrowed for as long as one connection lasts; when that drops, the queue
+// stays put for the next one.
+struct Queued<C, T>(Rc<Connection<C, T>>, bool);
+
+impl<C, T> Stream for Queued<C, T> {
+    type Item = Outgoing;
+    type Error = ClientError;
+
+    fn poll(&mut self) -> Poll<Option<Outgoing>, ClientError> {
+        if self.1 {
+            return Ok(Async::Ready(None));
+        }
+        match self.0.outbound.borrow_mut().poll().map_err(rx_failed)? {
+            Async::Ready(Some(msg)) => {
+                self.0.transcribe(Some(&msg), None);
+                Ok(Async::Ready(Some(Outgoing::Send(msg))))
+            }
+            Async::Ready(None) => {
+                self.1 = true;
+                Ok(Async::Ready(Some(Outgoing::Close)))
+            }
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+// Once `transport` is open, send `handshake`, and wait to be welcomed, returning the connection
+// (framed with `codec`) and the `Welcome`.
+fn open<C, T>(transport: Box<Future<Item = T, Error = io::Error>>,
+              handshake: Handshake,
+              codec: C)
+              -> Box<Future<Item = (Framed<T, C>, ServerMessage), Error = ClientError>>
+    where C: Codec<In = ServerMessage, Out = ClientMessage> + 'static,
+          T: Io + 'static
+{
+    let handshake_sent = transport.and_then(|stream| {
+        stream.framed(HandshakeCodec::new())
+            .send(handshake)
+            .map(|handshake_io| handshake_io.into_inner())
+    });
+    Box::new(handshake_sent.and_then(|socket| {
+            socket.framed(codec).into_future().map_err(|(err, _)| err)
+        })
+        .map_err(ClientError::from)
+        .and_then(|(greeting, framed)| {
+            match greeting {
+                Some(welcome @ ServerMessage::Welcome { .. }) => Ok((framed, welcome)),
+                Some(ServerMessage::Error(code, detail)) => Err(ClientError::Refused(code, detail)),
+                Some(greeting) => Err(ClientError::UnexpectedGreeting(greeting)),
+                None => Err(ClientError::Closed),
+            }
+        }))
+}
+
+// Run `connection`, starting with `framed`, reopening it as the settings allow, until the `Client`
+// closes it or we give up on it. Giving up is the last thing the `Client` hears.
+fn run<C, T>(connection: Rc<Connection<C, T>>,
+             framed: Framed<T, C>)
+             -> Box<Future<Item = (), Error = ()>>
+    where C: Codec<In = ServerMessage, Out = ClientMessage> + Clone + 'static,
+          T: Io + 'static
+{
+    let inbound = connection.inbound.clone();
+    let finished = connection.clone();
+    Box::new(future::loop_fn(framed, move |framed| {
+            let connection = connection.clone();
+            session(connection.clone(), framed).then(move |result| match result {
+                Ok(()) => future::Either::A(future::ok(Loop::Break(()))),
+                Err(err) => future::Either::B(reopen(connection, err).map(Loop::Continue)),
+            })
+        })
+        .then(move |result| {
+            finished.flush_log();
+            result
+        })
+        .map_err(move |err| {
+            let _ = inbound.unbounded_send(Err(err));
+        }))
+}
+
+// Carry messages both ways over `framed` until the `Client` closes the connection, which ends
+// this successfully, or it drops, which fails it.
+fn session<C, T>(connection: Rc<Connection<C, T>>,
+                 framed: Framed<T, C>)
+                 -> Box<Future<Item = (), Error = ClientError>>
+    where C: Codec<In = ServerMessage, Out = ClientMessage> + Clone + 'static,
+          T: Io + 'static
+{
+    let (to_server, from_server) = framed.split();
+    let hearing = connection.clone();
+    let reader = from_server.for_each(move |msg| {
+            hearing.hear(msg);
+            Ok(())
+        })
+        .map_err(ClientError::from)
+        .and_then(|()| Err(ClientError::Closed));
+
+    let heartbeat = match connection.settings.heartbeat {
+        Some(config) => {
+            let interval = match Interval::new(config.interval, &connection.handle) {
+                Ok(interval) => interval,
+                Err(err) => return Box::new(future::err(err.into())),
+            };
+            let beating = connection.clone();
+            let pings = interval.map_err(ClientError::from).filter_map(move |()| {
+                if !beating.heartbeat_agreed.get() {
+                    return None;
+                }
+                let id = beating.next_ping.get();
+                beating.next_ping.set(id + 1);
+                Some(Outgoing::Send(ClientMessage::Ping(id)))
+            });
+            // Checking how long it's been at the same rate as we ping is close enough.
+            let checking = connection.clone();
+            let pings = pings.and_then(move |ping| {
+                if checking.last_heard.get().elapsed() > config.timeout {
+                    return Err(ClientError::TimedOut);
+                }
+                Ok(ping)
+            });
+            Box::new(pings) as Box<Stream<Item = Outgoing, Error = ClientError>>
+        }
+        None => Box::new(::futures::stream::empty()),
+    };
+
+    let writer = Queued(connection, false)
+        .select(heartbeat)
+        .take_while(|outgoing| {
+            Ok(match *outgoing {
+                Outgoing::Send(_) => true,
+                Outgoing::Close => false,
+            })
+        })
+        .fold(to_server, |to_server, outgoing| match outgoing {
+            Outgoing::Send(msg) => to_server.send(msg).map_err(ClientError::from),
+            Outgoing::Close => unreachable!("take_while stops at Close"),
+        })
+        .map(|_| ());
+
+    Box::new(reader.select(writer).map(|_| ()).map_err(|(err, _)| err))
+}
+
+// Open `connection` again after it dropped with `err`, resuming the session, if the settings say
+// to, and fail with the last error if we can't.
+fn reopen<C, T>(connection: Rc<Connection<C, T>>,
+                err: ClientError)
+                -> Box<Future<Item = Framed<T, C>, Error = ClientError>>
+    where C: Codec<In = ServerMessage, Out = ClientMessage> + Clone + 'static,
+          T: Io + 'static
+{
+    let config = match connection.settings.reconnect {
+        Some(config) if config.attempts > 0 => config,
+        _ => return Box::new(future::err(err)),
+    };
+    Box::new(future::loop_fn(1, move |attempt| {
+        let connection = connection.clone();
+        let wait = future::result(Timeout::new(config.delay, &connection.handle)).flatten();
+        wait.map_err(ClientError::from)
+            .and_then(move |()| {
+                let mut handshake = connection.settings.handshake.clone();
+                handshake.resume_token = connection.resume_token.borrow().clone();
+                open((connection.transport)(&connection.handle),
+                     handshake,
+                     connection.settings.codec.clone())
+                    .map(move |(framed, welcome)| {
+                        connection.hear(welcome);
+                        framed
+                    })
+            })
+            .then(move |result| match result {
+                Ok(framed) => Ok(Loop::Break(framed)),
+                Err(_) if attempt < config.attempts => Ok(Loop::Continue(attempt + 1)),
+                Err(err) => Err(err),
+            })
+    }))
+}
+
+fn rx_failed(_: ()) -> ClientError {
+    unreachable!("rx can't fail")
+}
@@ -1,16 +1,38 @@
 use serde::{Serialize, Deserialize};
+use serde::de::impls::IgnoredAny;
 use serde_json;
 use tokio_core::io::{Codec, EasyBuf};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
+use std::cmp;
 use std::io;
 use std::marker::PhantomData;
 use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use stats::CodecStats;
+
+// The longest frame payload a u16 length prefix can describe, and so the default limit.
+pub const MAX_FRAME_LEN: usize = 0xffff;
+
+// The deepest nesting of JSON arrays and objects accepted by default. None of our messages come
+// anywhere close.
+pub const DEFAULT_MAX_DEPTH: usize = 32;
 
 pub struct LengthPrefixedJson<In, Out>
     where In: Serialize + Deserialize,
           Out: Serialize + Deserialize
 {
+    max_frame_len: usize,
+    max_depth: usize,
+    stats: Option<Arc<CodecStats>>,
+
+    // The payload length of the frame at the front of the buffer, once its prefix has been read
+    // and the rest of it is still on its way. A frame too big for one read is decoded over
+    // several calls, and this saves reading (and checking) its prefix again on each of them.
+    pending: Option<usize>,
+
     _in: PhantomData<In>,
     _out: PhantomData<Out>,
 }
@@ -20,7 +42,118 @@ impl<In, Out> LengthPrefixedJson<In, Out>
           Out: Serialize + Deserialize
 {
     pub fn new() -> LengthPrefixedJson<In, Out> {
+        LengthPrefixedJson::builder().build()
+    }
+
+    pub fn builder() -> LengthPrefixedJsonBuilder<In, Out> {
+        LengthPrefixedJsonBuilder::new()
+    }
+
+    // Refuse incoming frames with payloads longer than `max_frame_len` bytes. The check happens
+    // as soon as a frame's length prefix arrives, so an oversized frame fails the stream before
+    // any of its payload is buffered.
+    pub fn with_max_frame_len(mut self, max_frame_len: usize) -> LengthPrefixedJson<In, Out> {
+        self.max_frame_len = max_frame_len;
+        self
+    }
+
+    // Refuse incoming payloads with arrays and objects nested more than `max_depth` deep; see
+    // `check_depth`.
+    pub fn with_max_depth(mut self, max_depth: usize) -> LengthPrefixedJson<In, Out> {
+        self.max_depth = max_depth;
+        self
+    }
+}
+
+// Configures a `LengthPrefixedJson`. Anything left unset keeps its default: frames up to
+// `MAX_FRAME_LEN`, nesting up to `DEFAULT_MAX_DEPTH`, and no metrics.
+pub struct LengthPrefixedJsonBuilder<In, Out>
+    where In: Serialize + Deserialize,
+          Out: Serialize + Deserialize
+{
+    max_frame_len: usize,
+    max_depth: usize,
+    stats: Option<Arc<CodecStats>>,
+    _in: PhantomData<In>,
+    _out: PhantomData<Out>,
+}
+
+impl<In, Out> LengthPrefixedJsonBuilder<In, Out>
+    where In: Serialize + Deserialize,
+          Out: Serialize + Deserialize
+{
+    pub fn new() -> LengthPrefixedJsonBuilder<In, Out> {
+        LengthPrefixedJsonBuilder {
+            max_frame_len: MAX_FRAME_LEN,
+            max_depth: DEFAULT_MAX_DEPTH,
+            stats: None,
+            _in: PhantomData,
+            _out: PhantomData,
+        }
+    }
+
+    // See `LengthPrefixedJson::with_max_frame_len`.
+    pub fn max_frame_size(mut self, max_frame_len: usize) -> LengthPrefixedJsonBuilder<In, Out> {
+        self.max_frame_len = max_frame_len;
+        self
+    }
+
+    // See `LengthPrefixedJson::with_max_depth`.
+    pub fn max_depth(mut self, max_depth: usize) -> LengthPrefixedJsonBuilder<In, Out> {
+        self.max_depth = max_depth;
+        self
+    }
+
+    // Count messages, bytes and errors into `stats`, as a `StatsCodec` wrapped around the codec
+    // would, without the extra layer.
+    pub fn metrics(mut self, stats: Arc<CodecStats>) -> LengthPrefixedJsonBuilder<In, Out> {
+        self.stats = Some(stats);
+        self
+    }
+
+    pub fn build(self) -> LengthPrefixedJson<In, Out> {
+        LengthPrefixedJson {
+            max_frame_len: self.max_frame_len,
+            max_depth: self.max_depth,
+            stats: self.stats,
+            pending: None,
+            _in: PhantomData,
+            _out: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> Default for LengthPrefixedJsonBuilder<In, Out>
+    where In: Serialize + Deserialize,
+          Out: Serialize + Deserialize
+{
+    fn default() -> LengthPrefixedJsonBuilder<In, Out> {
+        LengthPrefixedJsonBuilder::new()
+    }
+}
+
+impl<In, Out> Default for LengthPrefixedJson<In, Out>
+    where In: Serialize + Deserialize,
+          Out: Serialize + Deserialize
+{
+    fn default() -> LengthPrefixedJson<In, Out> {
+        LengthPrefixedJson::new()
+    }
+}
+
+// Written out rather than derived, which would needlessly insist on `In` and `Out` being `Clone`
+// too. Clones share `stats`, if there are any, so they all count towards the same totals, but
+// start out with no frame in progress, ready for a stream of their own.
+impl<In, Out> Clone for LengthPrefixedJson<In, Out>
+    where In: Serialize + Deserialize,
+          Out: Serialize + Deserialize
+{
+    fn clone(&self) -> LengthPrefixedJson<In, Out> {
         LengthPrefixedJson {
+            max_frame_len: self.max_frame_len,
+            max_depth: self.max_depth,
+            stats: self.stats.clone(),
+            pending: None,
             _in: PhantomData,
             _out: PhantomData,
         }
@@ -38,46 +171,201 @@ impl<In, Out> Codec for LengthPrefixedJson<In, Out>
     type Out = Out;
 
     fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Self::In>> {
-        // Make sure we have at least the 2 u16 bytes we need.
-        let msg_size = match buf.as_ref().read_u16::<BigEndian>() {
-            Ok(msg_size) => msg_size,
-            Err(_) => return Ok(None),
-        };
-        let hdr_size = mem::size_of_val(&msg_size);
-        let msg_size = msg_size as usize + hdr_size;
+        let len_before = buf.len();
+        let result = self.decode_json(buf);
+        if let Some(ref stats) = self.stats {
+            match result {
+                Ok(Some(_)) => {
+                    stats.messages_decoded.fetch_add(1, Ordering::Relaxed);
+                    let consumed = len_before - buf.len();
+                    stats.bytes_decoded.fetch_add(consumed as u64, Ordering::Relaxed);
+                }
+                Ok(None) => {}
+                Err(_) => {
+                    stats.decode_errors.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        result
+    }
 
-        // Make sure our buffer has all the bytes indicated by msg_size.
-        if buf.len() < msg_size {
-            return Ok(None);
+    fn encode(&mut self, msg: Out, buf: &mut Vec<u8>) -> io::Result<()> {
+        let len_before = buf.len();
+        let result = encode_frame(&msg, buf);
+        if let Some(ref stats) = self.stats {
+            match result {
+                Ok(()) => {
+                    stats.messages_encoded.fetch_add(1, Ordering::Relaxed);
+                    let written = buf.len() - len_before;
+                    stats.bytes_encoded.fetch_add(written as u64, Ordering::Relaxed);
+                }
+                Err(_) => {
+                    stats.encode_errors.fetch_add(1, Ordering::Relaxed);
+                }
+            }
         }
+        result
+    }
+}
 
-        // Drain off the entire message.
-        let buf = buf.drain_to(msg_size);
+impl<In, Out> LengthPrefixedJson<In, Out>
+    where In: Serialize + Deserialize,
+          Out: Serialize + Deserialize
+{
+    // Check that `buf` starts with a frame `decode` would take, without taking it: the frame has
+    // to have arrived in full, be within the limits on its length and nesting, and hold JSON,
+    // though not necessarily a message. (The JSON is only parsed, so nothing is built from it.)
+    // Returns how many bytes the frame takes up, prefix and all, or `None` if it hasn't all
+    // arrived yet, which is when `decode` would say the same. That makes this safe for peeking
+    // at what's buffered, or checking over frames before anything's done with them.
+    pub fn validate(&self, buf: &EasyBuf) -> io::Result<Option<usize>> {
+        let len = match frame_len(buf) {
+            Some(len) => check_len(len, self.max_frame_len)?,
+            None => return Ok(None),
+        };
+        let frame_len = mem::size_of::<u16>() + len;
+        if buf.len() < frame_len {
+            return Ok(None);
+        }
+        let payload = &buf.as_ref()[mem::size_of::<u16>()..frame_len];
+        check_depth(payload, self.max_depth)
+            .and_then(|()| serde_json::from_slice::<IgnoredAny>(payload))
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(Some(frame_len))
+    }
 
-        // Trim off the u16 length bytes.
-        let msg_buf = &buf.as_ref()[hdr_size..];
+    fn decode_json(&mut self, buf: &mut EasyBuf) -> io::Result<Option<In>> {
+        let len = match self.pending.take() {
+            Some(len) => len,
+            None => {
+                match frame_len(buf) {
+                    Some(len) => check_len(len, self.max_frame_len)?,
+                    None => return Ok(None),
+                }
+            }
+        };
+        if buf.len() < mem::size_of::<u16>() + len {
+            self.pending = Some(len);
+            return Ok(None);
+        }
+        let msg_buf = take_frame(buf, len);
 
         // Decode!
-        let msg: In = serde_json::from_slice(msg_buf)
+        let msg: In = check_depth(msg_buf.as_ref(), self.max_depth)
+                   .and_then(|()| serde_json::from_slice(msg_buf.as_ref()))
                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
         Ok(Some(msg))
     }
+}
 
-    fn encode(&mut self, msg: Out, buf: &mut Vec<u8>) -> io::Result<()> {
-        // Encode directly into `buf`.
-        serde_json::to_writer(buf, &msg)
-            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+// The payload length of the next frame in `buf`, if its length prefix has arrived.
+pub fn frame_len(buf: &EasyBuf) -> Option<usize> {
+    buf.as_ref().read_u16::<BigEndian>().ok().map(|len| len as usize)
+}
+
+// Pull the JSON payload of the next frame off the front of `buf`, returning `None` if a complete
+// frame hasn't arrived yet. This is the framing half of `LengthPrefixedJson`'s `decode`, shared
+// with the other codecs in this crate that use the same wire format.
+pub fn decode_frame(buf: &mut EasyBuf) -> Option<EasyBuf> {
+    // Make sure we have at least the 2 u16 bytes we need, and then all the bytes they say follow.
+    let len = frame_len(buf)?;
+    if buf.len() < mem::size_of::<u16>() + len {
+        return None;
+    }
+    Some(take_frame(buf, len))
+}
+
+// Pull the frame at the front of `buf`, which has arrived in full and has a payload of `len`
+// bytes, off it, returning the payload.
+fn take_frame(buf: &mut EasyBuf, len: usize) -> EasyBuf {
+    let hdr_size = mem::size_of::<u16>();
+
+    // Drain off the entire message. This doesn't copy anything: `EasyBuf`s share one reference
+    // counted buffer, so draining just hands out a view of the front of it, and the payload is
+    // deserialized straight out of the bytes the socket was read into. (The only allocations a
+    // decode makes are the ones for the decoded message itself.)
+    let mut buf = buf.drain_to(hdr_size + len);
+
+    // Trim off the u16 length bytes.
+    buf.split_off(hdr_size)
+}
+
+// `len`, as long as that's a payload length within `max_frame_len`. Decoders check each frame's
+// length prefix with this as soon as it arrives, before buffering any of the payload.
+pub fn check_len(len: usize, max_frame_len: usize) -> io::Result<usize> {
+    if len > max_frame_len {
+        let msg = format!("frame of {} bytes exceeds the limit of {}", len, max_frame_len);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+    Ok(len)
+}
+
+// serde_json recurses once per level of nesting while parsing, and its own limit on that (128
+// levels, as of 0.8) can't be turned down. So that a peer can't make us chew through our stack with
+// deeply nested input, decoders scan each payload's nesting depth before handing it to serde_json.
+// This fails as soon as the depth passes `max_depth`, without recursing or allocating. The payload
+// needn't be in one piece, as long as its bytes come in order.
+pub fn check_depth<'a, P>(payload: P, max_depth: usize) -> Result<(), serde_json::Error>
+    where P: IntoIterator<Item = &'a u8>
+{
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    let (mut line, mut column) = (1, 0);
 
-        let len = buf.len() as u16;
+    for &byte in payload {
+        if byte == b'\n' {
+            line += 1;
+            column = 0;
+        } else {
+            column += 1;
+        }
 
-        // add space for our length
-        for _ in 0..mem::size_of_val(&len) {
-            buf.insert(0, 0);
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                if depth > max_depth {
+                    let msg = format!("nesting exceeds the limit of {} levels", max_depth);
+                    return Err(serde_json::Error::Syntax(serde_json::ErrorCode::Custom(msg),
+                                                         line,
+                                                         column));
+                }
+            }
+            // Unbalanced brackets are serde_json's problem to report.
+            b']' | b'}' => depth = cmp::max(depth, 1) - 1,
+            _ => {}
         }
+    }
+    Ok(())
+}
+
+// Append `msg` to `buf` as a complete frame. `buf` may already hold earlier frames; they're left
+// alone. This is the framing half of `LengthPrefixedJson`'s `encode`.
+pub fn encode_frame<T: Serialize>(msg: &T, buf: &mut Vec<u8>) -> io::Result<()> {
+    // Serialize the payload on its own first, so we know its length before writing anything.
+    let payload = serde_json::to_vec(msg)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
 
-        // Insert our length bytes at the front of `buf`.
-        let mut cursor = io::Cursor::new(buf);
-        cursor.set_position(0);
-        cursor.write_u16::<BigEndian>(len)
+    // The length has to fit in the u16 prefix.
+    if payload.len() > MAX_FRAME_LEN {
+        let msg = format!("frame of {} bytes exceeds the limit of {}",
+                          payload.len(),
+                          MAX_FRAME_LEN);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
     }
+
+    buf.reserve(mem::size_of::<u16>() + payload.len());
+    buf.write_u16::<BigEndian>(payload.len() as u16)?;
+    buf.extend_from_slice(&payload);
+    Ok(())
 }
@@ -0,0 +1,207 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{self, Map, Value};
+
+use {ClientMessage, ServerMessage};
+
+// Messages written out on one line for people to read, and read back in again, for poking at the
+// protocol from a shell. A message is its type in brackets, along with any fields that have names,
+// as `name=value`, followed by any that don't:
+//
+// ```text
+// [Who]
+// [Join] lobby
+// [Message] 7 alice "hello world"
+// [EditMessage id=7 new_body="hello, world"]
+// ```
+//
+// Each value is written as JSON, except for strings that can't be mistaken for anything else
+// (like `lobby` or `#general`), which are written bare. The result reads back as exactly the
+// message it was written from.
+
+impl fmt::Display for ClientMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_message(f, self)
+    }
+}
+
+impl FromStr for ClientMessage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ClientMessage, String> {
+        parse_message(s)
+    }
+}
+
+impl fmt::Display for ServerMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_message(f, self)
+    }
+}
+
+impl FromStr for ServerMessage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ServerMessage, String> {
+        parse_message(s)
+    }
+}
+
+// Write `message` out by way of its JSON, which is tagged with its type as described at
+// `unknown_type`.
+fn write_message<T: Serialize>(f: &mut fmt::Formatter, message: &T) -> fmt::Result {
+    let (variant, fields) = match serde_json::to_value(message) {
+        Value::String(variant) => return write!(f, "[{}]", variant),
+        Value::Object(tagged) => {
+            tagged.into_iter().next().expect("messages are tagged with their type")
+        }
+        _ => unreachable!("messages are tagged with their type"),
+    };
+    write!(f, "[{}", variant)?;
+    match fields {
+        Value::Object(named) => {
+            for (name, value) in named {
+                write!(f, " {}=", name)?;
+                write_value(f, &value)?;
+            }
+            write!(f, "]")
+        }
+        Value::Array(unnamed) => {
+            write!(f, "]")?;
+            for value in unnamed {
+                write!(f, " ")?;
+                write_value(f, &value)?;
+            }
+            Ok(())
+        }
+        only => {
+            write!(f, "] ")?;
+            write_value(f, &only)
+        }
+    }
+}
+
+fn write_value(f: &mut fmt::Formatter, value: &Value) -> fmt::Result {
+    match *value {
+        Value::String(ref s) if is_bare(s) => write!(f, "{}", s),
+        ref value => {
+            write!(f, "{}", serde_json::to_string(value).expect("JSON values always serialize"))
+        }
+    }
+}
+
+// Whether `s` can be written without quotes: it's all letters, digits and a few punctuation marks
+// that can't end a value, and doesn't start like a number or spell out a JSON literal.
+fn is_bare(s: &str) -> bool {
+    s.chars().all(|c| c.is_alphanumeric() || "#_.:@/+".contains(c)) &&
+    s.chars().next().is_some_and(|c| !c.is_ascii_digit()) &&
+    !["true", "false", "null"].contains(&s)
+}
+
+// Read a message written by `write_message`, by making its JSON back out of it.
+fn parse_message<T: Deserialize>(s: &str) -> Result<T, String> {
+    let rest = s.trim().strip_prefix('[').ok_or("expected `[` and the message type")?;
+    let end = rest.find(|c: char| c.is_whitespace() || c == ']').unwrap_or(rest.len());
+    let (variant, mut rest) = rest.split_at(end);
+    if variant.is_empty() {
+        return Err("expected the message type after `[`".to_string());
+    }
+
+    let mut named = Map::new();
+    loop {
+        rest = rest.trim_start();
+        if let Some(after) = rest.strip_prefix(']') {
+            rest = after;
+            break;
+        }
+        let name = match rest.find('=') {
+            Some(eq) if !rest[..eq].contains(char::is_whitespace) => &rest[..eq],
+            _ => return Err(format!("expected `name=value` or `]` at `{}`", rest)),
+        };
+        let (value, after) = parse_value(&rest[name.len() + 1..])?;
+        named.insert(name.to_string(), value);
+        rest = after;
+    }
+    let mut unnamed = Vec::new();
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+        let (value, after) = parse_value(rest)?;
+        unnamed.push(value);
+        rest = after;
+    }
+
+    let fields = match (named.is_empty(), unnamed.len()) {
+        (true, 0) => None,
+        (false, 0) => Some(Value::Object(named)),
+        (true, 1) => unnamed.pop(),
+        (true, _) => Some(Value::Array(unnamed)),
+        (false, _) => return Err("expected fields with names or without, not both".to_string()),
+    };
+    let tagged = match fields {
+        Some(fields) => {
+            let mut tagged = Map::new();
+            tagged.insert(variant.to_string(), fields);
+            Value::Object(tagged)
+        }
+        None => Value::String(variant.to_string()),
+    };
+    serde_json::from_value(tagged).map_err(|err| err.to_string())
+}
+
+// Read the value `s` starts with, returning it and whatever follows it.
+fn parse_value(s: &str) -> Result<(Value, &str), String> {
+    let end = match s.chars().next() {
+        Some('"') => closing_quote(s, 0).map(|end| end + 1),
+        Some('[') | Some('{') => closing_bracket(s),
+        _ => Some(s.find(|c: char| c.is_whitespace() || c == ']').unwrap_or(s.len())),
+    };
+    let end = end.ok_or_else(|| format!("`{}` isn't closed", s))?;
+    let (value, rest) = s.split_at(end);
+    if value.is_empty() {
+        return Err(format!("expected a value at `{}`", s));
+    }
+    match serde_json::from_str(value) {
+        Ok(value) => Ok((value, rest)),
+        Err(_) if is_bare(value) => Ok((Value::String(value.to_string()), rest)),
+        Err(err) => Err(format!("`{}`: {}", value, err)),
+    }
+}
+
+// Where the string opened by the quote at `start` in `s` is closed.
+fn closing_quote(s: &str, start: usize) -> Option<usize> {
+    let mut escaped = false;
+    for (i, c) in s[start + 1..].char_indices() {
+        match c {
+            '\\' if !escaped => escaped = true,
+            '"' if !escaped => return Some(start + 1 + i),
+            _ => escaped = false,
+        }
+    }
+    None
+}
+
+// How much of `s`, which starts with an array or an object, that array or object takes up.
+fn closing_bracket(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut i = 0;
+    while let Some(c) = s[i..].chars().next() {
+        match c {
+            '"' => i = closing_quote(s, i)?,
+            '[' | '{' => depth += 1,
+            ']' | '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i + 1);
+                }
+            }
+            _ => {}
+        }
+        i += c.len_utf8();
+    }
+    None
+}
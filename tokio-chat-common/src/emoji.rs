@@ -0,0 +1,72 @@
+// Shortcodes like `:smile:`, which clients can let their users type for emoji they'd otherwise
+// have to go looking for. Expanding them is entirely up to the client, before a message is sent;
+// the server (and everyone else) only ever sees the emoji.
+
+// Every shortcode `expand` knows, without its colons, in order so it can be searched.
+pub const SHORTCODES: &[(&str, &str)] = &[("+1", "\u{1f44d}"),
+                                          ("-1", "\u{1f44e}"),
+                                          ("angry", "\u{1f620}"),
+                                          ("beer", "\u{1f37a}"),
+                                          ("blush", "\u{1f60a}"),
+                                          ("broken_heart", "\u{1f494}"),
+                                          ("clap", "\u{1f44f}"),
+                                          ("coffee", "\u{2615}"),
+                                          ("cry", "\u{1f622}"),
+                                          ("eyes", "\u{1f440}"),
+                                          ("fire", "\u{1f525}"),
+                                          ("grin", "\u{1f601}"),
+                                          ("heart", "\u{2764}\u{fe0f}"),
+                                          ("joy", "\u{1f602}"),
+                                          ("laughing", "\u{1f606}"),
+                                          ("ok_hand", "\u{1f44c}"),
+                                          ("pray", "\u{1f64f}"),
+                                          ("rocket", "\u{1f680}"),
+                                          ("sad", "\u{1f61e}"),
+                                          ("see_no_evil", "\u{1f648}"),
+                                          ("shrug", "\u{1f937}"),
+                                          ("smile", "\u{1f604}"),
+                                          ("smiley", "\u{1f603}"),
+                                          ("sob", "\u{1f62d}"),
+                                          ("sparkles", "\u{2728}"),
+                                          ("star", "\u{2b50}"),
+                                          ("sunglasses", "\u{1f60e}"),
+                                          ("tada", "\u{1f389}"),
+                                          ("thinking", "\u{1f914}"),
+                                          ("thumbsdown", "\u{1f44e}"),
+                                          ("thumbsup", "\u{1f44d}"),
+                                          ("wave", "\u{1f44b}"),
+                                          ("wink", "\u{1f609}"),
+                                          ("x", "\u{274c}")];
+
+// The emoji `shortcode` (without its colons) stands for, if it's one of the `SHORTCODES`.
+pub fn lookup(shortcode: &str) -> Option<&'static str> {
+    SHORTCODES.binary_search_by_key(&shortcode, |&(code, _)| code)
+        .ok()
+        .map(|i| SHORTCODES[i].1)
+}
+
+// `body` with every known shortcode in it replaced by its emoji. Anything else between colons
+// (an unknown shortcode, or the `30` in `12:30:45`) is left as it was.
+pub fn expand(body: &str) -> String {
+    let mut expanded = String::with_capacity(body.len());
+    let mut rest = body;
+    while let Some(colon) = rest.find(':') {
+        expanded.push_str(&rest[..colon]);
+        let after = &rest[colon + 1..];
+        let emoji = after.find(':').and_then(|end| lookup(&after[..end]).map(|emoji| (end, emoji)));
+        match emoji {
+            Some((end, emoji)) => {
+                expanded.push_str(emoji);
+                rest = &after[end + 1..];
+            }
+            // The closing colon we looked at might open the next shortcode, so only this one's
+            // used up.
+            None => {
+                expanded.push(':');
+                rest = after;
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
@@ -0,0 +1,67 @@
+use {ContentType, MessageId, RoomConfig};
+
+// Where an event comes in its log, counting from zero.
+pub type EventId = u64;
+
+// Everything that changes a server's rooms, as it happens. With an event log (see the server's
+// `EventLog`), each is written to the log before it takes effect, so the log is a record of
+// everything that happened (for auditing it, say), and replaying it from the start gets a
+// restarted server's rooms back the way they were: their topics and settings, and their chat and
+// its edits, as far back as the history reaches.
+//
+// Who's in which room only lasts as long as their connections, so joining and leaving are only
+// recorded, not replayed, as are operators' actions that don't change anything.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    // `name` arrived in `room`, either by connecting or by moving there from another room.
+    Joined {
+        name: String,
+        room: String,
+    },
+
+    // `name` left `room`, either by moving to another room or by disconnecting.
+    Left {
+        name: String,
+        room: String,
+    },
+
+    // `from` said `body` in `room`, and it was numbered `id`.
+    Said {
+        id: MessageId,
+        room: String,
+        from: String,
+        body: String,
+        content_type: ContentType,
+    },
+
+    // The message numbered `id`, in `room`, was changed to say `new_body`.
+    Edited {
+        id: MessageId,
+        room: String,
+        new_body: String,
+    },
+
+    // The topic of `room` became `topic`, or was cleared, if that's empty.
+    TopicSet {
+        room: String,
+        topic: String,
+    },
+
+    // `room` was created with `config`.
+    RoomCreated {
+        room: String,
+        config: RoomConfig,
+    },
+
+    // The operator `by` made an announcement to everyone.
+    Announced {
+        by: String,
+        text: String,
+    },
+
+    // The operator `by` exported the chat in `room`.
+    Exported {
+        by: String,
+        room: String,
+    },
+}
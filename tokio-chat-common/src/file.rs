@@ -0,0 +1,111 @@
+use ClientMessage;
+
+// Files bigger than this are refused by the server (unless it's configured otherwise) and by
+// `FileAssembly`.
+pub const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+// How many bytes of a file `offer_file` puts in each `FileChunk`. Chunk data is sent as a JSON
+// array of numbers, so this leaves plenty of room under the 64KiB frame limit.
+pub const FILE_CHUNK_SIZE: usize = 8 * 1024;
+
+// Build the messages that send `data` as a file called `name`: a `ClientMessage::FileOffer`
+// followed by one `ClientMessage::FileChunk` per `FILE_CHUNK_SIZE` bytes. `transfer_id` must not
+// be reused by the same client for another file while this one is in flight.
+pub fn offer_file(transfer_id: u64, name: &str, data: &[u8]) -> Vec<ClientMessage> {
+    let chunks = data.chunks(FILE_CHUNK_SIZE);
+    let mut msgs = Vec::with_capacity(chunks.len() + 1);
+    msgs.push(ClientMessage::FileOffer {
+        transfer_id: transfer_id,
+        name: name.to_string(),
+        size: data.len() as u64,
+        chunk_count: chunks.len() as u32,
+    });
+    for (index, chunk) in chunks.enumerate() {
+        msgs.push(ClientMessage::FileChunk {
+            transfer_id: transfer_id,
+            index: index as u32,
+            data: chunk.to_vec(),
+        });
+    }
+    msgs
+}
+
+// Check that a file offer makes sense: it isn't bigger than `max_size`, and (since every chunk
+// but an empty file's carries at least one byte) its chunk count is consistent with its size.
+pub fn check_offer(size: u64, chunk_count: u32, max_size: u64) -> Result<(), String> {
+    if size > max_size {
+        return Err(format!("files are limited to {} bytes", max_size));
+    }
+    let chunk_count = chunk_count as u64;
+    if (size == 0 && chunk_count != 0) || (size > 0 && (chunk_count == 0 || chunk_count > size)) {
+        return Err(format!("{} bytes can't be sent in {} chunks", size, chunk_count));
+    }
+    Ok(())
+}
+
+// Collects the `FileChunk`s of an offered file on the receiving end and puts them back together.
+pub struct FileAssembly {
+    name: String,
+    size: u64,
+    chunks: Vec<Option<Vec<u8>>>,
+    received_chunks: u32,
+    received_bytes: u64,
+}
+
+impl FileAssembly {
+    pub fn new(name: String, size: u64, chunk_count: u32) -> Result<FileAssembly, String> {
+        check_offer(size, chunk_count, MAX_FILE_SIZE)?;
+        Ok(FileAssembly {
+            name: name,
+            size: size,
+            chunks: vec![None; chunk_count as usize],
+            received_chunks: 0,
+            received_bytes: 0,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // Store chunk `index`. Fails if `index` is past the end of the file, if that chunk already
+    // arrived, or if the chunk would make the file bigger (or, for the last chunk, smaller) than
+    // was offered.
+    pub fn add_chunk(&mut self, index: u32, data: Vec<u8>) -> Result<(), String> {
+        let index = index as usize;
+        match self.chunks.get(index) {
+            None => return Err(format!("chunk {} is out of range", index)),
+            Some(&Some(_)) => return Err(format!("chunk {} arrived twice", index)),
+            Some(&None) => {}
+        }
+        let received_bytes = self.received_bytes + data.len() as u64;
+        if received_bytes > self.size {
+            return Err(format!("chunk {} runs past the end of the file", index));
+        }
+        if self.received_chunks as usize + 1 == self.chunks.len() && received_bytes < self.size {
+            return Err(format!("chunk {} ends the file short of its size", index));
+        }
+
+        self.received_chunks += 1;
+        self.received_bytes = received_bytes;
+        self.chunks[index] = Some(data);
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received_chunks as usize == self.chunks.len()
+    }
+
+    // The file's contents. Only meaningful once `is_complete` returns true.
+    pub fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.size as usize);
+        for chunk in self.chunks.into_iter().flatten() {
+            bytes.extend_from_slice(&chunk);
+        }
+        bytes
+    }
+}
@@ -0,0 +1,38 @@
+use serde::{Serialize, Deserialize};
+use futures::Stream;
+use futures::stream::{SplitSink, SplitStream};
+use tokio_core::io::{Framed, Io};
+
+use codec::LengthPrefixedJson;
+
+// The writing half of a connection framed by `into_framed`: a `Sink` of the `Out` messages to
+// send, failing with the `io::Error` that broke the connection.
+pub type FramedSink<T, In, Out> = SplitSink<Framed<T, LengthPrefixedJson<In, Out>>>;
+
+// The reading half: a `Stream` of the `In` messages received, which ends when the peer hangs up
+// and fails, once, with an `io::Error` if the connection breaks or a frame can't be decoded.
+pub type FramedStream<T, In, Out> = SplitStream<Framed<T, LengthPrefixedJson<In, Out>>>;
+
+// Frame `transport` with `codec`, and split it into the `Sink` of messages to send and the
+// `Stream` of messages received, for code that would rather pass those around (to forward a
+// `futures::sync::mpsc` channel into, say) than call `encode` and `decode` itself. The halves are
+// just those of `Io::framed`, so they can be boxed up as trait objects like any others.
+pub fn into_framed<T, In, Out>(transport: T,
+                               codec: LengthPrefixedJson<In, Out>)
+                               -> (FramedSink<T, In, Out>, FramedStream<T, In, Out>)
+    where T: Io,
+          In: Serialize + Deserialize,
+          Out: Serialize + Deserialize
+{
+    transport.framed(codec).split()
+}
+
+impl<In, Out> LengthPrefixedJson<In, Out>
+    where In: Serialize + Deserialize,
+          Out: Serialize + Deserialize
+{
+    // `into_framed(transport, self)`.
+    pub fn framed<T: Io>(self, transport: T) -> (FramedSink<T, In, Out>, FramedStream<T, In, Out>) {
+        into_framed(transport, self)
+    }
+}
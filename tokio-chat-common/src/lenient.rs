@@ -0,0 +1,85 @@
+use serde::{Serialize, Deserialize};
+use serde_json;
+use tokio_core::io::{Codec, EasyBuf};
+
+use std::io;
+use std::marker::PhantomData;
+
+use codec::{check_depth, decode_frame, encode_frame, DEFAULT_MAX_DEPTH};
+
+// `LenientJson` speaks the same wire format as `LengthPrefixedJson`, but a frame whose payload
+// doesn't decode isn't fatal: it's handed out as an `Err` item instead of failing the stream, and
+// decoding carries on with the next frame. (The length prefix tells us exactly where that is, so a
+// bad payload can't throw off the framing.) What to do about bad frames is left to the caller.
+pub struct LenientJson<In, Out>
+    where In: Serialize + Deserialize,
+          Out: Serialize + Deserialize
+{
+    max_depth: usize,
+    _in: PhantomData<In>,
+    _out: PhantomData<Out>,
+}
+
+impl<In, Out> LenientJson<In, Out>
+    where In: Serialize + Deserialize,
+          Out: Serialize + Deserialize
+{
+    pub fn new() -> LenientJson<In, Out> {
+        LenientJson {
+            max_depth: DEFAULT_MAX_DEPTH,
+            _in: PhantomData,
+            _out: PhantomData,
+        }
+    }
+
+    // Same as `LengthPrefixedJson::with_max_depth`, except that payloads nested too deeply are
+    // handed out as `Err` items like any other that doesn't decode.
+    pub fn with_max_depth(mut self, max_depth: usize) -> LenientJson<In, Out> {
+        self.max_depth = max_depth;
+        self
+    }
+}
+
+impl<In, Out> Default for LenientJson<In, Out>
+    where In: Serialize + Deserialize,
+          Out: Serialize + Deserialize
+{
+    fn default() -> LenientJson<In, Out> {
+        LenientJson::new()
+    }
+}
+
+// Written out rather than derived, for the same reason as `LengthPrefixedJson`'s. There's nothing
+// to a `LenientJson` but its settings, so a clone is as good as a new one with the same settings.
+impl<In, Out> Clone for LenientJson<In, Out>
+    where In: Serialize + Deserialize,
+          Out: Serialize + Deserialize
+{
+    fn clone(&self) -> LenientJson<In, Out> {
+        LenientJson {
+            max_depth: self.max_depth,
+            _in: PhantomData,
+            _out: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> Codec for LenientJson<In, Out>
+    where In: Serialize + Deserialize,
+          Out: Serialize + Deserialize
+{
+    type In = Result<In, serde_json::Error>;
+    type Out = Out;
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Self::In>> {
+        let max_depth = self.max_depth;
+        Ok(decode_frame(buf).map(|msg_buf| {
+            check_depth(msg_buf.as_ref(), max_depth)
+                .and_then(|()| serde_json::from_slice(msg_buf.as_ref()))
+        }))
+    }
+
+    fn encode(&mut self, msg: Out, buf: &mut Vec<u8>) -> io::Result<()> {
+        encode_frame(&msg, buf)
+    }
+}
@@ -2,45 +2,363 @@
 //!
 //! This crate should be straightforward; see tokio-chat-server for a description of the
 //! client/server protocol.
+//!
+//! For programs that would rather not drive the codecs themselves, `client::ClientBuilder` sets
+//! up a connection to the server that handshakes, and optionally reconnects and pings, for them;
+//! those that only want the framing done can split a connection into a `Sink` and a `Stream` of
+//! messages with `into_framed`.
+//! `testing::MockServer` stands in for the server in tests of such programs. Timestamps in
+//! messages go over the wire as RFC 3339 strings; see `rfc3339`. Messages can also be written
+//! out on one line for people to read, with `Display`, and read back with `FromStr`. Clients that
+//! want to let their users type emoji as shortcodes, like `:smile:`, can expand them with `emoji`.
+//! Usernames have one normal form that servers keep them in; see `validation`.
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
+extern crate futures;
 
 extern crate serde;
 extern crate serde_json;
 extern crate tokio_core;
 extern crate byteorder;
+extern crate time;
+extern crate unicode_normalization;
+extern crate unicode_script;
 
+pub mod capability;
+pub mod client;
+pub mod emoji;
+pub mod rfc3339;
+pub mod testing;
+pub mod validation;
+
+mod batch;
 mod codec;
+mod display;
+mod event;
+mod file;
+mod framed;
+mod lenient;
+mod negotiate;
+mod stats;
+mod streaming;
+mod wire;
+
+pub use batch::{BatchCodec, BatchConfig, BatchEncoder};
+pub use codec::{LengthPrefixedJson, LengthPrefixedJsonBuilder, DEFAULT_MAX_DEPTH, MAX_FRAME_LEN};
+pub use event::{Event, EventId};
+pub use file::{check_offer, offer_file, FileAssembly, FILE_CHUNK_SIZE, MAX_FILE_SIZE};
+pub use framed::{into_framed, FramedSink, FramedStream};
+pub use lenient::LenientJson;
+pub use negotiate::{negotiate, FrameFormat, Hello, JsonFormat, NegotiatingCodec};
+pub use stats::{CodecStats, CodecStatsSnapshot, StatsCodec};
+pub use streaming::StreamingDecoder;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 // Handshake message sent from a client to a server when it first connects, identifying the
-// username of the client.
+// username of the client. `token` is only needed if the server was started with a shared secret;
+// servers without one ignore it. Presenting the server's admin token there instead makes the client
+// an operator. `resume_token` is the token from the `ServerMessage::Welcome` of
+// an earlier connection, for picking that session back up; see `Welcome`. An `observer` only
+// watches: it hears what's said in its room, but can't say anything itself, and nobody else can
+// tell it's there. `capabilities` lists the optional features the client supports; see
+// `capability`. `password` is needed to log in with a name that's been registered (see
+// `ClientMessage::Register`); servers only let in guests, with unregistered names and no password,
+// if they were started with `--allow-guests`. A registered user can give an `auth_token` from an
+// earlier `ServerMessage::Token` instead of their password.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Handshake {
     pub name: String,
+    pub token: Option<String>,
+    #[serde(default)]
+    pub resume_token: Option<String>,
+    #[serde(default)]
+    pub observer: bool,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub auth_token: Option<String>,
 }
 
 impl Handshake {
     pub fn new<S: Into<String>>(name: S) -> Handshake {
-        Handshake { name: name.into() }
+        Handshake {
+            name: name.into(),
+            token: None,
+            resume_token: None,
+            observer: false,
+            capabilities: Vec::new(),
+            password: None,
+            auth_token: None,
+        }
+    }
+
+    pub fn with_token<S: Into<String>>(mut self, token: S) -> Handshake {
+        self.token = Some(token.into());
+        self
+    }
+
+    pub fn with_resume_token<S: Into<String>>(mut self, resume_token: S) -> Handshake {
+        self.resume_token = Some(resume_token.into());
+        self
+    }
+
+    pub fn as_observer(mut self) -> Handshake {
+        self.observer = true;
+        self
+    }
+
+    pub fn with_capabilities<S: AsRef<str>>(mut self, capabilities: &[S]) -> Handshake {
+        self.capabilities = capabilities.iter().map(|c| c.as_ref().to_string()).collect();
+        self
+    }
+
+    pub fn with_password<S: Into<String>>(mut self, password: S) -> Handshake {
+        self.password = Some(password.into());
+        self
+    }
+
+    pub fn with_auth_token<S: Into<String>>(mut self, auth_token: S) -> Handshake {
+        self.auth_token = Some(auth_token.into());
+        self
     }
 }
 
-pub type HandshakeCodec = codec::LengthPrefixedJson<Handshake, Handshake>;
+pub type HandshakeCodec = LengthPrefixedJson<Handshake, Handshake>;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct ClientMessage(pub String);
+// The number the server gives each chat message; see `ServerMessage::Message`. Numbers are unique
+// for as long as the server runs (or, if it keeps its history in a database, for as long as the
+// database does).
+pub type MessageId = u64;
+
+// Every client starts out in this room after its handshake.
+pub const DEFAULT_ROOM: &str = "lobby";
+
+// What the body of a `FormattedMessage` is written in. Rendering it is up to the receiving client;
+// the server only makes sure `Html` is safe to render. `Unsupported` is any other name, from a
+// newer version of the protocol, which the server turns away with an
+// `ErrorCode::UnsupportedContentType` error. On the wire, each is just its name, as a string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContentType {
+    PlainText,
+    Markdown,
+    Html,
+    Unsupported(String),
+}
+
+impl ContentType {
+    pub fn name(&self) -> &str {
+        match *self {
+            ContentType::PlainText => "PlainText",
+            ContentType::Markdown => "Markdown",
+            ContentType::Html => "Html",
+            ContentType::Unsupported(ref name) => name,
+        }
+    }
+}
+
+impl Serialize for ContentType {
+    fn serialize<S: Serializer>(&self, serializer: &mut S) -> Result<(), S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl Deserialize for ContentType {
+    fn deserialize<D: Deserializer>(deserializer: &mut D) -> Result<ContentType, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Ok(match name.as_str() {
+            "PlainText" => ContentType::PlainText,
+            "Markdown" => ContentType::Markdown,
+            "Html" => ContentType::Html,
+            _ => ContentType::Unsupported(name),
+        })
+    }
+}
+
+// Enumerate possible messages clients can send to the server after the handshake. Their wire
+// format is written out by hand, in `wire`, for the sake of `Unknown`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientMessage {
+    // A chat message for everyone in the sender's current room.
+    Message(String),
+
+    // A chat message like `Message`, but with its `body` in the given format, which the room hears
+    // as a `ServerMessage::FormattedMessage`. (A `PlainText` one is just a `Message`.)
+    FormattedMessage {
+        body: String,
+        content_type: ContentType,
+    },
+
+    // Leave the current room and join the named one. Rooms don't need to be created; a room
+    // exists as long as somebody is in it. Joining a room tells the client about it with a
+    // `ServerMessage::RoomInfo`, if it has `capability::ROOMS`.
+    Join(String),
+
+    // Create the room `name` with settings of its own, which last until the server stops. The room
+    // can't already exist, either because it's been created or because somebody's in it, and only
+    // operators may create private rooms. The server answers with a `ServerMessage::RoomInfo`;
+    // creating a room doesn't join it.
+    CreateRoom {
+        name: String,
+        config: RoomConfig,
+    },
+
+    // Ask about the named room. The server answers with a `ServerMessage::RoomInfo`, unless the
+    // room is private and the sender isn't an operator, who gets `Unauthorized`.
+    GetRoomInfo(String),
+
+    // Set (or, with `None`, clear) a short status line like "away" that's shown alongside the
+    // sender's name.
+    SetStatus(Option<String>),
+
+    // Ask who's in the sender's current room. The server answers with `ServerMessage::Users`.
+    Who,
+
+    // Announce a file that's about to be sent to the current room, in `chunk_count` `FileChunk`s
+    // totalling `size` bytes. `transfer_id` is picked by the client and ties the chunks to this
+    // offer; see `offer_file`.
+    FileOffer {
+        transfer_id: u64,
+        name: String,
+        size: u64,
+        chunk_count: u32,
+    },
+
+    // One piece of a file announced by a `FileOffer` with the same `transfer_id`. `index` counts
+    // from zero.
+    FileChunk {
+        transfer_id: u64,
+        index: u32,
+        data: Vec<u8>,
+    },
+
+    // A notice for every connected client, in every room, such as a warning about upcoming
+    // maintenance. Only operators (clients whose `Handshake` carried the server's admin token) may
+    // send these; anyone else gets an `ErrorCode::Unauthorized` error back.
+    AdminAnnounce(String),
+
+    // Register `username` (not necessarily the sender's own name) with `password`, which must be
+    // at least 8 characters long, so that from then on logging in with that name needs the
+    // password. The server answers with `ServerMessage::Registered`, or an `InvalidMessage` error
+    // if the name is already registered or the password is too short.
+    Register {
+        username: String,
+        password: String,
+    },
+
+    // Replace the body of the sender's own message numbered `id` with `new_body`, which has to
+    // follow the same rules as a new message in that room. The server only remembers so many
+    // messages back (see `--history`); editing one it has forgotten, or that doesn't exist, gets an
+    // `InvalidMessage` error, and editing someone else's gets `Unauthorized`.
+    EditMessage {
+        id: MessageId,
+        new_body: String,
+    },
+
+    // Stop the server accepting `token`, one of the sender's own `ServerMessage::Token`s, before it
+    // expires; logging out, in effect. Revoking someone else's token gets `Unauthorized`.
+    RevokeToken {
+        token: String,
+    },
+
+    // Set the topic of `room`, which has to be the room the sender is in, to `topic`, or clear it
+    // if `topic` is empty. Everyone in the room hears about it with a
+    // `ServerMessage::TopicChanged`. A server started with `--restrict-topics` only lets operators
+    // do this, and answers anyone else with `Unauthorized`.
+    SetTopic {
+        room: String,
+        topic: String,
+    },
+
+    // Ask for the chat in the named room that the server still remembers (see `--history`), as
+    // last edited. Only operators may; the server answers them with a `ServerMessage::ExportData`,
+    // and anyone else with `Unauthorized`.
+    AdminExport(String),
+
+    // Check the server is still there. It answers with a `ServerMessage::Pong` carrying the same
+    // number, which is up to the client; see `client::HeartbeatConfig`.
+    Ping(u64),
+
+    // A message of a type this version of the protocol doesn't have, most likely from a client
+    // with a newer one, as it came: tagged with its type, as described at `unknown_type`. The
+    // server answers these with an `ErrorCode::UnsupportedMessageType` error.
+    Unknown(serde_json::Value),
+}
 
 impl ClientMessage {
     pub fn new<S: Into<String>>(message: S) -> ClientMessage {
-        ClientMessage(message.into())
+        ClientMessage::Message(message.into())
+    }
+
+    // The capability that has to have been negotiated before a client may send this, if any.
+    pub fn capability(&self) -> Option<&'static str> {
+        match *self {
+            ClientMessage::FileOffer { .. } |
+            ClientMessage::FileChunk { .. } => Some(capability::FILE_TRANSFER),
+            ClientMessage::SetStatus(_) => Some(capability::STATUS),
+            ClientMessage::AdminAnnounce(_) => Some(capability::ANNOUNCEMENTS),
+            ClientMessage::EditMessage { .. } => Some(capability::EDITS),
+            ClientMessage::SetTopic { .. } => Some(capability::TOPICS),
+            ClientMessage::AdminExport(_) => Some(capability::EXPORT),
+            ClientMessage::Ping(_) => Some(capability::HEARTBEAT),
+            ClientMessage::FormattedMessage { .. } => Some(capability::FORMATTING),
+            ClientMessage::CreateRoom { .. } |
+            ClientMessage::GetRoomInfo(_) => Some(capability::ROOMS),
+            _ => None,
+        }
+    }
+
+    // The type an `Unknown` message was tagged with.
+    pub fn unknown_type(&self) -> Option<&str> {
+        match *self {
+            ClientMessage::Unknown(serde_json::Value::String(ref kind)) => Some(kind),
+            ClientMessage::Unknown(serde_json::Value::Object(ref tagged)) => {
+                tagged.keys().next().map(String::as_str)
+            }
+            _ => None,
+        }
     }
 }
 
 // Enumerate possible messages the server can send to clients.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
 pub enum ServerMessage {
-    // A message from a client (first String) containing arbitrary content (second String).
-    Message(String, String),
+    // The first thing the server sends a client after accepting its `Handshake`. If the client's
+    // connection drops, it can reconnect with `resume_token` in its next `Handshake` (within the
+    // server's grace period) to get its old name, room, and status back, along with the chat it
+    // missed in the meantime. `capabilities` are the ones from the client's `Handshake` that the
+    // server supports too, which are all either side may use.
+    Welcome {
+        resume_token: String,
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
+
+    // A message from a client (the first String) containing arbitrary content (the second
+    // String), numbered by the server so it can be referred to later. Only clients in the same room
+    // as the sender receive it.
+    Message(MessageId, String, String),
+
+    // A `ClientMessage::FormattedMessage`, numbered like a `Message`. Clients that didn't
+    // negotiate `capability::FORMATTING` are sent it as a plain `Message` instead; see `fallback`.
+    FormattedMessage {
+        id: MessageId,
+        from: String,
+        body: String,
+        content_type: ContentType,
+    },
+
+    // The author of the message numbered `id` changed it to say `new_body` instead. Sent to the
+    // room the message was sent in.
+    MessageEdited {
+        id: MessageId,
+        new_body: String,
+    },
 
     // Notification of a new user connection. The associated String is the name that user provided
     // in their Handshake.
@@ -49,7 +367,245 @@ pub enum ServerMessage {
     // Notification of user disconnection. The associated String is the name that user provided
     // in their Handshake.
     UserDisconnected(String),
+
+    // Notification that a user (first String) joined a room (second String). Sent to everyone in
+    // that room, including the user who joined. The third field is the user's status, if they've
+    // set one.
+    UserJoined(String, String, Option<String>),
+
+    // Notification that a user (first String) left a room (second String) for another one. Sent
+    // to everyone remaining in the room they left.
+    UserLeft(String, String),
+
+    // A client (`from`) is about to send a file to everyone in its room. Its chunks follow as
+    // `FileChunk`s with the same `from` and `transfer_id`; use a `FileAssembly` to put them back
+    // together.
+    FileOffer {
+        from: String,
+        transfer_id: u64,
+        name: String,
+        size: u64,
+        chunk_count: u32,
+    },
+
+    // One piece of a file announced by a `FileOffer`.
+    FileChunk {
+        from: String,
+        transfer_id: u64,
+        index: u32,
+        data: Vec<u8>,
+    },
+
+    // Notification that a user (the String) set or cleared their status. Sent to everyone in
+    // their room.
+    StatusChanged(String, Option<String>),
+
+    // The topic of `room` is now `topic` (or there isn't one, if it's empty). Sent to everyone in
+    // the room when it changes, and to each client that joins a room with a topic.
+    TopicChanged {
+        room: String,
+        topic: String,
+    },
+
+    // What the room `name` was created with (or, for a room that wasn't created, the limits the
+    // server puts on it anyway), how many members it has, not counting observers, and its topic
+    // now. Sent to a client that joins a room, and in answer to `ClientMessage::CreateRoom` and
+    // `ClientMessage::GetRoomInfo`.
+    RoomInfo {
+        name: String,
+        config: RoomConfig,
+        member_count: u32,
+        topic: Option<String>,
+    },
+
+    // The answer to a `ClientMessage::Who`: everyone in the named room, including the asker.
+    Users(String, Vec<UserInfo>),
+
+    // A notice from the server's operators, sent to every connected client regardless of room. It
+    // comes from the server itself rather than from any user.
+    ServerAnnouncement(String),
+
+    // The server's message of the day (its rules, say, or links), if its operators set one. Sent
+    // right after the `Welcome` (and `Token`, if there is one), before anything else.
+    Motd(String),
+
+    // The answer to a successful `ClientMessage::Register`: the named user now needs their
+    // password to log in.
+    Registered(String),
+
+    // A token the client can log in with for the next `expires_in_secs` seconds instead of its
+    // password, by putting it in `Handshake::auth_token`. Sent right after the `Welcome` when a
+    // registered user logs in with their password, or with a token that's nearly expired (which
+    // this one then replaces).
+    Token {
+        token: String,
+        expires_in_secs: u64,
+    },
+
+    // The answer to a `ClientMessage::AdminExport`: the chat in `room` as of when it was asked
+    // for, oldest first.
+    ExportData {
+        room: String,
+        messages: Vec<ChatMessage>,
+    },
+
+    // The answer to a `ClientMessage::Ping` with the same number.
+    Pong(u64),
+
+    // Something the client did was refused. The String is a human-readable explanation.
+    Error(ErrorCode, String),
+}
+
+impl ServerMessage {
+    // The capability a client must have negotiated to be sent this, if any.
+    pub fn capability(&self) -> Option<&'static str> {
+        match *self {
+            ServerMessage::FileOffer { .. } |
+            ServerMessage::FileChunk { .. } => Some(capability::FILE_TRANSFER),
+            ServerMessage::StatusChanged(..) => Some(capability::STATUS),
+            ServerMessage::ServerAnnouncement(_) => Some(capability::ANNOUNCEMENTS),
+            ServerMessage::MessageEdited { .. } => Some(capability::EDITS),
+            ServerMessage::TopicChanged { .. } => Some(capability::TOPICS),
+            ServerMessage::ExportData { .. } => Some(capability::EXPORT),
+            ServerMessage::Pong(_) => Some(capability::HEARTBEAT),
+            ServerMessage::FormattedMessage { .. } => Some(capability::FORMATTING),
+            ServerMessage::RoomInfo { .. } => Some(capability::ROOMS),
+            _ => None,
+        }
+    }
+
+    // What to send a client that can't be sent this for want of its capability, if anything.
+    // Formatted chat is still chat, so it goes as a plain `Message` with the body as it was
+    // written; everything else is just left out.
+    pub fn fallback(&self) -> Option<ServerMessage> {
+        match *self {
+            ServerMessage::FormattedMessage { id, ref from, ref body, .. } => {
+                Some(ServerMessage::Message(id, from.clone(), body.clone()))
+            }
+            _ => None,
+        }
+    }
+}
+
+// Messages go over the wire tagged with their type: the name of their variant, as the only key of
+// a JSON object (`{"Join":"lobby"}`) or, for types without any fields, on its own (`"Who"`). So a
+// message can only ever decode as the type it was sent as. A `ClientMessage` of a type the server
+// doesn't know, from a client with a newer version of the protocol, decodes as a
+// `ClientMessage::Unknown`, but a `ServerMessage` of one fails to decode. This picks that case out
+// from other decoding errors: if `err` is from a message of an unknown type, it returns the type.
+// (The same goes for an unknown `ErrorCode` in a `ServerMessage::Error`.)
+pub fn unknown_type(err: &serde_json::Error) -> Option<&str> {
+    match *err {
+        serde_json::Error::Syntax(serde_json::ErrorCode::UnknownVariant(ref name), _, _) => {
+            Some(name)
+        }
+        _ => None,
+    }
 }
 
-pub type ServerToClientCodec = codec::LengthPrefixedJson<ClientMessage, ServerMessage>;
-pub type ClientToServerCodec = codec::LengthPrefixedJson<ServerMessage, ClientMessage>;
+// Nor can a message have fields its type doesn't: every message type denies unknown fields, so
+// one with a field from a newer version of the protocol fails to decode rather than having the
+// field quietly dropped. If that's why `err` happened, this returns the field.
+pub fn unknown_field(err: &serde_json::Error) -> Option<&str> {
+    match *err {
+        serde_json::Error::Syntax(serde_json::ErrorCode::UnknownField(ref name), _, _) => {
+            Some(name)
+        }
+        _ => None,
+    }
+}
+
+// The settings of a room created with `ClientMessage::CreateRoom`. Anything left `None` is up to
+// the server, as it is for rooms that weren't created.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct RoomConfig {
+    // How many members the room may have before it turns away joiners.
+    pub max_members: Option<u32>,
+
+    // How many messages may be sent in the room per minute, by everyone in it together. This is
+    // on top of the limit on each member.
+    pub messages_per_minute: Option<u32>,
+
+    // How many of the room's messages the server remembers, for resumed sessions, edits and
+    // exports. It's never more than the server remembers of all rooms together (see `--history`).
+    pub history_size: Option<u32>,
+
+    // The topic the room starts out with.
+    pub topic: Option<String>,
+
+    // Whether only operators may join the room, or ask about it.
+    #[serde(default)]
+    pub private: bool,
+}
+
+// What the server reports about a user in `ServerMessage::Users`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct UserInfo {
+    pub name: String,
+    pub status: Option<String>,
+}
+
+// One chat message, as the server reports it in `ServerMessage::ExportData`: what
+// `ServerMessage::Message` would have said, with any edits applied.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ChatMessage {
+    pub id: MessageId,
+    pub from: String,
+    pub body: String,
+}
+
+// Reasons the server may refuse a client's request, sent as part of `ServerMessage::Error`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    // The client's `Handshake` did not carry the token the server requires, or the right password
+    // for a registered name (or any password, on a server that doesn't allow guests), in which
+    // case the server closes the connection after sending this. Or else the client tried
+    // something only operators may do.
+    Unauthorized,
+
+    // The message was malformed or broke one of the current room's rules (e.g., too long), and
+    // was not delivered.
+    InvalidMessage,
+
+    // The client is sending messages faster than the current room allows. The message was not
+    // delivered.
+    RateLimited,
+
+    // The client's address already has as many connections open as the server allows. The server
+    // closes the connection after sending this, without waiting for a `Handshake`.
+    TooManyConnections,
+
+    // The client went longer than the server allows without sending anything. The server closes
+    // the connection after sending this.
+    IdleTimeout,
+
+    // The room the client tried to join already has as many members as it's allowed. The client
+    // stays in the room it was in.
+    RoomFull,
+
+    // The client sent a `FormattedMessage` in a format the server doesn't know. It was not
+    // delivered.
+    UnsupportedContentType,
+
+    // The client carried on sending messages faster than its room allows after a `RateLimited`
+    // warning that it would be disconnected for it. The server closes the connection after sending
+    // this.
+    Flooding,
+
+    // Too many logins from the client's address have failed lately, and it may not try again for
+    // a while; the error says how long. The server closes the connection after sending this.
+    TooManyAttempts,
+
+    // The client sent a message of a type the server doesn't know, most likely from a newer
+    // version of the protocol; see `unknown_type`. It was skipped, and the client can carry on.
+    UnsupportedMessageType,
+}
+
+pub type ServerToClientCodec = LengthPrefixedJson<ClientMessage, ServerMessage>;
+pub type ClientToServerCodec = LengthPrefixedJson<ServerMessage, ClientMessage>;
+
+// Like `ServerToClientCodec`, but yields malformed messages as `Err`s rather than failing.
+pub type LenientServerToClientCodec = LenientJson<ClientMessage, ServerMessage>;
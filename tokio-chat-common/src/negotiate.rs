@@ -0,0 +1,242 @@
+use serde::{Serialize, Deserialize};
+use serde_json;
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use futures::Future;
+use tokio_core::io::{read_exact, write_all, Codec, EasyBuf, Io};
+
+use std::io;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use codec::{check_depth, decode_frame, encode_frame, DEFAULT_MAX_DEPTH, MAX_FRAME_LEN};
+
+// The first frame each side of a `NegotiatingCodec` connection sends: the formats it can speak,
+// most preferred first. It's always JSON, framed like everything else in this crate.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Hello {
+    pub formats: Vec<String>,
+}
+
+// A way of turning messages into frame payloads and back, which a `NegotiatingCodec` can agree
+// with its peer to use. Formats are told apart by `name`, so both ends need to mean the same thing
+// by it.
+pub trait FrameFormat<In, Out>: Send + Sync {
+    fn name(&self) -> &str;
+
+    // Append `msg`, serialized, to `payload`.
+    fn encode(&self, msg: &Out, payload: &mut Vec<u8>) -> io::Result<()>;
+
+    fn decode(&self, payload: &[u8]) -> io::Result<In>;
+}
+
+// The format every `NegotiatingCodec` starts out in and can always fall back on, with the same
+// nesting limit as `LengthPrefixedJson`.
+pub struct JsonFormat;
+
+const JSON: &str = "json";
+
+impl<In, Out> FrameFormat<In, Out> for JsonFormat
+    where In: Deserialize,
+          Out: Serialize
+{
+    fn name(&self) -> &str {
+        JSON
+    }
+
+    fn encode(&self, msg: &Out, payload: &mut Vec<u8>) -> io::Result<()> {
+        serde_json::to_writer(payload, msg)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn decode(&self, payload: &[u8]) -> io::Result<In> {
+        check_depth(payload, DEFAULT_MAX_DEPTH)
+            .and_then(|()| serde_json::from_slice(payload))
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+// A codec that works out with its peer which format to speak. Each side's first frame is a
+// `Hello` listing the formats it has, and once it's heard the other side's, it switches to the
+// format they both have that they'd both most like (the one with the lowest combined rank in the
+// two lists, ties going to the name that sorts first, so both sides pick the same one). Until
+// then, it speaks JSON.
+//
+// So that the switch can happen at any point, each frame after the `Hello` starts with a byte
+// saying which of the sender's formats (by its place in the sender's `Hello`) the rest of it is in.
+// Otherwise these are the same length-prefixed frames as the other codecs use.
+//
+// Left to itself, the codec sends its `Hello` ahead of the first message it encodes, and anything
+// encoded before the peer's `Hello` arrives goes out as JSON. To have every message in the agreed
+// format, `negotiate` the connection first, which trades `Hello`s before anything else is sent:
+// it takes one round trip at most, as both sides send theirs straight away.
+//
+// Clones share their state, so a clone can encode while another decodes, on another thread if
+// need be, and both switch formats together.
+pub struct NegotiatingCodec<In, Out> {
+    formats: Arc<Vec<Box<FrameFormat<In, Out>>>>,
+    state: Arc<Mutex<Negotiation>>,
+}
+
+#[derive(Default)]
+struct Negotiation {
+    hello_sent: bool,
+
+    // The formats in the peer's `Hello`, once it's arrived, and which of ours we agreed on, as an
+    // index into `formats`.
+    theirs: Option<Vec<String>>,
+    agreed: Option<usize>,
+}
+
+impl<In, Out> NegotiatingCodec<In, Out>
+    where In: Deserialize + 'static,
+          Out: Serialize + 'static
+{
+    // A codec that only speaks JSON. Add more formats with `with_format`.
+    pub fn new() -> NegotiatingCodec<In, Out> {
+        NegotiatingCodec {
+            formats: Arc::new(vec![Box::new(JsonFormat)]),
+            state: Arc::new(Mutex::new(Negotiation::default())),
+        }
+    }
+
+    // Offer `format` as well, preferring it to JSON and to any format added after it, but not to
+    // any added before it. This has to be done before the codec is used or cloned.
+    pub fn with_format<F>(mut self, format: F) -> NegotiatingCodec<In, Out>
+        where F: FrameFormat<In, Out> + 'static
+    {
+        let formats = Arc::get_mut(&mut self.formats).expect("formats added after cloning");
+        let json = formats.len() - 1;
+        formats.insert(json, Box::new(format));
+        self
+    }
+}
+
+impl<In, Out> NegotiatingCodec<In, Out> {
+    // The name of the format we're speaking, if we've agreed on one yet.
+    pub fn agreed_format(&self) -> Option<String> {
+        let state = self.state.lock().expect("a codec panicked");
+        state.agreed.map(|i| self.formats[i].name().to_string())
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.formats.iter().map(|format| format.name().to_string()).collect()
+    }
+
+    // Append our `Hello` to `buf`, if it hasn't been sent already.
+    fn say_hello(&self, state: &mut Negotiation, buf: &mut Vec<u8>) -> io::Result<()> {
+        if !state.hello_sent {
+            encode_frame(&Hello { formats: self.names() }, buf)?;
+            state.hello_sent = true;
+        }
+        Ok(())
+    }
+
+    // Take in the peer's `Hello`, the payload of its first frame, and pick the format to speak.
+    fn hear_hello(&self, state: &mut Negotiation, payload: &[u8]) -> io::Result<()> {
+        let invalid = |err| io::Error::new(io::ErrorKind::InvalidData, err);
+        check_depth(payload, DEFAULT_MAX_DEPTH).map_err(invalid)?;
+        let hello = serde_json::from_slice::<Hello>(payload).map_err(invalid)?;
+        let ours = self.names();
+        let agreed = ours.iter()
+            .enumerate()
+            .filter_map(|(i, name)| {
+                hello.formats.iter().position(|theirs| theirs == name).map(|j| (i + j, name, i))
+            })
+            .min()
+            .map(|(_, _, i)| i);
+        if agreed.is_none() {
+            let msg = format!("no format in common with the peer's {:?}", hello.formats);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        state.theirs = Some(hello.formats);
+        state.agreed = agreed;
+        Ok(())
+    }
+}
+
+impl<In, Out> Clone for NegotiatingCodec<In, Out> {
+    fn clone(&self) -> NegotiatingCodec<In, Out> {
+        NegotiatingCodec {
+            formats: self.formats.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<In, Out> Codec for NegotiatingCodec<In, Out> {
+    type In = In;
+    type Out = Out;
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Self::In>> {
+        let mut state = self.state.lock().expect("a codec panicked");
+        loop {
+            let frame = match decode_frame(buf) {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+            let frame = frame.as_ref();
+            let theirs = match state.theirs {
+                Some(ref theirs) => theirs,
+                None => {
+                    self.hear_hello(&mut state, frame)?;
+                    continue;
+                }
+            };
+            let invalid = |msg: &str| Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+            let name = match frame.first().and_then(|&tag| theirs.get(tag as usize)) {
+                Some(name) => name,
+                None => return invalid("frame in a format the peer didn't offer"),
+            };
+            let format = match self.formats.iter().find(|format| format.name() == name) {
+                Some(format) => format,
+                None => return invalid("frame in a format we don't speak"),
+            };
+            return format.decode(&frame[1..]).map(Some);
+        }
+    }
+
+    fn encode(&mut self, msg: Out, buf: &mut Vec<u8>) -> io::Result<()> {
+        let mut state = self.state.lock().expect("a codec panicked");
+        self.say_hello(&mut state, buf)?;
+
+        // Until we've agreed on something better, JSON, which is always our last format.
+        let tag = state.agreed.unwrap_or(self.formats.len() - 1);
+        let mut payload = vec![tag as u8];
+        self.formats[tag].encode(&msg, &mut payload)?;
+        if payload.len() > MAX_FRAME_LEN {
+            let msg = format!("frame of {} bytes exceeds the limit of {}",
+                              payload.len(),
+                              MAX_FRAME_LEN);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        buf.reserve(mem::size_of::<u16>() + payload.len());
+        buf.write_u16::<BigEndian>(payload.len() as u16)?;
+        buf.extend_from_slice(&payload);
+        Ok(())
+    }
+}
+
+type Negotiated<T, In, Out> = Box<Future<Item = (T, NegotiatingCodec<In, Out>), Error = io::Error>>;
+
+// Trade `Hello`s with the peer on `io`, before anything else, so that `codec` speaks the agreed
+// format from the first message on. Only the peer's `Hello` is read off `io`, so it can be framed
+// with `codec` straight afterwards.
+pub fn negotiate<T, In, Out>(io: T, codec: NegotiatingCodec<In, Out>) -> Negotiated<T, In, Out>
+    where T: Io + 'static,
+          In: 'static,
+          Out: 'static
+{
+    let mut hello = Vec::new();
+    if let Err(err) = codec.say_hello(&mut codec.state.lock().expect("a codec panicked"),
+                                      &mut hello) {
+        return Box::new(::futures::future::err(err));
+    }
+    Box::new(write_all(io, hello)
+        .and_then(|(io, _)| read_exact(io, [0; 2]))
+        .and_then(|(io, len)| read_exact(io, vec![0; BigEndian::read_u16(&len) as usize]))
+        .and_then(move |(io, payload)| {
+            codec.hear_hello(&mut codec.state.lock().expect("a codec panicked"), &payload)?;
+            Ok((io, codec))
+        }))
+}
@@ -0,0 +1,44 @@
+// Timestamps as RFC 3339 strings, like `2017-03-14T15:09:26.535897Z`, rather than the struct of
+// seconds and nanoseconds serde would make of a `SystemTime` by itself. Anyone can read these, and
+// clients written in other languages can parse them with whatever date library they have. A
+// message field carrying a timestamp should use them:
+//
+//     #[serde(serialize_with = "rfc3339::serialize", deserialize_with = "rfc3339::deserialize")]
+//     pub sent_at: SystemTime,
+//
+// Timestamps are always written in UTC, with only as many fractional digits as they need, but
+// any offset is accepted when reading them back.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{de, ser, Deserialize, Deserializer, Serializer};
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+
+pub fn serialize<S: Serializer>(time: &SystemTime, serializer: &mut S) -> Result<(), S::Error> {
+    let formatted = format(*time).map_err(<S::Error as ser::Error>::custom)?;
+    serializer.serialize_str(&formatted)
+}
+
+pub fn deserialize<D: Deserializer>(deserializer: &mut D) -> Result<SystemTime, D::Error> {
+    let formatted = String::deserialize(deserializer)?;
+    parse(&formatted).map_err(<D::Error as de::Error>::custom)
+}
+
+// `time` in RFC 3339 form. Only the years 0 to 9999 can be written that way.
+pub fn format(time: SystemTime) -> Result<String, String> {
+    let nanos = match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_nanos() as i128,
+        Err(err) => -(err.duration().as_nanos() as i128),
+    };
+    OffsetDateTime::from_unix_timestamp_nanos(nanos)
+        .map_err(|err| err.to_string())
+        .and_then(|time| time.format(&Rfc3339).map_err(|err| err.to_string()))
+}
+
+// The time `formatted`, an RFC 3339 timestamp, stands for.
+pub fn parse(formatted: &str) -> Result<SystemTime, String> {
+    OffsetDateTime::parse(formatted, &Rfc3339)
+        .map(SystemTime::from)
+        .map_err(|err| format!("invalid RFC 3339 timestamp {:?}: {}", formatted, err))
+}
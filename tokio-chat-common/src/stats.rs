@@ -0,0 +1,129 @@
+use tokio_core::io::{Codec, EasyBuf};
+
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Running totals of what a `StatsCodec` has seen. The counters are atomics so they can be shared
+// (as an `Arc<CodecStats>`) with whoever wants to read them without getting in the codec's way.
+#[derive(Debug, Default)]
+pub struct CodecStats {
+    pub messages_encoded: AtomicU64,
+    pub bytes_encoded: AtomicU64,
+    pub messages_decoded: AtomicU64,
+    pub bytes_decoded: AtomicU64,
+    pub decode_errors: AtomicU64,
+    pub encode_errors: AtomicU64,
+}
+
+// A copy of `CodecStats` as of some moment, for reading without any atomics involved.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CodecStatsSnapshot {
+    pub messages_encoded: u64,
+    pub bytes_encoded: u64,
+    pub messages_decoded: u64,
+    pub bytes_decoded: u64,
+    pub decode_errors: u64,
+    pub encode_errors: u64,
+}
+
+impl CodecStats {
+    pub fn new() -> CodecStats {
+        CodecStats::default()
+    }
+
+    // The counters are read one at a time, so a snapshot taken while the codec is busy may be a
+    // message out between fields.
+    pub fn snapshot(&self) -> CodecStatsSnapshot {
+        CodecStatsSnapshot {
+            messages_encoded: self.messages_encoded.load(Ordering::Relaxed),
+            bytes_encoded: self.bytes_encoded.load(Ordering::Relaxed),
+            messages_decoded: self.messages_decoded.load(Ordering::Relaxed),
+            bytes_decoded: self.bytes_decoded.load(Ordering::Relaxed),
+            decode_errors: self.decode_errors.load(Ordering::Relaxed),
+            encode_errors: self.encode_errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// `StatsCodec` wraps another codec, passing everything through unchanged while counting messages,
+// bytes (of whole frames, length prefix included) and errors in each direction.
+//
+// Only errors the inner codec returns are counted as errors. A codec that hands malformed frames
+// out as items, like `LenientJson`, counts those as decoded messages; bump `decode_errors` on the
+// shared stats yourself if they should be counted separately.
+//
+// Clones share the stats, wrapping a clone of the inner codec, so a pool of connections made from
+// one `StatsCodec` keeps one set of totals for all of them.
+#[derive(Clone)]
+pub struct StatsCodec<C> {
+    inner: C,
+    stats: Arc<CodecStats>,
+}
+
+impl<C: Codec> StatsCodec<C> {
+    pub fn new(inner: C) -> StatsCodec<C> {
+        StatsCodec::with_stats(inner, Arc::new(CodecStats::new()))
+    }
+
+    // Count into `stats`, which may already have been shared elsewhere (or be shared with other
+    // codecs, to keep one set of totals for several of them).
+    pub fn with_stats(inner: C, stats: Arc<CodecStats>) -> StatsCodec<C> {
+        StatsCodec {
+            inner: inner,
+            stats: stats,
+        }
+    }
+
+    // A handle on the live counters, for reading them while the codec is in use elsewhere (e.g.,
+    // inside a `Framed`).
+    pub fn stats(&self) -> Arc<CodecStats> {
+        self.stats.clone()
+    }
+
+    pub fn snapshot(&self) -> CodecStatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C: Codec> Codec for StatsCodec<C> {
+    type In = C::In;
+    type Out = C::Out;
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Self::In>> {
+        let len_before = buf.len();
+        let result = self.inner.decode(buf);
+        match result {
+            Ok(Some(_)) => {
+                self.stats.messages_decoded.fetch_add(1, Ordering::Relaxed);
+                let consumed = len_before - buf.len();
+                self.stats.bytes_decoded.fetch_add(consumed as u64, Ordering::Relaxed);
+            }
+            Ok(None) => {}
+            Err(_) => {
+                self.stats.decode_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        result
+    }
+
+    fn encode(&mut self, msg: Self::Out, buf: &mut Vec<u8>) -> io::Result<()> {
+        let len_before = buf.len();
+        let result = self.inner.encode(msg, buf);
+        match result {
+            Ok(()) => {
+                self.stats.messages_encoded.fetch_add(1, Ordering::Relaxed);
+                let written = buf.len() - len_before;
+                self.stats.bytes_encoded.fetch_add(written as u64, Ordering::Relaxed);
+            }
+            Err(_) => {
+                self.stats.encode_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,137 @@
+use serde::Deserialize;
+use serde_json;
+use tokio_core::io::EasyBuf;
+use byteorder::{BigEndian, ReadBytesExt};
+
+use std::cmp;
+use std::collections::{vec_deque, VecDeque};
+use std::io::{self, Read};
+use std::marker::PhantomData;
+use std::mem;
+
+use codec::{check_depth, check_len, DEFAULT_MAX_DEPTH, MAX_FRAME_LEN};
+
+// `StreamingDecoder` reads the same wire format as `LengthPrefixedJson` (a Big Endian u16 length
+// followed by a JSON payload), but it doesn't wait for the entire frame to pile up in the framing
+// buffer before doing anything. Instead, as soon as the length prefix arrives, every subsequent
+// chunk of bytes is drained out of the `EasyBuf` and kept in a ring of the parts of the payload
+// that have arrived so far, so the framing buffer never has to hold more than whatever the last
+// socket read produced. Draining doesn't copy: each part is a view of the buffer it arrived in,
+// which stays alive while the framing buffer moves on to a fresh one for the next read.
+//
+// `serde_json` can't suspend a half-finished deserialization and pick it back up later, so the
+// actual parse still waits until the whole payload has arrived. Until then `decode` returns
+// `Ok(None)`, which is how a tokio `Codec` says "not ready yet, give me more bytes". Once the
+// payload is complete it is deserialized by reading through the parts in order; it is never
+// gathered into a single contiguous slice first.
+//
+// Frames are held to the same limits as `LengthPrefixedJson`'s, on their length as soon as their
+// prefix arrives and on their nesting before they're parsed, and fail decoding the same way.
+pub struct StreamingDecoder<In>
+    where In: Deserialize
+{
+    max_frame_len: usize,
+    max_depth: usize,
+
+    // Number of payload bytes of the current frame we haven't seen yet, or `None` if we're still
+    // waiting on the length prefix of the next frame.
+    remaining: Option<usize>,
+    parts: VecDeque<EasyBuf>,
+    _in: PhantomData<In>,
+}
+
+impl<In> Default for StreamingDecoder<In>
+    where In: Deserialize
+{
+    fn default() -> StreamingDecoder<In> {
+        StreamingDecoder::new()
+    }
+}
+
+impl<In> StreamingDecoder<In>
+    where In: Deserialize
+{
+    pub fn new() -> StreamingDecoder<In> {
+        StreamingDecoder {
+            max_frame_len: MAX_FRAME_LEN,
+            max_depth: DEFAULT_MAX_DEPTH,
+            remaining: None,
+            parts: VecDeque::new(),
+            _in: PhantomData,
+        }
+    }
+
+    // See `LengthPrefixedJson::with_max_frame_len`.
+    pub fn with_max_frame_len(mut self, max_frame_len: usize) -> StreamingDecoder<In> {
+        self.max_frame_len = max_frame_len;
+        self
+    }
+
+    // See `LengthPrefixedJson::with_max_depth`.
+    pub fn with_max_depth(mut self, max_depth: usize) -> StreamingDecoder<In> {
+        self.max_depth = max_depth;
+        self
+    }
+
+    // Same contract as `Codec::decode`, so this can be dropped into a `Codec` impl as-is.
+    pub fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<In>> {
+        let remaining = match self.remaining {
+            Some(remaining) => remaining,
+            None => {
+                // Make sure we have at least the 2 u16 bytes we need.
+                let msg_size = match buf.as_ref().read_u16::<BigEndian>() {
+                    Ok(msg_size) => msg_size,
+                    Err(_) => return Ok(None),
+                };
+                let msg_size = check_len(msg_size as usize, self.max_frame_len)?;
+                buf.drain_to(mem::size_of::<u16>());
+                msg_size
+            }
+        };
+
+        // Take however much of the payload has arrived so far.
+        let available = cmp::min(remaining, buf.len());
+        if available > 0 {
+            self.parts.push_back(buf.drain_to(available));
+        }
+        if available < remaining {
+            self.remaining = Some(remaining - available);
+            return Ok(None);
+        }
+        self.remaining = None;
+
+        // Decode! Drop the parts whether or not this succeeds so a bad payload can't bleed into
+        // the next frame.
+        let msg = check_depth(self.parts.iter().flat_map(|part| part.as_ref()), self.max_depth)
+            .and_then(|()| serde_json::from_reader(Parts::new(&self.parts)));
+        self.parts.clear();
+        msg.map(Some).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+// The parts of a payload, read one after the other as if they were one.
+struct Parts<'a> {
+    parts: vec_deque::Iter<'a, EasyBuf>,
+    current: &'a [u8],
+}
+
+impl<'a> Parts<'a> {
+    fn new(parts: &'a VecDeque<EasyBuf>) -> Parts<'a> {
+        Parts {
+            parts: parts.iter(),
+            current: &[],
+        }
+    }
+}
+
+impl<'a> Read for Parts<'a> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() {
+            match self.parts.next() {
+                Some(part) => self.current = part.as_ref(),
+                None => return Ok(0),
+            }
+        }
+        self.current.read(out)
+    }
+}
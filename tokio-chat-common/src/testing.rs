@@ -0,0 +1,272 @@
+// Stand-ins for testing clients without a real server. `MockServer::new` connects a real
+// `client::Client`, with the production codecs, to a `MockServer` over an in-memory pipe rather
+// than TCP, and has the two share a reactor, so a test can play the server's part one message at a
+// time:
+//
+//     let (mut server, mut client) = MockServer::new(Handshake::new("alice"));
+//     client.send(ClientMessage::new("hi"));
+//     server.expect_message(ClientMessage::new("hi"));
+//     server.send_response(ServerMessage::Motd("hello".to_string()));
+//     assert_eq!(client.recv(), ServerMessage::Motd("hello".to_string()));
+//
+// Everything here panics rather than returning errors, as assertions do, including when what's
+// being waited for doesn't turn up within `WAIT`.
+
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::{future, Async, Future, Sink, Stream};
+use futures::task::{self, Task};
+use tokio_core::io::{Framed, Io};
+use tokio_core::reactor::{Core, Timeout};
+
+use client::{Client, ClientBuilder, ClientError};
+use {ClientMessage, Handshake, HandshakeCodec, ServerMessage, ServerToClientCodec};
+
+// How long to wait for anything before failing the test.
+pub const WAIT: Duration = Duration::from_secs(5);
+
+// The server's end of the connection to a client made by `MockServer::new`.
+pub struct MockServer {
+    core: Rc<RefCell<Core>>,
+
+    // `None` only while we're waiting on it.
+    framed: Option<Framed<Pipe, ServerToClientCodec>>,
+    handshake: Handshake,
+}
+
+impl MockServer {
+    // Connect a client that introduces itself with `handshake`, welcome it, agreeing to every
+    // capability it asks for, and return both ends. The `Welcome` is the first thing the client
+    // will `recv`.
+    pub fn new(handshake: Handshake) -> (MockServer, MockClient) {
+        let core = Rc::new(RefCell::new(Core::new().expect("can't start a reactor")));
+        let handle = core.borrow().handle();
+        let (client_end, server_end) = Pipe::pair();
+
+        // There's only the one pipe, so the client can't reconnect, whatever it's told.
+        let client_end = RefCell::new(Some(client_end));
+        let connecting = ClientBuilder::new(handshake).connect_with(&handle, move |_| {
+            match client_end.borrow_mut().take() {
+                Some(pipe) => Box::new(future::ok(pipe)),
+                None => Box::new(future::err(Pipe::broken())),
+            }
+        });
+        let welcoming = server_end.framed(HandshakeCodec::new())
+            .into_future()
+            .map_err(|(err, _)| err)
+            .and_then(|(handshake, framed)| {
+                let handshake = handshake.expect("the client hung up before its handshake");
+                let welcome = ServerMessage::Welcome {
+                    resume_token: "mock".to_string(),
+                    capabilities: handshake.capabilities.clone(),
+                };
+                framed.into_inner()
+                    .framed(ServerToClientCodec::new())
+                    .send(welcome)
+                    .map(|framed| (framed, handshake))
+            })
+            .map_err(ClientError::from);
+        let (client, (framed, handshake)) =
+            wait(&core, "the client to connect", connecting.join(welcoming));
+        let server = MockServer {
+            core: core.clone(),
+            framed: Some(framed),
+            handshake: handshake,
+        };
+        let client = MockClient {
+            core: core,
+            client: client,
+        };
+        (server, client)
+    }
+
+    // The handshake the client introduced itself with.
+    pub fn handshake(&self) -> &Handshake {
+        &self.handshake
+    }
+
+    // The next message from the client.
+    pub fn recv_message(&mut self) -> ClientMessage {
+        let framed = self.framed.take().expect("the mock server is broken");
+        let next = framed.into_future().map_err(|(err, _)| err);
+        let (msg, framed) = wait(&self.core, "a message from the client", next);
+        self.framed = Some(framed);
+        msg.expect("the client hung up")
+    }
+
+    // Check the next message from the client is `expected`.
+    pub fn expect_message(&mut self, expected: ClientMessage) {
+        let msg = self.recv_message();
+        assert_eq!(msg, expected, "the client didn't send what we expected");
+    }
+
+    pub fn send_response(&mut self, msg: ServerMessage) {
+        let framed = self.framed.take().expect("the mock server is broken");
+        self.framed = Some(wait(&self.core, "the response to be sent", framed.send(msg)));
+    }
+}
+
+// The client's end, a `Client` along with the reactor it shares with the `MockServer`.
+pub struct MockClient {
+    core: Rc<RefCell<Core>>,
+    client: Client,
+}
+
+impl MockClient {
+    pub fn send(&self, msg: ClientMessage) {
+        self.client.send(msg).expect("the client is closed");
+    }
+
+    // The next message from the server.
+    pub fn recv(&mut self) -> ServerMessage {
+        let next = self.client.recv().into_future().map_err(|(err, _)| err);
+        let (msg, _) = wait(&self.core, "a message from the server", next);
+        msg.expect("the client was closed")
+    }
+
+    // The `Client` itself, for anything `send` and `recv` don't cover. Anything waited on needs
+    // to be `run` for the reactor to turn.
+    pub fn client(&mut self) -> &mut Client {
+        &mut self.client
+    }
+
+    // Run the reactor until `f` resolves, as `Core::run` does.
+    pub fn run<F>(&mut self, f: F) -> F::Item
+        where F: Future,
+              F::Error: fmt::Debug
+    {
+        wait(&self.core, "a future to resolve", f)
+    }
+}
+
+// Run `core` until `f` resolves, panicking if it fails or takes longer than `WAIT`. `what` is what
+// it's waiting for, for the panic message.
+fn wait<F>(core: &RefCell<Core>, what: &str, f: F) -> F::Item
+    where F: Future,
+          F::Error: fmt::Debug
+{
+    let mut core = core.borrow_mut();
+    let timeout = Timeout::new(WAIT, &core.handle()).expect("can't set a timeout");
+    let f = f.map(Some).map_err(|err| format!("{:?}", err));
+    let timeout = timeout.map(|()| None).map_err(|err| err.to_string());
+    match core.run(f.select(timeout)) {
+        Ok((Some(item), _)) => item,
+        Ok((None, _)) => panic!("still waiting for {}", what),
+        Err((err, _)) => panic!("failed waiting for {}: {}", what, err),
+    }
+}
+
+// One end of an in-memory, single-threaded stand-in for a TCP connection. It's unbounded, so
+// writes never block, and reading from it once the other end is dropped finds the end of the
+// stream, much as reading from a closed socket does.
+struct Pipe {
+    incoming: Rc<RefCell<Buffer>>,
+    outgoing: Rc<RefCell<Buffer>>,
+}
+
+// What's been written to one end of a `Pipe` and not yet read from the other.
+struct Buffer {
+    data: VecDeque<u8>,
+
+    // Set once either end is dropped.
+    closed: bool,
+
+    // The task waiting to read, if any.
+    reader: Option<Task>,
+}
+
+impl Buffer {
+    fn new() -> Rc<RefCell<Buffer>> {
+        Rc::new(RefCell::new(Buffer {
+            data: VecDeque::new(),
+            closed: false,
+            reader: None,
+        }))
+    }
+
+    fn wake(&mut self) {
+        if let Some(reader) = self.reader.take() {
+            reader.notify();
+        }
+    }
+}
+
+impl Pipe {
+    fn pair() -> (Pipe, Pipe) {
+        let (a, b) = (Buffer::new(), Buffer::new());
+        let first = Pipe {
+            incoming: a.clone(),
+            outgoing: b.clone(),
+        };
+        let second = Pipe {
+            incoming: b,
+            outgoing: a,
+        };
+        (first, second)
+    }
+
+    fn broken() -> io::Error {
+        io::Error::new(io::ErrorKind::BrokenPipe, "the mock server can't be reconnected to")
+    }
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = self.incoming.borrow_mut();
+        if incoming.data.is_empty() {
+            if incoming.closed {
+                return Ok(0);
+            }
+            incoming.reader = Some(task::current());
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = cmp::min(buf.len(), incoming.data.len());
+        for (slot, byte) in buf.iter_mut().zip(incoming.data.drain(..n)) {
+            *slot = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut outgoing = self.outgoing.borrow_mut();
+        if outgoing.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        outgoing.data.extend(buf);
+        outgoing.wake();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Io for Pipe {
+    fn poll_read(&mut self) -> Async<()> {
+        let mut incoming = self.incoming.borrow_mut();
+        if !incoming.data.is_empty() || incoming.closed {
+            return Async::Ready(());
+        }
+        incoming.reader = Some(task::current());
+        Async::NotReady
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        for buffer in &[&self.incoming, &self.outgoing] {
+            let mut buffer = buffer.borrow_mut();
+            buffer.closed = true;
+            buffer.wake();
+        }
+    }
+}
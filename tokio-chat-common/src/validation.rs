@@ -0,0 +1,40 @@
+// Usernames, made into the one form the server keeps them in. Names that look the same should be
+// the same name, or anyone could pass themselves off as someone else, so:
+//
+// - Names are put into Unicode Normalization Form C, which makes `e` followed by a combining acute
+//   accent the same `é` as the precomposed one, and so on for every other character that can be
+//   written more than one way.
+// - Names can't mix letters from different scripts, like a Cyrillic `а` among Latin ones, which
+//   is all it takes to make a name that looks like another but isn't. Digits, punctuation and
+//   combining marks belong to every script, and go with any of them. So do the scripts that are
+//   written together with Han: kana in Japanese, Hangul in Korean, and Bopomofo in Chinese.
+//
+// Servers apply this to every name they're handed, before checking whether it's taken, so a
+// client can send its name in whichever form it likes.
+
+use unicode_normalization::UnicodeNormalization;
+use unicode_script::{Script, UnicodeScript};
+
+// `name` in normal form, or, if it mixes scripts, a message saying so.
+pub fn normalize_username(name: &str) -> Result<String, String> {
+    let normalized = name.nfc().collect::<String>();
+    let mut scripts = Vec::new();
+    for script in normalized.chars().map(|c| c.script()) {
+        if script != Script::Common && script != Script::Inherited && !scripts.contains(&script) {
+            scripts.push(script);
+        }
+    }
+    let allowed = |set: &&[Script]| scripts.iter().all(|script| set.contains(script));
+    if scripts.len() > 1 && !WRITTEN_TOGETHER.iter().any(allowed) {
+        return Err(format!("{} mixes {} and {} letters; names must be written in one script",
+                           normalized,
+                           scripts[0].full_name(),
+                           scripts[1].full_name()));
+    }
+    Ok(normalized)
+}
+
+// Scripts that a name can mix, as long as it doesn't mix in any others.
+const WRITTEN_TOGETHER: &[&[Script]] = &[&[Script::Han, Script::Hiragana, Script::Katakana],
+                                         &[Script::Han, Script::Hangul],
+                                         &[Script::Han, Script::Bopomofo]];
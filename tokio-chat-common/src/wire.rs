@@ -0,0 +1,266 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::{self, Type};
+use serde_json::{self, Map, Value};
+
+use ClientMessage;
+
+// `ClientMessage`'s wire format, written out by hand rather than derived so that a message of a
+// type we don't know can still be read, as a `ClientMessage::Unknown`, instead of failing to
+// decode. Everything else goes over the wire just as a derived one would: tagged with its type,
+// as described at `unknown_type`, and with unknown fields refused.
+
+// The name serializers are given for `ClientMessage`. Each variant goes with its index too, which
+// is where it comes in the enum.
+const NAME: &'static str = "ClientMessage";
+
+impl Serialize for ClientMessage {
+    fn serialize<S: Serializer>(&self, serializer: &mut S) -> Result<(), S::Error> {
+        match *self {
+            ClientMessage::Message(ref body) => {
+                serializer.serialize_newtype_variant(NAME, 0, "Message", body)
+            }
+            ClientMessage::FormattedMessage { ref body, ref content_type } => {
+                let mut state =
+                    serializer.serialize_struct_variant(NAME, 1, "FormattedMessage", 2)?;
+                serializer.serialize_struct_variant_elt(&mut state, "body", body)?;
+                serializer.serialize_struct_variant_elt(&mut state, "content_type", content_type)?;
+                serializer.serialize_struct_variant_end(state)
+            }
+            ClientMessage::Join(ref room) => {
+                serializer.serialize_newtype_variant(NAME, 2, "Join", room)
+            }
+            ClientMessage::CreateRoom { ref name, ref config } => {
+                let mut state = serializer.serialize_struct_variant(NAME, 3, "CreateRoom", 2)?;
+                serializer.serialize_struct_variant_elt(&mut state, "name", name)?;
+                serializer.serialize_struct_variant_elt(&mut state, "config", config)?;
+                serializer.serialize_struct_variant_end(state)
+            }
+            ClientMessage::GetRoomInfo(ref room) => {
+                serializer.serialize_newtype_variant(NAME, 4, "GetRoomInfo", room)
+            }
+            ClientMessage::SetStatus(ref status) => {
+                serializer.serialize_newtype_variant(NAME, 5, "SetStatus", status)
+            }
+            ClientMessage::Who => serializer.serialize_unit_variant(NAME, 6, "Who"),
+            ClientMessage::FileOffer { transfer_id, ref name, size, chunk_count } => {
+                let mut state = serializer.serialize_struct_variant(NAME, 7, "FileOffer", 4)?;
+                serializer.serialize_struct_variant_elt(&mut state, "transfer_id", transfer_id)?;
+                serializer.serialize_struct_variant_elt(&mut state, "name", name)?;
+                serializer.serialize_struct_variant_elt(&mut state, "size", size)?;
+                serializer.serialize_struct_variant_elt(&mut state, "chunk_count", chunk_count)?;
+                serializer.serialize_struct_variant_end(state)
+            }
+            ClientMessage::FileChunk { transfer_id, index, ref data } => {
+                let mut state = serializer.serialize_struct_variant(NAME, 8, "FileChunk", 3)?;
+                serializer.serialize_struct_variant_elt(&mut state, "transfer_id", transfer_id)?;
+                serializer.serialize_struct_variant_elt(&mut state, "index", index)?;
+                serializer.serialize_struct_variant_elt(&mut state, "data", data)?;
+                serializer.serialize_struct_variant_end(state)
+            }
+            ClientMessage::AdminAnnounce(ref text) => {
+                serializer.serialize_newtype_variant(NAME, 9, "AdminAnnounce", text)
+            }
+            ClientMessage::Register { ref username, ref password } => {
+                let mut state = serializer.serialize_struct_variant(NAME, 10, "Register", 2)?;
+                serializer.serialize_struct_variant_elt(&mut state, "username", username)?;
+                serializer.serialize_struct_variant_elt(&mut state, "password", password)?;
+                serializer.serialize_struct_variant_end(state)
+            }
+            ClientMessage::EditMessage { id, ref new_body } => {
+                let mut state = serializer.serialize_struct_variant(NAME, 11, "EditMessage", 2)?;
+                serializer.serialize_struct_variant_elt(&mut state, "id", id)?;
+                serializer.serialize_struct_variant_elt(&mut state, "new_body", new_body)?;
+                serializer.serialize_struct_variant_end(state)
+            }
+            ClientMessage::RevokeToken { ref token } => {
+                let mut state = serializer.serialize_struct_variant(NAME, 12, "RevokeToken", 1)?;
+                serializer.serialize_struct_variant_elt(&mut state, "token", token)?;
+                serializer.serialize_struct_variant_end(state)
+            }
+            ClientMessage::SetTopic { ref room, ref topic } => {
+                let mut state = serializer.serialize_struct_variant(NAME, 13, "SetTopic", 2)?;
+                serializer.serialize_struct_variant_elt(&mut state, "room", room)?;
+                serializer.serialize_struct_variant_elt(&mut state, "topic", topic)?;
+                serializer.serialize_struct_variant_end(state)
+            }
+            ClientMessage::AdminExport(ref room) => {
+                serializer.serialize_newtype_variant(NAME, 14, "AdminExport", room)
+            }
+            ClientMessage::Ping(nonce) => {
+                serializer.serialize_newtype_variant(NAME, 15, "Ping", nonce)
+            }
+            // Sent on just as it came.
+            ClientMessage::Unknown(ref message) => message.serialize(serializer),
+        }
+    }
+}
+
+impl Deserialize for ClientMessage {
+    fn deserialize<D: Deserializer>(deserializer: &mut D) -> Result<ClientMessage, D::Error> {
+        let (kind, fields) = match Value::deserialize(deserializer)? {
+            Value::String(kind) => (kind, None),
+            Value::Object(tagged) => {
+                if tagged.len() != 1 {
+                    return Err(de::Error::invalid_length(tagged.len()));
+                }
+                let (kind, fields) = tagged.into_iter().next().expect("there's just the one");
+                (kind, Some(fields))
+            }
+            _ => return Err(de::Error::invalid_type(Type::Enum)),
+        };
+        match decode(&kind, fields) {
+            Ok(decoded) => decoded.map_err(from_json_error),
+            Err(fields) => {
+                let message = match fields {
+                    Some(fields) => {
+                        let mut tagged = Map::new();
+                        tagged.insert(kind, fields);
+                        Value::Object(tagged)
+                    }
+                    None => Value::String(kind),
+                };
+                Ok(ClientMessage::Unknown(message))
+            }
+        }
+    }
+}
+
+// Decode the `fields` of a message of type `kind`. If it's not a type we know, they're handed back
+// as they are.
+fn decode(kind: &str,
+          fields: Option<Value>)
+          -> Result<Result<ClientMessage, serde_json::Error>, Option<Value>> {
+    Ok(match kind {
+        "Message" => unnamed(fields).map(ClientMessage::Message),
+        "FormattedMessage" => {
+            Named::new(fields, &["body", "content_type"]).and_then(|mut fields| {
+                Ok(ClientMessage::FormattedMessage {
+                    body: fields.take("body")?,
+                    content_type: fields.take("content_type")?,
+                })
+            })
+        }
+        "Join" => unnamed(fields).map(ClientMessage::Join),
+        "CreateRoom" => {
+            Named::new(fields, &["name", "config"]).and_then(|mut fields| {
+                Ok(ClientMessage::CreateRoom {
+                    name: fields.take("name")?,
+                    config: fields.take("config")?,
+                })
+            })
+        }
+        "GetRoomInfo" => unnamed(fields).map(ClientMessage::GetRoomInfo),
+        "SetStatus" => unnamed(fields).map(ClientMessage::SetStatus),
+        "Who" => {
+            match fields {
+                None | Some(Value::Null) => Ok(ClientMessage::Who),
+                Some(_) => Err(de::Error::invalid_type(Type::UnitVariant)),
+            }
+        }
+        "FileOffer" => {
+            Named::new(fields, &["transfer_id", "name", "size", "chunk_count"])
+                .and_then(|mut fields| {
+                    Ok(ClientMessage::FileOffer {
+                        transfer_id: fields.take("transfer_id")?,
+                        name: fields.take("name")?,
+                        size: fields.take("size")?,
+                        chunk_count: fields.take("chunk_count")?,
+                    })
+                })
+        }
+        "FileChunk" => {
+            Named::new(fields, &["transfer_id", "index", "data"]).and_then(|mut fields| {
+                Ok(ClientMessage::FileChunk {
+                    transfer_id: fields.take("transfer_id")?,
+                    index: fields.take("index")?,
+                    data: fields.take("data")?,
+                })
+            })
+        }
+        "AdminAnnounce" => unnamed(fields).map(ClientMessage::AdminAnnounce),
+        "Register" => {
+            Named::new(fields, &["username", "password"]).and_then(|mut fields| {
+                Ok(ClientMessage::Register {
+                    username: fields.take("username")?,
+                    password: fields.take("password")?,
+                })
+            })
+        }
+        "EditMessage" => {
+            Named::new(fields, &["id", "new_body"]).and_then(|mut fields| {
+                Ok(ClientMessage::EditMessage {
+                    id: fields.take("id")?,
+                    new_body: fields.take("new_body")?,
+                })
+            })
+        }
+        "RevokeToken" => {
+            Named::new(fields, &["token"]).and_then(|mut fields| {
+                Ok(ClientMessage::RevokeToken { token: fields.take("token")? })
+            })
+        }
+        "SetTopic" => {
+            Named::new(fields, &["room", "topic"]).and_then(|mut fields| {
+                Ok(ClientMessage::SetTopic {
+                    room: fields.take("room")?,
+                    topic: fields.take("topic")?,
+                })
+            })
+        }
+        "AdminExport" => unnamed(fields).map(ClientMessage::AdminExport),
+        "Ping" => unnamed(fields).map(ClientMessage::Ping),
+        _ => return Err(fields),
+    })
+}
+
+// The one field of a message whose field has no name.
+fn unnamed<T: Deserialize>(fields: Option<Value>) -> Result<T, serde_json::Error> {
+    match fields {
+        Some(field) => serde_json::from_value(field),
+        None => Err(de::Error::invalid_type(Type::Unit)),
+    }
+}
+
+// The fields of a message whose fields have names, taken out one at a time as they're decoded.
+struct Named(Map<String, Value>);
+
+impl Named {
+    // The fields in `fields`, as long as there are no others than `names`.
+    fn new(fields: Option<Value>, names: &[&str]) -> Result<Named, serde_json::Error> {
+        let fields = match fields {
+            Some(Value::Object(fields)) => fields,
+            _ => return Err(de::Error::invalid_type(Type::StructVariant)),
+        };
+        if let Some(unknown) = fields.keys().find(|name| !names.contains(&name.as_str())) {
+            return Err(de::Error::unknown_field(unknown));
+        }
+        Ok(Named(fields))
+    }
+
+    fn take<T: Deserialize>(&mut self, name: &'static str) -> Result<T, serde_json::Error> {
+        match self.0.remove(name) {
+            Some(value) => serde_json::from_value(value),
+            None => Err(de::Error::missing_field(name)),
+        }
+    }
+}
+
+// Pass on an error decoding a message's fields as the same kind of error from whatever was
+// decoding the message, so that it can still be told apart by `unknown_field` and the like.
+fn from_json_error<E: de::Error>(err: serde_json::Error) -> E {
+    match err {
+        serde_json::Error::Syntax(code, _, _) => {
+            match code {
+                serde_json::ErrorCode::Custom(msg) => E::custom(msg),
+                serde_json::ErrorCode::InvalidType(ty) => E::invalid_type(ty),
+                serde_json::ErrorCode::InvalidValue(msg) => E::invalid_value(&msg),
+                serde_json::ErrorCode::InvalidLength(len) => E::invalid_length(len),
+                serde_json::ErrorCode::UnknownVariant(variant) => E::unknown_variant(&variant),
+                serde_json::ErrorCode::UnknownField(field) => E::unknown_field(&field),
+                serde_json::ErrorCode::MissingField(field) => E::missing_field(field),
+                code => E::custom(format!("{:?}", code)),
+            }
+        }
+        err => E::custom(err.to_string()),
+    }
+}
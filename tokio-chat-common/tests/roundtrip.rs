@@ -0,0 +1,817 @@
+// Property tests for the codecs: whatever message goes in one end must come out the other
+// unchanged, a frame that hasn't fully arrived must read as "not yet" rather than as an error, and
+// back-to-back frames must come out as separate messages in order.
+//
+// Every codec in this crate speaks JSON, so that's the only backend covered here; the properties
+// are checked against each codec that decodes it. `NegotiatingCodec`, which can speak others, gets
+// tests of its own at the end.
+
+extern crate futures;
+extern crate proptest;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate tokio_core;
+extern crate tokio_chat_common;
+
+use futures::{Future, Sink, Stream};
+use futures::sync::mpsc;
+use proptest::prelude::*;
+use tokio_core::io::{Codec, EasyBuf, Io};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Core;
+use tokio_chat_common::{ClientMessage, ServerMessage, ErrorCode, UserInfo, ChatMessage, ContentType,
+                        RoomConfig,
+                        ClientToServerCodec, ServerToClientCodec, LenientServerToClientCodec,
+                        LenientJson, StreamingDecoder, LengthPrefixedJson, MAX_FRAME_LEN,
+                        negotiate, FrameFormat, JsonFormat, NegotiatingCodec, rfc3339, emoji,
+                        unknown_field, validation, Handshake,
+                        into_framed, BatchCodec, StatsCodec};
+
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Keep generated strings and byte vectors short so that every message fits comfortably in one
+// frame (the u16 length prefix caps payloads at 64KiB).
+fn text() -> BoxedStrategy<String> {
+    ".{0,64}".boxed()
+}
+
+fn bytes() -> BoxedStrategy<Vec<u8>> {
+    prop::collection::vec(any::<u8>(), 0..256).boxed()
+}
+
+fn error_code() -> BoxedStrategy<ErrorCode> {
+    prop_oneof![Just(ErrorCode::Unauthorized),
+                Just(ErrorCode::InvalidMessage),
+                Just(ErrorCode::RateLimited),
+                Just(ErrorCode::TooManyConnections),
+                Just(ErrorCode::IdleTimeout),
+                Just(ErrorCode::RoomFull),
+                Just(ErrorCode::UnsupportedContentType),
+                Just(ErrorCode::Flooding),
+                Just(ErrorCode::TooManyAttempts),
+                Just(ErrorCode::UnsupportedMessageType)]
+        .boxed()
+}
+
+// Unsupported content types are all lower case, so they can't be mistaken for the supported ones.
+fn content_type() -> BoxedStrategy<ContentType> {
+    prop_oneof![Just(ContentType::PlainText),
+                Just(ContentType::Markdown),
+                Just(ContentType::Html),
+                "[a-z]{1,16}".prop_map(ContentType::Unsupported)]
+        .boxed()
+}
+
+fn room_config() -> BoxedStrategy<RoomConfig> {
+    (prop::option::of(any::<u32>()),
+     prop::option::of(any::<u32>()),
+     prop::option::of(any::<u32>()),
+     prop::option::of(text()),
+     any::<bool>())
+        .prop_map(|(max_members, messages_per_minute, history_size, topic, private)| {
+            RoomConfig {
+                max_members: max_members,
+                messages_per_minute: messages_per_minute,
+                history_size: history_size,
+                topic: topic,
+                private: private,
+            }
+        })
+        .boxed()
+}
+
+fn user_info() -> BoxedStrategy<UserInfo> {
+    (text(), prop::option::of(text()))
+        .prop_map(|(name, status)| UserInfo { name: name, status: status })
+        .boxed()
+}
+
+fn chat_message() -> BoxedStrategy<ChatMessage> {
+    (any::<u64>(), text(), text())
+        .prop_map(|(id, from, body)| ChatMessage { id: id, from: from, body: body })
+        .boxed()
+}
+
+fn client_message() -> BoxedStrategy<ClientMessage> {
+    prop_oneof![
+        text().prop_map(ClientMessage::Message),
+        (text(), content_type()).prop_map(|(body, content_type)| {
+            ClientMessage::FormattedMessage {
+                body: body,
+                content_type: content_type,
+            }
+        }),
+        text().prop_map(ClientMessage::Join),
+        (text(), room_config())
+            .prop_map(|(name, config)| ClientMessage::CreateRoom { name: name, config: config }),
+        text().prop_map(ClientMessage::GetRoomInfo),
+        prop::option::of(text()).prop_map(ClientMessage::SetStatus),
+        Just(ClientMessage::Who),
+        (any::<u64>(), text(), any::<u64>(), any::<u32>())
+            .prop_map(|(transfer_id, name, size, chunk_count)| {
+                ClientMessage::FileOffer {
+                    transfer_id: transfer_id,
+                    name: name,
+                    size: size,
+                    chunk_count: chunk_count,
+                }
+            }),
+        (any::<u64>(), any::<u32>(), bytes()).prop_map(|(transfer_id, index, data)| {
+            ClientMessage::FileChunk {
+                transfer_id: transfer_id,
+                index: index,
+                data: data,
+            }
+        }),
+        text().prop_map(ClientMessage::AdminAnnounce),
+        (text(), text()).prop_map(|(username, password)| {
+            ClientMessage::Register {
+                username: username,
+                password: password,
+            }
+        }),
+        (any::<u64>(), text()).prop_map(|(id, new_body)| {
+            ClientMessage::EditMessage {
+                id: id,
+                new_body: new_body,
+            }
+        }),
+        text().prop_map(|token| ClientMessage::RevokeToken { token: token }),
+        (text(), text()).prop_map(|(room, topic)| {
+            ClientMessage::SetTopic {
+                room: room,
+                topic: topic,
+            }
+        }),
+        text().prop_map(ClientMessage::AdminExport),
+        any::<u64>().prop_map(ClientMessage::Ping),
+        (prop_oneof![Just("Shout"), Just("Nudge")], text()).prop_map(|(kind, payload)| {
+            let mut tagged = serde_json::Map::new();
+            tagged.insert(kind.to_string(), serde_json::Value::String(payload));
+            ClientMessage::Unknown(serde_json::Value::Object(tagged))
+        }),
+    ]
+        .boxed()
+}
+
+fn server_message() -> BoxedStrategy<ServerMessage> {
+    prop_oneof![
+        (text(), prop::collection::vec(text(), 0..4)).prop_map(|(resume_token, capabilities)| {
+            ServerMessage::Welcome {
+                resume_token: resume_token,
+                capabilities: capabilities,
+            }
+        }),
+        (any::<u64>(), text(), text())
+            .prop_map(|(id, from, body)| ServerMessage::Message(id, from, body)),
+        (any::<u64>(), text(), text(), content_type())
+            .prop_map(|(id, from, body, content_type)| {
+                ServerMessage::FormattedMessage {
+                    id: id,
+                    from: from,
+                    body: body,
+                    content_type: content_type,
+                }
+            }),
+        (any::<u64>(), text()).prop_map(|(id, new_body)| {
+            ServerMessage::MessageEdited {
+                id: id,
+                new_body: new_body,
+            }
+        }),
+        text().prop_map(ServerMessage::UserConnected),
+        text().prop_map(ServerMessage::UserDisconnected),
+        (text(), text(), prop::option::of(text()))
+            .prop_map(|(name, room, status)| ServerMessage::UserJoined(name, room, status)),
+        (text(), text()).prop_map(|(name, room)| ServerMessage::UserLeft(name, room)),
+        (text(), prop::option::of(text()))
+            .prop_map(|(name, status)| ServerMessage::StatusChanged(name, status)),
+        (text(), text()).prop_map(|(room, topic)| {
+            ServerMessage::TopicChanged {
+                room: room,
+                topic: topic,
+            }
+        }),
+        (text(), room_config(), any::<u32>(), prop::option::of(text()))
+            .prop_map(|(name, config, member_count, topic)| {
+                ServerMessage::RoomInfo {
+                    name: name,
+                    config: config,
+                    member_count: member_count,
+                    topic: topic,
+                }
+            }),
+        (text(), prop::collection::vec(user_info(), 0..8))
+            .prop_map(|(room, users)| ServerMessage::Users(room, users)),
+        text().prop_map(ServerMessage::ServerAnnouncement),
+        text().prop_map(ServerMessage::Motd),
+        text().prop_map(ServerMessage::Registered),
+        (text(), any::<u64>()).prop_map(|(token, expires_in_secs)| {
+            ServerMessage::Token {
+                token: token,
+                expires_in_secs: expires_in_secs,
+            }
+        }),
+        (text(), any::<u64>(), text(), any::<u64>(), any::<u32>())
+            .prop_map(|(from, transfer_id, name, size, chunk_count)| {
+                ServerMessage::FileOffer {
+                    from: from,
+                    transfer_id: transfer_id,
+                    name: name,
+                    size: size,
+                    chunk_count: chunk_count,
+                }
+            }),
+        (text(), any::<u64>(), any::<u32>(), bytes())
+            .prop_map(|(from, transfer_id, index, data)| {
+                ServerMessage::FileChunk {
+                    from: from,
+                    transfer_id: transfer_id,
+                    index: index,
+                    data: data,
+                }
+            }),
+        (text(), prop::collection::vec(chat_message(), 0..8)).prop_map(|(room, messages)| {
+            ServerMessage::ExportData {
+                room: room,
+                messages: messages,
+            }
+        }),
+        any::<u64>().prop_map(ServerMessage::Pong),
+        (error_code(), text()).prop_map(|(code, detail)| ServerMessage::Error(code, detail)),
+    ]
+        .boxed()
+}
+
+// Encode `msg` as a standalone frame.
+fn encode<C: Codec>(mut codec: C, msg: C::Out) -> Vec<u8> {
+    let mut buf = Vec::new();
+    codec.encode(msg, &mut buf).unwrap();
+    buf
+}
+
+// A frame carrying `payload` as it is, whether or not it's a message, or even JSON.
+fn raw_frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![(payload.len() >> 8) as u8, payload.len() as u8];
+    frame.extend_from_slice(payload);
+    frame
+}
+
+// A `Message` with its body wrapped in arrays, so that it's `depth` levels deep in all. It
+// reads as JSON, but not as a message.
+fn nested(depth: usize) -> Vec<u8> {
+    format!("{{\"Message\":{}\"x\"{}}}", "[".repeat(depth - 1), "]".repeat(depth - 1)).into_bytes()
+}
+
+// Bytes for `validate` and `decode` to disagree about, if they can.
+fn frame_bytes() -> BoxedStrategy<Vec<u8>> {
+    let frame = || client_message().prop_map(|msg| encode(ClientToServerCodec::new(), msg));
+    prop_oneof![prop::collection::vec(any::<u8>(), 0..64),
+                (0..64usize, prop::collection::vec(any::<u8>(), 0..64)).prop_map(|(len, rest)| {
+                    let mut bytes = vec![0, len as u8];
+                    bytes.extend(rest);
+                    bytes
+                }),
+                (frame(), any::<prop::sample::Index>()).prop_map(|(mut frame, cut)| {
+                    let len = cut.index(frame.len() + 1);
+                    frame.truncate(len);
+                    frame
+                }),
+                (frame(), any::<prop::sample::Index>(), any::<u8>())
+                    .prop_map(|(mut frame, at, byte)| {
+                        let at = at.index(frame.len());
+                        frame[at] = byte;
+                        frame
+                    })]
+        .boxed()
+}
+
+// A decoder under test, boxed up so the same checks can run against each codec. The lenient
+// codec's items are unwrapped on the way out, since everything we feed it is valid.
+type Decoder<T> = Box<FnMut(&mut EasyBuf) -> io::Result<Option<T>>>;
+
+// Check the three properties against the decoders `make` builds, given the encoded frames for
+// `first` and `second`.
+fn check<T, F>(make: F,
+               first: &T,
+               first_frame: &[u8],
+               second: &T,
+               second_frame: &[u8])
+               -> Result<(), TestCaseError>
+    where T: PartialEq + fmt::Debug,
+          F: Fn() -> Decoder<T>
+{
+    // Roundtrip.
+    let mut buf = EasyBuf::from(first_frame.to_vec());
+    let decoded = make()(&mut buf).unwrap();
+    prop_assert_eq!(decoded.as_ref(), Some(first));
+
+    // Every proper prefix of a frame is incomplete, not an error. Each prefix gets a fresh
+    // decoder, since some decoders hang on to the part of a frame they've already seen.
+    for len in 0..first_frame.len() {
+        let mut buf = EasyBuf::from(first_frame[..len].to_vec());
+        prop_assert!(make()(&mut buf).unwrap().is_none(), "prefix of {} bytes", len);
+    }
+
+    // Two frames back to back come out as exactly two messages, in order.
+    let mut both = first_frame.to_vec();
+    both.extend_from_slice(second_frame);
+    let mut buf = EasyBuf::from(both);
+    let mut decoder = make();
+    let (decoded_first, decoded_second) = (decoder(&mut buf).unwrap(), decoder(&mut buf).unwrap());
+    prop_assert_eq!(decoded_first.as_ref(), Some(first));
+    prop_assert_eq!(decoded_second.as_ref(), Some(second));
+    prop_assert!(decoder(&mut buf).unwrap().is_none());
+    Ok(())
+}
+
+proptest! {
+    #[test]
+    fn client_messages_roundtrip(first in client_message(), second in client_message()) {
+        let first_frame = encode(ClientToServerCodec::new(), first.clone());
+        let second_frame = encode(ClientToServerCodec::new(), second.clone());
+        let check = |make: fn() -> Decoder<ClientMessage>| {
+            check(make, &first, &first_frame, &second, &second_frame)
+        };
+
+        check(|| {
+            let mut codec = ServerToClientCodec::new();
+            Box::new(move |buf| codec.decode(buf))
+        })?;
+        check(|| {
+            let mut codec = LenientServerToClientCodec::new();
+            Box::new(move |buf| codec.decode(buf).map(|item| item.map(|msg| msg.unwrap())))
+        })?;
+        check(|| {
+            let mut decoder = StreamingDecoder::new();
+            Box::new(move |buf| decoder.decode(buf))
+        })?;
+    }
+
+    #[test]
+    fn frames_share_a_buffer(first in server_message(), second in server_message()) {
+        // Encoding appends, so two messages encoded into one buffer read back as both of them.
+        let mut buf = Vec::new();
+        let mut codec = ServerToClientCodec::new();
+        codec.encode(first.clone(), &mut buf).unwrap();
+        codec.encode(second.clone(), &mut buf).unwrap();
+
+        let mut buf = EasyBuf::from(buf);
+        let mut codec = ClientToServerCodec::new();
+        prop_assert_eq!(codec.decode(&mut buf).unwrap(), Some(first));
+        prop_assert_eq!(codec.decode(&mut buf).unwrap(), Some(second));
+        prop_assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn server_messages_roundtrip(first in server_message(), second in server_message()) {
+        let first_frame = encode(ServerToClientCodec::new(), first.clone());
+        let second_frame = encode(ServerToClientCodec::new(), second.clone());
+        let check = |make: fn() -> Decoder<ServerMessage>| {
+            check(make, &first, &first_frame, &second, &second_frame)
+        };
+
+        check(|| {
+            let mut codec = ClientToServerCodec::new();
+            Box::new(move |buf| codec.decode(buf))
+        })?;
+        check(|| {
+            let mut codec = LenientJson::<ServerMessage, ClientMessage>::new();
+            Box::new(move |buf| codec.decode(buf).map(|item| item.map(|msg| msg.unwrap())))
+        })?;
+        check(|| {
+            let mut decoder = StreamingDecoder::new();
+            Box::new(move |buf| decoder.decode(buf))
+        })?;
+    }
+
+    // Like a fuzzer would, but with some help getting past the framing: besides bytes that are
+    // anything at all, some inputs are real frames (in part, or with a byte changed) or the
+    // start of one.
+    #[test]
+    fn validating_agrees_with_decoding(bytes in frame_bytes()) {
+        let codec = ServerToClientCodec::new();
+        let validated = codec.validate(&EasyBuf::from(bytes.clone()));
+
+        // Validating is decoding JSON, without keeping the JSON...
+        let mut json = LengthPrefixedJson::<serde_json::Value, serde_json::Value>::new();
+        let mut buf = EasyBuf::from(bytes.clone());
+        match (&validated, json.decode(&mut buf)) {
+            (&Ok(None), Ok(None)) | (&Err(_), Err(_)) => {}
+            (&Ok(Some(len)), Ok(Some(_))) => prop_assert_eq!(len, bytes.len() - buf.len()),
+            (validated, decoded) => {
+                prop_assert!(false, "validated as {:?} but decoded as {:?}", validated, decoded)
+            }
+        }
+
+        // ... so it agrees with decoding a message on whether there's a whole frame there, and
+        // only passes frames that are messages or at least JSON.
+        let decoded = codec.clone().decode(&mut EasyBuf::from(bytes));
+        prop_assert_eq!(matches!(validated, Ok(None)), matches!(decoded, Ok(None)));
+        prop_assert!(validated.is_ok() || decoded.is_err());
+    }
+
+    #[test]
+    fn messages_read_back_as_displayed(client in client_message(), server in server_message()) {
+        prop_assert_eq!(client.to_string().parse::<ClientMessage>(), Ok(client.clone()));
+        prop_assert_eq!(server.to_string().parse::<ServerMessage>(), Ok(server.clone()));
+    }
+}
+
+#[test]
+fn streaming_frames_decode_once_they_have_all_arrived() {
+    let msg = ClientMessage::Message("x".repeat(1000));
+    let frame = encode(ClientToServerCodec::new(), msg.clone());
+    let chunks = frame.chunks(300).collect::<Vec<_>>();
+    let (last, earlier) = chunks.split_last().unwrap();
+
+    // Each chunk is taken out of the framing buffer as it arrives, but nothing comes of it until
+    // the last one.
+    let mut decoder = StreamingDecoder::new();
+    let mut buf = EasyBuf::new();
+    for chunk in earlier {
+        buf.get_mut().extend_from_slice(chunk);
+        assert_eq!(decoder.decode(&mut buf).unwrap(), None);
+        assert_eq!(buf.len(), 0);
+    }
+    buf.get_mut().extend_from_slice(last);
+    assert_eq!(decoder.decode(&mut buf).unwrap(), Some(msg));
+
+    // It's held to the same limits as the other codecs: a frame that's too long is refused on
+    // its prefix alone, and one nested too deeply once it's arrived.
+    let mut decoder = StreamingDecoder::<ClientMessage>::new().with_max_frame_len(32);
+    let err = decoder.decode(&mut EasyBuf::from(frame[..2].to_vec())).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    let chunk = ClientMessage::FileChunk {
+        transfer_id: 1,
+        index: 0,
+        data: vec![1, 2, 3],
+    };
+    let mut decoder = StreamingDecoder::<ClientMessage>::new().with_max_depth(2);
+    let shallow = encode(ClientToServerCodec::new(), ClientMessage::Message("hi".to_string()));
+    assert!(decoder.decode(&mut EasyBuf::from(shallow)).unwrap().is_some());
+    let deep = encode(ClientToServerCodec::new(), chunk);
+    let err = decoder.decode(&mut EasyBuf::from(deep)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn oversized_payloads_are_refused() {
+    // Too long for a u16 length prefix, so there's no frame that could carry it.
+    let msg = ServerMessage::Message(0, "someone".to_string(), "x".repeat(MAX_FRAME_LEN));
+    let mut buf = b"earlier".to_vec();
+    assert!(ServerToClientCodec::new().encode(msg, &mut buf).is_err());
+    assert_eq!(buf, b"earlier");
+}
+
+#[test]
+fn builders_set_the_frame_limit() {
+    let msg = ClientMessage::Message("x".repeat(64));
+    let frame = encode(ClientToServerCodec::new(), msg.clone());
+
+    let mut codec = LengthPrefixedJson::<ClientMessage, ServerMessage>::builder()
+        .max_frame_size(32)
+        .build();
+    assert!(codec.decode(&mut EasyBuf::from(frame.clone())).is_err());
+
+    let mut codec = LengthPrefixedJson::<ClientMessage, ServerMessage>::builder()
+        .max_frame_size(frame.len())
+        .build();
+    assert_eq!(codec.decode(&mut EasyBuf::from(frame)).unwrap(), Some(msg));
+}
+
+#[test]
+fn deeply_nested_frames_are_refused() {
+    // Deep enough that decoding it recursively would run out of stack, were it ever tried.
+    let deep = raw_frame(&nested(30000));
+    let mut codec = LengthPrefixedJson::<serde_json::Value, serde_json::Value>::new();
+    assert!(codec.validate(&EasyBuf::from(deep.clone())).is_err());
+    let err = codec.decode(&mut EasyBuf::from(deep.clone())).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    let mut codec = LenientJson::<serde_json::Value, serde_json::Value>::new();
+    assert!(codec.decode(&mut EasyBuf::from(deep)).unwrap().unwrap().is_err());
+
+    // The limit is whatever the codec was built with.
+    let mut codec = LengthPrefixedJson::<serde_json::Value, serde_json::Value>::builder()
+        .max_depth(4)
+        .build();
+    assert!(codec.decode(&mut EasyBuf::from(raw_frame(&nested(4)))).unwrap().is_some());
+    let err = codec.decode(&mut EasyBuf::from(raw_frame(&nested(5)))).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn large_frames_decode_across_many_reads() {
+    let msg = ClientMessage::Message("x".repeat(40000));
+    let frame = encode(ClientToServerCodec::new(), msg.clone());
+    let mut codec = LengthPrefixedJson::<ClientMessage, ServerMessage>::builder()
+        .max_frame_size(frame.len())
+        .build();
+
+    // The frame trickles in a hundred bytes at a time. Once its length prefix has been read,
+    // it's scribbled over with a length past the limit: if the prefix were read again, the
+    // frame would be refused, or waited on forever.
+    let mut buf = EasyBuf::new();
+    let mut decoded = Vec::new();
+    for (i, chunk) in frame.chunks(100).enumerate() {
+        buf.get_mut().extend_from_slice(chunk);
+        if i == 1 {
+            buf.get_mut()[..2].copy_from_slice(&[0xff, 0xff]);
+        }
+        decoded.extend(codec.decode(&mut buf).unwrap());
+    }
+    assert_eq!(decoded, vec![msg]);
+    assert_eq!(buf.len(), 0);
+}
+
+#[test]
+fn batches_are_all_or_nothing() {
+    let msg = ClientMessage::Message("x".repeat(64));
+    let frame = encode(ClientToServerCodec::new(), msg.clone());
+
+    // A batch is held to the same limits as a single message.
+    let mut codec = BatchCodec::<ClientMessage, ServerMessage>::new().with_max_frame_len(32);
+    let err = codec.decode(&mut EasyBuf::from(frame[..2].to_vec())).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    let mut codec = BatchCodec::<serde_json::Value, ServerMessage>::new().with_max_depth(8);
+    assert!(codec.decode(&mut EasyBuf::from(raw_frame(&nested(8)))).is_ok());
+    let err = codec.decode(&mut EasyBuf::from(raw_frame(&nested(9)))).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    // A batch with a message that doesn't decode is refused whole, and none of the messages
+    // before it turn up later.
+    let mut bytes = raw_frame(br#"[{"Message":"a"},{"Message":2},{"Message":"c"}]"#);
+    bytes.extend_from_slice(&frame);
+    let mut buf = EasyBuf::from(bytes);
+    let mut codec = BatchCodec::<ClientMessage, ServerMessage>::new();
+    let err = codec.decode(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(msg));
+    assert_eq!(codec.decode(&mut buf).unwrap(), None);
+}
+
+#[test]
+fn codecs_clone_for_each_connection() {
+    fn assert_clone<T: Clone>() {}
+    assert_clone::<LengthPrefixedJson<ClientMessage, ServerMessage>>();
+    assert_clone::<LenientJson<ClientMessage, ServerMessage>>();
+    assert_clone::<BatchCodec<ClientMessage, ServerMessage>>();
+    assert_clone::<StatsCodec<ServerToClientCodec>>();
+    assert_clone::<NegotiatingCodec<ClientMessage, ServerMessage>>();
+
+    // A pool's codec that's partway through a frame hands out clones that aren't: each new
+    // connection's stream starts from its own first frame.
+    let pool = Arc::new(Mutex::new(ServerToClientCodec::new()));
+    let frame = encode(ClientToServerCodec::new(), ClientMessage::new("x".repeat(1000)));
+    let mut buf = EasyBuf::from(frame[..100].to_vec());
+    assert_eq!(pool.lock().unwrap().decode(&mut buf).unwrap(), None);
+    let mut codec = pool.lock().unwrap().clone();
+    let mut buf = EasyBuf::from(encode(ClientToServerCodec::new(), ClientMessage::Who));
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(ClientMessage::Who));
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Stamped {
+    #[serde(serialize_with = "rfc3339::serialize", deserialize_with = "rfc3339::deserialize")]
+    at: SystemTime,
+}
+
+#[test]
+fn timestamps_are_rfc3339() {
+    let at = UNIX_EPOCH + Duration::new(1_489_504_166, 535_897_000);
+    let json = serde_json::to_string(&Stamped { at: at }).unwrap();
+    assert_eq!(json, r#"{"at":"2017-03-14T15:09:26.535897Z"}"#);
+    assert_eq!(serde_json::from_str::<Stamped>(&json).unwrap(), Stamped { at: at });
+
+    // The same instant, written elsewhere, is still the same instant.
+    let elsewhere = r#"{"at":"2017-03-14T16:39:26.535897+01:30"}"#;
+    assert_eq!(serde_json::from_str::<Stamped>(elsewhere).unwrap(), Stamped { at: at });
+    assert!(serde_json::from_str::<Stamped>(r#"{"at":"last tuesday"}"#).is_err());
+}
+
+#[test]
+fn messages_display_on_one_line() {
+    let cases = vec![
+        (ClientMessage::Who, "[Who]"),
+        (ClientMessage::Join("#general".to_string()), "[Join] #general"),
+        (ClientMessage::new("hello world"), r#"[Message] "hello world""#),
+        (ClientMessage::SetStatus(None), "[SetStatus] null"),
+        (ClientMessage::EditMessage {
+             id: 7,
+             new_body: "hi\nthere".to_string(),
+         },
+         r#"[EditMessage id=7 new_body="hi\nthere"]"#),
+    ];
+    for (msg, line) in cases {
+        assert_eq!(msg.to_string(), line);
+        assert_eq!(line.parse::<ClientMessage>(), Ok(msg));
+    }
+    let said = ServerMessage::Message(7, "alice".to_string(), "true".to_string());
+    assert_eq!(said.to_string(), r#"[Message] 7 alice "true""#);
+
+    assert!("Who".parse::<ClientMessage>().is_err());
+    assert!("[Message".parse::<ClientMessage>().is_err());
+    assert!(r#"[Message] "unclosed"#.parse::<ClientMessage>().is_err());
+    // Messages of types we don't know read as what they look like, but only as a `ClientMessage`.
+    let shout = serde_json::from_str::<ClientMessage>(r#"{"Shout":"hello"}"#).unwrap();
+    assert_eq!("[Shout] hello".parse::<ClientMessage>(), Ok(shout));
+    assert!("[Shout] hello".parse::<ServerMessage>().is_err());
+}
+
+// JSON backwards, standing in for a second format for `NegotiatingCodec`s to agree on.
+struct Reversed;
+
+impl FrameFormat<ClientMessage, ClientMessage> for Reversed {
+    fn name(&self) -> &str {
+        "reversed"
+    }
+
+    fn encode(&self, msg: &ClientMessage, payload: &mut Vec<u8>) -> io::Result<()> {
+        let mut json = Vec::new();
+        FrameFormat::<ClientMessage, ClientMessage>::encode(&JsonFormat, msg, &mut json)?;
+        payload.extend(json.into_iter().rev());
+        Ok(())
+    }
+
+    fn decode(&self, payload: &[u8]) -> io::Result<ClientMessage> {
+        let json = payload.iter().rev().cloned().collect::<Vec<_>>();
+        FrameFormat::<ClientMessage, ClientMessage>::decode(&JsonFormat, &json)
+    }
+}
+
+fn negotiating() -> NegotiatingCodec<ClientMessage, ClientMessage> {
+    NegotiatingCodec::new().with_format(Reversed)
+}
+
+#[test]
+fn codecs_negotiate_a_format() {
+    let (mut alice, mut bob) = (negotiating(), negotiating());
+    let send = |from: &mut NegotiatingCodec<_, _>, to: &mut NegotiatingCodec<_, _>, body| {
+        let mut frames = Vec::new();
+        from.encode(ClientMessage::new(body), &mut frames).unwrap();
+        let mut buf = EasyBuf::from(frames.clone());
+        assert_eq!(to.decode(&mut buf).unwrap(), Some(ClientMessage::new(body)));
+        assert_eq!(buf.len(), 0);
+        frames
+    };
+
+    // Alice hasn't heard from bob when she first says something, so it goes out in JSON, after
+    // her `Hello`...
+    let frames = send(&mut alice, &mut bob, "one");
+    assert!(frames.ends_with(b"\"one\"}"));
+    assert_eq!(alice.agreed_format(), None);
+    assert_eq!(bob.agreed_format(), Some("reversed".to_string()));
+
+    // ... but bob has heard from her, and from then on they both speak the format they'd rather.
+    let frames = send(&mut bob, &mut alice, "two");
+    assert!(frames.ends_with(b"{"));
+    assert_eq!(alice.agreed_format(), Some("reversed".to_string()));
+    let frames = send(&mut alice, &mut bob, "three");
+    assert!(frames.ends_with(b"{"));
+
+    // A codec that only has JSON gets JSON.
+    let (mut carol, mut dave) = (negotiating(), NegotiatingCodec::new());
+    send(&mut dave, &mut carol, "four");
+    send(&mut carol, &mut dave, "five");
+    assert_eq!(carol.agreed_format(), Some("json".to_string()));
+    assert_eq!(dave.agreed_format(), Some("json".to_string()));
+}
+
+#[test]
+fn negotiated_connections_agree_before_the_first_message() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = listener.incoming()
+        .into_future()
+        .map_err(|(err, _)| err)
+        .and_then(|(accepted, _)| negotiate(accepted.unwrap().0, negotiating()));
+    let client = TcpStream::connect(&addr, &handle).and_then(|socket| {
+        negotiate(socket, negotiating()).and_then(|(socket, codec)| {
+            assert_eq!(codec.agreed_format(), Some("reversed".to_string()));
+            socket.framed(codec).send(ClientMessage::new("hi"))
+        })
+    });
+    let ((socket, codec), _) = core.run(server.join(client)).unwrap();
+    assert_eq!(codec.agreed_format(), Some("reversed".to_string()));
+    let (msg, _) = core.run(socket.framed(codec).into_future().map_err(|(err, _)| err)).unwrap();
+    assert_eq!(msg, Some(ClientMessage::new("hi")));
+}
+
+#[test]
+fn framed_connections_are_a_sink_and_a_stream() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    // The client's messages come from a channel, forwarded into its sink...
+    let (tx, rx) = mpsc::unbounded();
+    tx.unbounded_send(ClientMessage::new("one")).unwrap();
+    tx.unbounded_send(ClientMessage::Who).unwrap();
+    drop(tx);
+    let client = TcpStream::connect(&addr, &handle).and_then(|socket| {
+        let (sink, _) = into_framed(socket, ClientToServerCodec::new());
+        let rx = rx.map_err(|()| unreachable!("rx can't fail"));
+        // Hanging up once they've all gone ends the server's stream.
+        rx.forward(sink).map(|_| ())
+    });
+
+    // ... and the server's are a stream, behind a trait object.
+    let server = listener.incoming()
+        .into_future()
+        .map_err(|(err, _)| err)
+        .and_then(|(accepted, _)| {
+            let (_, stream) = ServerToClientCodec::new().framed(accepted.unwrap().0);
+            let stream: Box<Stream<Item = ClientMessage, Error = io::Error>> = Box::new(stream);
+            stream.collect()
+        });
+    let (received, _) = core.run(server.join(client)).unwrap();
+    assert_eq!(received, vec![ClientMessage::new("one"), ClientMessage::Who]);
+}
+
+#[test]
+fn shortcodes_expand_into_emoji() {
+    assert_eq!(emoji::expand(":smile:"), "\u{1f604}");
+    assert_eq!(emoji::expand("nice :+1::tada:"), "nice \u{1f44d}\u{1f389}");
+
+    // Unknown shortcodes, and colons that weren't meant as any, are left alone...
+    assert_eq!(emoji::expand(":not_an_emoji:"), ":not_an_emoji:");
+    assert_eq!(emoji::expand("meet at 12:30: or :later"), "meet at 12:30: or :later");
+
+    // ... even when they're right up against known ones.
+    assert_eq!(emoji::expand("a :nope:smile: and :wave: :huh: :"),
+               "a :nope\u{1f604} and \u{1f44b} :huh: :");
+
+    // `lookup` searches the table, which only works if it's in order.
+    assert!(emoji::SHORTCODES.windows(2).all(|pair| pair[0].0 < pair[1].0));
+}
+
+#[test]
+fn unknown_fields_are_refused() {
+    let refused = |json: &str| {
+        let err = serde_json::from_str::<ClientMessage>(json).unwrap_err();
+        unknown_field(&err).map(str::to_string)
+    };
+    assert_eq!(refused(r#"{"EditMessage":{"id":1,"new_body":"hi","urgent":true}}"#),
+               Some("urgent".to_string()));
+    let create = r#"{"CreateRoom":{"name":"den","config":{"private":true,"colour":"red"}}}"#;
+    assert_eq!(refused(create), Some("colour".to_string()));
+    // A message that's wrong some other way isn't mistaken for one with an unknown field.
+    assert_eq!(refused(r#"{"EditMessage":{"id":1}}"#), None);
+
+    let handshake = r#"{"name":"alice","token":null,"mood":"cheery"}"#;
+    let err = serde_json::from_str::<Handshake>(handshake).unwrap_err();
+    assert_eq!(unknown_field(&err), Some("mood"));
+}
+
+#[test]
+fn unknown_message_types_are_kept() {
+    // A message of a type we don't know decodes all the same, and goes back out unchanged.
+    for &(json, kind) in &[(r#"{"Shout":"hello"}"#, "Shout"),
+                           (r#"{"Nudge":{"times":3,"who":"bob"}}"#, "Nudge"),
+                           (r#""Wave""#, "Wave")] {
+        let msg = serde_json::from_str::<ClientMessage>(json).unwrap();
+        assert_eq!(msg.unknown_type(), Some(kind));
+        assert_eq!(serde_json::to_string(&msg).unwrap(), json);
+    }
+    assert_eq!(ClientMessage::Who.unknown_type(), None);
+
+    // Ones we do know are still held to their fields, and anything not tagged with a type at all
+    // still fails.
+    assert!(serde_json::from_str::<ClientMessage>(r#""Join""#).is_err());
+    assert!(serde_json::from_str::<ClientMessage>(r#"{"Join":"den","Who":null}"#).is_err());
+    assert!(serde_json::from_str::<ClientMessage>(r#"{"Join":"den","Who":null}"#).is_err());
+    assert!(serde_json::from_str::<ClientMessage>("[]").is_err());
+}
+
+#[test]
+fn usernames_are_normalized() {
+    use validation::normalize_username;
+
+    // However `é` is written, it's the same name...
+    assert_eq!(normalize_username("cafe\u{301}"), Ok("caf\u{e9}".to_string()));
+    assert_eq!(normalize_username("caf\u{e9}"), Ok("caf\u{e9}".to_string()));
+    // ... as are names built up from several characters that compose, or already normal.
+    assert_eq!(normalize_username("A\u{30a}ngstro\u{308}m"),
+               Ok("\u{c5}ngstr\u{f6}m".to_string()));
+    assert_eq!(normalize_username("alice"), Ok("alice".to_string()));
+    assert_eq!(normalize_username(""), Ok("".to_string()));
+
+    // A Cyrillic `а` among Latin letters isn't allowed, whichever comes first...
+    let err = normalize_username("\u{430}lice").unwrap_err();
+    assert!(err.contains("Cyrillic") && err.contains("Latin"), "{}", err);
+    assert!(normalize_username("ali\u{441}e").is_err());
+    // ... though names in one script, with digits and punctuation, are fine...
+    assert_eq!(normalize_username("\u{430}\u{43b}\u{438}\u{441}\u{430}_99"),
+               Ok("\u{430}\u{43b}\u{438}\u{441}\u{430}_99".to_string()));
+    // ... and so are Japanese names, in kanji and kana together.
+    assert!(normalize_username("\u{5c71}\u{7530}\u{305f}\u{308d}\u{3046}").is_ok());
+    assert!(normalize_username("\u{5c71}\u{7530}bob").is_err());
+}
@@ -0,0 +1,88 @@
+// Compile-time checks that the codecs and the messages they carry can be moved to, and shared
+// between, threads: a codec is made on one thread and often ends up framing a connection on
+// another, and a pool's codec is shared behind a lock. Every codec (and every message type) this
+// crate exports belongs in the lists below; one that can't go in them can't be used like the
+// others, and this file won't build until it can.
+
+#[macro_use]
+extern crate static_assertions;
+extern crate serde;
+extern crate tokio_core;
+extern crate tokio_chat_common;
+
+use serde::{Deserialize, Serialize};
+use tokio_core::io::{Codec, EasyBuf};
+use tokio_chat_common::{BatchCodec, ChatMessage, ClientMessage, ClientToServerCodec, CodecStats,
+                        CodecStatsSnapshot, ContentType, ErrorCode, Event, Handshake,
+                        HandshakeCodec, Hello, JsonFormat, LengthPrefixedJson,
+                        LengthPrefixedJsonBuilder, LenientJson, LenientServerToClientCodec,
+                        NegotiatingCodec, RoomConfig, ServerMessage, ServerToClientCodec,
+                        StatsCodec, StreamingDecoder, UserInfo};
+
+use std::thread;
+
+assert_impl_all!(LengthPrefixedJson<ClientMessage, ServerMessage>: Send, Sync, Unpin);
+assert_impl_all!(LengthPrefixedJsonBuilder<ClientMessage, ServerMessage>: Send, Sync, Unpin);
+assert_impl_all!(LenientJson<ClientMessage, ServerMessage>: Send, Sync, Unpin);
+assert_impl_all!(BatchCodec<ClientMessage, ServerMessage>: Send, Sync, Unpin);
+assert_impl_all!(StatsCodec<ServerToClientCodec>: Send, Sync, Unpin);
+assert_impl_all!(NegotiatingCodec<ClientMessage, ServerMessage>: Send, Sync, Unpin);
+assert_impl_all!(StreamingDecoder<ClientMessage>: Send, Sync, Unpin);
+assert_impl_all!(JsonFormat: Send, Sync, Unpin);
+assert_impl_all!(CodecStats: Send, Sync, Unpin);
+assert_impl_all!(ServerToClientCodec: Send, Sync, Unpin);
+assert_impl_all!(ClientToServerCodec: Send, Sync, Unpin);
+assert_impl_all!(LenientServerToClientCodec: Send, Sync, Unpin);
+assert_impl_all!(HandshakeCodec: Send, Sync, Unpin);
+
+assert_impl_all!(Handshake: Send, Sync, Unpin);
+assert_impl_all!(Hello: Send, Sync, Unpin);
+assert_impl_all!(ClientMessage: Send, Sync, Unpin);
+assert_impl_all!(ServerMessage: Send, Sync, Unpin);
+assert_impl_all!(ContentType: Send, Sync, Unpin);
+assert_impl_all!(ErrorCode: Send, Sync, Unpin);
+assert_impl_all!(UserInfo: Send, Sync, Unpin);
+assert_impl_all!(ChatMessage: Send, Sync, Unpin);
+assert_impl_all!(RoomConfig: Send, Sync, Unpin);
+assert_impl_all!(Event: Send, Sync, Unpin);
+assert_impl_all!(CodecStatsSnapshot: Send, Sync, Unpin);
+
+// The codecs are generic over what they carry, so the checks above only cover the messages they
+// name; these cover whatever else they might carry, as long as it's thread-safe itself.
+fn thread_safe<T: Send + Sync + Unpin + 'static>() {}
+
+fn codecs_are_thread_safe<In, Out>()
+    where In: Serialize + Deserialize + Send + Sync + Unpin + 'static,
+          Out: Serialize + Deserialize + Send + Sync + Unpin + 'static
+{
+    thread_safe::<LengthPrefixedJson<In, Out>>();
+    thread_safe::<LenientJson<In, Out>>();
+    thread_safe::<BatchCodec<In, Out>>();
+    thread_safe::<StatsCodec<LengthPrefixedJson<In, Out>>>();
+    thread_safe::<NegotiatingCodec<In, Out>>();
+    thread_safe::<StreamingDecoder<In>>();
+}
+
+#[test]
+fn codecs_carry_any_thread_safe_messages() {
+    codecs_are_thread_safe::<ClientMessage, ServerMessage>();
+    codecs_are_thread_safe::<Handshake, Handshake>();
+}
+
+// What `Send` and `'static` are for: a codec made here, framing a connection on a thread of its
+// own, with nothing borrowed from the thread that made it.
+#[test]
+fn codecs_work_on_other_threads() {
+    let mut client = ClientToServerCodec::new();
+    let mut frame = Vec::new();
+    client.encode(ClientMessage::Who, &mut frame).unwrap();
+
+    let server = ServerToClientCodec::new();
+    let decoded = thread::spawn(move || {
+            let mut server = server;
+            server.decode(&mut EasyBuf::from(frame)).unwrap()
+        })
+        .join()
+        .unwrap();
+    assert_eq!(decoded, Some(ClientMessage::Who));
+}
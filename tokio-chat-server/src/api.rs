@@ -0,0 +1,423 @@
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::str;
+
+use futures::{future, Future, Stream};
+use futures::future::Loop;
+use serde_json::{self, Value};
+use serde_json::builder::ObjectBuilder;
+use tokio_core::io::{read, write_all};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_chat_common::{ClientMessage, MessageId};
+
+use auth;
+use config::Config;
+use connection::ConnectionMetadata;
+use middleware::{self, ConnectionContext, MessageMiddleware, MiddlewareAction};
+use slack_bridge::{self, Event};
+use {Chat, IoFuture, MAX_REPLAY_LEN};
+
+// Requests longer than this, headers and body together, are turned away.
+const MAX_REQUEST_LEN: usize = 64 * 1024;
+
+// A response's status line and the JSON that goes with it.
+type Response = (&'static str, Value);
+
+// Answer HTTP requests on `listener`, for bots and integrations that can't keep a chat connection
+// open. Every request must carry `token` in an `Authorization: Bearer` header. Everything is JSON:
+//
+//     GET /rooms                            the rooms anyone's in (on this node):
+//                                           {"rooms": ["lobby", ...]}
+//     GET /rooms/ROOM/messages?after_seq=N  the chat in ROOM numbered after N (or all of it, if
+//                                           there's no N), as last edited, oldest first:
+//                                           {"messages": [{"seq": 3, "from": "alice",
+//                                                          "body": "hi"}, ...]}
+//     POST /rooms/ROOM/messages             say {"from": "ci", "body": "build passed"} in ROOM,
+//                                           as if `from` had: {"seq": 4}
+//     POST /slack/events                    an Events API request from Slack, if a room is
+//                                           mirrored there (see `slack_bridge`); signed by
+//                                           Slack instead of carrying the token
+//
+// Posted messages go through the same middleware and length limit as a client's would; rate
+// limits are per connection, so they don't apply. Messages are listed from the database if there
+// is one, and otherwise from the history, so only as far back as `--history` reaches.
+//
+// Like `metrics::serve`, this is only as much of HTTP as it takes: one request per connection,
+// answered with HTTP/1.0. A request that takes longer than `--read-timeout` to arrive in full is
+// answered with a 408, rather than holding its connection open for as long as it likes.
+pub fn serve(listener: TcpListener,
+             token: String,
+             chat: Chat,
+             config: Rc<Config>,
+             middleware: Rc<Vec<Box<MessageMiddleware>>>,
+             handle: Handle)
+             -> Box<Future<Item = (), Error = io::Error>> {
+    let api = Rc::new(Api {
+        token: token,
+        chat: chat,
+        config: config,
+        middleware: middleware,
+    });
+    Box::new(listener.incoming().for_each(move |(socket, addr)| {
+        let api = api.clone();
+        let socket = Shared(Rc::new(socket));
+        let request = read_request(socket.clone()).map(|request| {
+            request.ok_or_else(|| error("400 Bad Request", "malformed request"))
+        });
+        let request: IoFuture<Result<Request, Response>> = match api.config.read_timeout {
+            Some(timeout) => {
+                let timed_out = future::result(Timeout::new(timeout, &handle))
+                    .flatten()
+                    .map(|()| Err(error("408 Request Timeout", "the request took too long")));
+                let request = request.select(timed_out)
+                    .map(|(request, _)| request)
+                    .map_err(|(err, _)| err);
+                Box::new(request)
+            }
+            None => Box::new(request),
+        };
+        let response = request.and_then(move |request| {
+            let response = match request {
+                Ok(request) => api.respond(&addr, request),
+                Err(response) => ready(response),
+            };
+            response.and_then(move |(status, body)| write_response(socket, status, &body))
+        });
+        handle.spawn(response.map_err(move |err| println!("HTTP API request from {:?} failed: {}",
+                                                          addr,
+                                                          err)));
+        Ok(())
+    }))
+}
+
+struct Api {
+    token: String,
+    chat: Chat,
+    config: Rc<Config>,
+    middleware: Rc<Vec<Box<MessageMiddleware>>>,
+}
+
+impl Api {
+    // Work out the response to `request`, from `addr`.
+    fn respond(&self, addr: &SocketAddr, request: Request) -> IoFuture<Response> {
+        if request.path == "/slack/events" {
+            return self.slack_event(addr, &request);
+        }
+        let token = self.token.as_bytes();
+        let authorized = request.bearer
+            .as_ref()
+            .map_or(false, |bearer| auth::constant_time_eq(token, bearer.as_bytes()));
+        if !authorized {
+            return ready(error("401 Unauthorized", "missing or incorrect token"));
+        }
+
+        let path = request.path.split('/').skip(1).map(decode).collect::<Option<Vec<_>>>();
+        let path = match path {
+            Some(path) => path,
+            None => return ready(error("400 Bad Request", "malformed path")),
+        };
+        let path = path.iter().map(|segment| segment.as_str()).collect::<Vec<_>>();
+        match (request.method.as_str(), &path[..]) {
+            ("GET", ["rooms"]) => ready(self.rooms()),
+            ("GET", ["rooms", room, "messages"]) => {
+                match after_seq(&request.query) {
+                    Some(after) => self.messages(room, after.map_or(0, |seq| seq + 1)),
+                    None => ready(error("400 Bad Request", "after_seq must be a message number")),
+                }
+            }
+            ("POST", ["rooms", room, "messages"]) => self.post(addr, room, &request.body),
+            (_, ["rooms"]) | (_, ["rooms", _, "messages"]) => {
+                ready(error("405 Method Not Allowed", "method not allowed"))
+            }
+            _ => ready(error("404 Not Found", "not found")),
+        }
+    }
+
+    fn rooms(&self) -> Response {
+        let mut rooms = self.chat.clients.rooms().into_iter().collect::<Vec<_>>();
+        rooms.sort();
+        ("200 OK", ObjectBuilder::new().insert("rooms", rooms).build())
+    }
+
+    // The messages in `room` numbered `seq` or later.
+    fn messages(&self, room: &str, seq: u64) -> IoFuture<Response> {
+        let listed: IoFuture<Vec<Value>> = match self.chat.messages {
+            Some(ref messages) => {
+                Box::new(messages.get_since(room, seq, MAX_REPLAY_LEN).map(|stored| {
+                    stored.into_iter()
+                        .filter(|msg| !msg.deleted)
+                        .map(|msg| message(msg.seq, &msg.from_user, msg.current_body()))
+                        .collect()
+                }))
+            }
+            None => {
+                let mut listed = self.chat.history.borrow().chat(seq, room);
+                listed.truncate(MAX_REPLAY_LEN);
+                let listed = listed.iter().map(|msg| message(msg.id, &msg.from, &msg.body));
+                Box::new(future::ok(listed.collect()))
+            }
+        };
+        Box::new(listed.then(|listed| {
+            Ok(match listed {
+                Ok(listed) => ("200 OK", ObjectBuilder::new().insert("messages", listed).build()),
+                Err(err) => {
+                    println!("STORE failed: {}", err);
+                    error("500 Internal Server Error", "couldn't read the messages")
+                }
+            })
+        }))
+    }
+
+    // Say what `body` asks to in `room`, on behalf of a request from `addr`.
+    fn post(&self, addr: &SocketAddr, room: &str, body: &[u8]) -> IoFuture<Response> {
+        let posted = serde_json::from_slice::<Value>(body).ok();
+        let field = |name: &str| {
+            posted.as_ref().and_then(|posted| posted.find(name)).and_then(Value::as_str)
+        };
+        let (from, body) = match (field("from"), field("body")) {
+            (Some(from), Some(body)) if !from.is_empty() => (from.to_string(), body.to_string()),
+            _ => {
+                let reason = "expected {\"from\": NAME, \"body\": TEXT}";
+                return ready(error("400 Bad Request", reason));
+            }
+        };
+        if room.is_empty() {
+            return ready(error("400 Bad Request", "room names can't be empty"));
+        }
+        self.say(addr, room, &from, body)
+    }
+
+    // Act on an Events API request from Slack, if Slack signed it. Slack tries again with anything
+    // that isn't answered with a 2xx, so what we can't do anything with is still answered with a
+    // 200, including messages that the middleware or the room's policy turn away.
+    fn slack_event(&self, addr: &SocketAddr, request: &Request) -> IoFuture<Response> {
+        let slack = match self.chat.slack {
+            Some(ref slack) => slack.clone(),
+            None => return ready(error("404 Not Found", "not found")),
+        };
+        if request.method != "POST" {
+            return ready(error("405 Method Not Allowed", "method not allowed"));
+        }
+        if !slack.verify(request.header("x-slack-request-timestamp"),
+                         request.header("x-slack-signature"),
+                         &request.body,
+                         self.chat.clock.unix_time()) {
+            return ready(error("401 Unauthorized", "missing, stale or incorrect signature"));
+        }
+        let ok = ("200 OK", ObjectBuilder::new().build());
+        match slack_bridge::parse_event(&request.body) {
+            Some(Event::Challenge(challenge)) => {
+                ready(("200 OK", ObjectBuilder::new().insert("challenge", challenge).build()))
+            }
+            // Slack sends an event again if we were slow to acknowledge it, saying so in a header,
+            // and we've already said it the first time round.
+            Some(Event::Message(..)) if request.header("x-slack-retry-num").is_some() => ready(ok),
+            Some(Event::Message(user, text)) => {
+                let from = format!("{}{}", slack_bridge::NAME_PREFIX, user);
+                Box::new(self.say(addr, slack.room(), &from, text).map(move |_| ok))
+            }
+            Some(Event::Ignored) => ready(ok),
+            None => ready(error("400 Bad Request", "expected an Events API request")),
+        }
+    }
+
+    // Say `body` in `room` as `from`, on behalf of a request from `addr`, if the room's policy and
+    // the middleware let us.
+    fn say(&self, addr: &SocketAddr, room: &str, from: &str, body: String) -> IoFuture<Response> {
+        let policy = self.config.policies.for_room(room);
+        if body.len() > policy.max_body_len {
+            let reason = format!("messages in {} are limited to {} bytes",
+                                 room,
+                                 policy.max_body_len);
+            return ready(error("413 Payload Too Large", &reason));
+        }
+
+        let mut msg = ClientMessage::Message(body);
+        let metadata = ConnectionMetadata::new();
+        let ctx = ConnectionContext {
+            addr: *addr,
+            name: from,
+            room: room,
+            metadata: &metadata,
+        };
+        // Messages the middleware drops are quietly accepted, as they would be from a client.
+        let dropped = ("202 Accepted", ObjectBuilder::new().build());
+        match middleware::run(&self.middleware, &mut msg, &ctx) {
+            MiddlewareAction::Allow | MiddlewareAction::Modify => {}
+            MiddlewareAction::Drop => return ready(dropped),
+            MiddlewareAction::Error(reason) => return ready(error("400 Bad Request", &reason)),
+        }
+        let body = match msg {
+            ClientMessage::Message(body) => body,
+            _ => return ready(dropped),
+        };
+
+        Box::new(self.chat.say(room, from, body).map(|seq| {
+            ("201 Created", ObjectBuilder::new().insert("seq", seq).build())
+        }))
+    }
+}
+
+fn message(seq: MessageId, from: &str, body: &str) -> Value {
+    ObjectBuilder::new().insert("seq", seq).insert("from", from).insert("body", body).build()
+}
+
+fn ready(response: Response) -> IoFuture<Response> {
+    Box::new(future::ok(response))
+}
+
+fn error(status: &'static str, reason: &str) -> Response {
+    (status, ObjectBuilder::new().insert("error", reason).build())
+}
+
+// The `after_seq` in `query`: `Some(None)` if there isn't one, `None` if it isn't a number.
+fn after_seq(query: &str) -> Option<Option<u64>> {
+    match query.split('&').find_map(|param| param.strip_prefix("after_seq=")) {
+        Some(seq) => seq.parse().ok().map(Some),
+        None => Some(None),
+    }
+}
+
+// Undo the percent-encoding of a path segment, if it decodes to UTF-8.
+fn decode(segment: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(segment.len());
+    let mut bytes = segment.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            decoded.push(byte);
+            continue;
+        }
+        let hex = [bytes.next()?, bytes.next()?];
+        let hex = str::from_utf8(&hex).ok()?;
+        decoded.push(u8::from_str_radix(hex, 16).ok()?);
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// The parts of a request we look at.
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    bearer: Option<String>,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    // The value of the header `name`, whatever its case, if the request has one.
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|&&(ref header, _)| header.eq_ignore_ascii_case(name))
+            .map(|&(_, ref value)| value.as_str())
+    }
+}
+
+enum Parsed {
+    Incomplete,
+    Malformed,
+    Done(Request),
+}
+
+// A connection, shared between reading its request and answering it, so that it can still be
+// answered if reading the request is given up on.
+#[derive(Clone)]
+struct Shared(Rc<TcpStream>);
+
+impl Read for Shared {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self.0).read(buf)
+    }
+}
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self.0).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.0).flush()
+    }
+}
+
+// Read a whole request off of `socket`: the headers, then as much body as they say there is. The
+// request is `None` if it's malformed, too long, or cut short.
+fn read_request(socket: Shared) -> IoFuture<Option<Request>> {
+    Box::new(future::loop_fn((socket, Vec::new()), |(socket, mut buf)| {
+        read(socket, vec![0; 4096]).map(move |(socket, chunk, n)| {
+            buf.extend_from_slice(&chunk[..n]);
+            match parse(&buf) {
+                Parsed::Done(request) => Loop::Break(Some(request)),
+                Parsed::Incomplete if n > 0 && buf.len() <= MAX_REQUEST_LEN => {
+                    Loop::Continue((socket, buf))
+                }
+                _ => Loop::Break(None),
+            }
+        })
+    }))
+}
+
+fn parse(buf: &[u8]) -> Parsed {
+    let head_len = match buf.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(end) => end + 4,
+        None => return Parsed::Incomplete,
+    };
+    let head = match str::from_utf8(&buf[..head_len]) {
+        Ok(head) => head,
+        Err(_) => return Parsed::Malformed,
+    };
+
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let (method, target) = match (request_line.next(), request_line.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return Parsed::Malformed,
+    };
+    let mut content_len = 0;
+    let mut bearer = None;
+    let mut headers = Vec::new();
+    for line in lines {
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => continue,
+        };
+        headers.push((name.to_string(), value.to_string()));
+        if name.eq_ignore_ascii_case("content-length") {
+            content_len = match value.parse() {
+                Ok(len) => len,
+                Err(_) => return Parsed::Malformed,
+            };
+        } else if name.eq_ignore_ascii_case("authorization") {
+            bearer = value.strip_prefix("Bearer ").map(|token| token.trim().to_string());
+        }
+    }
+    if buf.len() - head_len < content_len {
+        return Parsed::Incomplete;
+    }
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    Parsed::Done(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        bearer: bearer,
+        headers: headers,
+        body: buf[head_len..head_len + content_len].to_vec(),
+    })
+}
+
+fn write_response(socket: Shared, status: &str, body: &Value) -> IoFuture<()> {
+    let body = serde_json::to_string(body).expect("JSON values always serialize");
+    let response = format!("HTTP/1.0 {}\r\n\
+                            Content-Type: application/json\r\n\
+                            Content-Length: {}\r\n\
+                            \r\n\
+                            {}",
+                           status,
+                           body.len(),
+                           body);
+    Box::new(write_all(socket, response.into_bytes()).map(|_| ()))
+}
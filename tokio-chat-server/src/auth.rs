@@ -0,0 +1,192 @@
+use std::cmp;
+use std::io;
+use std::rc::Rc;
+
+use bcrypt;
+use futures::{future, Future};
+use futures_cpupool::CpuPool;
+use tokio_chat_common::{validation, Handshake};
+
+use store::{StoreFuture, StoredUser, UserStore};
+
+// Check the token presented in `handshake` against the one the server was started with (if any).
+// With no `expected` token every handshake is accepted.
+pub fn authorized(expected: Option<&str>, handshake: &Handshake) -> bool {
+    match (expected, handshake.token.as_ref()) {
+        (None, _) => true,
+        (Some(expected), Some(presented)) => constant_time_eq(expected.as_bytes(),
+                                                              presented.as_bytes()),
+        (Some(_), None) => false,
+    }
+}
+
+// Whether `handshake` carries the server's admin token, making its client an operator. Servers
+// started without one have no operators.
+pub fn is_admin(admin_token: Option<&str>, handshake: &Handshake) -> bool {
+    admin_token.is_some() && authorized(admin_token, handshake)
+}
+
+// Registered users' passwords must be at least this many characters long.
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+// Passwords are never hashed with a bcrypt cost below this, whatever the server was configured
+// with.
+pub const MIN_PASSWORD_COST: u32 = 12;
+
+// How a client's attempt to log in went; see `sign_in`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignIn {
+    Admitted { admin: bool },
+
+    // The name is registered and the password missing or wrong, or the name isn't registered and
+    // a password was given anyway.
+    WrongPassword,
+
+    // The name isn't registered, no password was given, and the server doesn't take guests.
+    GuestsNotAllowed,
+
+    // The login token wasn't one of ours, had expired or been revoked, or was for another name.
+    InvalidToken,
+}
+
+// Check `handshake`'s claim to its name against `users`, at `now` (in seconds since the Unix
+// epoch).
+//
+// A registered name needs its password, and then keeps whatever operator status it had before; a
+// successful login is noted as the user's `last_seen`. An unregistered name with no password is a
+// guest, let in only if `allow_guests` (or if they're an operator, who get in regardless). `admin`
+// says whether the handshake carried the admin token, which makes the user an operator now (and,
+// if they're registered, from now on).
+//
+// Checking a password is deliberately slow, so it happens on `hasher` instead of the event loop.
+pub fn sign_in(users: Rc<UserStore>,
+               hasher: CpuPool,
+               handshake: &Handshake,
+               admin: bool,
+               allow_guests: bool,
+               now: u64)
+               -> StoreFuture<SignIn> {
+    let password = handshake.password.clone();
+    Box::new(users.get_user(&handshake.name).and_then(move |stored| -> StoreFuture<SignIn> {
+        // Users stored without a password are left over from before registration was explicit;
+        // their names are anyone's.
+        let stored = stored.filter(|user| !user.password_hash.is_empty());
+        match (stored, password) {
+            (None, None) if allow_guests || admin => {
+                Box::new(future::ok(SignIn::Admitted { admin: admin }))
+            }
+            (None, None) => Box::new(future::ok(SignIn::GuestsNotAllowed)),
+            (None, Some(_)) | (Some(_), None) => Box::new(future::ok(SignIn::WrongPassword)),
+            (Some(user), Some(password)) => {
+                let hash = user.password_hash.clone();
+                let verified = hasher.spawn_fn(move || {
+                    bcrypt::verify(password, &hash).map_err(hash_error)
+                });
+                Box::new(verified.and_then(move |verified| -> StoreFuture<SignIn> {
+                    if !verified {
+                        return Box::new(future::ok(SignIn::WrongPassword));
+                    }
+                    admit(users, user, admin, now)
+                }))
+            }
+        }
+    }))
+}
+
+// Log in `username` on the strength of a login token (see `Tokens::verify`), as `sign_in` would
+// with their password.
+pub fn sign_in_with_token(users: Rc<UserStore>,
+                          username: &str,
+                          admin: bool,
+                          now: u64)
+                          -> StoreFuture<SignIn> {
+    Box::new(users.get_user(username).and_then(move |stored| -> StoreFuture<SignIn> {
+        // Tokens are only issued to registered users, but the user store may have been replaced
+        // since.
+        match stored.filter(|user| !user.password_hash.is_empty()) {
+            Some(user) => admit(users, user, admin, now),
+            None => Box::new(future::ok(SignIn::WrongPassword)),
+        }
+    }))
+}
+
+// Let in registered `user`, who's been seen at `now` and is an operator if they were before or if
+// `admin`.
+fn admit(users: Rc<UserStore>, user: StoredUser, admin: bool, now: u64) -> StoreFuture<SignIn> {
+    let admin = admin || user.is_admin;
+    let user = StoredUser {
+        is_admin: admin,
+        last_seen: now,
+        ..user
+    };
+    let admitted = SignIn::Admitted { admin: admin };
+    Box::new(users.upsert_user(user).map(move |()| admitted))
+}
+
+// Register `username` (in its normal form; see `validation`) with `password`, hashed at `cost` (or
+// `MIN_PASSWORD_COST`, if that's higher), as of `now`. On failure, returns a message to send back
+// to whoever asked.
+pub fn register(users: Rc<UserStore>,
+                hasher: CpuPool,
+                cost: u32,
+                username: String,
+                password: String,
+                now: u64)
+                -> StoreFuture<Result<String, String>> {
+    let username = match validation::normalize_username(&username) {
+        Ok(username) => username,
+        Err(reason) => return Box::new(future::ok(Err(reason))),
+    };
+    if username.is_empty() {
+        return Box::new(future::ok(Err("can't register an empty name".to_string())));
+    }
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        let reason = format!("passwords must be at least {} characters", MIN_PASSWORD_LEN);
+        return Box::new(future::ok(Err(reason)));
+    }
+
+    // Hash first and only then check whether the name is taken, so the check and the write that
+    // follows it are as close together as they can be.
+    let cost = cmp::max(cost, MIN_PASSWORD_COST);
+    let hash = hasher.spawn_fn(move || bcrypt::hash(password, cost).map_err(hash_error));
+    Box::new(hash.and_then(move |hash| {
+        users.get_user(&username).and_then(move |stored| -> StoreFuture<_> {
+            let user = match stored {
+                Some(ref user) if !user.password_hash.is_empty() => {
+                    let reason = format!("{} is already registered", username);
+                    return Box::new(future::ok(Err(reason)));
+                }
+                Some(user) => {
+                    StoredUser {
+                        password_hash: hash,
+                        ..user
+                    }
+                }
+                None => {
+                    StoredUser {
+                        username: username.clone(),
+                        password_hash: hash,
+                        is_admin: false,
+                        created_at: now,
+                        last_seen: now,
+                    }
+                }
+            };
+            Box::new(users.upsert_user(user).map(move |()| Ok(username)))
+        })
+    }))
+}
+
+fn hash_error(err: bcrypt::BcryptError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+// Compare two byte strings in time that depends only on their lengths, not on where they first
+// differ, so a client can't recover the token one byte at a time by timing rejections. (The
+// length itself does leak, which is acceptable for a shared secret.)
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
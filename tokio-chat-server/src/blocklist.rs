@@ -0,0 +1,116 @@
+use std::ops::Range;
+use std::str::FromStr;
+
+use tokio_chat_common::ClientMessage;
+
+use middleware::{ConnectionContext, MessageMiddleware, MiddlewareAction};
+
+// What `Blocklist` does with a chat message containing a blocked phrase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockMode {
+    // Replace each matching letter with `*` and let the message through.
+    Censor,
+
+    // Turn the message away with an `InvalidMessage` error.
+    Reject,
+}
+
+impl FromStr for BlockMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<BlockMode, ()> {
+        match s {
+            "censor" => Ok(BlockMode::Censor),
+            "reject" => Ok(BlockMode::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+// Middleware that screens chat messages (and edits to them) for any of a list of blocked words and
+// phrases.
+//
+// Matching ignores case, and looks only at letters and digits: spaces and punctuation are skipped
+// on both sides, so "Bad Word" is caught as "b.a.d w-o-r-d" or "BADWORD" just the same, as well as
+// when it turns up inside a longer word. That does mean a phrase can also be caught where it
+// straddles two innocent words; deployments pick their list with that in mind.
+pub struct Blocklist {
+    // Each phrase, lowercased and stripped down to its letters and digits.
+    phrases: Vec<Vec<char>>,
+    mode: BlockMode,
+}
+
+impl Blocklist {
+    pub fn new(phrases: &[String], mode: BlockMode) -> Blocklist {
+        Blocklist {
+            phrases: phrases.iter()
+                .map(|phrase| normalize(phrase).into_iter().map(|(c, _)| c).collect::<Vec<_>>())
+                .filter(|phrase| !phrase.is_empty())
+                .collect(),
+            mode: mode,
+        }
+    }
+
+    // The byte ranges of `body` covered by blocked phrases, in no particular order. Ranges may
+    // overlap.
+    fn matches(&self, body: &str) -> Vec<Range<usize>> {
+        let text = normalize(body);
+        let mut found = Vec::new();
+        for phrase in &self.phrases {
+            if phrase.len() > text.len() {
+                continue;
+            }
+            for start in 0..text.len() - phrase.len() + 1 {
+                let window = &text[start..start + phrase.len()];
+                if window.iter().zip(phrase).all(|(&(c, _), &p)| c == p) {
+                    found.push(window[0].1.start..window[phrase.len() - 1].1.end);
+                }
+            }
+        }
+        found
+    }
+}
+
+impl MessageMiddleware for Blocklist {
+    fn process(&self, msg: &mut ClientMessage, _: &ConnectionContext) -> MiddlewareAction {
+        let body = match *msg {
+            ClientMessage::Message(ref mut body) |
+            ClientMessage::FormattedMessage { ref mut body, .. } |
+            ClientMessage::EditMessage { new_body: ref mut body, .. } => body,
+            _ => return MiddlewareAction::Allow,
+        };
+        let found = self.matches(body);
+        if found.is_empty() {
+            return MiddlewareAction::Allow;
+        }
+
+        match self.mode {
+            BlockMode::Reject => {
+                MiddlewareAction::Error("message contains a blocked word".to_string())
+            }
+            BlockMode::Censor => {
+                // Star out the letters and digits of each match, leaving the punctuation between
+                // them alone.
+                *body = body.char_indices()
+                    .map(|(i, c)| {
+                        let hit = found.iter().any(|range| range.start <= i && i < range.end);
+                        if hit && c.is_alphanumeric() { '*' } else { c }
+                    })
+                    .collect();
+                MiddlewareAction::Modify
+            }
+        }
+    }
+}
+
+// The lowercased letters and digits of `s`, each with the byte range of `s` it came from. (A
+// character can lowercase to several, which then share a range.)
+fn normalize(s: &str) -> Vec<(char, Range<usize>)> {
+    s.char_indices()
+        .filter(|&(_, c)| c.is_alphanumeric())
+        .flat_map(|(i, c)| {
+            let range = i..i + c.len_utf8();
+            c.to_lowercase().map(move |lower| (lower, range.clone()))
+        })
+        .collect()
+}
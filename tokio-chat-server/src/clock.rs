@@ -0,0 +1,80 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Where the server gets the time from. Everything that depends on the time (rate limits, idle
+// timeouts, session and login token expiry, when users were last seen) asks its `Clock` rather
+// than the system, so tests can swap in a `MockClock` and move time along themselves.
+pub trait Clock {
+    fn now(&self) -> Instant;
+
+    // The same moment as `now`, in milliseconds since the Unix epoch, for times that are stored
+    // or sent to clients.
+    fn unix_time_ms(&self) -> u64;
+
+    // Likewise, in seconds.
+    fn unix_time(&self) -> u64 {
+        self.unix_time_ms() / 1000
+    }
+}
+
+// The real time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn unix_time_ms(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_millis() as u64)
+            .unwrap_or(0)
+    }
+}
+
+// A clock that starts out at the real time and then only moves when it's told to.
+pub struct MockClock {
+    start: Instant,
+    start_unix_time_ms: u64,
+    elapsed: Mutex<Duration>,
+}
+
+impl MockClock {
+    pub fn new() -> MockClock {
+        MockClock {
+            start: SystemClock.now(),
+            start_unix_time_ms: SystemClock.unix_time_ms(),
+            elapsed: Mutex::new(Duration::from_secs(0)),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().expect("nothing panics while holding the time") += by;
+    }
+
+    fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().expect("nothing panics while holding the time")
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn unix_time_ms(&self) -> u64 {
+        self.start_unix_time_ms + self.elapsed().as_millis() as u64
+    }
+}
+
+// How the server holds on to its clock. Tests keep a handle on their `MockClock` to move it along
+// from another thread, hence `Send + Sync`.
+pub type SharedClock = Arc<Clock + Send + Sync>;
@@ -0,0 +1,243 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::{mpsc as std_mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use futures::{Future, Stream};
+use futures::sync::{mpsc, oneshot};
+use redis::{self, Commands};
+use serde_json;
+use tokio_chat_common::ServerMessage;
+use tokio_core::reactor::Handle;
+
+use session;
+
+// Messages from other nodes, as they arrive.
+pub type RecvStream<T> = mpsc::UnboundedReceiver<T>;
+
+// How servers running as nodes of one cluster pass chat along to each other, so that clients
+// connected to different nodes can talk to each other as if they were on one server.
+pub trait ClusterBus {
+    // Send `msg`, broadcast in `room` on this node, to the other nodes.
+    fn publish(&self, room: &str, msg: ServerMessage);
+
+    // The messages other nodes publish to `room` from now on, until the stream is dropped. Nodes
+    // don't hear their own messages back.
+    fn subscribe(&self, room: &str) -> RecvStream<ServerMessage>;
+}
+
+// How the server holds on to its bus, which may be shared with other servers in the same process.
+pub type SharedBus = Arc<ClusterBus + Send + Sync>;
+
+// The nodes of a cluster inside a single process, e.g. several servers in one test. Each
+// `LocalBus` is one node; `another_node` adds more.
+pub struct LocalBus {
+    node: u64,
+    hub: Arc<Mutex<Hub>>,
+}
+
+#[derive(Default)]
+struct Hub {
+    next_node: u64,
+    subscribers: HashMap<String, Vec<(u64, mpsc::UnboundedSender<ServerMessage>)>>,
+}
+
+impl LocalBus {
+    pub fn new() -> LocalBus {
+        LocalBus {
+            node: 0,
+            hub: Arc::new(Mutex::new(Hub { next_node: 1, ..Hub::default() })),
+        }
+    }
+
+    // A new node on the same bus as this one.
+    pub fn another_node(&self) -> LocalBus {
+        let mut hub = self.hub.lock().expect("nothing panics while holding the hub");
+        let node = hub.next_node;
+        hub.next_node += 1;
+        LocalBus {
+            node: node,
+            hub: self.hub.clone(),
+        }
+    }
+}
+
+impl Default for LocalBus {
+    fn default() -> LocalBus {
+        LocalBus::new()
+    }
+}
+
+impl ClusterBus for LocalBus {
+    fn publish(&self, room: &str, msg: ServerMessage) {
+        let mut hub = self.hub.lock().expect("nothing panics while holding the hub");
+        if let Some(subscribers) = hub.subscribers.get_mut(room) {
+            // Subscribers whose streams were dropped are forgotten as we go.
+            let node = self.node;
+            subscribers.retain(|&(subscriber, ref tx)| {
+                subscriber == node || tx.unbounded_send(msg.clone()).is_ok()
+            });
+        }
+    }
+
+    fn subscribe(&self, room: &str) -> RecvStream<ServerMessage> {
+        let (tx, rx) = mpsc::unbounded();
+        self.hub
+            .lock()
+            .expect("nothing panics while holding the hub")
+            .subscribers
+            .entry(room.to_string())
+            .or_default()
+            .push((self.node, tx));
+        rx
+    }
+}
+
+// A cluster whose nodes talk through Redis pub/sub, one channel per room. Each message is
+// published as the id of the node that sent it, a newline, and the message's JSON, so nodes can
+// skip their own.
+//
+// The Redis client blocks, so publishing happens on a thread of its own, and each subscription
+// gets a thread (and a connection) too.
+pub struct RedisBackend {
+    client: redis::Client,
+    node: String,
+    publish_tx: Mutex<std_mpsc::Sender<(String, String)>>,
+}
+
+impl RedisBackend {
+    // Connect to the Redis at `url`, e.g. `redis://127.0.0.1/`.
+    pub fn open(url: &str) -> io::Result<RedisBackend> {
+        let client = redis::Client::open(url).map_err(redis_error)?;
+        let mut publisher = client.get_connection().map_err(redis_error)?;
+
+        let (publish_tx, publish_rx) = std_mpsc::channel::<(String, String)>();
+        thread::spawn(move || {
+            for (channel, payload) in publish_rx {
+                if let Err(err) = publisher.publish::<_, _, ()>(channel, payload) {
+                    println!("CLUSTER publish failed: {}", err);
+                }
+            }
+        });
+
+        Ok(RedisBackend {
+            client: client,
+            node: session::new_token(),
+            publish_tx: Mutex::new(publish_tx),
+        })
+    }
+}
+
+impl ClusterBus for RedisBackend {
+    fn publish(&self, room: &str, msg: ServerMessage) {
+        let payload = match serde_json::to_string(&msg) {
+            Ok(json) => format!("{}\n{}", self.node, json),
+            Err(err) => return println!("CLUSTER couldn't encode {:?}: {}", msg, err),
+        };
+        // The publishing thread only stops if it panics, in which case there's nothing to do.
+        let _ = self.publish_tx
+            .lock()
+            .expect("nothing panics while holding the publisher")
+            .send((channel(room), payload));
+    }
+
+    fn subscribe(&self, room: &str) -> RecvStream<ServerMessage> {
+        let (tx, rx) = mpsc::unbounded();
+        let client = self.client.clone();
+        let node = self.node.clone();
+        let channel = channel(room);
+        thread::spawn(move || {
+            if let Err(err) = relay(&client, &node, &channel, &tx) {
+                println!("CLUSTER subscription to {} failed: {}", channel, err);
+            }
+        });
+        rx
+    }
+}
+
+// Pass messages from other nodes on `channel` to `tx` until it's dropped. We wake up every so
+// often even when nothing's arriving, to check.
+fn relay(client: &redis::Client,
+         node: &str,
+         channel: &str,
+         tx: &mpsc::UnboundedSender<ServerMessage>)
+         -> redis::RedisResult<()> {
+    let mut connection = client.get_connection()?;
+    let mut pubsub = connection.as_pubsub();
+    pubsub.subscribe(channel)?;
+    pubsub.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+    while !tx.is_closed() {
+        let payload: String = match pubsub.get_message() {
+            Ok(msg) => msg.get_payload()?,
+            Err(ref err) if err.is_timeout() => continue,
+            Err(err) => return Err(err),
+        };
+        let json = match payload.split_once('\n') {
+            Some((from, _)) if from == node => continue,
+            Some((_, json)) => json,
+            None => continue,
+        };
+        match serde_json::from_str(json) {
+            Ok(msg) => {
+                if tx.unbounded_send(msg).is_err() {
+                    break;
+                }
+            }
+            Err(err) => println!("CLUSTER couldn't decode a message on {}: {}", channel, err),
+        }
+    }
+    Ok(())
+}
+
+fn channel(room: &str) -> String {
+    format!("tokio-chat:room:{}", room)
+}
+
+fn redis_error(err: redis::RedisError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+// The rooms a node is subscribed to on its bus, each with a way to cancel the subscription.
+pub struct Subscriptions {
+    bus: SharedBus,
+    rooms: HashMap<String, oneshot::Sender<()>>,
+}
+
+impl Subscriptions {
+    pub fn new(bus: SharedBus) -> Subscriptions {
+        Subscriptions {
+            bus: bus,
+            rooms: HashMap::new(),
+        }
+    }
+
+    pub fn publish(&self, room: &str, msg: ServerMessage) {
+        self.bus.publish(room, msg);
+    }
+
+    // Make sure we're subscribed to exactly `rooms`: start listening to those we weren't already,
+    // handing what arrives in each to `deliver` (on `handle`), and stop listening to the rest.
+    pub fn update<F>(&mut self, rooms: HashSet<String>, handle: &Handle, deliver: F)
+        where F: Fn(&str, ServerMessage) -> Box<Future<Item = (), Error = ()>> + Clone + 'static
+    {
+        // Dropping a room's cancel sender ends its task, which drops the subscription.
+        self.rooms.retain(|room, _| rooms.contains(room));
+
+        for room in rooms {
+            if self.rooms.contains_key(&room) {
+                continue;
+            }
+            let (cancel_tx, cancel_rx) = oneshot::channel();
+            let messages = self.bus.subscribe(&room);
+            let deliver = deliver.clone();
+            let relay = {
+                let room = room.clone();
+                messages.for_each(move |msg| deliver(&room, msg))
+            };
+            handle.spawn(relay.select(cancel_rx.then(|_| Ok(()))).then(|_| Ok(())));
+            self.rooms.insert(room, cancel_tx);
+        }
+    }
+}
@@ -0,0 +1,528 @@
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::process;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bcrypt;
+
+use tokio_chat_common::{capability, MAX_FILE_SIZE};
+
+use auth;
+use blocklist::BlockMode;
+use clock::{SharedClock, SystemClock};
+use lifecycle::SharedLifecycle;
+use trace::SharedSubscriber;
+use cluster::SharedBus;
+use discord_bridge::DiscordConfig;
+use policy::{Policies, RoomPolicy};
+use slack_bridge::SlackConfig;
+use throttle::ThrottlePolicy;
+use webhooks::WebhookRegistry;
+use xmpp_gateway::XmppConfig;
+
+const USAGE: &str = "\
+usage: tokio-chat-server [options]
+
+options:
+    --token SECRET              require clients to present SECRET in their handshake
+    --admin-token SECRET        treat clients presenting SECRET in their handshake as operators,
+                                who may send announcements (and are let in regardless of --token
+                                and --allow-guests)
+    --allow-guests              let in clients with unregistered names and no password
+    --restrict-topics           only let operators set rooms' topics
+    --max-body-len BYTES        longest chat message accepted by default (default 1024)
+    --rate-limit N              messages per second each client may send by default; 0 for no
+                                limit (default 5)
+    --max-room-members N        members each room may have by default before joins to it are
+                                refused; 0 for no limit (default 0)
+    --room-policy ROOM:BYTES:N  give ROOM its own max message length and rate limit, and, as
+                                ROOM:BYTES:N:MEMBERS, its own member limit; may be repeated
+    --flood-warn-after N        warn clients after N messages refused for going over the rate
+                                limit; 0 to never warn or disconnect them (default 10)
+    --flood-disconnect-after N  disconnect warned clients after N more (default 10)
+    --flood-grace SECS          forget a client's refused messages, warning and all, once it's
+                                gone SECS without one (default 10)
+    --block PHRASE              screen chat messages for PHRASE; may be repeated
+    --block-mode MODE           what to do with messages containing a blocked phrase: censor
+                                the phrase or reject the message (default censor)
+    --max-connections-per-ip N  connections each address may have open at once (default 16)
+    --login-attempts N          lock out addresses after N failed logins, for longer each time;
+                                0 to never do so (default 5)
+    --login-window SECS         how long failed logins count towards --login-attempts after the
+                                first of them (default 60)
+    --login-lockout SECS        how long an address's first lockout lasts; each after that lasts
+                                twice as long as the last (default 30)
+    --login-max-lockout SECS    the longest a lockout can last (default 3600)
+    --login-throttle-file FILE  keep failed logins and lockouts in FILE, so they outlast a
+                                restart (default off)
+    --proxy-protocol            expect each connection to start with a PROXY protocol v1 header
+                                giving the client's address, as load balancers like HAProxy can
+                                send; connections without one are dropped (default off)
+    --max-file-size BYTES       largest file clients may send (default 1048576)
+    --max-bad-frames N          disconnect clients after more than N malformed messages in a
+                                row (default 3)
+    --strict-protocol           answer messages with fields this server doesn't know with an
+                                error saying so, without counting them as malformed (default off)
+    --motd TEXT                 greet each client with TEXT as the message of the day
+    --history N                 chat messages to keep for resumed sessions to catch up on
+                                (default 100)
+    --resume-grace SECS         how long a disconnected client's session can be resumed
+                                (default 30)
+    --handshake-timeout SECS    disconnect clients that haven't sent their handshake within SECS
+                                of connecting; 0 to wait forever (default 30)
+    --idle-timeout SECS         disconnect clients that send nothing for SECS once they're in; 0
+                                to never do so (default 0)
+    --read-timeout SECS         disconnect clients that take longer than SECS to finish sending
+                                a message they've started on, and HTTP API requests that take
+                                longer than SECS to arrive; 0 for no limit (default 60)
+    --write-timeout SECS        disconnect clients that take more than SECS to take anything
+                                we've sent them off our hands; 0 for no limit (default 60)
+    --capabilities LIST         comma-separated optional features to offer clients (default
+                                file-transfer,status,announcements,edits)
+    --metrics-addr ADDR         serve per-connection traffic stats for Prometheus on ADDR, e.g.
+                                127.0.0.1:9100 (default off)
+    --db-url URL                keep registered users and chat history in the SQLite database
+                                at URL, e.g. sqlite://chat.db (default: in memory, forgotten on
+                                exit)
+    --event-log FILE            record everything that changes the rooms in FILE, one line of
+                                JSON each, and get rooms' topics, settings and chat back from it
+                                on starting (default off)
+    --password-cost N           bcrypt cost for hashing new passwords, at least 12 (default 12)
+    --jwt-secret SECRET         sign login tokens with SECRET, so they stay good across restarts
+                                (default: a new random secret each run)
+    --token-lifetime SECS       how long login tokens are good for (default 86400)
+    --redis-url URL             run as one node of a cluster, sharing chat with the other nodes
+                                through the Redis at URL, e.g. redis://127.0.0.1/ (default: run
+                                alone)
+    --http-port PORT            also take chat over HTTP on PORT, for bots and integrations; needs
+                                --http-token (default off)
+    --http-token SECRET         require HTTP requests to carry SECRET as a bearer token
+    --irc-port PORT             also let IRC clients chat, on PORT (default off)
+    --xmpp-component HOST:PORT  bridge rooms to XMPP multi-user chat, as a component of the XMPP
+                                server taking component connections at HOST:PORT; needs
+                                --xmpp-domain and --xmpp-secret (default off)
+    --xmpp-domain DOMAIN        the component's domain, e.g. conference.example.com; each room is
+                                ROOM@DOMAIN
+    --xmpp-secret SECRET        the secret the component shares with the XMPP server
+    --discord-api URL           mirror a room to a Discord channel, through Discord's HTTP API at
+                                URL (plain http only, e.g. a TLS-terminating proxy for
+                                https://discord.com/api/v10); needs --discord-token,
+                                --discord-channel and --discord-room (default off)
+    --discord-token TOKEN       the Discord bot token to post and read messages with
+    --discord-channel ID        the Discord channel to mirror
+    --discord-room ROOM         the room to mirror it to
+    --slack-webhook URL         mirror a room to a Slack channel, posting to the Incoming Webhook
+                                at URL (plain http only, as for --webhooks) and taking Events API
+                                requests at /slack/events on the HTTP API; needs --http-port,
+                                --slack-signing-secret and --slack-room (default off)
+    --slack-signing-secret SECRET
+                                the secret Slack signs its requests with
+    --slack-room ROOM           the room to mirror the channel to
+    --webhooks FILE             POST room events to the webhooks listed in the TOML FILE, as
+                                [[webhook]] tables with a room, a url, and optionally the events
+                                (message, edit) to send; just messages if left out
+    --webhook-timeout-ms MS     how long each attempt at delivering to a webhook may take
+                                (default 5000)";
+
+// Server settings, filled in from the command line at startup. `Config::default()` gives the
+// settings used when no options are passed.
+pub struct Config {
+    // If set, clients must present this token in their `Handshake` or be turned away.
+    pub token: Option<String>,
+
+    // Clients presenting this token instead are operators, allowed to make announcements.
+    pub admin_token: Option<String>,
+
+    // Whether clients may log in without registering first; see `auth::sign_in`.
+    pub allow_guests: bool,
+
+    // Whether only operators may set rooms' topics, rather than anyone in the room.
+    pub restrict_topics: bool,
+
+    // Message size and rate rules for each room.
+    pub policies: Policies,
+
+    // Words and phrases chat messages are screened for, and what happens to messages containing
+    // them; see `Blocklist`.
+    pub blocked: Vec<String>,
+    pub block_mode: BlockMode,
+
+    // How many connections a single IP address may have open at once.
+    pub max_connections_per_ip: usize,
+
+    // How many failed logins an address may have before it's locked out for a while, and the
+    // file they're kept in to outlast restarts, if any; see `LoginThrottler`.
+    pub login_throttle: ThrottlePolicy,
+    pub login_throttle_file: Option<String>,
+
+    // Whether connections come through a load balancer that starts each one with a PROXY protocol
+    // header, which then stands in for the address it came from; see `proxy::read_header`.
+    pub proxy_protocol: bool,
+
+    // Largest file clients may offer, in bytes.
+    pub max_file_size: u64,
+
+    // How many malformed messages in a row a client can get away with. Each one is answered with
+    // an error; the one after that closes the connection.
+    pub max_bad_frames: u32,
+
+    // Whether messages with fields we don't know, most likely from a client on a newer version of
+    // the protocol, are reported to the client as such. Otherwise they're just malformed messages,
+    // like any others that don't decode.
+    pub strict_protocol: bool,
+
+    // The message of the day clients are greeted with, if any.
+    pub motd: Option<String>,
+
+    // How many recent chat messages to remember for replaying to resumed sessions.
+    pub history_len: usize,
+
+    // How long after a client disconnects it can still resume its session.
+    pub resume_grace: Duration,
+
+    // How long a client may take to send its `Handshake` after connecting, and after that, go
+    // without sending anything, before it's disconnected, if there are limits.
+    pub handshake_timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+
+    // How long a client may take over sending the rest of a message once it's started on one,
+    // and how long writing to it may be stuck (because it isn't reading what we've sent), before
+    // it's disconnected, if there are limits; see `Deadlines`. The read timeout also bounds how
+    // long an HTTP API request may take to arrive.
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+
+    // The optional protocol features the server offers; see `capability`.
+    pub capabilities: Vec<String>,
+
+    // Where to serve traffic stats for Prometheus, if anywhere.
+    pub metrics_addr: Option<SocketAddr>,
+
+    // The database registered users and chat history are kept in, if any; see `store::open` and
+    // `store::open_messages`.
+    pub db_url: Option<String>,
+
+    // The file every `Event` is recorded in, and replayed from at startup, if any; see
+    // `JsonlEventLog`.
+    pub event_log: Option<String>,
+
+    // How much work hashing a new password takes, as a bcrypt cost. Anything below
+    // `auth::MIN_PASSWORD_COST` is treated as that.
+    pub password_cost: u32,
+
+    // The key login tokens are signed with, if it should outlast the server; see `Tokens`.
+    pub jwt_secret: Option<String>,
+
+    // How long a login token is good for after it's issued, in seconds.
+    pub token_lifetime: u64,
+
+    // The Redis that cluster nodes share chat through, if the server is part of a cluster.
+    pub redis_url: Option<String>,
+
+    // A bus to share chat over instead of Redis, for clusters of servers in one process (see
+    // `LocalBus`). Not settable from the command line.
+    pub cluster: Option<SharedBus>,
+
+    // Where to serve the HTTP API, if anywhere, and the bearer token its requests must carry; see
+    // `api::serve`. The command line sets the port, always on all interfaces.
+    pub http_addr: Option<SocketAddr>,
+    pub http_token: Option<String>,
+
+    // Where to let IRC clients in, if anywhere; see `irc_gateway::serve`. As with the HTTP API,
+    // the command line sets the port, always on all interfaces.
+    pub irc_addr: Option<SocketAddr>,
+
+    // How to bridge rooms to XMPP, if at all; see `xmpp_gateway::connect`.
+    pub xmpp: Option<XmppConfig>,
+
+    // How to mirror a room to Discord, if at all; see `discord_bridge::connect`.
+    pub discord: Option<DiscordConfig>,
+
+    // How to mirror a room to Slack, if at all; see `slack_bridge`. Slack's requests come in
+    // through the HTTP API, so this needs an `http_addr`.
+    pub slack: Option<SlackConfig>,
+
+    // Where to send room events, and how long to give each attempt at delivering one; see
+    // `Webhooks`.
+    pub webhooks: WebhookRegistry,
+    pub webhook_timeout: Duration,
+
+    // Where the time comes from; see `Clock`. Not settable from the command line, which always
+    // gets the `SystemClock`.
+    pub clock: SharedClock,
+
+    // Where each connection's events go, if anywhere; see `Subscriber`. Likewise only for
+    // programs that run the server themselves.
+    pub subscriber: Option<SharedSubscriber>,
+
+    // Hooks to run as clients connect, log in and disconnect, if any; see `ConnectionLifecycle`.
+    // Likewise only for programs that run the server themselves.
+    pub lifecycle: Option<SharedLifecycle>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            token: None,
+            admin_token: None,
+            allow_guests: false,
+            restrict_topics: false,
+            policies: Policies::default(),
+            blocked: Vec::new(),
+            block_mode: BlockMode::Censor,
+            max_connections_per_ip: 16,
+            login_throttle: ThrottlePolicy::default(),
+            login_throttle_file: None,
+            proxy_protocol: false,
+            max_file_size: MAX_FILE_SIZE,
+            max_bad_frames: 3,
+            strict_protocol: false,
+            motd: None,
+            history_len: 100,
+            resume_grace: Duration::from_secs(30),
+            handshake_timeout: Some(Duration::from_secs(30)),
+            idle_timeout: None,
+            read_timeout: Some(Duration::from_secs(60)),
+            write_timeout: Some(Duration::from_secs(60)),
+            capabilities: capability::ALL.iter().map(|c| c.to_string()).collect(),
+            metrics_addr: None,
+            db_url: None,
+            event_log: None,
+            password_cost: bcrypt::DEFAULT_COST,
+            jwt_secret: None,
+            token_lifetime: 24 * 60 * 60,
+            redis_url: None,
+            cluster: None,
+            http_addr: None,
+            http_token: None,
+            irc_addr: None,
+            xmpp: None,
+            discord: None,
+            slack: None,
+            webhooks: WebhookRegistry::new(),
+            webhook_timeout: Duration::from_millis(5000),
+            clock: Arc::new(SystemClock),
+            subscriber: None,
+            lifecycle: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args() -> Config {
+        let mut config = Config::default();
+
+        let (mut xmpp_server, mut xmpp_domain, mut xmpp_secret) = (None, None, None);
+        let (mut discord_api, mut discord_token) = (None, None);
+        let (mut discord_channel, mut discord_room) = (None, None);
+        let (mut slack_webhook, mut slack_signing_secret, mut slack_room) = (None, None, None);
+
+        let mut room_policies = Vec::new();
+
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--token" => config.token = Some(value(&mut args)),
+                "--admin-token" => config.admin_token = Some(value(&mut args)),
+                "--allow-guests" => config.allow_guests = true,
+                "--restrict-topics" => config.restrict_topics = true,
+                "--max-body-len" => config.policies.default.max_body_len = parse(&mut args),
+                "--rate-limit" => config.policies.default.rate_per_sec = parse(&mut args),
+                "--max-room-members" => config.policies.default.max_members = parse(&mut args),
+                "--room-policy" => room_policies.push(value(&mut args)),
+                "--flood-warn-after" => config.policies.flood.warn_after = parse(&mut args),
+                "--flood-disconnect-after" => {
+                    config.policies.flood.disconnect_after = parse(&mut args)
+                }
+                "--flood-grace" => {
+                    config.policies.flood.grace = Duration::from_secs(parse(&mut args))
+                }
+                "--block" => config.blocked.push(value(&mut args)),
+                "--block-mode" => config.block_mode = parse(&mut args),
+                "--max-connections-per-ip" => config.max_connections_per_ip = parse(&mut args),
+                "--login-attempts" => config.login_throttle.max_failures = parse(&mut args),
+                "--login-window" => {
+                    config.login_throttle.window = Duration::from_secs(parse(&mut args))
+                }
+                "--login-lockout" => {
+                    config.login_throttle.lockout = Duration::from_secs(parse(&mut args))
+                }
+                "--login-max-lockout" => {
+                    config.login_throttle.max_lockout = Duration::from_secs(parse(&mut args))
+                }
+                "--login-throttle-file" => config.login_throttle_file = Some(value(&mut args)),
+                "--proxy-protocol" => config.proxy_protocol = true,
+                "--max-file-size" => config.max_file_size = parse(&mut args),
+                "--max-bad-frames" => config.max_bad_frames = parse(&mut args),
+                "--strict-protocol" => config.strict_protocol = true,
+                "--motd" => config.motd = Some(value(&mut args)).filter(|motd| !motd.is_empty()),
+                "--history" => config.history_len = parse(&mut args),
+                "--resume-grace" => config.resume_grace = Duration::from_secs(parse(&mut args)),
+                "--handshake-timeout" => {
+                    config.handshake_timeout = match parse(&mut args) {
+                        0 => None,
+                        secs => Some(Duration::from_secs(secs)),
+                    }
+                }
+                "--idle-timeout" => {
+                    config.idle_timeout = match parse(&mut args) {
+                        0 => None,
+                        secs => Some(Duration::from_secs(secs)),
+                    }
+                }
+                "--read-timeout" => {
+                    config.read_timeout = match parse(&mut args) {
+                        0 => None,
+                        secs => Some(Duration::from_secs(secs)),
+                    }
+                }
+                "--write-timeout" => {
+                    config.write_timeout = match parse(&mut args) {
+                        0 => None,
+                        secs => Some(Duration::from_secs(secs)),
+                    }
+                }
+                "--capabilities" => config.capabilities = capabilities(&value(&mut args)),
+                "--metrics-addr" => config.metrics_addr = Some(parse(&mut args)),
+                "--db-url" => config.db_url = Some(value(&mut args)),
+                "--event-log" => config.event_log = Some(value(&mut args)),
+                "--jwt-secret" => config.jwt_secret = Some(value(&mut args)),
+                "--token-lifetime" => config.token_lifetime = parse(&mut args),
+                "--redis-url" => config.redis_url = Some(value(&mut args)),
+                "--http-port" => {
+                    let port: u16 = parse(&mut args);
+                    config.http_addr = Some(SocketAddr::from(([0, 0, 0, 0], port)));
+                }
+                "--http-token" => config.http_token = Some(value(&mut args)),
+                "--irc-port" => {
+                    let port: u16 = parse(&mut args);
+                    config.irc_addr = Some(SocketAddr::from(([0, 0, 0, 0], port)));
+                }
+                "--xmpp-component" => xmpp_server = Some(value(&mut args)),
+                "--xmpp-domain" => xmpp_domain = Some(value(&mut args)),
+                "--xmpp-secret" => xmpp_secret = Some(value(&mut args)),
+                "--discord-api" => discord_api = Some(value(&mut args)),
+                "--discord-token" => discord_token = Some(value(&mut args)),
+                "--discord-channel" => discord_channel = Some(value(&mut args)),
+                "--discord-room" => discord_room = Some(value(&mut args)),
+                "--slack-webhook" => slack_webhook = Some(value(&mut args)),
+                "--slack-signing-secret" => slack_signing_secret = Some(value(&mut args)),
+                "--slack-room" => slack_room = Some(value(&mut args)),
+                "--webhooks" => config.webhooks = webhooks(&value(&mut args)),
+                "--webhook-timeout-ms" => {
+                    config.webhook_timeout = Duration::from_millis(parse(&mut args))
+                }
+                "--password-cost" => {
+                    config.password_cost = parse(&mut args);
+                    if config.password_cost < auth::MIN_PASSWORD_COST ||
+                       config.password_cost > 31 {
+                        usage();
+                    }
+                }
+                _ => usage(),
+            }
+        }
+        // Rooms' policies can leave out their member limits, to have the default's, whichever
+        // order the options came in.
+        for spec in room_policies {
+            let (room, policy) = room_policy(&spec, &config.policies.default);
+            config.policies.rooms.insert(room, policy);
+        }
+        if config.http_addr.is_some() && config.http_token.is_none() {
+            usage();
+        }
+        config.xmpp = match (xmpp_server, xmpp_domain, xmpp_secret) {
+            (Some(server), Some(domain), Some(secret)) => {
+                Some(XmppConfig {
+                    server: server,
+                    domain: domain,
+                    secret: secret,
+                })
+            }
+            (None, None, None) => None,
+            _ => usage(),
+        };
+        config.discord = match (discord_api, discord_token, discord_channel, discord_room) {
+            (Some(api), Some(token), Some(channel_id), Some(room)) => {
+                Some(DiscordConfig {
+                    api: api,
+                    token: token,
+                    channel_id: channel_id,
+                    room: room,
+                })
+            }
+            (None, None, None, None) => None,
+            _ => usage(),
+        };
+        config.slack = match (slack_webhook, slack_signing_secret, slack_room) {
+            (Some(webhook), Some(signing_secret), Some(room)) if config.http_addr.is_some() => {
+                Some(SlackConfig {
+                    webhook: webhook,
+                    signing_secret: signing_secret,
+                    room: room,
+                })
+            }
+            (None, None, None) => None,
+            _ => usage(),
+        };
+
+        config
+    }
+}
+
+// Pull the value for the option we just saw off of `args`, bailing out if it's missing.
+fn value<I: Iterator<Item = String>>(args: &mut I) -> String {
+    args.next().unwrap_or_else(|| usage())
+}
+
+// Like `value`, but also parse it.
+fn parse<T: FromStr, I: Iterator<Item = String>>(args: &mut I) -> T {
+    value(args).parse().unwrap_or_else(|_| usage())
+}
+
+// Parse a `ROOM:BYTES:N` or `ROOM:BYTES:N:MEMBERS` room policy, with the `default` policy's
+// member limit if it hasn't got its own.
+fn room_policy(spec: &str, default: &RoomPolicy) -> (String, RoomPolicy) {
+    let parts: Vec<&str> = spec.split(':').collect();
+    if !(parts.len() == 3 || parts.len() == 4) || parts[0].is_empty() {
+        usage();
+    }
+    let policy = RoomPolicy {
+        max_body_len: parts[1].parse().unwrap_or_else(|_| usage()),
+        rate_per_sec: parts[2].parse().unwrap_or_else(|_| usage()),
+        max_members: parts.get(3)
+            .map_or(default.max_members, |members| members.parse().unwrap_or_else(|_| usage())),
+    };
+    (parts[0].to_string(), policy)
+}
+
+// Parse a comma-separated capability list, refusing any we don't know.
+fn capabilities(list: &str) -> Vec<String> {
+    let capabilities = list.split(',')
+        .filter(|c| !c.is_empty())
+        .map(|c| c.to_string())
+        .collect::<Vec<_>>();
+    if capabilities.iter().any(|c| !capability::ALL.contains(&c.as_str())) {
+        usage();
+    }
+    capabilities
+}
+
+// Load the webhooks listed in the file at `path`, bailing out if that doesn't work.
+fn webhooks(path: &str) -> WebhookRegistry {
+    let registry = fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|text| WebhookRegistry::from_toml(&text));
+    registry.unwrap_or_else(|err| {
+        println!("couldn't load webhooks from {}: {}", path, err);
+        process::exit(1);
+    })
+}
+
+fn usage() -> ! {
+    println!("{}", USAGE);
+    process::exit(1);
+}
@@ -0,0 +1,43 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+
+// A bag of arbitrary data attached to a single connection, keyed by type (much like
+// `http::Extensions`). This lets code built on top of the server keep its own per-client state
+// without having to add fields to `Client`: each extension defines a type for its state and
+// stores one value of it here.
+//
+// Nothing in the server itself reads or writes metadata, hence the `allow(dead_code)`.
+#[derive(Default)]
+pub struct ConnectionMetadata {
+    map: HashMap<TypeId, Box<Any + Send>>,
+}
+
+#[allow(dead_code)]
+impl ConnectionMetadata {
+    pub fn new() -> ConnectionMetadata {
+        ConnectionMetadata::default()
+    }
+
+    // Store `value`, returning the value of the same type that was stored previously, if any.
+    pub fn insert<T: 'static + Send>(&mut self, value: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|old| old.downcast().ok())
+            .map(|old| *old)
+    }
+
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.map.get(&TypeId::of::<T>()).and_then(|value| value.downcast_ref())
+    }
+
+    pub fn get_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.map.get_mut(&TypeId::of::<T>()).and_then(|value| value.downcast_mut())
+    }
+
+    pub fn remove<T: 'static>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|old| old.downcast().ok())
+            .map(|old| *old)
+    }
+}
@@ -0,0 +1,164 @@
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::{Async, Future};
+use tokio_core::io::Io;
+use tokio_core::reactor::{Handle, Timeout};
+
+// A client's socket, with limits on how long reading from it and writing to it may be stuck
+// before the connection is given up on, with a `TimedOut` error.
+//
+// Reads are only held to `read_timeout` in the middle of a frame: a client may stay quiet as long
+// as it likes between messages (that's `--idle-timeout`'s business), but once it's started on one
+// it has to finish within the limit, or it's just tying up the connection. Frames are spotted the
+// way every codec here frames them, by their two-byte length prefix.
+//
+// Writes are held to `write_timeout` whenever the socket won't take any more of what we have for
+// it, which happens when the client stops reading.
+pub struct Deadlines<T> {
+    io: T,
+    addr: SocketAddr,
+    handle: Handle,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+
+    // Where we are in the current frame from the client, and the timers running, if any.
+    frame: FrameProgress,
+    reading: Option<Timeout>,
+    writing: Option<Timeout>,
+}
+
+impl<T> Deadlines<T> {
+    pub fn new(io: T,
+               addr: SocketAddr,
+               read_timeout: Option<Duration>,
+               write_timeout: Option<Duration>,
+               handle: &Handle)
+               -> Deadlines<T> {
+        Deadlines {
+            io: io,
+            addr: addr,
+            handle: handle.clone(),
+            read_timeout: read_timeout,
+            write_timeout: write_timeout,
+            frame: FrameProgress::default(),
+            reading: None,
+            writing: None,
+        }
+    }
+}
+
+// Whether `timer`, started with `timeout` if it hasn't been already, has fired. If it hasn't, the
+// current task is woken when it does. `what` is what's being timed, for the error.
+fn expired(timer: &mut Option<Timeout>,
+           timeout: Duration,
+           handle: &Handle,
+           addr: &SocketAddr,
+           what: &str)
+           -> io::Result<()> {
+    if timer.is_none() {
+        *timer = Some(Timeout::new(timeout, handle)?);
+    }
+    match timer.as_mut().expect("the timer was just started").poll()? {
+        Async::Ready(()) => {
+            println!("TIMED OUT {:?}: {} took longer than {:?}", addr, what, timeout);
+            Err(io::Error::new(io::ErrorKind::TimedOut, format!("{} timed out", what)))
+        }
+        Async::NotReady => Ok(()),
+    }
+}
+
+impl<T: Read> Read for Deadlines<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.io.read(buf) {
+            Ok(n) => {
+                // Every frame gets the whole of `read_timeout` to itself.
+                if self.frame.advance(&buf[..n]) || !self.frame.is_partial() {
+                    self.reading = None;
+                }
+                Ok(n)
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                if let (true, Some(timeout)) = (self.frame.is_partial(), self.read_timeout) {
+                    expired(&mut self.reading, timeout, &self.handle, &self.addr, "a read")?;
+                }
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl<T: Write> Write for Deadlines<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.io.write(buf) {
+            Ok(n) => {
+                self.writing = None;
+                Ok(n)
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                if let Some(timeout) = self.write_timeout {
+                    expired(&mut self.writing, timeout, &self.handle, &self.addr, "a write")?;
+                }
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<T: Io> Io for Deadlines<T> {
+    fn poll_read(&mut self) -> Async<()> {
+        self.io.poll_read()
+    }
+
+    fn poll_write(&mut self) -> Async<()> {
+        self.io.poll_write()
+    }
+}
+
+// How far into a frame (a big-endian u16 length, then that many bytes) the bytes read so far
+// have got.
+#[derive(Default)]
+struct FrameProgress {
+    // The first byte of the length, if we've only got that far.
+    half_header: Option<u8>,
+
+    // How many bytes of the payload are still to come.
+    remaining: usize,
+}
+
+impl FrameProgress {
+    // Take in `bytes`, the next read, returning whether any frames were finished by it.
+    fn advance(&mut self, mut bytes: &[u8]) -> bool {
+        let mut finished = false;
+        while !bytes.is_empty() {
+            if self.remaining > 0 {
+                let n = cmp::min(self.remaining, bytes.len());
+                self.remaining -= n;
+                bytes = &bytes[n..];
+                finished |= self.remaining == 0;
+                continue;
+            }
+            match self.half_header.take() {
+                None => self.half_header = Some(bytes[0]),
+                Some(high) => {
+                    self.remaining = (high as usize) << 8 | bytes[0] as usize;
+                    finished |= self.remaining == 0;
+                }
+            }
+            bytes = &bytes[1..];
+        }
+        finished
+    }
+
+    fn is_partial(&self) -> bool {
+        self.half_header.is_some() || self.remaining > 0
+    }
+}
@@ -0,0 +1,291 @@
+use std::cell::Cell;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::{future, stream, Future, Stream};
+use futures::sync::mpsc;
+use serde_json::{self, Value};
+use serde_json::builder::ObjectBuilder;
+use tokio_core::io::{read_to_end, write_all};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Interval, Timeout};
+use tokio_chat_common::ClientMessage;
+use url::Url;
+
+use config::Config;
+use connection::ConnectionMetadata;
+use middleware::{self, ConnectionContext, MessageMiddleware, MiddlewareAction};
+use {Chat, IoFuture};
+
+// What Discord users are called in the room: `discord/USERNAME`. That's also how we know not to
+// send what they say back to Discord.
+const NAME_PREFIX: &str = "discord/";
+
+// How often we ask Discord for new messages, and how long we give each request.
+const POLL_INTERVAL_MS: u64 = 1000;
+const REQUEST_TIMEOUT_MS: u64 = 10_000;
+
+// How to mirror a room to a Discord channel; see `connect`.
+#[derive(Debug, Clone)]
+pub struct DiscordConfig {
+    // Where Discord's HTTP API is. Only plain `http` URLs are supported, so this is normally a
+    // TLS-terminating proxy in front of `https://discord.com/api/v10`.
+    pub api: String,
+
+    // The bot token to authenticate with, the channel to mirror, and the room to mirror it to.
+    pub token: String,
+    pub channel_id: String,
+    pub room: String,
+}
+
+// Where what's said in the mirrored room goes on its way to Discord. `Chat::say` passes everything
+// said anywhere through here; what's for the mirrored room, and didn't come from Discord in the
+// first place, is queued up for `connect` to post.
+pub struct DiscordBridge {
+    room: String,
+
+    // Formatted messages for the channel; see `connect`.
+    outbox: mpsc::UnboundedSender<String>,
+}
+
+impl DiscordBridge {
+    pub fn new(room: String, outbox: mpsc::UnboundedSender<String>) -> DiscordBridge {
+        DiscordBridge {
+            room: room,
+            outbox: outbox,
+        }
+    }
+
+    // Post `body`, said by `from` in `room`, to the channel, as `**from**: body`.
+    pub fn relay(&self, room: &str, from: &str, body: &str) {
+        if room != self.room || from.starts_with(NAME_PREFIX) {
+            return;
+        }
+        // If the bridge is down, there's no one to tell.
+        let _ = self.outbox.unbounded_send(format!("**{}**: {}", from, body));
+    }
+}
+
+// Mirror `discord.room` to the channel `discord.channel_id`, posting what `outbox` carries (see
+// `DiscordBridge`) and polling the channel for new messages, which are said in the room as
+// `discord/USERNAME`. Our own posts are left out, and so is anything said in the channel before we
+// started. What Discord users say goes through the middleware and the room's length limit, as a
+// post to the HTTP API would; there's no one to tell when it's refused, so it's just dropped.
+//
+// This talks to Discord over its HTTP API alone, since a connection to its gateway is more than
+// this server has the means for. The returned future fails if we can't get started; after that, a
+// request that fails is logged and skipped, and the bridge carries on.
+pub fn connect(discord: &DiscordConfig,
+               chat: Chat,
+               config: Rc<Config>,
+               middleware: Rc<Vec<Box<MessageMiddleware>>>,
+               outbox: mpsc::UnboundedReceiver<String>)
+               -> IoFuture<()> {
+    let api = match Api::new(discord, chat.handle.clone()) {
+        Ok(api) => Rc::new(api),
+        Err(err) => return Box::new(future::err(err)),
+    };
+    let messages = format!("channels/{}/messages", discord.channel_id);
+
+    // We need to know who we are to leave out our own posts, and where the channel's up to so we
+    // only mirror what's said from now on.
+    let me = api.request("GET", "users/@me", None).map(|user| string(&user, "id"));
+    let latest = api.request("GET", &format!("{}?limit=1", messages), None).map(|latest| {
+        latest.as_array().and_then(|latest| latest.first()).map_or(0, |msg| snowflake(msg))
+    });
+
+    let room = discord.room.clone();
+    let channel_id = discord.channel_id.clone();
+    Box::new(me.join(latest).and_then(move |(me, latest)| {
+        println!("DISCORD BRIDGE mirroring {} to channel {}", room, channel_id);
+        let interval = match Interval::new(Duration::from_millis(POLL_INTERVAL_MS), &chat.handle) {
+            Ok(interval) => interval,
+            Err(err) => return future::Either::A(future::err(err)),
+        };
+        let bridge = Rc::new(Bridge {
+            chat: chat,
+            config: config,
+            middleware: middleware,
+            api: api.clone(),
+            me: me,
+            room: room,
+            messages: messages.clone(),
+            last_seen: Cell::new(latest),
+        });
+
+        let poller = interval.for_each(move |()| {
+            poll(bridge.clone()).or_else(|err| {
+                println!("DISCORD poll failed: {}", err);
+                Ok(())
+            })
+        });
+        let poster = outbox.map_err(|()| unreachable!("rx can't fail"))
+            .for_each(move |content| {
+                let post = ObjectBuilder::new().insert("content", content).build();
+                api.request("POST", &messages, Some(post)).then(|result| {
+                    if let Err(err) = result {
+                        println!("DISCORD post failed: {}", err);
+                    }
+                    Ok(())
+                })
+            });
+        future::Either::B(poller.select(poster).map(|_| ()).map_err(|(err, _)| err))
+    }))
+}
+
+struct Bridge {
+    chat: Chat,
+    config: Rc<Config>,
+    middleware: Rc<Vec<Box<MessageMiddleware>>>,
+    api: Rc<Api>,
+
+    // Our own user id, and the room we mirror the channel to.
+    me: String,
+    room: String,
+
+    // The path of the channel's messages, and the id of the newest one we've seen.
+    messages: String,
+    last_seen: Cell<u64>,
+}
+
+// Ask Discord for what's been said in the channel since we last looked, and say it in the room.
+fn poll(bridge: Rc<Bridge>) -> IoFuture<()> {
+    let path = format!("{}?after={}", bridge.messages, bridge.last_seen.get());
+    Box::new(bridge.api.request("GET", &path, None).and_then(move |messages| {
+        // Discord lists the newest first, but we want to say them in the order they were said.
+        let mut messages = messages.as_array().cloned().unwrap_or_default();
+        messages.sort_by_key(snowflake);
+        if let Some(newest) = messages.last() {
+            bridge.last_seen.set(snowflake(newest));
+        }
+        stream::iter(messages.into_iter().map(Ok)).for_each(move |msg| {
+            let author = msg.find("author");
+            let author_id = author.map_or(String::new(), |author| string(author, "id"));
+            let username = author.map_or(String::new(), |author| string(author, "username"));
+            let content = string(&msg, "content");
+            if author_id == bridge.me || content.is_empty() {
+                return Box::new(future::ok(())) as IoFuture<()>;
+            }
+            say(&bridge, &format!("{}{}", NAME_PREFIX, username), content)
+        })
+    }))
+}
+
+// Say `body` in the mirrored room as `name`, if the middleware and the room's policy let us.
+fn say(bridge: &Bridge, name: &str, body: String) -> IoFuture<()> {
+    let room = bridge.room.as_str();
+    if body.len() > bridge.config.policies.for_room(room).max_body_len {
+        return Box::new(future::ok(()));
+    }
+
+    let mut msg = ClientMessage::Message(body);
+    let metadata = ConnectionMetadata::new();
+    let ctx = ConnectionContext {
+        addr: bridge.api.addr,
+        name: name,
+        room: room,
+        metadata: &metadata,
+    };
+    match middleware::run(&bridge.middleware, &mut msg, &ctx) {
+        MiddlewareAction::Allow | MiddlewareAction::Modify => {}
+        MiddlewareAction::Drop | MiddlewareAction::Error(_) => return Box::new(future::ok(())),
+    }
+    match msg {
+        ClientMessage::Message(body) => Box::new(bridge.chat.say(room, name, body).map(|_| ())),
+        _ => Box::new(future::ok(())),
+    }
+}
+
+// The string field `name` of `value`, or an empty one if it hasn't got one.
+fn string(value: &Value, name: &str) -> String {
+    value.find(name).and_then(Value::as_str).unwrap_or("").to_string()
+}
+
+// The id of a Discord message. Ids are numbers in strings, and later messages have bigger ones.
+fn snowflake(msg: &Value) -> u64 {
+    string(msg, "id").parse().unwrap_or(0)
+}
+
+// Discord's HTTP API, as reached through `DiscordConfig::api`.
+struct Api {
+    // The API's address, which also stands in for any one Discord user's in the middleware's
+    // `ConnectionContext`.
+    addr: SocketAddr,
+    host: String,
+
+    // What the API's paths start with, without a trailing `/`.
+    base_path: String,
+    token: String,
+    handle: Handle,
+}
+
+impl Api {
+    fn new(discord: &DiscordConfig, handle: Handle) -> io::Result<Api> {
+        let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidInput, reason);
+        let url = Url::parse(&discord.api)
+            .map_err(|err| invalid(format!("bad Discord API URL {}: {}", discord.api, err)))?;
+        let host = match url.host_str() {
+            Some(host) if url.scheme() == "http" => host.to_string(),
+            _ => return Err(invalid(format!("the Discord API URL must be http://HOST/..., not {}",
+                                            url))),
+        };
+        let port = url.port_or_known_default().unwrap_or(80);
+        let no_addresses = || {
+            io::Error::new(io::ErrorKind::NotFound, "Discord API has no addresses")
+        };
+        let addr = (host.as_str(), port).to_socket_addrs()?.next().ok_or_else(no_addresses)?;
+        Ok(Api {
+            addr: addr,
+            host: host,
+            base_path: url.path().trim_end_matches('/').to_string(),
+            token: discord.token.clone(),
+            handle: handle,
+        })
+    }
+
+    // Make a `method` request of `path` (relative to the API's URL), sending `body` if there is
+    // one, and return the JSON Discord answers with. Anything but a 2xx status is an error.
+    fn request(&self, method: &str, path: &str, body: Option<Value>) -> IoFuture<Value> {
+        let body = body.map_or(String::new(), |body| {
+            serde_json::to_string(&body).expect("JSON values always serialize")
+        });
+        // HTTP/1.0 keeps chunked encoding out of the answer.
+        let request = format!("{} {}/{} HTTP/1.0\r\n\
+                               Host: {}\r\n\
+                               Authorization: Bot {}\r\n\
+                               Content-Type: application/json\r\n\
+                               Content-Length: {}\r\n\
+                               \r\n\
+                               {}",
+                              method,
+                              self.base_path,
+                              path,
+                              self.host,
+                              self.token,
+                              body.len(),
+                              body);
+
+        let exchange = TcpStream::connect(&self.addr, &self.handle)
+            .and_then(move |socket| write_all(socket, request.into_bytes()))
+            .and_then(|(socket, _)| read_to_end(socket, Vec::new()))
+            .and_then(|(_, response)| {
+                let response = String::from_utf8_lossy(&response);
+                let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+                let status_line = head.lines().next().unwrap_or("no response");
+                let status = status_line.split(' ').nth(1).unwrap_or("");
+                if !status.starts_with('2') || status.len() != 3 {
+                    return Err(io::Error::new(io::ErrorKind::Other, status_line.to_string()));
+                }
+                serde_json::from_str(body)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
+            });
+
+        let timed_out = future::result(Timeout::new(Duration::from_millis(REQUEST_TIMEOUT_MS),
+                                                    &self.handle))
+            .flatten()
+            .and_then(|()| Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")));
+        Box::new(exchange.select(timed_out).map(|(value, _)| value).map_err(|(err, _)| err))
+    }
+}
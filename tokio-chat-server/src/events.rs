@@ -0,0 +1,92 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use futures::{future, stream, Stream};
+use futures_cpupool::CpuPool;
+use serde_json;
+use tokio_chat_common::{Event, EventId};
+
+use store::StoreFuture;
+
+// Somewhere to keep `Event`s, in order, for good: they're only ever added to, never changed.
+pub trait EventLog {
+    // Add `event` to the end of the log.
+    fn append(&self, event: &Event) -> StoreFuture<EventId>;
+
+    // Every event in the log from `from` on, oldest first.
+    fn replay(&self, from: EventId) -> Box<Stream<Item = Event, Error = io::Error>>;
+}
+
+// Events kept in a file, as one line of JSON each. Writing to the file blocks, so lines are
+// written out on a thread of their own rather than on the event loop, one at a time and in the
+// order they were appended; an append resolves once its line is in the file.
+pub struct JsonlEventLog {
+    path: PathBuf,
+    // The file, and the id the next event appended to it gets.
+    file: Arc<Mutex<(File, EventId)>>,
+    pool: CpuPool,
+}
+
+impl JsonlEventLog {
+    // Open (creating if need be) the log at `path`. A last line that was only partly written, by a
+    // server that stopped in the middle of it, is dropped.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<JsonlEventLog> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(&path)?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        let complete = contents.iter().rposition(|&b| b == b'\n').map_or(0, |end| end + 1);
+        if complete < contents.len() {
+            println!("EVENT LOG dropping {} bytes of a partly written event",
+                     contents.len() - complete);
+            file.set_len(complete as u64)?;
+            file.seek(SeekFrom::End(0))?;
+        }
+        let lines = contents[..complete].iter().filter(|&&b| b == b'\n').count();
+        Ok(JsonlEventLog {
+            path: path,
+            file: Arc::new(Mutex::new((file, lines as EventId))),
+            pool: CpuPool::new(1),
+        })
+    }
+}
+
+impl EventLog for JsonlEventLog {
+    fn append(&self, event: &Event) -> StoreFuture<EventId> {
+        let mut line = match serde_json::to_vec(event) {
+            Ok(line) => line,
+            Err(err) => {
+                return Box::new(future::err(io::Error::new(io::ErrorKind::InvalidData, err)))
+            }
+        };
+        line.push(b'\n');
+        let file = self.file.clone();
+        Box::new(self.pool.spawn_fn(move || {
+            let mut file = file.lock().expect("an append panicked while holding the event log");
+            let (ref mut file, ref mut next_id) = *file;
+            file.write_all(&line)?;
+            let id = *next_id;
+            *next_id += 1;
+            Ok(id)
+        }))
+    }
+
+    fn replay(&self, from: EventId) -> Box<Stream<Item = Event, Error = io::Error>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) => return Box::new(stream::once(Err(err))),
+        };
+        let lines = BufReader::new(file).lines().skip(from as usize);
+        let events = lines.enumerate().map(move |(i, line)| {
+            line.and_then(|line| {
+                serde_json::from_str(&line).map_err(|err| {
+                    let msg = format!("event {}: {}", from + i as EventId, err);
+                    io::Error::new(io::ErrorKind::InvalidData, msg)
+                })
+            })
+        });
+        Box::new(stream::iter(events))
+    }
+}
@@ -0,0 +1,642 @@
+use std::cell::RefCell;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+use futures::{future, stream, Future, Sink, Stream};
+use futures::future::Loop;
+use futures::sync::mpsc;
+use futures_cpupool::CpuPool;
+use tokio_core::io::{Codec, EasyBuf, Framed, Io};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_chat_common::{capability, validation, ClientMessage, ErrorCode, Handshake,
+                        ServerMessage, DEFAULT_ROOM};
+
+use auth::{self, SignIn};
+use config::Config;
+use limit::IpLimits;
+use middleware::{MessageMiddleware, MiddlewareAction};
+use priority::Prioritized;
+use store::{self, UserStore};
+use throttle::{self, LoginThrottler};
+use {follow_rooms, Chat, Client, IoFuture};
+
+// What the gateway calls itself, as the source of its replies and the host of everyone's mask.
+const SERVER_NAME: &str = "tokio-chat";
+
+// Lines longer than this (RFC 1459 allows 512 bytes, line ending included) are refused.
+const MAX_LINE_LEN: usize = 512;
+
+// How many names go in each `RPL_NAMREPLY`.
+const NAMES_PER_REPLY: usize = 20;
+
+type IrcSocket = Framed<TcpStream, IrcCodec>;
+
+// The messages the rest of the server sends one IRC client; see `Client`.
+type Outbox = Prioritized<mpsc::Receiver<ServerMessage>, mpsc::Receiver<ServerMessage>>;
+
+// Let IRC clients (irssi, weechat and the like) chat alongside native ones, on `listener`. An IRC
+// client is a client like any other once it's registered: it's in one room at a time, which it
+// sees as the channel `#ROOM`, it's subject to the same room policies, middleware, connection
+// limits and login lockouts, and it logs in against the same user store. Only a subset of RFC
+// 1459 is understood:
+//
+//     PASS, NICK, USER  registration; `PASS` is a registered user's password, or, on a server
+//                       with a `--token` (or for an operator), `TOKEN:PASSWORD` (`TOKEN:` for a
+//                       guest)
+//     JOIN #ROOM        move to ROOM (leaving the channel we were in); `JOIN 0` goes back to the
+//                       `DEFAULT_ROOM`
+//     PART #ROOM        likewise back to the `DEFAULT_ROOM`
+//     PRIVMSG #ROOM     say something in ROOM, which must be the channel we're in; there are no
+//                       private messages
+//     NAMES             who's in our channel
+//     PING, PONG, QUIT  as usual
+//
+// `WHO` and `MODE` get empty answers, to keep clients that ask happy. Chat, joins, parts and
+// disconnects are relayed as `PRIVMSG`, `JOIN`, `PART` and `QUIT`; errors and operators'
+// announcements come as `NOTICE`s from the server.
+pub fn serve(listener: TcpListener,
+             chat: Chat,
+             config: Rc<Config>,
+             middleware: Rc<Vec<Box<MessageMiddleware>>>,
+             users: Rc<UserStore>,
+             hasher: CpuPool,
+             limits: Rc<RefCell<IpLimits>>,
+             throttler: Rc<RefCell<LoginThrottler>>)
+             -> IoFuture<()> {
+    let gateway = Rc::new(Gateway {
+        chat: chat,
+        config: config,
+        middleware: middleware,
+        users: users,
+        hasher: hasher,
+        throttler: throttler,
+    });
+    Box::new(listener.incoming().for_each(move |(socket, addr)| {
+        let handle = gateway.chat.handle.clone();
+        if !limits.borrow_mut().acquire(addr.ip()) {
+            println!("REJECTED IRC {:?}: too many connections", addr);
+            let error = "ERROR :Closing link: too many connections from your address".to_string();
+            handle.spawn(socket.framed(IrcCodec).send(error).then(|_| Ok(())));
+            return Ok(());
+        }
+
+        let limits = limits.clone();
+        let gateway_inner = gateway.clone();
+        let connection = register(socket.framed(IrcCodec))
+            .and_then({
+                let gateway = gateway.clone();
+                move |(socket, registration)| gateway.sign_in(addr, socket, registration)
+            })
+            .and_then(move |(socket, nick, rx)| converse(gateway_inner, addr, socket, nick, rx));
+
+        let gateway = gateway.clone();
+        handle.spawn(connection.then(move |r| {
+            println!("IRC DISCONNECTED from {:?} with result {:?}", addr, r);
+            limits.borrow_mut().release(addr.ip());
+
+            // As with native clients, the rest are told, and the user is marked as last seen now.
+            // (There's no session to come back to, though.)
+            let chat = &gateway.chat;
+            let msg = chat.clients.remove(&addr).map(|client| {
+                let seen = store::seen(gateway.users.clone(),
+                                       &client.name,
+                                       chat.clock.unix_time());
+                chat.handle.spawn(seen.map_err(|err| println!("STORE failed: {}", err)));
+                ServerMessage::UserDisconnected(client.name)
+            });
+            follow_rooms(&chat.subscriptions, &chat.clients, &chat.handle);
+            let clients = chat.clients.clone();
+            stream::iter(msg.map(Ok)).fold((), move |(), m| clients.broadcast(m))
+        }));
+        Ok(())
+    }))
+}
+
+struct Gateway {
+    chat: Chat,
+    config: Rc<Config>,
+    middleware: Rc<Vec<Box<MessageMiddleware>>>,
+    users: Rc<UserStore>,
+    hasher: CpuPool,
+    throttler: Rc<RefCell<LoginThrottler>>,
+}
+
+impl Gateway {
+    // Log in the client at `addr` as its `registration` asks, the way `serve` logs in native
+    // clients. Once it's in and has been welcomed, the future resolves to the socket, the
+    // client's nick, and where to find what the rest of the server sends it.
+    fn sign_in(&self,
+               addr: SocketAddr,
+               socket: IrcSocket,
+               registration: Registration)
+               -> IoFuture<(IrcSocket, String, Outbox)> {
+        let nick = registration.nick.expect("registration needs a nick");
+        let mut handshake = Handshake::new(nick.clone());
+        match registration.password {
+            Some(ref pass) if pass.contains(':') => {
+                let (token, password) = pass.split_once(':').expect("pass contains a colon");
+                handshake.token = Some(token.to_string()).filter(|token| !token.is_empty());
+                handshake.password = Some(password.to_string()).filter(|pw| !pw.is_empty());
+            }
+            Some(ref pass) => handshake.password = Some(pass.clone()),
+            None => {}
+        }
+
+        let locked_out = self.throttler.borrow().locked_out(addr.ip());
+        if let Some(retry_after) = locked_out {
+            let reason = throttle::too_many_attempts(retry_after);
+            println!("REJECTED IRC {:?} with name {}: {}", addr, nick, reason);
+            return Box::new(socket.send(format!("ERROR :Closing link: {}", reason))
+                .and_then(|_| Err(io::Error::new(io::ErrorKind::PermissionDenied, "locked out"))));
+        }
+
+        let config = &self.config;
+        let admin = auth::is_admin(config.admin_token.as_deref(), &handshake);
+        let signed_in = if admin || auth::authorized(config.token.as_deref(), &handshake) {
+            auth::sign_in(self.users.clone(),
+                          self.hasher.clone(),
+                          &handshake,
+                          admin,
+                          config.allow_guests,
+                          self.chat.clock.unix_time())
+        } else {
+            Box::new(future::ok(SignIn::InvalidToken))
+        };
+
+        let clients = self.chat.clients.clone();
+        let subscriptions = self.chat.subscriptions.clone();
+        let handle = self.chat.handle.clone();
+        let clock = self.chat.clock.clone();
+        let capabilities = capability::negotiate(&config.capabilities,
+                                                 &[capability::ANNOUNCEMENTS,
+                                                   capability::TOPICS]);
+        let motd = config.motd.clone();
+        let throttler = self.throttler.clone();
+        Box::new(signed_in.and_then(move |signed_in| -> IoFuture<_> {
+            let admin = match signed_in {
+                SignIn::Admitted { admin } => {
+                    throttler.borrow_mut().succeeded(addr.ip());
+                    admin
+                }
+                rejected => {
+                    if rejected != SignIn::GuestsNotAllowed {
+                        throttler.borrow_mut().failed(addr.ip());
+                    }
+                    let reason = match rejected {
+                        SignIn::WrongPassword => "incorrect name or password",
+                        SignIn::GuestsNotAllowed => "this server doesn't allow guests",
+                        _ => "missing or incorrect token",
+                    };
+                    println!("REJECTED IRC {:?} with name {}: {}", addr, nick, reason);
+                    let replies = vec![numeric(464, &nick, &format!(":{}", reason)),
+                                       format!("ERROR :Closing link: {}", reason)];
+                    let replies = stream::iter(replies.into_iter().map(Ok::<_, io::Error>));
+                    return Box::new(socket.send_all(replies)
+                        .and_then(move |_| {
+                            Err(io::Error::new(io::ErrorKind::PermissionDenied, reason))
+                        }));
+                }
+            };
+            println!("IRC CONNECTED from {:?} with name {}", addr, nick);
+
+            let version = format!("{} {} o o", SERVER_NAME, env!("CARGO_PKG_VERSION"));
+            let mut welcome = vec![numeric(1, &nick, &format!(":Welcome to the chat, {}", nick)),
+                                   numeric(2, &nick, &format!(":Your host is {}", SERVER_NAME)),
+                                   numeric(4, &nick, &version)];
+            match motd {
+                Some(motd) => {
+                    let start = format!(":- {} Message of the day -", SERVER_NAME);
+                    welcome.push(numeric(375, &nick, &start));
+                    welcome.extend(motd.lines().map(|line| {
+                        numeric(372, &nick, &format!(":- {}", line))
+                    }));
+                    welcome.push(numeric(376, &nick, ":End of /MOTD command."));
+                }
+                None => welcome.push(numeric(422, &nick, ":MOTD File is missing")),
+            }
+
+            // To the rest of the server, this is an ordinary client, and like any other it starts
+            // out in the default room.
+            let (control_tx, control_rx) = mpsc::channel(8);
+            let (chat_tx, chat_rx) = mpsc::channel(8);
+            let mut client = Client::new(control_tx, chat_tx, nick.clone(), clock.now());
+            client.admin = admin;
+            client.capabilities = capabilities;
+            clients.insert(addr, client);
+            follow_rooms(&subscriptions, &clients, &handle);
+
+            let rx = Prioritized::new(control_rx, chat_rx);
+            let welcome = stream::iter(welcome.into_iter().map(Ok::<_, io::Error>));
+            Box::new(socket.send_all(welcome)
+                .map(move |(socket, _)| (socket, nick, rx)))
+        }))
+    }
+
+    // Act on `command` from the client at `addr`, whose nick is `nick`. Replies meant for it alone
+    // are sent on `replies`.
+    fn handle(&self,
+              addr: &SocketAddr,
+              nick: &str,
+              command: Command,
+              replies: &mpsc::Sender<String>)
+              -> IoFuture<()> {
+        let clients = &self.chat.clients;
+        clients.touch(addr, self.chat.clock.now());
+        let reply = |line: String| -> IoFuture<()> {
+            Box::new(replies.clone().send(line).then(|_| Ok(())))
+        };
+
+        let room = clients.room_of(addr).expect("messages only come from connected clients");
+        match (command.name.as_str(), &command.params[..]) {
+            // There are no private messages, and we can only talk in the one channel we're in.
+            ("PRIVMSG", [target, text, ..]) if *target == irc_channel(&room) => {
+                self.privmsg(addr, nick, text.clone())
+            }
+            ("PRIVMSG", [target, _, ..]) if target.starts_with('#') => {
+                reply(numeric(404, nick, &format!("{} :Cannot send to channel", target)))
+            }
+            ("PRIVMSG", [target, _, ..]) => {
+                reply(numeric(401, nick, &format!("{} :No such nick/channel", target)))
+            }
+            ("PRIVMSG", [_]) => reply(numeric(412, nick, ":No text to send")),
+            ("JOIN", [channels, ..]) => {
+                if channels == "0" {
+                    return self.join(addr, nick, DEFAULT_ROOM.to_string(), replies);
+                }
+                // We're only ever in one channel, so of several, the last one wins.
+                let channel = channels.rsplit(',').next().unwrap_or("");
+                match channel_room(channel) {
+                    Some(room) => self.join(addr, nick, room, replies),
+                    None => reply(numeric(403, nick, &format!("{} :No such channel", channel))),
+                }
+            }
+            ("PART", [channels, ..]) => {
+                if !channels.split(',').any(|parted| parted == irc_channel(&room)) {
+                    let not_on = format!("{} :You're not on that channel", channels);
+                    return reply(numeric(442, nick, &not_on));
+                }
+                if room == DEFAULT_ROOM {
+                    let reason = format!("you can only leave {} for another channel",
+                                         irc_channel(DEFAULT_ROOM));
+                    return reply(notice(nick, &reason));
+                }
+                self.join(addr, nick, DEFAULT_ROOM.to_string(), replies)
+            }
+            ("TOPIC", [channel, ..]) if *channel != irc_channel(&room) => {
+                reply(numeric(442, nick, &format!("{} :You're not on that channel", channel)))
+            }
+            ("TOPIC", [channel]) => {
+                match self.chat.topics.borrow().get(&room) {
+                    Some(topic) => reply(numeric(332, nick, &format!("{} :{}", channel, topic))),
+                    None => reply(numeric(331, nick, &format!("{} :No topic is set", channel))),
+                }
+            }
+            ("TOPIC", [_, topic, ..]) => {
+                self.chat.set_topic(addr, room, topic.clone(), self.config.restrict_topics)
+            }
+            ("NAMES", _) => clients.who(addr),
+            ("WHO", params) => {
+                let mask = params.first().map_or("*", |mask| mask.as_str());
+                reply(numeric(315, nick, &format!("{} :End of /WHO list.", mask)))
+            }
+            ("MODE", [target, ..]) if target.starts_with('#') => {
+                reply(numeric(324, nick, &format!("{} +", target)))
+            }
+            ("MODE", [_, ..]) => reply(numeric(221, nick, "+")),
+            ("PING", params) => reply(pong(params.first())),
+            ("PONG", _) | ("CAP", _) | ("NOTICE", _) => Box::new(future::ok(())),
+            ("NICK", [_, ..]) => reply(notice(nick, "names can't be changed while connected")),
+            ("PASS", _) | ("USER", _) => reply(numeric(462, nick, ":You may not reregister")),
+            (name, []) if ["PRIVMSG", "JOIN", "PART", "MODE", "NICK", "TOPIC"].contains(&name) => {
+                reply(numeric(461, nick, &format!("{} :Not enough parameters", name)))
+            }
+            (name, _) => reply(numeric(421, nick, &format!("{} :Unknown command", name))),
+        }
+    }
+
+    // Say `text` in the channel the client at `addr` is in, as if it were a native client's
+    // `ClientMessage::Message`.
+    fn privmsg(&self, addr: &SocketAddr, nick: &str, text: String) -> IoFuture<()> {
+        let clients = &self.chat.clients;
+        let mut msg = ClientMessage::Message(text);
+        match clients.filter(addr, &mut msg, &self.middleware) {
+            MiddlewareAction::Allow | MiddlewareAction::Modify => {}
+            MiddlewareAction::Drop => return Box::new(future::ok(())),
+            MiddlewareAction::Error(reason) => {
+                let error = ServerMessage::Error(ErrorCode::InvalidMessage, reason);
+                return clients.send_to(addr, error);
+            }
+        }
+        let body = match msg {
+            ClientMessage::Message(body) => body,
+            _ => return Box::new(future::ok(())),
+        };
+        let now = self.chat.clock.now();
+        match clients.admit(addr, None, &body, &self.config.policies, now) {
+            Ok(room) => Box::new(self.chat.say(&room, nick, body).map(|_| ())),
+            Err(error) => clients.send_to(addr, error),
+        }
+    }
+
+    // Move the client at `addr` to `room`, then tell it the room's topic and who's there. A full
+    // room turns it away instead, with a reply on `replies`.
+    fn join(&self,
+            addr: &SocketAddr,
+            nick: &str,
+            room: String,
+            replies: &mpsc::Sender<String>)
+            -> IoFuture<()> {
+        let addr = *addr;
+        let chat = self.chat.clone();
+        let old_room = chat.clients
+            .room_of(&addr)
+            .expect("messages only come from connected clients");
+        if old_room == room {
+            return chat.clients.who(&addr);
+        }
+        if chat.clients.is_full(&room, self.config.policies.for_room(&room).max_members) {
+            let full = format!("{} :Cannot join channel (+l)", irc_channel(&room));
+            return Box::new(replies.clone().send(numeric(471, nick, &full)).then(|_| Ok(())));
+        }
+
+        // A client isn't in its old room any more by the time that room's told it left, so it
+        // has to hear about that from us.
+        let left = chat.clients.send_to(&addr, ServerMessage::UserLeft(nick.to_string(), old_room));
+        let config = self.config.clone();
+        Box::new(left.and_then(move |()| {
+                chat.join(&addr, room, &config.policies).map(move |()| chat)
+            })
+            .and_then(move |chat| chat.clients.who(&addr)))
+    }
+}
+
+// Talk IRC with the client at `addr`, now that it's `nick`: act on what it says, and pass on
+// what `rx` tells it, translated. The client's arrival is announced first, as a native client's
+// is, and it's told the topic of the channel it starts out in and who's there.
+fn converse(gateway: Rc<Gateway>,
+            addr: SocketAddr,
+            socket: IrcSocket,
+            nick: String,
+            rx: Outbox)
+            -> IoFuture<()> {
+    let clients = gateway.chat.clients.clone();
+    let (to_client, from_client) = socket.split();
+    let (reply_tx, reply_rx) = mpsc::channel(8);
+
+    let topic = gateway.chat.topic(DEFAULT_ROOM);
+    let announce = clients.broadcast(ServerMessage::UserConnected(nick.clone()))
+        .and_then({
+            let clients = clients.clone();
+            move |()| stream::iter(topic.map(Ok)).for_each(move |msg| clients.send_to(&addr, msg))
+        })
+        .and_then({
+            let clients = clients.clone();
+            move |()| clients.who(&addr)
+        });
+
+    let reader = {
+        let nick = nick.clone();
+        from_client.take_while(|command| Ok(command.name != "QUIT"))
+            .for_each(move |command| gateway.handle(&addr, &nick, command, &reply_tx))
+    };
+
+    // An `ERROR` is the last thing a client hears before we hang up; see `to_irc`.
+    let translated = rx.map(move |msg| {
+            let room = clients.room_of(&addr).unwrap_or_default();
+            stream::iter(to_irc(msg, &nick, &room).into_iter().map(Ok))
+        })
+        .flatten();
+    let writer = translated.select(reply_rx)
+        .map_err(|()| unreachable!("rx can't fail"))
+        .fold(to_client, |to_client, line: String| {
+            let closing = line.starts_with("ERROR ");
+            to_client.send(line).and_then(move |to_client| {
+                if closing {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "idle too long"));
+                }
+                Ok(to_client)
+            })
+        })
+        .map(|_| ());
+
+    Box::new(announce.and_then(move |()| {
+        reader.select(writer).map(|_| ()).map_err(|(err, _)| err)
+    }))
+}
+
+// What registering took: the optional `PASS`, the `NICK`, and whether `USER` has been sent (we
+// have no use for anything it says).
+#[derive(Default)]
+struct Registration {
+    password: Option<String>,
+    nick: Option<String>,
+    user: bool,
+}
+
+// Read commands off of `socket` until the client has registered.
+fn register(socket: IrcSocket) -> IoFuture<(IrcSocket, Registration)> {
+    Box::new(future::loop_fn((socket, Registration::default()), |(socket, mut registration)| {
+        socket.into_future()
+            .map_err(|(err, _)| err)
+            .and_then(move |(command, socket)| -> IoFuture<_> {
+                let command = match command {
+                    Some(ref command) if command.name == "QUIT" => None,
+                    command => command,
+                };
+                let command = match command {
+                    Some(command) => command,
+                    None => {
+                        let eof = io::Error::from(io::ErrorKind::UnexpectedEof);
+                        return Box::new(future::err(eof));
+                    }
+                };
+                let reply = match (command.name.as_str(), command.params.first()) {
+                    ("PASS", Some(pass)) => {
+                        registration.password = Some(pass.clone());
+                        None
+                    }
+                    ("NICK", Some(nick)) => {
+                        match validation::normalize_username(nick) {
+                            Ok(ref normalized) if valid_nick(normalized) => {
+                                registration.nick = Some(normalized.clone());
+                                None
+                            }
+                            _ => Some(numeric(432, "*", &format!("{} :Erroneous nickname", nick))),
+                        }
+                    }
+                    ("USER", Some(_)) => {
+                        registration.user = true;
+                        None
+                    }
+                    ("PASS", None) | ("NICK", None) | ("USER", None) => {
+                        Some(numeric(461, "*", &format!("{} :Not enough parameters", command.name)))
+                    }
+                    ("PING", token) => Some(pong(token)),
+                    ("PONG", _) | ("CAP", _) => None,
+                    _ => Some(numeric(451, "*", ":You have not registered")),
+                };
+
+                match reply {
+                    Some(reply) => {
+                        Box::new(socket.send(reply)
+                            .map(move |socket| Loop::Continue((socket, registration))))
+                    }
+                    None if registration.nick.is_some() && registration.user => {
+                        Box::new(future::ok(Loop::Break((socket, registration))))
+                    }
+                    None => Box::new(future::ok(Loop::Continue((socket, registration)))),
+                }
+            })
+    }))
+}
+
+// Nicks end up in masks and name lists, so they can't have the characters those are made of.
+fn valid_nick(nick: &str) -> bool {
+    !nick.is_empty() && !nick.starts_with(|c| c == '#' || c == ':') &&
+    !nick.contains(|c| c == '!' || c == '@' || c == ',')
+}
+
+// How IRC sees `msg`, sent to the client whose nick is `nick` while it's in `room`: as no lines
+// at all, if it means nothing to it.
+fn to_irc(msg: ServerMessage, nick: &str, room: &str) -> Vec<String> {
+    match msg {
+        // IRC clients show what they've said themselves.
+        ServerMessage::Message(_, ref from, _) if from == nick => Vec::new(),
+        ServerMessage::Message(_, from, body) => {
+            body.lines()
+                .map(|line| format!(":{} PRIVMSG {} :{}", mask(&from), irc_channel(room), line))
+                .collect()
+        }
+        // Everyone starts out in the default room, so that's where they turn up.
+        ServerMessage::UserConnected(user) if room == DEFAULT_ROOM => {
+            vec![format!(":{} JOIN {}", mask(&user), irc_channel(room))]
+        }
+        ServerMessage::UserDisconnected(user) => vec![format!(":{} QUIT :Quit", mask(&user))],
+        ServerMessage::UserJoined(user, joined, _) => {
+            vec![format!(":{} JOIN {}", mask(&user), irc_channel(&joined))]
+        }
+        ServerMessage::UserLeft(user, left) => {
+            vec![format!(":{} PART {}", mask(&user), irc_channel(&left))]
+        }
+        ServerMessage::Users(room, users) => {
+            let channel = irc_channel(&room);
+            let mut lines = users.chunks(NAMES_PER_REPLY)
+                .map(|users| {
+                    let names = users.iter().map(|user| irc_name(&user.name)).collect::<Vec<_>>();
+                    numeric(353, nick, &format!("= {} :{}", channel, names.join(" ")))
+                })
+                .collect::<Vec<_>>();
+            lines.push(numeric(366, nick, &format!("{} :End of /NAMES list.", channel)));
+            lines
+        }
+        ServerMessage::TopicChanged { room, topic } => {
+            vec![format!(":{} TOPIC {} :{}", SERVER_NAME, irc_channel(&room), topic)]
+        }
+        ServerMessage::ServerAnnouncement(text) => vec![notice(nick, &text)],
+        ServerMessage::Error(ErrorCode::IdleTimeout, reason) |
+        ServerMessage::Error(ErrorCode::Flooding, reason) => {
+            vec![format!("ERROR :Closing link: {}", reason)]
+        }
+        ServerMessage::Error(_, reason) => vec![notice(nick, &reason)],
+        // The rest are either only sent to native clients as they connect, or need capabilities
+        // IRC clients don't have.
+        _ => Vec::new(),
+    }
+}
+
+// The channel `room` is, and the room `channel` is (if it's a channel at all). Names can't have
+// spaces in IRC, so rooms with them in show up with underscores instead (and can't be joined).
+fn irc_channel(room: &str) -> String {
+    format!("#{}", room.replace(' ', "_"))
+}
+
+fn channel_room(channel: &str) -> Option<String> {
+    channel.strip_prefix('#').filter(|room| !room.is_empty()).map(|room| room.to_string())
+}
+
+// Likewise for users' names.
+fn irc_name(name: &str) -> String {
+    name.replace(' ', "_")
+}
+
+// Who `name` appears to be: `nick!user@host`.
+fn mask(name: &str) -> String {
+    format!("{0}!{0}@{1}", irc_name(name), SERVER_NAME)
+}
+
+// A numeric reply to `nick`, followed by `rest` (its parameters).
+fn numeric(code: u16, nick: &str, rest: &str) -> String {
+    format!(":{} {:03} {} {}", SERVER_NAME, code, nick, rest)
+}
+
+fn notice(nick: &str, text: &str) -> String {
+    format!(":{} NOTICE {} :{}", SERVER_NAME, nick, text)
+}
+
+fn pong(token: Option<&String>) -> String {
+    format!(":{0} PONG {0} :{1}", SERVER_NAME, token.map_or(SERVER_NAME, |token| token.as_str()))
+}
+
+// One line of IRC, `[:PREFIX] COMMAND [PARAMS...] [:TRAILING]`, with the command in upper case
+// and the trailing parameter (which can have spaces in it) last among the `params`. We've no use
+// for the prefix, nor for IRCv3 tags.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Command {
+    name: String,
+    params: Vec<String>,
+}
+
+impl Command {
+    // Parse `line`, or return `None` if it's blank.
+    fn parse(line: &str) -> Option<Command> {
+        let mut rest = line.trim_end_matches(|c| c == '\r' || c == '\n');
+        for sigil in &['@', ':'] {
+            if rest.starts_with(*sigil) {
+                rest = rest.split_once(' ').map_or("", |(_, rest)| rest);
+            }
+        }
+        let (rest, trailing) = match rest.split_once(" :") {
+            Some((rest, trailing)) => (rest, Some(trailing)),
+            None => (rest, None),
+        };
+        let mut words = rest.split(' ').filter(|word| !word.is_empty());
+        let name = words.next()?.to_ascii_uppercase();
+        let mut params = words.map(|word| word.to_string()).collect::<Vec<_>>();
+        params.extend(trailing.map(|trailing| trailing.to_string()));
+        Some(Command {
+            name: name,
+            params: params,
+        })
+    }
+}
+
+// Frames IRC: lines in, as `Command`s, and lines out.
+struct IrcCodec;
+
+impl Codec for IrcCodec {
+    type In = Command;
+    type Out = String;
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Command>> {
+        loop {
+            let end = match buf.as_slice().iter().position(|&b| b == b'\n') {
+                Some(end) if end < MAX_LINE_LEN => end,
+                None if buf.len() < MAX_LINE_LEN => return Ok(None),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long")),
+            };
+            let line = buf.drain_to(end + 1);
+            if let Some(command) = Command::parse(&String::from_utf8_lossy(line.as_slice())) {
+                return Ok(Some(command));
+            }
+        }
+    }
+
+    // A line break in `line` (and chat can have them) would make whatever followed it look like
+    // a command of its own, so they're sent as spaces.
+    fn encode(&mut self, line: String, buf: &mut Vec<u8>) -> io::Result<()> {
+        buf.extend(line.bytes().map(|b| if b == b'\r' || b == b'\n' { b' ' } else { b }));
+        buf.extend_from_slice(b"\r\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,2203 @@
+//! A chat server that broadcasts messages to all connections.
+//!
+//! The server expects to send and receive messages via codecs provided by tokio-chat-common.
+//! The message protocol between the client and server is:
+//!
+//! 1. A new client connects to the server. It must send a single `Handshake` message. If the server
+//!    was started with `--token`, the `Handshake` must carry the same token (or the
+//!    `--admin-token`); otherwise the server replies with a `ServerMessage::Error` and closes the
+//!    connection. It does the same with an `ErrorCode::InvalidMessage` error if the name asked for
+//!    mixes scripts; any other name is taken in its normal form (see
+//!    `tokio_chat_common::validation`), however it was written. A client whose address already has
+//!    `--max-connections-per-ip` connections open doesn't get that far: it's sent an
+//!    `ErrorCode::TooManyConnections` error and disconnected straight away. Behind a load balancer
+//!    started with `--proxy-protocol`, each connection must begin with a PROXY protocol v1 header,
+//!    whose source address is the one that counts; connections without a valid one are dropped
+//!    before the `Handshake`. A client that hasn't sent its `Handshake` within
+//!    `--handshake-timeout` of connecting is sent an `ErrorCode::IdleTimeout` error and
+//!    disconnected. An address whose logins keep failing (with a bad token, password or login
+//!    token) is locked out for a while after `--login-attempts` of them, and for longer each
+//!    time; meanwhile, its clients are sent an `ErrorCode::TooManyAttempts` error saying how long
+//!    to wait, and disconnected.
+//! 2. After receiving the `Handshake`, the server sends the client a `ServerMessage::Welcome`
+//!    carrying a resume token (and its `--motd` as a `ServerMessage::Motd`, if it has one), then
+//!    broadcasts a `ServerMessage::UserConnected` message to all connected clients (including
+//!    the new one that triggered this message). A client that
+//!    reconnects within `--resume-grace` with that token in its `Handshake` gets its previous
+//!    session's name, room and status back, followed by the chat messages it missed (as far back
+//!    as `--history` reaches, or, with a `--db-url`, as far back as the database goes).
+//!    The `Handshake` lists the optional features (see `capability`) the client supports, and the
+//!    `Welcome` lists the ones the server supports as well. Neither side sends messages needing a
+//!    capability outside that set: the server leaves the client out of such broadcasts, and
+//!    answers such a message from the client with an `ErrorCode::InvalidMessage` error.
+//!    A `Handshake` can also ask for the client to be an observer, which hears what's said in its
+//!    room but can send nothing besides `ClientMessage::Join`, `ClientMessage::Who`,
+//!    `ClientMessage::GetRoomInfo` and `ClientMessage::Ping` (anything else is refused with
+//!    `ErrorCode::Unauthorized`). Observers aren't announced when they come, go or change rooms,
+//!    don't show up in user lists, and can't resume sessions.
+//! 3. The client may send any number of `ClientMessage`s to the server. Every client starts out in
+//!    the `DEFAULT_ROOM`; sending `ClientMessage::Join` moves it to another room, and the server
+//!    sends `ServerMessage::UserLeft` to the old room and `ServerMessage::UserJoined` to the new
+//!    one. A room whose `RoomPolicy` caps its members (see `--max-room-members`) turns away
+//!    joiners once it's full with an `ErrorCode::RoomFull` error, leaving them where they were.
+//!    Rooms can also be created ahead of time with a `ClientMessage::CreateRoom`, with settings
+//!    of their own: a cap on members in place of the policy's, a limit on the messages sent in
+//!    the room each minute by all its members together, how much of its chat the history keeps,
+//!    its first topic, and whether only operators may join it (only operators may create such
+//!    private rooms). Each joiner is told about its new room with a `ServerMessage::RoomInfo`, as
+//!    is anyone who asks with a `ClientMessage::GetRoomInfo`.
+//!    `ClientMessage::SetStatus` sets a status line that's announced to the sender's room and
+//!    included when the server reports on users, such as in answer to `ClientMessage::Who`. For
+//!    each incoming `ClientMessage::Message`, the server broadcasts a `ServerMessage::Message` to
+//!    every client in the sender's room (including the sender), as long as the message fits that
+//!    room's `RoomPolicy`. If it doesn't, only the sender hears about it, via a
+//!    `ServerMessage::Error`. Clients with `capability::FORMATTING` may send a
+//!    `ClientMessage::FormattedMessage` in Markdown or HTML instead, which is passed on as a
+//!    `ServerMessage::FormattedMessage` (or as a plain `ServerMessage::Message`, to clients
+//!    without the capability) once any HTML has been stripped of everything that isn't simple
+//!    formatting; see `sanitize`. Each broadcast message carries a number, which its author can
+//!    use to replace it with a `ClientMessage::EditMessage` (while the server still remembers it);
+//!    the room then gets a `ServerMessage::MessageEdited`. Files are sent as a
+//!    `ClientMessage::FileOffer` followed by its `ClientMessage::FileChunk`s, which the server
+//!    relays to the rest of the sender's room as long as they stay within what was offered and
+//!    `--max-file-size`. Clients that presented the `--admin-token` in their `Handshake` are
+//!    operators, and may send a `ClientMessage::AdminAnnounce`, which reaches every client in
+//!    every room as a `ServerMessage::ServerAnnouncement`, and a `ClientMessage::AdminExport`,
+//!    answered with what the history has of a room's chat as a `ServerMessage::ExportData`;
+//!    anyone else gets an `ErrorCode::Unauthorized` error. Anyone in a room may set its topic
+//!    with a `ClientMessage::SetTopic` (only operators may, with `--restrict-topics`), which the
+//!    room hears as a `ServerMessage::TopicChanged`, as does everyone who joins it later. A
+//!    `ClientMessage::Ping` is answered straight back with a `ServerMessage::Pong`. A message that
+//!    can't be decoded gets an `ErrorCode::InvalidMessage` error back, but only a run of more than
+//!    `--max-bad-frames` of them closes the connection. (One of a type the server doesn't know,
+//!    from a newer version of the protocol, gets an `ErrorCode::UnsupportedMessageType` error
+//!    instead, and never counts towards closing the connection. Every message type denies fields it
+//!    doesn't have, so a message with fields from a newer version of the protocol doesn't decode
+//!    either; with `--strict-protocol`, it gets an error saying which field, and doesn't count
+//!    either.) So does sending nothing at all
+//!    for longer than `--idle-timeout`, if the server was given one, after an
+//!    `ErrorCode::IdleTimeout` error, and carrying on sending over the rate limit after an
+//!    `ErrorCode::RateLimited` warning (see `FloodPolicy`), after an `ErrorCode::Flooding` error.
+//!    Without any warning, so does stalling partway through sending a message for longer than
+//!    `--read-timeout`, or not reading what the server sends for longer than `--write-timeout`.
+//! 4. When a client disconnects, the server broadcasts a `ServerMessage::UserDisconnected`
+//!    message to all remaining connected clients. This step is skipped if the client disconnecting
+//!    never completed the `Handshake` in step 1.
+//!
+//! Several servers can run as nodes of one cluster, given the same `--redis-url`: chat messages
+//! sent on one node then also reach the members of the same room on every other node. (Only chat
+//! is shared; everything else, including who's online, is still per node.)
+//!
+//! Given an `--event-log`, the server records every `Event` that changes its rooms there, as it
+//! happens, and replays the log when it starts, so rooms' topics, settings and recent chat outlast
+//! a restart; see `EventLog`.
+//!
+//! Bots and integrations that can't keep a connection open can chat over HTTP instead, given an
+//! `--http-port` and `--http-token`; see `api::serve` for the endpoints. Services that only need
+//! to hear what's said can be sent it as it happens, by listing them in a `--webhooks` file; see
+//! `WebhookRegistry`. IRC clients can join in too, given an `--irc-port`; see `irc_gateway::serve`
+//! for how much of IRC that speaks. And rooms can be bridged to XMPP multi-user chat, given an
+//! `--xmpp-component` to connect to; see `xmpp_gateway::connect`. One room can also be mirrored to
+//! a Discord channel, given a `--discord-api` to reach Discord through; see
+//! `discord_bridge::connect`. Or to a Slack channel, given a `--slack-webhook` to post to, with
+//! Slack's events coming in through the HTTP API; see `slack_bridge`.
+//!
+//! To test this, run
+//!
+//! ```text
+//! cargo run -- --allow-guests
+//! ```
+//!
+//! in this project and then in another window run one or more instances the tokio-chat-client
+//! binary. The server itself lives in this library as `serve`, so it can also be run in-process;
+//! the tokio-chat-server binary just parses its `Config` from the command line and calls that.
+//! Run that way, it can be given a `Subscriber` to hear about everything that happens on each
+//! connection, span by span, and a `ConnectionLifecycle` to run hooks of its own as clients
+//! connect, log in and disconnect (see `AuditLogger` for one that keeps an audit log).
+
+extern crate bcrypt;
+extern crate futures;
+extern crate futures_cpupool;
+extern crate jsonwebtoken;
+extern crate rand;
+extern crate redis;
+extern crate ring;
+extern crate rusqlite;
+// serde 1.0, not the 0.8 everything on the wire goes through (via tokio-chat-common), because
+// that's what jsonwebtoken takes. It's only for `token::Claims`, which imports its derives itself.
+extern crate serde;
+extern crate serde_derive;
+extern crate serde_json;
+extern crate tokio_core;
+extern crate tokio_chat_common;
+extern crate toml;
+extern crate url;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio_core::io::{Framed, Io};
+use tokio_core::reactor::{Handle, Interval, Timeout};
+use tokio_core::net::{TcpListener, TcpStream};
+use futures::{Stream, Sink, Future};
+use futures::{future, stream};
+use futures::future::Either;
+use futures::stream::StreamFuture;
+use futures::sync::mpsc;
+use futures_cpupool::CpuPool;
+use serde_json::Value;
+use tokio_chat_common::{Handshake, HandshakeCodec, ClientMessage, ServerMessage, ContentType,
+                        ServerToClientCodec, LenientServerToClientCodec, ErrorCode, UserInfo,
+                        RoomConfig, DEFAULT_ROOM, CodecStats, CodecStatsSnapshot, StatsCodec,
+                        MessageId, Event, capability, check_offer, unknown_field,
+                        validation};
+
+mod api;
+mod auth;
+mod blocklist;
+mod clock;
+mod cluster;
+mod config;
+mod connection;
+mod deadline;
+mod discord_bridge;
+mod events;
+mod irc_gateway;
+mod lifecycle;
+mod limit;
+mod metrics;
+mod middleware;
+mod outbound;
+mod policy;
+mod priority;
+mod proxy;
+mod rooms;
+mod sanitize;
+mod session;
+mod slack_bridge;
+mod store;
+mod throttle;
+mod token;
+mod trace;
+mod transfer;
+mod webhooks;
+mod xmpp_gateway;
+pub use self::blocklist::BlockMode;
+pub use self::clock::{Clock, MockClock, SharedClock, SystemClock};
+pub use self::cluster::{ClusterBus, LocalBus, RecvStream, RedisBackend, SharedBus};
+pub use self::config::Config;
+pub use self::discord_bridge::DiscordConfig;
+pub use self::events::{EventLog, JsonlEventLog};
+pub use self::lifecycle::{AuditLogger, ConnectionLifecycle, DisconnectReason, LifecycleFuture,
+                         SharedLifecycle};
+pub use self::slack_bridge::SlackConfig;
+pub use self::store::{MemoryUserStore, MessageStore, SqliteMessageStore, SqliteUserStore,
+                      StoreFuture, StoredMessage, StoredUser, UserStore};
+pub use self::token::Claims;
+pub use self::trace::{ConnectionEvent, SharedSubscriber, Span, Subscriber};
+pub use self::webhooks::{EventFilter, WebhookRegistry};
+pub use self::xmpp_gateway::XmppConfig;
+use self::auth::SignIn;
+use self::blocklist::Blocklist;
+use self::cluster::Subscriptions;
+use self::connection::ConnectionMetadata;
+use self::deadline::Deadlines;
+use self::discord_bridge::DiscordBridge;
+use self::lifecycle::Hooks;
+use self::limit::IpLimits;
+use self::middleware::{ConnectionContext, MessageMiddleware, MiddlewareAction};
+use self::outbound::SkipUnencodable;
+use self::policy::{FloodCount, FloodVerdict, Policies, RateWindow};
+use self::priority::Prioritized;
+use self::rooms::RoomRegistry;
+use self::session::{History, Sessions};
+use self::trace::Tracer;
+use self::slack_bridge::SlackBridge;
+use self::throttle::LoginThrottler;
+use self::token::Tokens;
+use self::transfer::Transfer;
+use self::webhooks::Webhooks;
+use self::xmpp_gateway::XmppBridge;
+
+// Statuses longer than this many bytes are refused.
+const MAX_STATUS_LEN: usize = 100;
+
+// Likewise for rooms' topics.
+const MAX_TOPIC_LEN: usize = 300;
+
+// A resumed session catches up on at most this many messages from the database.
+const MAX_REPLAY_LEN: usize = 1000;
+
+// For each client that connects, we hang on to a pair of mpsc::Senders (to send the task managing
+// that client messages), the name they gave us during handshaking, whether they're an operator or
+// an observer, the optional features we agreed on, their status (if they've set one), the room
+// they're in, the token they can use to resume their session later, how fast they've been
+// talking, when they last sent anything (and whether they've been timed out for not doing so), the
+// files they're in the middle of sending, what's gone over the wire, and any metadata extensions
+// have attached to them. Control messages (connects, disconnects) and chat messages
+// travel on separate channels so the task writing to the client can always send control messages
+// first; see `Prioritized`.
+struct Client {
+    control_tx: mpsc::Sender<ServerMessage>,
+    chat_tx: mpsc::Sender<ServerMessage>,
+    name: String,
+    admin: bool,
+    observer: bool,
+    capabilities: Vec<String>,
+    status: Option<String>,
+    room: String,
+    resume_token: String,
+    rate: RateWindow,
+    flood: FloodCount,
+    last_active: Instant,
+    timed_out: bool,
+    transfers: HashMap<u64, Transfer>,
+    stats: Arc<CodecStats>,
+    metadata: ConnectionMetadata,
+}
+
+impl Client {
+    fn new<S: Into<String>>(control_tx: mpsc::Sender<ServerMessage>,
+                            chat_tx: mpsc::Sender<ServerMessage>,
+                            name: S,
+                            now: Instant)
+                            -> Client {
+        Client {
+            control_tx: control_tx,
+            chat_tx: chat_tx,
+            name: name.into(),
+            admin: false,
+            observer: false,
+            capabilities: Vec::new(),
+            status: None,
+            room: DEFAULT_ROOM.to_string(),
+            resume_token: session::new_token(),
+            rate: RateWindow::new(now),
+            flood: FloodCount::new(),
+            last_active: now,
+            timed_out: false,
+            transfers: HashMap::new(),
+            stats: Arc::new(CodecStats::new()),
+            metadata: ConnectionMetadata::new(),
+        }
+    }
+
+    // Whether we agreed with this client on `capability` during the handshake.
+    fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    // Pick the channel `message` should be queued on. Only ordinary chat (and edits to it) and
+    // files go on the low priority channel; everything else is considered a control message. (A
+    // message and its edits, like a file's offer and its chunks, must share a channel so they
+    // arrive in order.)
+    fn tx_for(&self, message: &ServerMessage) -> &mpsc::Sender<ServerMessage> {
+        match *message {
+            ServerMessage::Message(..) |
+            ServerMessage::FormattedMessage { .. } |
+            ServerMessage::MessageEdited { .. } |
+            ServerMessage::FileOffer { .. } |
+            ServerMessage::FileChunk { .. } => &self.chat_tx,
+            _ => &self.control_tx,
+        }
+    }
+}
+
+// The server is single-threaded, so we can keep all clients in a single Rc<RefCell<HashMap<_>>>.
+#[derive(Clone)]
+struct ConnectedClients(Rc<RefCell<HashMap<SocketAddr, Client>>>);
+
+impl ConnectedClients {
+    fn new() -> ConnectedClients {
+        ConnectedClients(Rc::new(RefCell::new(HashMap::new())))
+    }
+
+    // Called when a new client connects and sends us a `Handshake`.
+    fn insert(&self, addr: SocketAddr, client: Client) {
+        self.0.borrow_mut().insert(addr, client);
+    }
+
+    // Called when a client disconnects. The return value will be `Some(client)` if `addr` had
+    // successfully sent us a `Handshake` and `None` otherwise.
+    fn remove(&self, addr: &SocketAddr) -> Option<Client> {
+        self.0.borrow_mut().remove(addr)
+    }
+
+    // Broadcast `message` to all clients. The return type of this method involves closures,
+    // so we either have to Box it (as in this case) or wait for `impl Trait`. Note that the
+    // broadcast performed here doesn't actually do any socket communication at all; it merely
+    // sends the message along the `mpsc::Sender` associated with each connected `Client`.
+    //
+    // Note that the `Error` type of the returned future can be anything at all. This makes it
+    // easier to insert calls to this method in other contexts. This method itself will never
+    // fail. Perhaps the return type could change to `Box<Future<Item = (), Error = !>>` once
+    // `!` lands?
+    fn broadcast<E: 'static>(&self, message: ServerMessage) -> Box<Future<Item = (), Error = E>> {
+        self.send_where(message, |_, _| true)
+    }
+
+    // Like `broadcast`, but only to the clients currently in `room`.
+    fn broadcast_room<E: 'static>(&self,
+                                  room: &str,
+                                  message: ServerMessage)
+                                  -> Box<Future<Item = (), Error = E>> {
+        self.send_where(message, |_, client| client.room == room)
+    }
+
+    // Like `broadcast_room`, but leaving out the client at `addr`.
+    fn broadcast_room_except<E: 'static>(&self,
+                                         room: &str,
+                                         addr: &SocketAddr,
+                                         message: ServerMessage)
+                                         -> Box<Future<Item = (), Error = E>> {
+        self.send_where(message,
+                        |client_addr, client| client.room == room && client_addr != addr)
+    }
+
+    // Like `broadcast`, but only to the client at `addr` (if it's still connected).
+    fn send_to<E: 'static>(&self,
+                           addr: &SocketAddr,
+                           message: ServerMessage)
+                           -> Box<Future<Item = (), Error = E>> {
+        self.send_where(message, |client_addr, _| client_addr == addr)
+    }
+
+    // The guts of all of the broadcast variants above: send `message` to every client for which
+    // `include` returns true. Clients that didn't negotiate the capability `message` needs
+    // wouldn't know what to make of it, so they get its `fallback` instead, or are left out if it
+    // hasn't got one.
+    fn send_where<E, F>(&self,
+                        message: ServerMessage,
+                        include: F)
+                        -> Box<Future<Item = (), Error = E>>
+        where E: 'static,
+              F: Fn(&SocketAddr, &Client) -> bool
+    {
+        let client_map = self.0.borrow();
+
+        // For each client, clone the appropriate `mpsc::Sender` (because sending consumes the
+        // sender) and start sending a clone of `message`. This produces an iterator of Futures.
+        let capability = message.capability();
+        let fallback = capability.and_then(|_| message.fallback());
+        let all_sends = client_map.iter()
+            .filter(|&(addr, client)| include(addr, client))
+            .filter_map(|(_, client)| {
+                if capability.map_or(true, |c| client.has_capability(c)) {
+                    Some((client, &message))
+                } else {
+                    fallback.as_ref().map(|fallback| (client, fallback))
+                }
+            })
+            .map(|(client, message)| client.tx_for(message).clone().send(message.clone()));
+
+        // Collect the futures into a stream. We don't care about:
+        //
+        //    1. what order they finish (hence `futures_unordered`)
+        //    2. the result of any individual send (hence the `.then(|_| Ok(()))`. If the send
+        //       succeeds we don't need the `Sender` back since we still have it in our hashmap.
+        //       If the send fails its because the receiver is gone, so we don't need to broadcast
+        //       to them anyway.
+        let send_stream = stream::futures_unordered(all_sends).then(|_| Ok(()));
+
+        // Convert the stream to a future that runs all the sends and box it up.
+        Box::new(send_stream.for_each(|()| Ok(())))
+    }
+
+    // Run `msg` from the client at `addr` through `middleware`, which may change it.
+    fn filter(&self,
+              addr: &SocketAddr,
+              msg: &mut ClientMessage,
+              middleware: &[Box<MessageMiddleware>])
+              -> MiddlewareAction {
+        let client_map = self.0.borrow();
+        let client = client_map.get(addr).expect("messages only come from connected clients");
+        let ctx = ConnectionContext {
+            addr: *addr,
+            name: &client.name,
+            room: &client.room,
+            metadata: &client.metadata,
+        };
+        middleware::run(middleware, msg, &ctx)
+    }
+
+    // Check a chat message sent at `now` from the client at `addr` against the policy of the room
+    // it's in. On success, returns the room the message should be broadcast to; on failure,
+    // returns the error to send back to the client instead.
+    fn admit(&self,
+             addr: &SocketAddr,
+             room: Option<&str>,
+             body: &str,
+             policies: &Policies,
+             now: Instant)
+             -> Result<String, ServerMessage> {
+        let mut client_map = self.0.borrow_mut();
+        let client = client_map.get_mut(addr).expect("messages only come from connected clients");
+        let room = room.unwrap_or(&client.room).to_string();
+        let policy = policies.for_room(&room);
+
+        if body.len() > policy.max_body_len {
+            return Err(ServerMessage::Error(ErrorCode::InvalidMessage,
+                                            format!("messages in {} are limited to {} bytes",
+                                                    room,
+                                                    policy.max_body_len)));
+        }
+        if !client.rate.allow(now, policy.rate_per_sec) {
+            let limit = format!("{} allows {} messages per second", room, policy.rate_per_sec);
+            return Err(match client.flood.refuse(now, &policies.flood) {
+                FloodVerdict::Refuse => ServerMessage::Error(ErrorCode::RateLimited, limit),
+                FloodVerdict::Warn => {
+                    println!("WARNED {:?} with name {} for flooding", addr, client.name);
+                    let warning = format!("{}; slow down for {} seconds or be disconnected",
+                                          limit,
+                                          policies.flood.grace.as_secs());
+                    ServerMessage::Error(ErrorCode::RateLimited, warning)
+                }
+                FloodVerdict::Disconnect => {
+                    println!("DISCONNECTING {:?} with name {} for flooding", addr, client.name);
+                    ServerMessage::Error(ErrorCode::Flooding,
+                                         format!("{}, and still too many were sent", limit))
+                }
+            });
+        }
+        Ok(room)
+    }
+
+    // Start relaying a file from the client at `addr` to the rest of its room, as long as the offer
+    // is within `max_size` and doesn't reuse the id of a transfer that's still going.
+    fn offer_file<E: 'static>(&self,
+                              addr: &SocketAddr,
+                              transfer_id: u64,
+                              name: String,
+                              size: u64,
+                              chunk_count: u32,
+                              max_size: u64)
+                              -> Box<Future<Item = (), Error = E>> {
+        let offered = {
+            let mut client_map = self.0.borrow_mut();
+            let client = client_map.get_mut(addr)
+                .expect("messages only come from connected clients");
+            check_offer(size, chunk_count, max_size).and_then(|()| {
+                if client.transfers.contains_key(&transfer_id) {
+                    return Err(format!("file transfer {} is already in progress", transfer_id));
+                }
+                // An empty file is finished as soon as it's offered.
+                if chunk_count > 0 {
+                    let transfer = Transfer::new(client.room.clone(), size, chunk_count);
+                    client.transfers.insert(transfer_id, transfer);
+                }
+                Ok((client.name.clone(), client.room.clone()))
+            })
+        };
+
+        match offered {
+            Ok((from, room)) => {
+                let offer = ServerMessage::FileOffer {
+                    from: from,
+                    transfer_id: transfer_id,
+                    name: name,
+                    size: size,
+                    chunk_count: chunk_count,
+                };
+                self.broadcast_room_except(&room, addr, offer)
+            }
+            Err(reason) => {
+                self.send_to(addr, ServerMessage::Error(ErrorCode::InvalidMessage, reason))
+            }
+        }
+    }
+
+    // Relay one chunk of a file the client at `addr` offered earlier. A chunk that doesn't fit
+    // its offer cancels the whole transfer.
+    fn relay_chunk<E: 'static>(&self,
+                               addr: &SocketAddr,
+                               transfer_id: u64,
+                               index: u32,
+                               data: Vec<u8>)
+                               -> Box<Future<Item = (), Error = E>> {
+        let relayed = {
+            let mut client_map = self.0.borrow_mut();
+            let client = client_map.get_mut(addr)
+                .expect("messages only come from connected clients");
+            let result = match client.transfers.get_mut(&transfer_id) {
+                Some(transfer) => {
+                    transfer.receive(index, data.len()).map(|done| (done, transfer.room.clone()))
+                }
+                None => Err(format!("no file transfer {} is in progress", transfer_id)),
+            };
+            match result {
+                Ok((done, room)) => {
+                    if done {
+                        client.transfers.remove(&transfer_id);
+                    }
+                    Ok((client.name.clone(), room))
+                }
+                Err(reason) => {
+                    client.transfers.remove(&transfer_id);
+                    Err(reason)
+                }
+            }
+        };
+
+        match relayed {
+            Ok((from, room)) => {
+                let chunk = ServerMessage::FileChunk {
+                    from: from,
+                    transfer_id: transfer_id,
+                    index: index,
+                    data: data,
+                };
+                self.broadcast_room_except(&room, addr, chunk)
+            }
+            Err(reason) => {
+                self.send_to(addr, ServerMessage::Error(ErrorCode::InvalidMessage, reason))
+            }
+        }
+    }
+
+    // Move the client at `addr` into `room`, letting the members of both its old room and its new
+    // room know, unless `room` already has `max_members` (if that isn't zero). Observers don't
+    // count as members, and can go anywhere.
+    fn join<E: 'static>(&self,
+                        addr: &SocketAddr,
+                        room: String,
+                        max_members: usize)
+                        -> Box<Future<Item = (), Error = E>> {
+        if room.is_empty() {
+            let error = ServerMessage::Error(ErrorCode::InvalidMessage,
+                                             "room names can't be empty".to_string());
+            return self.send_to(addr, error);
+        }
+        let moving = self.room_of(addr).as_ref() != Some(&room);
+        if moving && !self.is_observer(addr) && self.is_full(&room, max_members) {
+            let error = ServerMessage::Error(ErrorCode::RoomFull,
+                                             format!("{} is full ({} members)", room, max_members));
+            return self.send_to(addr, error);
+        }
+
+        let (name, status, old_room, observer) = {
+            let mut client_map = self.0.borrow_mut();
+            let client = client_map.get_mut(addr)
+                .expect("messages only come from connected clients");
+            let old_room = mem::replace(&mut client.room, room.clone());
+            (client.name.clone(), client.status.clone(), old_room, client.observer)
+        };
+        // Observers come and go unannounced; instead, they're told who's in their new room.
+        if observer {
+            return self.who(addr);
+        }
+        if old_room == room {
+            return Box::new(future::ok(()));
+        }
+
+        let left = ServerMessage::UserLeft(name.clone(), old_room.clone());
+        let left = self.broadcast_room(&old_room, left);
+        let joined = ServerMessage::UserJoined(name, room.clone(), status);
+        let joined = self.broadcast_room(&room, joined);
+        Box::new(left.join(joined).map(|_| ()))
+    }
+
+    // Set (or clear) the status of the client at `addr`, and let its room know.
+    fn set_status<E: 'static>(&self,
+                              addr: &SocketAddr,
+                              status: Option<String>)
+                              -> Box<Future<Item = (), Error = E>> {
+        if status.as_ref().is_some_and(|status| status.len() > MAX_STATUS_LEN) {
+            let error = ServerMessage::Error(ErrorCode::InvalidMessage,
+                                             format!("statuses are limited to {} bytes",
+                                                     MAX_STATUS_LEN));
+            return self.send_to(addr, error);
+        }
+
+        let (name, room) = {
+            let mut client_map = self.0.borrow_mut();
+            let client = client_map.get_mut(addr)
+                .expect("messages only come from connected clients");
+            client.status = status.clone();
+            (client.name.clone(), client.room.clone())
+        };
+        self.broadcast_room(&room, ServerMessage::StatusChanged(name, status))
+    }
+
+    // Send an operator's announcement from the client at `addr` to everyone, in every room. Anyone
+    // else trying this just gets an error.
+    fn announce<E: 'static>(&self,
+                            addr: &SocketAddr,
+                            text: String)
+                            -> Box<Future<Item = (), Error = E>> {
+        let admin = self.0
+            .borrow()
+            .get(addr)
+            .expect("messages only come from connected clients")
+            .admin;
+        if !admin {
+            let error = ServerMessage::Error(ErrorCode::Unauthorized,
+                                             "only operators can make announcements".to_string());
+            return self.send_to(addr, error);
+        }
+        self.broadcast(ServerMessage::ServerAnnouncement(text))
+    }
+
+    // Note that the client at `addr` sent something at `now`.
+    fn touch(&self, addr: &SocketAddr, now: Instant) {
+        self.0
+            .borrow_mut()
+            .get_mut(addr)
+            .expect("messages only come from connected clients")
+            .last_active = now;
+    }
+
+    // Time out every client that hasn't sent anything in the `timeout` before `now`. Each is sent
+    // an `IdleTimeout` error, which is the last thing it hears before it's disconnected; see
+    // `serve`.
+    fn time_out_idle<E: 'static>(&self,
+                                 now: Instant,
+                                 timeout: Duration)
+                                 -> Box<Future<Item = (), Error = E>> {
+        let mut timed_out = Vec::new();
+        for (addr, client) in self.0.borrow_mut().iter_mut() {
+            if !client.timed_out && now.duration_since(client.last_active) > timeout {
+                println!("TIMED OUT {:?} with name {}", addr, client.name);
+                client.timed_out = true;
+                timed_out.push(*addr);
+            }
+        }
+        let error = ServerMessage::Error(ErrorCode::IdleTimeout,
+                                         format!("nothing sent for over {} seconds",
+                                                 timeout.as_secs()));
+        self.send_where(error, |addr, _| timed_out.contains(addr))
+    }
+
+    // Whether the client at `addr` negotiated `capability`.
+    fn has_capability(&self, addr: &SocketAddr, capability: &str) -> bool {
+        self.0
+            .borrow()
+            .get(addr)
+            .expect("messages only come from connected clients")
+            .has_capability(capability)
+    }
+
+    // Whether the client at `addr` is an operator.
+    fn is_admin(&self, addr: &SocketAddr) -> bool {
+        self.0.borrow().get(addr).expect("messages only come from connected clients").admin
+    }
+
+    // Whether the client at `addr` connected as an observer.
+    fn is_observer(&self, addr: &SocketAddr) -> bool {
+        self.0.borrow().get(addr).expect("messages only come from connected clients").observer
+    }
+
+    // Whether `room` has `max_members` members already (if that isn't zero), not counting
+    // observers.
+    fn is_full(&self, room: &str, max_members: usize) -> bool {
+        max_members > 0 && self.member_count(room) >= max_members
+    }
+
+    // How many clients are in `room`, not counting observers.
+    fn member_count(&self, room: &str) -> usize {
+        self.0.borrow().values().filter(|client| client.room == room && !client.observer).count()
+    }
+
+    // The name of the client at `addr`.
+    fn name_of(&self, addr: &SocketAddr) -> String {
+        self.0.borrow().get(addr).expect("messages only come from connected clients").name.clone()
+    }
+
+    // The room the client at `addr` is in, if it's (still) connected.
+    fn room_of(&self, addr: &SocketAddr) -> Option<String> {
+        self.0.borrow().get(addr).map(|client| client.room.clone())
+    }
+
+    // Every room with at least one client in it.
+    fn rooms(&self) -> HashSet<String> {
+        self.0.borrow().values().map(|client| client.room.clone()).collect()
+    }
+
+    // The name and codec stats of every connected client, by address.
+    fn stats(&self) -> Vec<(SocketAddr, String, CodecStatsSnapshot)> {
+        self.0
+            .borrow()
+            .iter()
+            .map(|(addr, client)| (*addr, client.name.clone(), client.stats.snapshot()))
+            .collect()
+    }
+
+    // Tell the client at `addr` who's in its room. Observers aren't counted.
+    fn who<E: 'static>(&self, addr: &SocketAddr) -> Box<Future<Item = (), Error = E>> {
+        let (room, mut users) = {
+            let client_map = self.0.borrow();
+            let room = &client_map.get(addr)
+                .expect("messages only come from connected clients")
+                .room;
+            let users = client_map.values()
+                .filter(|client| client.room == *room && !client.observer)
+                .map(|client| {
+                    UserInfo {
+                        name: client.name.clone(),
+                        status: client.status.clone(),
+                    }
+                })
+                .collect::<Vec<_>>();
+            (room.clone(), users)
+        };
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+        self.send_to(addr, ServerMessage::Users(room, users))
+    }
+}
+
+// Shorthand for the boxed futures that closures below have to return when their branches produce
+// different kinds of futures.
+type IoFuture<T> = Box<Future<Item = T, Error = io::Error>>;
+
+type HandshakeIo = Framed<Deadlines<TcpStream>, HandshakeCodec>;
+
+// Wait for `reading`, the `Handshake` from the client at `addr`, until `timer`, set for `timeout`,
+// goes off. If it goes off first, the client is sent an `IdleTimeout` error and dropped. (This is
+// what `select2` is for: it hands back whichever future didn't finish, so we can have the socket
+// back from the read still waiting on it.)
+fn handshake_within(reading: StreamFuture<HandshakeIo>,
+                    timer: Timeout,
+                    timeout: Duration,
+                    addr: SocketAddr)
+                    -> IoFuture<(Option<Handshake>, HandshakeIo)> {
+    Box::new(reading.select2(timer).then(move |result| -> IoFuture<_> {
+        match result {
+            Ok(Either::A((read, _))) => Box::new(future::ok(read)),
+            Err(Either::A(((err, _), _))) | Err(Either::B((err, _))) => Box::new(future::err(err)),
+            Ok(Either::B(((), reading))) => {
+                println!("TIMED OUT {:?}: no handshake within {:?}", addr, timeout);
+                let socket = reading.into_inner().expect("the handshake is still being read");
+                let error = ServerMessage::Error(ErrorCode::IdleTimeout,
+                                                 format!("no handshake within {:?}", timeout));
+                Box::new(socket.into_inner()
+                    .framed(ServerToClientCodec::new())
+                    .send(error)
+                    .and_then(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "no handshake"))))
+            }
+        }
+    }))
+}
+
+// Helper function for figuring out the types of futures. A common tool for getting the compiler
+// to tell you the type of a variable is
+//
+//      let x: () = some_variable;
+//
+//  but this largely fails spectacularly with futures because their types are so involved. This
+//  helper function lets you do this instead:
+//
+//      _debugf(some_future)
+//
+//  which will _usually_ fail to compile with a useful error message about the type of the
+//  future's `Item` or `Error` being whatever it really is instead of `()`. This isn't perfect
+//  because calling this function can sometimes interfere with type inference, but that can often
+//  be worked around as well with some reordering or extra temporary variables.
+fn _debugf<F: Future<Item = (), Error = ()>>(_: F) {}
+
+// Like `_debugf` but for `Stream`s instead of `Future`s.
+fn _debugs<S: Stream<Item = (), Error = ()>>(_: S) {}
+
+// With clustering on, keep our `subscriptions` to exactly the rooms `clients` are in, passing what
+// other nodes say in them along to the rooms' members here.
+fn follow_rooms(subscriptions: &Option<Rc<RefCell<Subscriptions>>>,
+                clients: &ConnectedClients,
+                handle: &Handle) {
+    if let Some(ref subscriptions) = *subscriptions {
+        let deliver = {
+            let clients = clients.clone();
+            move |room: &str, msg: ServerMessage| -> Box<Future<Item = (), Error = ()>> {
+                clients.broadcast_room(room, msg)
+            }
+        };
+        subscriptions.borrow_mut().update(clients.rooms(), handle, deliver);
+    }
+}
+
+// Where chat goes once it's been accepted, whoever it came from (a client, the HTTP API, or one
+// of the gateways and bridges; see `api`, `irc_gateway`, `xmpp_gateway`, `discord_bridge` and
+// `slack_bridge`): into the history and the database, if there is one, out to the other nodes of
+// the cluster, if there are any, to any webhooks listening, to the room's XMPP occupants, if it's
+// bridged to XMPP, to Discord or Slack, if it's the room mirrored there, and to the members of its
+// room here. Rooms' topics are kept here too, though only on this node and only until it stops
+// (unless there's an event log to get them back from), and so are the rooms clients have created,
+// with their settings. Everything that changes any of that is recorded as an `Event`.
+#[derive(Clone)]
+struct Chat {
+    clients: ConnectedClients,
+    topics: Rc<RefCell<HashMap<String, String>>>,
+    rooms: Rc<RefCell<RoomRegistry>>,
+    history: Rc<RefCell<History>>,
+    messages: Option<Rc<MessageStore>>,
+    events: Option<Rc<EventLog>>,
+    subscriptions: Option<Rc<RefCell<Subscriptions>>>,
+    webhooks: Rc<Webhooks>,
+    xmpp: Option<Rc<XmppBridge>>,
+    discord: Option<Rc<DiscordBridge>>,
+    slack: Option<Rc<SlackBridge>>,
+    clock: SharedClock,
+    handle: Handle,
+}
+
+impl Chat {
+    // Say `body` in `room` as `from`. The returned future resolves to the number the message got
+    // once it's been handed to everyone in the room, or fails if it can't be recorded.
+    fn say(&self, room: &str, from: &str, body: String) -> IoFuture<MessageId> {
+        self.say_formatted(room, from, body, ContentType::PlainText)
+    }
+
+    // Like `say`, with `body` in `content_type`, which the room hears about as long as it isn't
+    // plain text (though everywhere else only keeps the body). `Html` has to have been sanitized
+    // already.
+    fn say_formatted(&self,
+                     room: &str,
+                     from: &str,
+                     body: String,
+                     content_type: ContentType)
+                     -> IoFuture<MessageId> {
+        // The message's number is taken now, so that nothing said while this is being recorded
+        // gets it too.
+        let id = self.history.borrow_mut().reserve();
+        let said = Event::Said {
+            id: id,
+            room: room.to_string(),
+            from: from.to_string(),
+            body: body.clone(),
+            content_type: content_type.clone(),
+        };
+        let chat = self.clone();
+        let (room, from) = (room.to_string(), from.to_string());
+        Box::new(self.record(said).and_then(move |()| {
+            if let Some(ref messages) = chat.messages {
+                let stored = StoredMessage {
+                    seq: id,
+                    room: room.clone(),
+                    from_user: from.clone(),
+                    body: body.clone(),
+                    timestamp_ms: chat.clock.unix_time_ms(),
+                    reply_to: None,
+                    edited_body: None,
+                    deleted: false,
+                };
+                chat.handle.spawn(messages.append(&stored).map_err(|err| {
+                    println!("STORE failed: {}", err)
+                }));
+            }
+            chat.webhooks.notify(&room,
+                                 EventFilter::Messages,
+                                 vec![("seq", Value::U64(id)),
+                                      ("from", Value::String(from.clone())),
+                                      ("body", Value::String(body.clone()))]);
+            if let Some(ref xmpp) = chat.xmpp {
+                xmpp.relay(&room, &from, &body);
+            }
+            if let Some(ref discord) = chat.discord {
+                discord.relay(&room, &from, &body);
+            }
+            if let Some(ref slack) = chat.slack {
+                slack.relay(&room, &from, &body);
+            }
+            let msg = chat_message(id, from, body, content_type);
+            if let Some(ref subscriptions) = chat.subscriptions {
+                subscriptions.borrow().publish(&room, msg.clone());
+            }
+            chat.clients.broadcast_room(&room, msg).map(move |()| id)
+        }))
+    }
+
+    // Write `event` to the event log, if there is one, then apply it. Nothing else that follows
+    // from the event should happen until the returned future resolves: an event the log couldn't
+    // keep never happened, and the future fails with why.
+    fn record(&self, event: Event) -> IoFuture<()> {
+        let chat = self.clone();
+        let appended = match self.events {
+            Some(ref events) => events.append(&event),
+            None => Box::new(future::ok(0)),
+        };
+        Box::new(appended.then(move |appended| {
+            match appended {
+                Ok(_) => chat.apply(&event),
+                Err(ref err) => println!("EVENT LOG failed: {}", err),
+            }
+            appended.map(|_| ())
+        }))
+    }
+
+    // Make the change `event` describes to the rooms. This is all that's done with events
+    // replayed from the log; everything else that happens along with them, like broadcasting chat
+    // to the room, only happens the first time.
+    fn apply(&self, event: &Event) {
+        let now = self.clock.now();
+        match *event {
+            Event::Said { id, ref room, ref from, ref body, ref content_type } => {
+                let msg = chat_message(id, from.clone(), body.clone(), content_type.clone());
+                self.history.borrow_mut().record_as(id, room, msg);
+            }
+            Event::Edited { id, ref room, ref new_body } => {
+                let edited = ServerMessage::MessageEdited {
+                    id: id,
+                    new_body: new_body.clone(),
+                };
+                self.history.borrow_mut().record(room, edited);
+            }
+            Event::TopicSet { ref room, ref topic } => {
+                if topic.is_empty() {
+                    self.topics.borrow_mut().remove(room);
+                } else {
+                    self.topics.borrow_mut().insert(room.clone(), topic.clone());
+                }
+            }
+            Event::RoomCreated { ref room, ref config } => {
+                // Whatever topic an empty room was left with goes, along with its old members.
+                match config.topic {
+                    Some(ref topic) if !topic.is_empty() => {
+                        self.topics.borrow_mut().insert(room.clone(), topic.clone());
+                    }
+                    _ => {
+                        self.topics.borrow_mut().remove(room);
+                    }
+                }
+                if let Some(history_size) = config.history_size {
+                    self.history.borrow_mut().limit_room(room, history_size as usize);
+                }
+                self.rooms.borrow_mut().create(room.clone(), config.clone(), now);
+            }
+            Event::Joined { .. } |
+            Event::Left { .. } |
+            Event::Announced { .. } |
+            Event::Exported { .. } => {}
+        }
+    }
+
+    // Check a chat message from the client at `addr` as `ConnectedClients::admit` does, then
+    // against the limit on its room as a whole, if it was created with one.
+    fn admit(&self,
+             addr: &SocketAddr,
+             room: Option<&str>,
+             body: &str,
+             policies: &Policies,
+             now: Instant)
+             -> Result<String, ServerMessage> {
+        let room = self.clients.admit(addr, room, body, policies, now)?;
+        if let Err(limit) = self.rooms.borrow_mut().allow(&room, now) {
+            return Err(ServerMessage::Error(ErrorCode::RateLimited,
+                                            format!("{} allows {} messages per minute",
+                                                    room,
+                                                    limit)));
+        }
+        Ok(room)
+    }
+
+    // Move the client at `addr` into `room` (see `ConnectedClients::join`), as long as the room's
+    // policy (among `policies`, unless it was created with a limit of its own) has space for it,
+    // then tell it the room's topic, if it has one, and what else there is to know about the room.
+    // Only operators may join private rooms.
+    fn join(&self, addr: &SocketAddr, room: String, policies: &Policies) -> IoFuture<()> {
+        if self.rooms.borrow().is_private(&room) && !self.clients.is_admin(addr) {
+            let error = ServerMessage::Error(ErrorCode::Unauthorized,
+                                             format!("only operators can join {}", room));
+            return self.clients.send_to(addr, error);
+        }
+        let max_members = self.rooms.borrow().policy(&room, policies.for_room(&room)).max_members;
+
+        // The move is recorded before it's made, as long as `ConnectedClients::join` won't turn
+        // it away (a client that was is still in its old room, and doesn't need to hear about
+        // this one).
+        let old_room = self.clients.room_of(addr).filter(|old_room| *old_room != room);
+        let admitted = !room.is_empty() &&
+                       (self.clients.is_observer(addr) ||
+                        !self.clients.is_full(&room, max_members));
+        let recorded: IoFuture<()> = match old_room.filter(|_| admitted) {
+            Some(old_room) => {
+                let name = self.clients.name_of(addr);
+                let left = self.record(Event::Left {
+                    name: name.clone(),
+                    room: old_room,
+                });
+                let joined = Event::Joined {
+                    name: name,
+                    room: room.clone(),
+                };
+                let chat = self.clone();
+                Box::new(left.and_then(move |()| chat.record(joined)))
+            }
+            None => Box::new(future::ok(())),
+        };
+        let chat = self.clone();
+        let addr = *addr;
+        let policies = policies.clone();
+        Box::new(recorded.and_then(move |()| -> IoFuture<()> {
+            let joined = chat.clients.join(&addr, room.clone(), max_members);
+            follow_rooms(&chat.subscriptions, &chat.clients, &chat.handle);
+            if chat.clients.room_of(&addr).as_ref() != Some(&room) {
+                return joined;
+            }
+            let about = chat.topic(&room).into_iter().chain(Some(chat.room_info(&room, &policies)));
+            let clients = chat.clients.clone();
+            Box::new(joined.and_then(move |()| {
+                stream::iter(about.map(Ok)).for_each(move |msg| clients.send_to(&addr, msg))
+            }))
+        }))
+    }
+
+    // Create `room` with `config` for the client at `addr`, and tell it how the room turned out.
+    // The room mustn't exist already, and only operators may create private rooms.
+    fn create_room(&self,
+                   addr: &SocketAddr,
+                   room: String,
+                   config: RoomConfig,
+                   policies: &Policies)
+                   -> IoFuture<()> {
+        let refusal = if room.is_empty() {
+            Some((ErrorCode::InvalidMessage, "room names can't be empty".to_string()))
+        } else if config.private && !self.clients.is_admin(addr) {
+            Some((ErrorCode::Unauthorized, "only operators can create private rooms".to_string()))
+        } else if self.rooms.borrow().contains(&room) || self.clients.rooms().contains(&room) {
+            Some((ErrorCode::InvalidMessage, format!("{} already exists", room)))
+        } else if config.topic.as_ref().is_some_and(|topic| topic.len() > MAX_TOPIC_LEN) {
+            Some((ErrorCode::InvalidMessage,
+                  format!("topics are limited to {} bytes", MAX_TOPIC_LEN)))
+        } else {
+            None
+        };
+        if let Some((code, reason)) = refusal {
+            return self.clients.send_to(addr, ServerMessage::Error(code, reason));
+        }
+
+        println!("CREATED {} for {:?}", room, addr);
+        let created = self.record(Event::RoomCreated {
+            room: room.clone(),
+            config: config,
+        });
+        let (chat, addr, policies) = (self.clone(), *addr, policies.clone());
+        Box::new(created.and_then(move |()| {
+            chat.clients.send_to(&addr, chat.room_info(&room, &policies))
+        }))
+    }
+
+    // Tell the client at `addr` about `room`, unless it's private and the client isn't an
+    // operator.
+    fn get_room_info<E: 'static>(&self,
+                                 addr: &SocketAddr,
+                                 room: String,
+                                 policies: &Policies)
+                                 -> Box<Future<Item = (), Error = E>> {
+        if self.rooms.borrow().is_private(&room) && !self.clients.is_admin(addr) {
+            let error = ServerMessage::Error(ErrorCode::Unauthorized,
+                                             format!("{} is private", room));
+            return self.clients.send_to(addr, error);
+        }
+        self.clients.send_to(addr, self.room_info(&room, policies))
+    }
+
+    // The `RoomInfo` about `room`. One that wasn't created is described by its policy (among
+    // `policies`), as far as a `RoomConfig` can say.
+    fn room_info(&self, room: &str, policies: &Policies) -> ServerMessage {
+        let config = self.rooms.borrow().config(room).cloned().unwrap_or_else(|| {
+            let max_members = policies.for_room(room).max_members;
+            RoomConfig {
+                max_members: if max_members > 0 { Some(max_members as u32) } else { None },
+                ..RoomConfig::default()
+            }
+        });
+        ServerMessage::RoomInfo {
+            name: room.to_string(),
+            config: config,
+            member_count: self.clients.member_count(room) as u32,
+            topic: self.topics.borrow().get(room).cloned(),
+        }
+    }
+
+    // The `TopicChanged` that tells a client joining `room` what its topic is, if it has one.
+    fn topic(&self, room: &str) -> Option<ServerMessage> {
+        self.topics.borrow().get(room).map(|topic| {
+            ServerMessage::TopicChanged {
+                room: room.to_string(),
+                topic: topic.clone(),
+            }
+        })
+    }
+
+    // Set the topic of `room` to `topic` (or clear it, if that's empty) for the client at `addr`,
+    // and let the room know. The client has to be in the room, and if topics are `restricted`, be
+    // an operator.
+    fn set_topic(&self,
+                 addr: &SocketAddr,
+                 room: String,
+                 topic: String,
+                 restricted: bool)
+                 -> IoFuture<()> {
+        let refusal = if restricted && !self.clients.is_admin(addr) {
+            Some((ErrorCode::Unauthorized, "only operators can set topics".to_string()))
+        } else if self.clients.room_of(addr).as_ref() != Some(&room) {
+            Some((ErrorCode::InvalidMessage, format!("you're not in {}", room)))
+        } else if topic.len() > MAX_TOPIC_LEN {
+            Some((ErrorCode::InvalidMessage,
+                  format!("topics are limited to {} bytes", MAX_TOPIC_LEN)))
+        } else {
+            None
+        };
+        if let Some((code, reason)) = refusal {
+            return self.clients.send_to(addr, ServerMessage::Error(code, reason));
+        }
+
+        let set = self.record(Event::TopicSet {
+            room: room.clone(),
+            topic: topic.clone(),
+        });
+        let clients = self.clients.clone();
+        Box::new(set.and_then(move |()| {
+            let changed = ServerMessage::TopicChanged {
+                room: room.clone(),
+                topic: topic,
+            };
+            clients.broadcast_room(&room, changed)
+        }))
+    }
+
+    // Make an announcement for the client at `addr`, if it's an operator; see
+    // `ConnectedClients::announce`.
+    fn announce(&self, addr: &SocketAddr, text: String) -> IoFuture<()> {
+        if !self.clients.is_admin(addr) {
+            return self.clients.announce(addr, text);
+        }
+        let announced = self.record(Event::Announced {
+            by: self.clients.name_of(addr),
+            text: text.clone(),
+        });
+        let (clients, addr) = (self.clients.clone(), *addr);
+        Box::new(announced.and_then(move |()| clients.announce(&addr, text)))
+    }
+
+    // Send the client at `addr`, if it's an operator, the chat in `room` that the history still
+    // has. What's said after this doesn't make it in, even if it's said before the export is sent.
+    fn export(&self, addr: &SocketAddr, room: String) -> IoFuture<()> {
+        if !self.clients.is_admin(addr) {
+            let error = ServerMessage::Error(ErrorCode::Unauthorized,
+                                             "only operators can export rooms".to_string());
+            return self.clients.send_to(addr, error);
+        }
+        let messages = self.history.borrow().chat(0, &room);
+        println!("EXPORTED {} messages from {} for {:?}", messages.len(), room, addr);
+        let exported = self.record(Event::Exported {
+            by: self.clients.name_of(addr),
+            room: room.clone(),
+        });
+        let (clients, addr) = (self.clients.clone(), *addr);
+        Box::new(exported.and_then(move |()| {
+            clients.send_to(&addr,
+                            ServerMessage::ExportData {
+                                room: room,
+                                messages: messages,
+                            })
+        }))
+    }
+}
+
+// The answer to a `ClientMessage::Unknown`.
+fn unsupported(unknown: &ClientMessage) -> ServerMessage {
+    let reason = format!("unsupported message type {}", unknown.unknown_type().unwrap_or_default());
+    ServerMessage::Error(ErrorCode::UnsupportedMessageType, reason)
+}
+
+// A chat message numbered `id`, as the room hears it: a plain `Message`, unless it's in some
+// other format.
+fn chat_message(id: MessageId,
+                from: String,
+                body: String,
+                content_type: ContentType)
+                -> ServerMessage {
+    match content_type {
+        ContentType::PlainText => ServerMessage::Message(id, from, body),
+        content_type => {
+            ServerMessage::FormattedMessage {
+                id: id,
+                from: from,
+                body: body,
+                content_type: content_type,
+            }
+        }
+    }
+}
+
+// Serve chat to every client that connects to `listener`, according to `config`. The returned
+// future runs until accepting a connection fails; each connection is spawned onto `handle` as its
+// own task.
+pub fn serve(listener: TcpListener,
+             config: Config,
+             handle: Handle)
+             -> Box<Future<Item = (), Error = io::Error>> {
+    let config = Rc::new(config);
+    let clock = config.clock.clone();
+
+    // Every message a client sends passes through these before the server acts on it; see
+    // `MessageMiddleware`. The only one the server ships is the blocklist, if it was given one.
+    let mut middleware: Vec<Box<MessageMiddleware>> = Vec::new();
+    if !config.blocked.is_empty() {
+        middleware.push(Box::new(Blocklist::new(&config.blocked, config.block_mode)));
+    }
+    let middleware = Rc::new(middleware);
+
+    // Registered users, and threads to hash their passwords on.
+    let users = match store::open(config.db_url.as_deref()) {
+        Ok(users) => users,
+        Err(err) => return Box::new(future::err(err)),
+    };
+    let hasher = CpuPool::new_num_cpus();
+
+    // All the chat there's been, if we have a database to keep it in. Message numbers carry on
+    // from wherever the last server using the database left off.
+    let messages = match store::open_messages(config.db_url.as_deref()) {
+        Ok(messages) => messages,
+        Err(err) => return Box::new(future::err(err)),
+    };
+    let next_seq = messages.as_ref().map_or(0, |messages| messages.next_seq());
+
+    // Everything that's changed the rooms, if we're keeping a log of it; see `Event`.
+    let events = match config.event_log {
+        Some(ref path) => {
+            match JsonlEventLog::open(path) {
+                Ok(events) => Some(Rc::new(events) as Rc<EventLog>),
+                Err(err) => return Box::new(future::err(err)),
+            }
+        }
+        None => None,
+    };
+
+    // Recent chat, and the sessions of clients that disconnected recently enough to resume them.
+    // Numbering starts from the beginning for the event log's messages to get the numbers they
+    // had, and moves on to `next_seq` once they're in.
+    let history = Rc::new(RefCell::new(History::new(config.history_len, 0)));
+    let sessions = Rc::new(RefCell::new(Sessions::new(config.resume_grace, clock.clone())));
+
+    // Login tokens for registered users. Without a configured secret, we make one up, so tokens
+    // only last as long as the server does.
+    let secret = config.jwt_secret.clone().unwrap_or_else(session::new_token);
+    let tokens = Tokens::new(secret.as_bytes(), config.token_lifetime, clock.clone());
+    let tokens = Rc::new(RefCell::new(tokens));
+
+    // If we're one node of a cluster, the bus we share chat with the other nodes over, and the
+    // rooms we're listening to on it.
+    let bus = match (config.cluster.clone(), config.redis_url.as_ref()) {
+        (Some(bus), _) => Some(bus),
+        (None, Some(url)) => {
+            match RedisBackend::open(url) {
+                Ok(bus) => Some(Arc::new(bus) as SharedBus),
+                Err(err) => return Box::new(future::err(err)),
+            }
+        }
+        (None, None) => None,
+    };
+    let subscriptions = bus.map(|bus| Rc::new(RefCell::new(Subscriptions::new(bus))));
+
+    // How many connections each host has open.
+    let limits = Rc::new(RefCell::new(IpLimits::new(config.max_connections_per_ip)));
+
+    // And how many logins each has got wrong lately.
+    let throttler = match config.login_throttle_file {
+        Some(ref path) => LoginThrottler::open(path, config.login_throttle, clock.clone()),
+        None => Ok(LoginThrottler::new(config.login_throttle, clock.clone())),
+    };
+    let throttler = match throttler {
+        Ok(throttler) => Rc::new(RefCell::new(throttler)),
+        Err(err) => return Box::new(future::err(err)),
+    };
+
+    // If we're bridging rooms to XMPP, who's in them there, and the stanzas on their way to the
+    // XMPP server.
+    let (xmpp, xmpp_outbox) = match config.xmpp {
+        Some(ref xmpp) => {
+            let (tx, rx) = mpsc::unbounded();
+            (Some(Rc::new(XmppBridge::new(xmpp.domain.clone(), tx))), Some(rx))
+        }
+        None => (None, None),
+    };
+
+    // Likewise for what's on its way to Discord, if we're mirroring a room there.
+    let (discord, discord_outbox) = match config.discord {
+        Some(ref discord) => {
+            let (tx, rx) = mpsc::unbounded();
+            (Some(Rc::new(DiscordBridge::new(discord.room.clone(), tx))), Some(rx))
+        }
+        None => (None, None),
+    };
+
+    // And to Slack.
+    let (slack, slack_outbox) = match config.slack {
+        Some(ref slack) => {
+            let (tx, rx) = mpsc::unbounded();
+            (Some(Rc::new(SlackBridge::new(slack, tx))), Some(rx))
+        }
+        None => (None, None),
+    };
+
+    // Create our (currently empty) stash of clients, and the chat they'll be having.
+    let clients = ConnectedClients::new();
+    let chat = Chat {
+        clients: clients.clone(),
+        topics: Rc::new(RefCell::new(HashMap::new())),
+        rooms: Rc::new(RefCell::new(RoomRegistry::new())),
+        history: history.clone(),
+        messages: messages.clone(),
+        events: events.clone(),
+        subscriptions: subscriptions.clone(),
+        webhooks: Rc::new(Webhooks::new(config.webhooks.clone(),
+                                        config.webhook_timeout,
+                                        handle.clone())),
+        xmpp: xmpp,
+        discord: discord,
+        slack: slack,
+        clock: clock.clone(),
+        handle: handle.clone(),
+    };
+
+    // Put the rooms back the way the event log left them, before anything else gets to see them.
+    // This waits for the whole log, so an `EventLog` has to be able to replay it without the event
+    // loop's help, as one reading it from a file can.
+    if let Some(ref events) = events {
+        let mut replayed = 0;
+        for event in events.replay(0).wait() {
+            match event {
+                Ok(event) => chat.apply(&event),
+                Err(err) => return Box::new(future::err(err)),
+            }
+            replayed += 1;
+        }
+        println!("REPLAYED {} events", replayed);
+    }
+    history.borrow_mut().skip_to(next_seq);
+
+    // If asked to, report on the clients' traffic for Prometheus to scrape. That runs alongside
+    // the chat server; if it fails, chat carries on without it.
+    if let Some(metrics_addr) = config.metrics_addr {
+        match TcpListener::bind(&metrics_addr, &handle) {
+            Ok(listener) => {
+                let metrics = metrics::serve(listener, clients.clone(), handle.clone());
+                handle.spawn(metrics.map_err(|err| println!("METRICS failed: {}", err)));
+            }
+            Err(err) => return Box::new(future::err(err)),
+        }
+    }
+
+    // Likewise, bots and integrations can chat over HTTP if we're asked to let them; see `api`.
+    if let Some(http_addr) = config.http_addr {
+        let token = match config.http_token.clone() {
+            Some(token) => token,
+            None => {
+                return Box::new(future::err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                           "the HTTP API needs a token")))
+            }
+        };
+        match TcpListener::bind(&http_addr, &handle) {
+            Ok(listener) => {
+                let api = api::serve(listener,
+                                     token,
+                                     chat.clone(),
+                                     config.clone(),
+                                     middleware.clone(),
+                                     handle.clone());
+                handle.spawn(api.map_err(|err| println!("HTTP API failed: {}", err)));
+            }
+            Err(err) => return Box::new(future::err(err)),
+        }
+    }
+
+    // And IRC clients can connect on a port of their own; see `irc_gateway`.
+    if let Some(irc_addr) = config.irc_addr {
+        match TcpListener::bind(&irc_addr, &handle) {
+            Ok(listener) => {
+                let gateway = irc_gateway::serve(listener,
+                                                 chat.clone(),
+                                                 config.clone(),
+                                                 middleware.clone(),
+                                                 users.clone(),
+                                                 hasher.clone(),
+                                                 limits.clone(),
+                                                 throttler.clone());
+                handle.spawn(gateway.map_err(|err| println!("IRC GATEWAY failed: {}", err)));
+            }
+            Err(err) => return Box::new(future::err(err)),
+        }
+    }
+
+    // Then there's XMPP, where it's us that connects. If the connection fails or closes, the
+    // bridge stays down; chat here carries on regardless.
+    if let (Some(xmpp), Some(outbox)) = (config.xmpp.as_ref(), xmpp_outbox) {
+        let gateway = xmpp_gateway::connect(xmpp,
+                                            chat.clone(),
+                                            config.clone(),
+                                            middleware.clone(),
+                                            outbox);
+        handle.spawn(gateway.then(|r| {
+            println!("XMPP GATEWAY disconnected with result {:?}", r);
+            Ok(())
+        }));
+    }
+
+    // The same goes for Discord.
+    if let (Some(discord), Some(outbox)) = (config.discord.as_ref(), discord_outbox) {
+        let bridge = discord_bridge::connect(discord,
+                                             chat.clone(),
+                                             config.clone(),
+                                             middleware.clone(),
+                                             outbox);
+        handle.spawn(bridge.then(|r| {
+            println!("DISCORD BRIDGE stopped with result {:?}", r);
+            Ok(())
+        }));
+    }
+
+    // Slack's half of the bridge comes in through the HTTP API, so all there is to start here is
+    // posting to its webhook.
+    if let (Some(slack), Some(outbox)) = (config.slack.as_ref(), slack_outbox) {
+        let bridge = slack_bridge::forward(slack, outbox, config.webhook_timeout, handle.clone());
+        handle.spawn(bridge.then(|r| {
+            println!("SLACK BRIDGE stopped with result {:?}", r);
+            Ok(())
+        }));
+    }
+
+    // If clients can time out, check on them every second. (Timing is up to the clock; the timer
+    // just decides how often we look.)
+    if let Some(idle_timeout) = config.idle_timeout {
+        let interval = match Interval::new(Duration::from_secs(1), &handle) {
+            Ok(interval) => interval,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        let clients = clients.clone();
+        let clock = clock.clone();
+        handle.spawn(interval.map_err(|err| println!("IDLE TIMER failed: {}", err))
+            .for_each(move |()| clients.time_out_idle(clock.now(), idle_timeout)));
+    }
+
+    // What to do with each connection, once we know who it's from.
+    let proxy_protocol = config.proxy_protocol;
+    let handle_outer = handle.clone();
+    let accept = Rc::new(move |socket: TcpStream, addr: SocketAddr| -> io::Result<()> {
+        let socket = Deadlines::new(socket,
+                                    addr,
+                                    config.read_timeout,
+                                    config.write_timeout,
+                                    &handle);
+        let tracer = Tracer::new(addr, config.subscriber.clone());
+        tracer.event(ConnectionEvent::Accepted);
+        let hooks = Hooks::new(config.lifecycle.clone(), handle.clone());
+        hooks.connected(addr);
+
+        // Turn away hosts that already have as many connections open as they're allowed, before
+        // they get as far as handshaking. They're told why, then dropped.
+        if !limits.borrow_mut().acquire(addr.ip()) {
+            println!("REJECTED {:?}: too many connections", addr);
+            hooks.disconnected(None, addr, DisconnectReason::Refused);
+            let error = ServerMessage::Error(ErrorCode::TooManyConnections,
+                                             format!("at most {} connections per address",
+                                                     config.max_connections_per_ip));
+            handle.spawn(socket.framed(ServerToClientCodec::new())
+                .send(error)
+                .then(|_| Ok(())));
+            return Ok(());
+        }
+
+        // Frame the socket in a codec that will give us a `Handshake`.
+        let handshake_io = socket.framed(HandshakeCodec::new());
+
+        // `handshake_io` is a stream, but we just want to read a single `Handshake` off of it
+        // then convert the socket into a different kind of stream. `.into_future()` lets us
+        // do exactly that, giving us a `Future<Item=(Option<Handshake>, S)>` where `S` is the
+        // `handshake_io` itself.
+        //
+        // If an error occurs, we just want the error and can discard the stream.
+        // But a client that connects and then says nothing would hold its slot forever, so
+        // unless we've been told to wait, it only gets until `--handshake-timeout`.
+        let reading = handshake_io.into_future();
+        let handshake: IoFuture<_> = match config.handshake_timeout {
+            None => Box::new(reading.map_err(|(err, _)| err)),
+            Some(timeout) => {
+                handshake_within(reading, Timeout::new(timeout, &handle)?, timeout, addr)
+            }
+        };
+        let handshake = handshake.and_then(move |(h, io)| {
+                // `h` here is an `Option<Handshake>`. If we did not get a `Handshake`, throw
+                // an error. This can happen if a client connects then disconnects, for example.
+                // If we did get a handshake, log the client's name and return both the handshake
+                // and the unframed socket. (`io.into_inner()` removes the framing and gives back
+                // the underlying `Io` handle, which is `socket` in this case.
+                h.map_or_else(|| Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                              move |h| {
+                                  println!("CONNECTED from {:?} with name {}", addr, h.name);
+                                  Ok((h, io.into_inner()))
+                              })
+            });
+
+        // If the server requires a token, check the one the client presented before we do
+        // anything else with them. (An operator's admin token will do as well.) A client with a
+        // bad token is sent an `Unauthorized` error and then dropped; their name is never
+        // registered or announced. As with `broadcast`, the two branches here are different types
+        // of futures, so we box them. The name is put into its normal form first (see
+        // `validation`), and a client asking for a name that can't be is turned away the same
+        // way, with an `InvalidMessage` error. So is a client whose address has been locked out
+        // for getting logins wrong (see `LoginThrottler`), with a `TooManyAttempts` error, and a
+        // bad token counts as getting one wrong.
+        let config_inner = config.clone();
+        let throttler_inner = throttler.clone();
+        let authorized = handshake.and_then(move |(handshake, socket)| -> IoFuture<_> {
+            let handshake = match validation::normalize_username(&handshake.name) {
+                Ok(name) => Handshake { name: name, ..handshake },
+                Err(reason) => {
+                    println!("REJECTED {:?} with name {}: {}", addr, handshake.name, reason);
+                    let error = ServerMessage::Error(ErrorCode::InvalidMessage, reason);
+                    return Box::new(socket.framed(ServerToClientCodec::new())
+                        .send(error)
+                        .and_then(|_| {
+                            Err(io::Error::new(io::ErrorKind::PermissionDenied, "bad name"))
+                        }));
+                }
+            };
+            let locked_out = throttler_inner.borrow().locked_out(addr.ip());
+            if let Some(retry_after) = locked_out {
+                let reason = throttle::too_many_attempts(retry_after);
+                println!("REJECTED {:?} with name {}: {}", addr, handshake.name, reason);
+                let error = ServerMessage::Error(ErrorCode::TooManyAttempts, reason);
+                return Box::new(socket.framed(ServerToClientCodec::new())
+                    .send(error)
+                    .and_then(|_| {
+                        Err(io::Error::new(io::ErrorKind::PermissionDenied, "locked out"))
+                    }));
+            }
+            if auth::authorized(config_inner.token.as_deref(), &handshake) ||
+               auth::is_admin(config_inner.admin_token.as_deref(), &handshake) {
+                return Box::new(future::ok((handshake, socket)));
+            }
+
+            println!("REJECTED {:?} with name {}: bad token", addr, handshake.name);
+            throttler_inner.borrow_mut().failed(addr.ip());
+            let error = ServerMessage::Error(ErrorCode::Unauthorized,
+                                             "missing or incorrect token".to_string());
+            Box::new(socket.framed(ServerToClientCodec::new())
+                .send(error)
+                .and_then(|_| Err(io::Error::new(io::ErrorKind::PermissionDenied, "bad token"))))
+        });
+
+        // Next, log the client in, making sure it's entitled to the name it asked for (see
+        // `auth::sign_in`) and turning it away the same way if it isn't. A client resuming a
+        // session gets that session's name back, so it has nothing to prove. A wrong password or
+        // login token counts against the client's address, and getting in clears its slate.
+        let config_inner = config.clone();
+        let throttler_inner = throttler.clone();
+        let sessions_inner = sessions.clone();
+        let users_inner = users.clone();
+        let hasher_inner = hasher.clone();
+        let tokens_inner = tokens.clone();
+        let clock_inner = clock.clone();
+        let signed_in = authorized.and_then(move |(handshake, socket)| -> IoFuture<_> {
+            let admin = auth::is_admin(config_inner.admin_token.as_deref(), &handshake);
+            let resuming = handshake.resume_token
+                .as_ref()
+                .filter(|_| !handshake.observer)
+                .map_or(false, |token| sessions_inner.borrow().is_live(token));
+            if resuming {
+                return Box::new(future::ok((handshake, socket, admin, None)));
+            }
+
+            // A login token stands in for the password, as long as it checks out and is for the
+            // name the client asked for.
+            let claims = handshake.auth_token
+                .as_ref()
+                .map(|token| tokens_inner.borrow_mut().verify(token));
+            let sign_in = match claims {
+                Some(Some(ref claims)) if claims.sub == handshake.name => {
+                    auth::sign_in_with_token(users_inner.clone(),
+                                             &handshake.name,
+                                             admin,
+                                             clock_inner.unix_time())
+                }
+                Some(_) => Box::new(future::ok(SignIn::InvalidToken)),
+                None => {
+                    auth::sign_in(users_inner.clone(),
+                                  hasher_inner.clone(),
+                                  &handshake,
+                                  admin,
+                                  config_inner.allow_guests,
+                                  clock_inner.unix_time())
+                }
+            };
+            let tokens = tokens_inner.clone();
+            let clock = clock_inner.clone();
+            let throttler = throttler_inner.clone();
+            Box::new(sign_in.and_then(move |signed_in| -> IoFuture<_> {
+                let reason = match signed_in {
+                    SignIn::Admitted { admin } => {
+                        throttler.borrow_mut().succeeded(addr.ip());
+                        // Registered users who logged in with their password get a token for
+                        // next time, as do those whose token is about to run out.
+                        let issue = match claims {
+                            Some(Some(ref claims)) => claims.needs_refresh(clock.unix_time()),
+                            _ => handshake.password.is_some(),
+                        };
+                        let token = if issue {
+                            let (token, expires_in_secs) = tokens.borrow().issue(&handshake.name);
+                            Some(ServerMessage::Token {
+                                token: token,
+                                expires_in_secs: expires_in_secs,
+                            })
+                        } else {
+                            None
+                        };
+                        return Box::new(future::ok((handshake, socket, admin, token)));
+                    }
+                    SignIn::WrongPassword => "incorrect name or password",
+                    SignIn::GuestsNotAllowed => "this server doesn't allow guests",
+                    SignIn::InvalidToken => "invalid or expired login token",
+                };
+                println!("REJECTED {:?} with name {}: {}", addr, handshake.name, reason);
+                if signed_in != SignIn::GuestsNotAllowed {
+                    throttler.borrow_mut().failed(addr.ip());
+                }
+                let error = ServerMessage::Error(ErrorCode::Unauthorized, reason.to_string());
+                Box::new(socket.framed(ServerToClientCodec::new())
+                    .send(error)
+                    .and_then(move |_| {
+                        Err(io::Error::new(io::ErrorKind::PermissionDenied, reason))
+                    }))
+            }))
+        });
+
+        // Once the client is in, the next step is to welcome it and broadcast the
+        // `UserConnected` message.
+        let clients_inner = clients.clone();
+        let config_inner = config.clone();
+        let history_inner = history.clone();
+        let sessions_inner = sessions.clone();
+        let clock_inner = clock.clone();
+        let subscriptions_inner = subscriptions.clone();
+        let handle_inner = handle.clone();
+        let messages_inner = messages.clone();
+        let chat_inner = chat.clone();
+        let tracer_inner = tracer.clone();
+        let hooks_inner = hooks.clone();
+        let announce_connect = signed_in.and_then(move |(handshake, socket, admin, token)| {
+            let clients = clients_inner.clone();
+            let observer = handshake.observer;
+            let capabilities = capability::negotiate(&config_inner.capabilities,
+                                                     &handshake.capabilities);
+
+            // A client presenting a live resume token gets its old session back, including its
+            // old name (regardless of what it asked for this time). Observers don't have sessions
+            // to resume.
+            let session = handshake.resume_token
+                .as_ref()
+                .filter(|_| !observer)
+                .and_then(|token| sessions_inner.borrow_mut().resume(token));
+            let name = session.as_ref().map_or(handshake.name, |session| session.name.clone());
+
+            // Create the Sender/Receiver pairs for this newly-connected client, and store the
+            // Senders in our HashMap. The two Receivers are merged into a single stream that
+            // always yields pending control messages ahead of pending chat messages.
+            let (control_tx, control_rx) = mpsc::channel(8);
+            let (chat_tx, chat_rx) = mpsc::channel(8);
+            let mut client = Client::new(control_tx, chat_tx, name.clone(), clock_inner.now());
+            client.admin = admin;
+            client.observer = observer;
+            client.capabilities = capabilities.clone();
+            let missed: IoFuture<Vec<ServerMessage>> = match session {
+                Some(session) => {
+                    println!("RESUMED session of {} in {}", name, session.room);
+                    client.room = session.room;
+                    client.status = session.status;
+                    let history = history_inner.borrow();
+                    match messages_inner {
+                        // If we've forgotten some of what the client missed, the database hasn't.
+                        // It has every message as last edited, so edits needn't be replayed.
+                        Some(ref messages) if !history.covers(session.missed_from) => {
+                            let stored = messages.get_since(&client.room,
+                                                            session.missed_from,
+                                                            MAX_REPLAY_LEN);
+                            Box::new(stored.map(|stored| {
+                                stored.into_iter()
+                                    .filter(|msg| !msg.deleted)
+                                    .map(|msg| {
+                                        let body = msg.current_body().to_string();
+                                        ServerMessage::Message(msg.seq, msg.from_user, body)
+                                    })
+                                    .collect()
+                            }))
+                        }
+                        _ => {
+                            let missed = history.since(session.missed_from, &client.room);
+                            Box::new(future::ok(missed))
+                        }
+                    }
+                }
+                None => Box::new(future::ok(Vec::new())),
+            };
+            let welcome = ServerMessage::Welcome {
+                resume_token: client.resume_token.clone(),
+                capabilities: capabilities,
+            };
+            let stats = client.stats.clone();
+            let topic = chat_inner.topic(&client.room);
+            tracer_inner.named(&name);
+            tracer_inner.event(ConnectionEvent::Handshake);
+            tracer_inner.event(ConnectionEvent::Joined(&client.room));
+            hooks_inner.logged_in(&name, addr);
+            let rx = Prioritized::new(control_rx, chat_rx);
+
+            // The client only joins its room once that's been recorded.
+            let recorded = chat_inner.record(Event::Joined {
+                name: name.clone(),
+                room: client.room.clone(),
+            });
+            let joined = recorded.map({
+                let clients = clients.clone();
+                let subscriptions = subscriptions_inner.clone();
+                let handle = handle_inner.clone();
+                move |()| {
+                    clients.insert(addr, client);
+                    follow_rooms(&subscriptions, &clients, &handle);
+                }
+            });
+
+            // Welcome the client (handing it a login token, the message of the day and its room's
+            // topic, if it's getting them), broadcast the message (unless it's an observer, which
+            // arrive unannounced), then replay anything a resumed client missed. Finally, send
+            // this client's name, `mpsc::Receiver`, socket and stats as the `Item` of this future.
+            let motd = config_inner.motd.clone().map(ServerMessage::Motd);
+            let greeting = Some(welcome).into_iter().chain(token).chain(motd).chain(topic);
+            let greeting = joined.and_then({
+                let clients = clients.clone();
+                move |()| {
+                    stream::iter(greeting.map(Ok)).for_each(move |msg| clients.send_to(&addr, msg))
+                }
+            });
+            let replay = missed.and_then({
+                let clients = clients.clone();
+                move |missed| {
+                    stream::iter(missed.into_iter().map(Ok))
+                        .for_each(move |msg| clients.send_to(&addr, msg))
+                }
+            });
+            greeting
+                .and_then(move |()| -> IoFuture<_> {
+                    if observer {
+                        return Box::new(future::ok(name));
+                    }
+                    let connected = ServerMessage::UserConnected(name.clone());
+                    Box::new(clients.broadcast(connected).map(|()| name))
+                })
+                .and_then(|name| replay.map(|()| name))
+                .map(|name| (name, rx, socket, stats))
+        });
+
+        // After broadcasting the announcment, the next step is to set up the futures that
+        // sit on top of the reading/writing of the socket.
+        let clients_inner = clients.clone();
+        let config_inner = config.clone();
+        let middleware_inner = middleware.clone();
+        let history_inner = history.clone();
+        let users_inner = users.clone();
+        let hasher_inner = hasher.clone();
+        let tokens_inner = tokens.clone();
+        let clock_inner = clock.clone();
+        let messages_inner = messages.clone();
+        let chat_inner = chat.clone();
+        let tracer_inner = tracer.clone();
+        let connection = announce_connect.and_then(move |(name, rx, socket, stats)| {
+            // Frame the socket in a codec that lets us receive `ClientMessage`s and send
+            // `ServerMessage`s. We use the lenient flavor so that a message we can't make sense
+            // of doesn't cost the client its connection; see `bad_frames` below. Nor does a
+            // message to the client that we can't encode (see `SkipUnencodable`). Everything that
+            // goes through it is counted in the client's `stats`.
+            let codec = StatsCodec::with_stats(LenientServerToClientCodec::new(), stats.clone());
+            let codec = SkipUnencodable::new(codec, addr);
+            let (to_client, from_client) = socket.framed(codec).split();
+            let mut bad_frames = 0;
+
+            // Each incoming message first runs the middleware gauntlet. For each
+            // `ClientMessage::Message` that survives, make sure it's acceptable in the sender's
+            // room, then attach the sending client's `name` and broadcast the resulting
+            // `ServerMessage::Message` to everyone in the room (on every node, in a cluster).
+            // `Join`s just move the client (and tell it the new room's topic), file offers and
+            // chunks are checked and relayed to the rest of the room, operators' announcements go
+            // out to everybody, new topics to their rooms, and registrations are passed on to the
+            // user store. Edits are held to the same rules as new messages, in the room the
+            // original was sent to, and only its author may make them. Users may revoke their own
+            // login tokens.
+            //
+            // A message that doesn't decode is answered with an `InvalidMessage` error and
+            // otherwise skipped, unless the client has sent more than `max_bad_frames` of them in
+            // a row, in which case we give up on it. One of a type we don't know most likely
+            // comes from a newer client, so it decodes as a `ClientMessage::Unknown`, which is
+            // answered with an `UnsupportedMessageType` error instead, and doesn't count against
+            // the client. With `strict_protocol`, nor does one that only has fields we don't know,
+            // which gets an error saying which.
+            let reader = from_client.for_each(move |msg| -> IoFuture<()> {
+                let now = clock_inner.now();
+                clients_inner.touch(&addr, now);
+                let mut msg = match msg {
+                    Ok(unknown @ ClientMessage::Unknown(_)) => {
+                        println!("UNKNOWN MESSAGE from {:?}: {}", addr, unknown);
+                        bad_frames = 0;
+                        tracer_inner.event(ConnectionEvent::Message(&unknown));
+                        return clients_inner.send_to(&addr, unsupported(&unknown));
+                    }
+                    Ok(msg) => {
+                        bad_frames = 0;
+                        tracer_inner.event(ConnectionEvent::Message(&msg));
+                        msg
+                    }
+                    Err(err) => {
+                        println!("BAD MESSAGE from {:?}: {}", addr, err);
+                        stats.decode_errors.fetch_add(1, Ordering::Relaxed);
+                        if let Some(field) = unknown_field(&err)
+                            .filter(|_| config_inner.strict_protocol) {
+                            let reason = format!("unknown fields in message: {}", field);
+                            let error = ServerMessage::Error(ErrorCode::InvalidMessage, reason);
+                            return clients_inner.send_to(&addr, error);
+                        }
+                        bad_frames += 1;
+                        if bad_frames > config_inner.max_bad_frames {
+                            return Box::new(future::err(io::Error::new(io::ErrorKind::InvalidData,
+                                                                       "too many bad messages")));
+                        }
+                        let reason = format!("couldn't decode message: {}", err);
+                        let error = ServerMessage::Error(ErrorCode::InvalidMessage, reason);
+                        return clients_inner.send_to(&addr, error);
+                    }
+                };
+
+                // Optional features can only be used if they were agreed on.
+                if let Some(capability) = msg.capability() {
+                    if !clients_inner.has_capability(&addr, capability) {
+                        let reason = format!("the {} capability wasn't negotiated", capability);
+                        let error = ServerMessage::Error(ErrorCode::InvalidMessage, reason);
+                        return clients_inner.send_to(&addr, error);
+                    }
+                }
+
+                // Observers can look around (and check they're still connected), but that's all.
+                if clients_inner.is_observer(&addr) {
+                    match msg {
+                        ClientMessage::Join(_) |
+                        ClientMessage::Who |
+                        ClientMessage::GetRoomInfo(_) |
+                        ClientMessage::Ping(_) => {}
+                        _ => {
+                            let error = ServerMessage::Error(ErrorCode::Unauthorized,
+                                                             "observers can't send messages"
+                                                                 .to_string());
+                            return clients_inner.send_to(&addr, error);
+                        }
+                    }
+                }
+
+                match clients_inner.filter(&addr, &mut msg, &middleware_inner) {
+                    MiddlewareAction::Allow | MiddlewareAction::Modify => {}
+                    MiddlewareAction::Drop => return Box::new(future::ok(())),
+                    MiddlewareAction::Error(reason) => {
+                        let error = ServerMessage::Error(ErrorCode::InvalidMessage, reason);
+                        return clients_inner.send_to(&addr, error);
+                    }
+                }
+
+                match msg {
+                    ClientMessage::Message(body) => {
+                        match chat_inner.admit(&addr,
+                                                None,
+                                                &body,
+                                                &config_inner.policies,
+                                                now) {
+                            Ok(room) => Box::new(chat_inner.say(&room, &name, body).map(|_| ())),
+                            Err(error) => clients_inner.send_to(&addr, error),
+                        }
+                    }
+                    ClientMessage::FormattedMessage { body, content_type } => {
+                        let body = match content_type {
+                            ContentType::Html => sanitize::html(&body),
+                            ContentType::Unsupported(ref name) => {
+                                let error = ServerMessage::Error(ErrorCode::UnsupportedContentType,
+                                                                 format!("unsupported content \
+                                                                          type {}",
+                                                                         name));
+                                return clients_inner.send_to(&addr, error);
+                            }
+                            _ => body,
+                        };
+                        match chat_inner.admit(&addr,
+                                                None,
+                                                &body,
+                                                &config_inner.policies,
+                                                now) {
+                            Ok(room) => {
+                                Box::new(chat_inner.say_formatted(&room, &name, body, content_type)
+                                    .map(|_| ()))
+                            }
+                            Err(error) => clients_inner.send_to(&addr, error),
+                        }
+                    }
+                    ClientMessage::EditMessage { id, new_body } => {
+                        // Edits to HTML are sanitized just like the original was.
+                        let original = history_inner.borrow().get(id).and_then(|(room, msg)| {
+                            match *msg {
+                                ServerMessage::Message(_, ref from, _) => {
+                                    Some((room.to_string(), from.clone(), false))
+                                }
+                                ServerMessage::FormattedMessage {
+                                    ref from, ref content_type, ..
+                                } => {
+                                    let html = *content_type == ContentType::Html;
+                                    Some((room.to_string(), from.clone(), html))
+                                }
+                                _ => None,
+                            }
+                        });
+                        let (room, new_body) = match original {
+                            Some((room, ref from, html)) if *from == name => {
+                                (room, if html { sanitize::html(&new_body) } else { new_body })
+                            }
+                            Some(_) => {
+                                let error = ServerMessage::Error(ErrorCode::Unauthorized,
+                                                                 "you can only edit your own \
+                                                                  messages"
+                                                                     .to_string());
+                                return clients_inner.send_to(&addr, error);
+                            }
+                            None => {
+                                let error = ServerMessage::Error(ErrorCode::InvalidMessage,
+                                                                 format!("no message {} to edit",
+                                                                         id));
+                                return clients_inner.send_to(&addr, error);
+                            }
+                        };
+                        match chat_inner.admit(&addr,
+                                                Some(&room),
+                                                &new_body,
+                                                &config_inner.policies,
+                                                now) {
+                            Ok(room) => {
+                                let edited = chat_inner.record(Event::Edited {
+                                    id: id,
+                                    room: room.clone(),
+                                    new_body: new_body.clone(),
+                                });
+                                let chat = chat_inner.clone();
+                                let messages = messages_inner.clone();
+                                Box::new(edited.and_then(move |()| {
+                                    if let Some(ref messages) = messages {
+                                        chat.handle.spawn(messages.edit(id, &new_body)
+                                            .map_err(|err| println!("STORE failed: {}", err)));
+                                    }
+                                    chat.webhooks.notify(&room,
+                                                         EventFilter::Edits,
+                                                         vec![("seq", Value::U64(id)),
+                                                              ("body",
+                                                               Value::String(new_body.clone()))]);
+                                    let msg = ServerMessage::MessageEdited {
+                                        id: id,
+                                        new_body: new_body,
+                                    };
+                                    chat.clients.broadcast_room(&room, msg)
+                                }))
+                            }
+                            Err(error) => clients_inner.send_to(&addr, error),
+                        }
+                    }
+                    ClientMessage::Join(room) => {
+                        let joined = chat_inner.join(&addr, room.clone(), &config_inner.policies);
+                        let clients = clients_inner.clone();
+                        let tracer = tracer_inner.clone();
+                        Box::new(joined.map(move |()| {
+                            if clients.room_of(&addr).as_ref() == Some(&room) {
+                                tracer.event(ConnectionEvent::Joined(&room));
+                            }
+                        }))
+                    }
+                    ClientMessage::CreateRoom { name: room, config: room_config } => {
+                        chat_inner.create_room(&addr, room, room_config, &config_inner.policies)
+                    }
+                    ClientMessage::GetRoomInfo(room) => {
+                        chat_inner.get_room_info(&addr, room, &config_inner.policies)
+                    }
+                    ClientMessage::SetStatus(status) => clients_inner.set_status(&addr, status),
+                    ClientMessage::Who => clients_inner.who(&addr),
+                    ClientMessage::FileOffer { transfer_id, name, size, chunk_count } => {
+                        clients_inner.offer_file(&addr,
+                                                 transfer_id,
+                                                 name,
+                                                 size,
+                                                 chunk_count,
+                                                 config_inner.max_file_size)
+                    }
+                    ClientMessage::FileChunk { transfer_id, index, data } => {
+                        clients_inner.relay_chunk(&addr, transfer_id, index, data)
+                    }
+                    ClientMessage::AdminAnnounce(text) => chat_inner.announce(&addr, text),
+                    ClientMessage::SetTopic { room, topic } => {
+                        chat_inner.set_topic(&addr, room, topic, config_inner.restrict_topics)
+                    }
+                    ClientMessage::AdminExport(room) => chat_inner.export(&addr, room),
+                    ClientMessage::Ping(id) => {
+                        clients_inner.send_to(&addr, ServerMessage::Pong(id))
+                    }
+                    // Middleware may have made one of these out of a message we know.
+                    unknown @ ClientMessage::Unknown(_) => {
+                        clients_inner.send_to(&addr, unsupported(&unknown))
+                    }
+                    ClientMessage::Register { username, password } => {
+                        let clients = clients_inner.clone();
+                        let registered = auth::register(users_inner.clone(),
+                                                        hasher_inner.clone(),
+                                                        config_inner.password_cost,
+                                                        username,
+                                                        password,
+                                                        clock_inner.unix_time());
+                        Box::new(registered.and_then(move |registered| {
+                            let reply = match registered {
+                                Ok(username) => {
+                                    println!("REGISTERED {}", username);
+                                    ServerMessage::Registered(username)
+                                }
+                                Err(reason) => ServerMessage::Error(ErrorCode::InvalidMessage,
+                                                                    reason),
+                            };
+                            clients.send_to(&addr, reply)
+                        }))
+                    }
+                    ClientMessage::RevokeToken { token } => {
+                        let mut tokens = tokens_inner.borrow_mut();
+                        let (code, reason) = match tokens.verify(&token) {
+                            Some(ref claims) if claims.sub == name => {
+                                tokens.revoke(claims);
+                                return Box::new(future::ok(()));
+                            }
+                            Some(_) => (ErrorCode::Unauthorized, "that token isn't yours"),
+                            None => (ErrorCode::InvalidMessage, "invalid or expired login token"),
+                        };
+                        clients_inner.send_to(&addr, ServerMessage::Error(code, reason.to_string()))
+                    }
+                }
+            });
+
+            // Writing to the socket involves receiving messages on the channels that were
+            // initially created in `announce_connect` above and sent to us as part of the
+            // `Item` type of that future.
+            let writer = rx
+                .map_err(|()| unreachable!("rx can't fail"))
+
+                // `fold` seems to be the most straightforward way to handle this. It takes
+                // an initial value of `to_client` (the sending half of the framed socket);
+                // for each message, it tries to send the message, and the future returned
+                // by `to_client.send` gives back `to_client` itself on success, ready for the
+                // next step of the fold. An `IdleTimeout` or `Flooding` error is the last thing a
+                // client gets: once it's sent, we fail the fold to hang up.
+                .fold(to_client, |to_client, msg| {
+                    let hang_up = match msg {
+                        ServerMessage::Error(ErrorCode::IdleTimeout, _) => {
+                            Some((io::ErrorKind::TimedOut, "idle too long"))
+                        }
+                        ServerMessage::Error(ErrorCode::Flooding, _) => {
+                            Some((io::ErrorKind::InvalidData, "flooding"))
+                        }
+                        _ => None,
+                    };
+                    to_client.send(msg).and_then(move |to_client| {
+                        if let Some((kind, why)) = hang_up {
+                            return Err(io::Error::new(kind, why));
+                        }
+                        Ok(to_client)
+                    })
+                })
+
+                // Once the rx stream is exhausted (because the sender has been dropped), we
+                // no longer need the writing half of the socket, so discard it.
+                .map(|_| ());
+
+            // Use select to allow either the reading or writing half dropping to drop the other
+            // half. The `map` and `map_err` here effectively force this drop.
+            reader.select(writer).map(|_| ()).map_err(|(err, _)| err)
+        });
+
+        // Finally, spawn off the connection.
+        let clients_inner = clients.clone();
+        let history_inner = history.clone();
+        let sessions_inner = sessions.clone();
+        let limits_inner = limits.clone();
+        let users_inner = users.clone();
+        let clock_inner = clock.clone();
+        let subscriptions_inner = subscriptions.clone();
+        let handle_inner = handle.clone();
+        let chat_inner = chat.clone();
+        handle.spawn(connection.then(move |r| {
+            println!("DISCONNECTED from {:?} with result {:?}", addr, r);
+            tracer.event(ConnectionEvent::Disconnected);
+            limits_inner.borrow_mut().release(addr.ip());
+
+            // When a client disconnects, we want to send a message to all remaining clients. This
+            // is a little tricky since `msg` here is an `Option<Client>` (and will be `None`
+            // if this disconnection is a client who never sent a `Handshake`). The natural thing
+            // to try to write here is something like
+            //
+            //      if let Some(msg) = msg {
+            //          clients_inner.broadcast(msg)
+            //      } else {
+            //          // ... now what? has to have the same return type as the `if branch`
+            //      }
+            //
+            // but we can sidestep this by taking advantage of the fact that `Option` can also
+            // act as an `Iterator` over its single (or no) element, convert that to a `Stream`
+            // via `stream::iter`, then `fold` over the 0-or-1 long stream to send the message.
+            //
+            // A client that goes away also leaves its session behind, in case it comes back, and
+            // is marked as last seen now. Observers do none of this.
+            let client = clients_inner.remove(&addr);
+            hooks.disconnected(client.as_ref().map(|client| client.name.as_str()),
+                               addr,
+                               DisconnectReason::of(r));
+            // There's nobody left to tell if the log can't keep this, and `record` has already
+            // said so.
+            if let Some(ref client) = client {
+                let left = chat_inner.record(Event::Left {
+                    name: client.name.clone(),
+                    room: client.room.clone(),
+                });
+                handle_inner.spawn(left.map_err(|_| ()));
+            }
+            let msg = client.filter(|client| !client.observer).map(|client| {
+                let seen = store::seen(users_inner.clone(), &client.name, clock_inner.unix_time());
+                handle_inner.spawn(seen.map_err(|err| println!("STORE failed: {}", err)));
+                sessions_inner.borrow_mut().suspend(client.resume_token,
+                                                    client.name.clone(),
+                                                    client.room,
+                                                    client.status,
+                                                    history_inner.borrow().next_seq());
+                ServerMessage::UserDisconnected(client.name)
+            });
+            follow_rooms(&subscriptions_inner, &clients_inner, &handle_inner);
+            stream::iter(msg.map(|m| Ok(m))).fold((), move |(), m| clients_inner.broadcast(m))
+        }));
+
+        Ok(())
+    });
+
+    // Behind a load balancer, who a connection's from is whatever its PROXY header says; see
+    // `proxy`. Until the header's in, the connection isn't counted against anyone's limit.
+    Box::new(listener.incoming().for_each(move |(socket, peer)| {
+        if !proxy_protocol {
+            return accept(socket, peer);
+        }
+        let accept = accept.clone();
+        handle_outer.spawn(proxy::read_header(socket, peer).then(move |result| {
+            match result {
+                Ok((socket, addr)) => accept(socket, addr),
+                Err(err) => {
+                    println!("REJECTED {:?}: {}", peer, err);
+                    Ok(())
+                }
+            }
+        }).map_err(|_: io::Error| ()));
+        Ok(())
+    }))
+}
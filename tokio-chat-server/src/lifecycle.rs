@@ -0,0 +1,191 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+use futures::{future, Future};
+use futures_cpupool::CpuPool;
+use tokio_chat_common::rfc3339;
+use tokio_core::reactor::Handle;
+
+use clock::SharedClock;
+
+// Hooks for programs running the server themselves, to hear when clients connect, log in and
+// disconnect, and do something about it of their own: audit logging, say, or keeping count
+// somewhere else. Each hook gets a future to do its work in, which the server runs in the
+// background, so a slow hook holds nothing up; what it makes of the event is its own business.
+// Every hook does nothing unless it's implemented.
+//
+// Unlike a `Subscriber`, which hears everything that happens on a connection, these only hear
+// about its beginning and end, with a reason for the end.
+pub trait ConnectionLifecycle {
+    // A connection from `peer` (the client's address, as given by a PROXY protocol header if the
+    // server takes those) was accepted, and is about to handshake.
+    fn on_connect(&self, _peer: SocketAddr) -> LifecycleFuture {
+        Box::new(future::ok(()))
+    }
+
+    // The client at `peer` handshaked and was let in as `username`.
+    fn on_login(&self, _username: &str, _peer: SocketAddr) -> LifecycleFuture {
+        Box::new(future::ok(()))
+    }
+
+    // The connection from `peer` is gone, for `reason`. `username` is the name it logged in as,
+    // if it got that far.
+    fn on_disconnect(&self,
+                     _username: Option<&str>,
+                     _peer: SocketAddr,
+                     _reason: DisconnectReason)
+                     -> LifecycleFuture {
+        Box::new(future::ok(()))
+    }
+}
+
+pub type LifecycleFuture = Box<Future<Item = (), Error = ()>>;
+
+pub type SharedLifecycle = Arc<ConnectionLifecycle + Send + Sync>;
+
+// Why a connection ended.
+#[derive(Debug)]
+pub enum DisconnectReason {
+    // The client hung up.
+    Closed,
+
+    // The client was turned away: it had too many connections open, a bad token, or no right to
+    // the name it asked for.
+    Refused,
+
+    // The client went quiet for too long, before its handshake or after it, or stopped reading
+    // what it was sent.
+    TimedOut,
+
+    // The client sent too many messages that didn't decode, or too many over the rate limit.
+    Misbehaved,
+
+    // The connection failed.
+    Error(io::Error),
+}
+
+impl DisconnectReason {
+    // Why a connection that finished with `result` ended.
+    pub fn of(result: io::Result<()>) -> DisconnectReason {
+        let err = match result {
+            Ok(()) => return DisconnectReason::Closed,
+            Err(err) => err,
+        };
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => DisconnectReason::Closed,
+            io::ErrorKind::PermissionDenied => DisconnectReason::Refused,
+            io::ErrorKind::TimedOut => DisconnectReason::TimedOut,
+            io::ErrorKind::InvalidData => DisconnectReason::Misbehaved,
+            _ => DisconnectReason::Error(err),
+        }
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DisconnectReason::Closed => write!(f, "closed"),
+            DisconnectReason::Refused => write!(f, "refused"),
+            DisconnectReason::TimedOut => write!(f, "timed out"),
+            DisconnectReason::Misbehaved => write!(f, "misbehaved"),
+            DisconnectReason::Error(ref err) => write!(f, "error: {}", err),
+        }
+    }
+}
+
+// A connection's way to its server's `ConnectionLifecycle`, if it has one, running each hook's
+// future on `handle`.
+#[derive(Clone)]
+pub struct Hooks {
+    lifecycle: Option<SharedLifecycle>,
+    handle: Handle,
+}
+
+impl Hooks {
+    pub fn new(lifecycle: Option<SharedLifecycle>, handle: Handle) -> Hooks {
+        Hooks {
+            lifecycle: lifecycle,
+            handle: handle,
+        }
+    }
+
+    pub fn connected(&self, peer: SocketAddr) {
+        if let Some(ref lifecycle) = self.lifecycle {
+            self.handle.spawn(lifecycle.on_connect(peer));
+        }
+    }
+
+    pub fn logged_in(&self, username: &str, peer: SocketAddr) {
+        if let Some(ref lifecycle) = self.lifecycle {
+            self.handle.spawn(lifecycle.on_login(username, peer));
+        }
+    }
+
+    pub fn disconnected(&self, username: Option<&str>, peer: SocketAddr, reason: DisconnectReason) {
+        if let Some(ref lifecycle) = self.lifecycle {
+            self.handle.spawn(lifecycle.on_disconnect(username, peer, reason));
+        }
+    }
+}
+
+// A `ConnectionLifecycle` that writes a line for each event, stamped with the time by `clock`, to
+// whatever it's given: a file, most likely, or stderr. Writing may block, so lines are written out
+// on a thread of their own, one at a time and in order, as `JsonlEventLog` does.
+//
+// ```text
+// 2017-03-14T15:09:26.535897Z CONNECT 127.0.0.1:50123
+// 2017-03-14T15:09:26.541002Z LOGIN alice 127.0.0.1:50123
+// 2017-03-14T15:10:02.100733Z DISCONNECT alice 127.0.0.1:50123 closed
+// ```
+//
+// A connection that never logged in is written as `-` in place of a name.
+pub struct AuditLogger {
+    out: Arc<Mutex<Box<Write + Send>>>,
+    clock: SharedClock,
+    pool: CpuPool,
+}
+
+impl AuditLogger {
+    pub fn new<W: Write + Send + 'static>(out: W, clock: SharedClock) -> AuditLogger {
+        AuditLogger {
+            out: Arc::new(Mutex::new(Box::new(out))),
+            clock: clock,
+            pool: CpuPool::new(1),
+        }
+    }
+
+    fn log(&self, line: fmt::Arguments) -> LifecycleFuture {
+        let now = UNIX_EPOCH + Duration::from_millis(self.clock.unix_time_ms());
+        let at = rfc3339::format(now).unwrap_or_else(|_| "-".to_string());
+        let line = format!("{} {}\n", at, line);
+        let out = self.out.clone();
+        Box::new(self.pool.spawn_fn(move || {
+            let mut out = out.lock().expect("the audit log's writer panicked");
+            if let Err(err) = out.write_all(line.as_bytes()).and_then(|()| out.flush()) {
+                println!("AUDIT LOG failed: {}", err);
+            }
+            Ok(())
+        }))
+    }
+}
+
+impl ConnectionLifecycle for AuditLogger {
+    fn on_connect(&self, peer: SocketAddr) -> LifecycleFuture {
+        self.log(format_args!("CONNECT {}", peer))
+    }
+
+    fn on_login(&self, username: &str, peer: SocketAddr) -> LifecycleFuture {
+        self.log(format_args!("LOGIN {} {}", username, peer))
+    }
+
+    fn on_disconnect(&self,
+                     username: Option<&str>,
+                     peer: SocketAddr,
+                     reason: DisconnectReason)
+                     -> LifecycleFuture {
+        self.log(format_args!("DISCONNECT {} {} {}", username.unwrap_or("-"), peer, reason))
+    }
+}
@@ -0,0 +1,43 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+// How many connections are open from each IP address, so that no single host can hog the server.
+// Connections are counted from the moment they're accepted, handshake or no handshake.
+pub struct IpLimits {
+    max_per_ip: usize,
+    counts: HashMap<IpAddr, usize>,
+}
+
+impl IpLimits {
+    pub fn new(max_per_ip: usize) -> IpLimits {
+        IpLimits {
+            max_per_ip: max_per_ip,
+            counts: HashMap::new(),
+        }
+    }
+
+    // Count a new connection from `ip`, unless `ip` is already at its limit. Every successful
+    // `acquire` must be matched by a `release` once the connection closes.
+    pub fn acquire(&mut self, ip: IpAddr) -> bool {
+        let count = self.counts.entry(ip).or_insert(0);
+        if *count >= self.max_per_ip {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    pub fn release(&mut self, ip: IpAddr) {
+        let gone = match self.counts.get_mut(&ip) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+        // Forget addresses with nothing open so the map doesn't grow with every host ever seen.
+        if gone {
+            self.counts.remove(&ip);
+        }
+    }
+}
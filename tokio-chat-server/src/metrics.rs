@@ -0,0 +1,84 @@
+use std::fmt::Write;
+use std::io;
+
+use futures::{Future, Stream};
+use tokio_core::io::{read, write_all};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Handle;
+
+use tokio_chat_common::CodecStatsSnapshot;
+
+use ConnectedClients;
+
+// Answer every connection to `listener` with the current per-connection codec stats, in the
+// Prometheus text exposition format. This is deliberately about as simple as an HTTP server can
+// be: whatever the request was, it reads (up to) the first chunk of it, sends the metrics back,
+// and closes the connection.
+pub fn serve(listener: TcpListener,
+             clients: ConnectedClients,
+             handle: Handle)
+             -> Box<Future<Item = (), Error = io::Error>> {
+    Box::new(listener.incoming().for_each(move |(socket, _)| {
+        let clients = clients.clone();
+        let response = read(socket, vec![0; 1024]).and_then(move |(socket, _, _)| {
+            let body = render(&clients);
+            let response = format!("HTTP/1.0 200 OK\r\n\
+                                    Content-Type: text/plain; version=0.0.4\r\n\
+                                    Content-Length: {}\r\n\
+                                    \r\n\
+                                    {}",
+                                   body.len(),
+                                   body);
+            write_all(socket, response.into_bytes())
+        });
+        handle.spawn(response.then(|_| Ok(())));
+        Ok(())
+    }))
+}
+
+// The counters we export: metric name, help text, and how to get the value from a snapshot.
+const METRICS: &[(&str, &str, fn(&CodecStatsSnapshot) -> u64)] =
+    &[("chat_connection_messages_decoded_total",
+       "Messages received from each connected client.",
+       |stats| stats.messages_decoded),
+      ("chat_connection_bytes_decoded_total",
+       "Bytes of whole frames received from each connected client.",
+       |stats| stats.bytes_decoded),
+      ("chat_connection_decode_errors_total",
+       "Frames from each connected client that couldn't be decoded.",
+       |stats| stats.decode_errors),
+      ("chat_connection_messages_encoded_total",
+       "Messages sent to each connected client.",
+       |stats| stats.messages_encoded),
+      ("chat_connection_bytes_encoded_total",
+       "Bytes of whole frames sent to each connected client.",
+       |stats| stats.bytes_encoded),
+      ("chat_connection_encode_errors_total",
+       "Messages to each connected client that couldn't be encoded.",
+       |stats| stats.encode_errors)];
+
+fn render(clients: &ConnectedClients) -> String {
+    let mut connections = clients.stats();
+    connections.sort_by_key(|&(addr, _, _)| addr);
+
+    let mut out = String::new();
+    for &(metric, help, value) in METRICS {
+        let _ = writeln!(out, "# HELP {} {}", metric, help);
+        let _ = writeln!(out, "# TYPE {} counter", metric);
+        for &(addr, ref name, ref stats) in &connections {
+            let _ = writeln!(out,
+                             "{}{{addr=\"{}\",name=\"{}\"}} {}",
+                             metric,
+                             addr,
+                             escape(name),
+                             value(stats));
+        }
+    }
+    out
+}
+
+// Escape a label value: backslashes, double quotes and newlines are the only characters the format
+// can't take as-is.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
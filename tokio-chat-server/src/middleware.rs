@@ -0,0 +1,78 @@
+// The server's own middleware (see `Blocklist`) doesn't need everything here, so parts of this
+// module only get used once someone registers more.
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+
+use tokio_chat_common::ClientMessage;
+
+use connection::ConnectionMetadata;
+
+// What a `MessageMiddleware` wants done with the message it was shown.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MiddlewareAction {
+    // Let the message through untouched.
+    Allow,
+
+    // The middleware changed the message in place; let the changed version through.
+    Modify,
+
+    // Silently discard the message.
+    Drop,
+
+    // Discard the message and send the sender a `ServerMessage::Error` with this explanation.
+    Error(String),
+}
+
+// What a middleware gets to know about the client that sent a message.
+pub struct ConnectionContext<'a> {
+    pub addr: SocketAddr,
+    pub name: &'a str,
+    pub room: &'a str,
+    pub metadata: &'a ConnectionMetadata,
+}
+
+// A hook that sees every `ClientMessage` after it's been read from a client and before the server
+// acts on it, for things like moderation and bots. Middleware is run in the order it was
+// registered in `serve`, and the first one that returns `Drop` or `Error` stops the message from
+// going any further. For example, a filter that turns away messages containing any of a list of
+// words could look like
+//
+//     struct ProfanityFilter {
+//         words: Vec<String>,
+//     }
+//
+//     impl MessageMiddleware for ProfanityFilter {
+//         fn process(&self, msg: &mut ClientMessage, _: &ConnectionContext) -> MiddlewareAction {
+//             if let ClientMessage::Message(ref body) = *msg {
+//                 let body = body.to_lowercase();
+//                 if self.words.iter().any(|word| body.contains(word.as_str())) {
+//                     return MiddlewareAction::Error("watch your language".to_string());
+//                 }
+//             }
+//             MiddlewareAction::Allow
+//         }
+//     }
+//
+// and would be registered by pushing `Box::new(ProfanityFilter { words: ... })` onto the
+// middleware list in `serve`. (`Blocklist` is a more thorough take on the same idea.)
+pub trait MessageMiddleware {
+    fn process(&self, msg: &mut ClientMessage, ctx: &ConnectionContext) -> MiddlewareAction;
+}
+
+// Run `msg` through each of `middleware` in turn. Returns `Modify` if any of them changed the
+// message, or the first `Drop` or `Error`.
+pub fn run(middleware: &[Box<MessageMiddleware>],
+           msg: &mut ClientMessage,
+           ctx: &ConnectionContext)
+           -> MiddlewareAction {
+    let mut result = MiddlewareAction::Allow;
+    for m in middleware {
+        match m.process(msg, ctx) {
+            MiddlewareAction::Allow => {}
+            MiddlewareAction::Modify => result = MiddlewareAction::Modify,
+            action => return action,
+        }
+    }
+    result
+}
@@ -0,0 +1,42 @@
+use std::io;
+use std::net::SocketAddr;
+
+use tokio_core::io::{Codec, EasyBuf};
+
+// Wraps the codec a client's messages are written with so that one that can't be encoded (e.g.
+// because it wouldn't fit in a frame) costs the client only that message, rather than its
+// connection: the message is logged and left out, and writing carries on with the next one.
+// Wrap this around a `StatsCodec`, not inside one, so the failure is still counted.
+pub struct SkipUnencodable<C> {
+    inner: C,
+    addr: SocketAddr,
+}
+
+impl<C: Codec> SkipUnencodable<C> {
+    // Skip messages to the client at `addr` that `inner` can't encode.
+    pub fn new(inner: C, addr: SocketAddr) -> SkipUnencodable<C> {
+        SkipUnencodable {
+            inner: inner,
+            addr: addr,
+        }
+    }
+}
+
+impl<C: Codec> Codec for SkipUnencodable<C> {
+    type In = C::In;
+    type Out = C::Out;
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Self::In>> {
+        self.inner.decode(buf)
+    }
+
+    fn encode(&mut self, msg: Self::Out, buf: &mut Vec<u8>) -> io::Result<()> {
+        let len_before = buf.len();
+        if let Err(err) = self.inner.encode(msg, buf) {
+            // Don't leave half a frame behind to throw off the ones after it.
+            buf.truncate(len_before);
+            println!("DROPPED message to {:?}: {}", self.addr, err);
+        }
+        Ok(())
+    }
+}
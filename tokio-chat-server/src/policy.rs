@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// The rules a room applies to chat messages sent in it, and to who may join it.
+#[derive(Debug, Clone, Copy)]
+pub struct RoomPolicy {
+    // Longest message body (in bytes) the room accepts.
+    pub max_body_len: usize,
+
+    // How many messages each member may send per second. Zero means no limit.
+    pub rate_per_sec: u32,
+
+    // How many members the room may have before it turns away joiners. Zero means no limit.
+    pub max_members: usize,
+}
+
+impl Default for RoomPolicy {
+    fn default() -> RoomPolicy {
+        RoomPolicy {
+            max_body_len: 1024,
+            rate_per_sec: 5,
+            max_members: 0,
+        }
+    }
+}
+
+// The policy for every room: a global default plus any per-room overrides given at startup. How
+// flooders are dealt with is the same in every room.
+#[derive(Debug, Clone, Default)]
+pub struct Policies {
+    pub default: RoomPolicy,
+    pub rooms: HashMap<String, RoomPolicy>,
+    pub flood: FloodPolicy,
+}
+
+impl Policies {
+    pub fn for_room(&self, room: &str) -> RoomPolicy {
+        self.rooms.get(room).cloned().unwrap_or(self.default)
+    }
+}
+
+// What happens to a client that keeps going over its room's rate limit, besides having those
+// messages refused. A burst (from pasting something long, say) can't be helped, so a client is
+// first warned, and only disconnected if it carries on regardless.
+#[derive(Debug, Clone, Copy)]
+pub struct FloodPolicy {
+    // How many messages the client can have refused before it's warned. Zero means it never is,
+    // nor disconnected.
+    pub warn_after: u32,
+
+    // How many more it can have refused after the warning before it's disconnected.
+    pub disconnect_after: u32,
+
+    // How long it has to go without a refused message for all of that to be forgotten.
+    pub grace: Duration,
+}
+
+impl Default for FloodPolicy {
+    fn default() -> FloodPolicy {
+        FloodPolicy {
+            warn_after: 10,
+            disconnect_after: 10,
+            grace: Duration::from_secs(10),
+        }
+    }
+}
+
+// What to do about a message refused for going over the rate limit; see `FloodCount`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FloodVerdict {
+    Refuse,
+    Warn,
+    Disconnect,
+}
+
+// Per-client count of refused messages, for applying a `FloodPolicy`.
+pub struct FloodCount {
+    refused: u32,
+    warned: bool,
+    last_refused: Option<Instant>,
+}
+
+impl FloodCount {
+    pub fn new() -> FloodCount {
+        FloodCount {
+            refused: 0,
+            warned: false,
+            last_refused: None,
+        }
+    }
+
+    // Count a message refused at `now`, and say what `policy` has to say about it.
+    pub fn refuse(&mut self, now: Instant, policy: &FloodPolicy) -> FloodVerdict {
+        if policy.warn_after == 0 {
+            return FloodVerdict::Refuse;
+        }
+        if self.last_refused.is_some_and(|last| now.duration_since(last) >= policy.grace) {
+            self.refused = 0;
+            self.warned = false;
+        }
+        self.last_refused = Some(now);
+        self.refused += 1;
+        if !self.warned && self.refused >= policy.warn_after {
+            self.warned = true;
+            self.refused = 0;
+            FloodVerdict::Warn
+        } else if self.warned && self.refused >= policy.disconnect_after {
+            FloodVerdict::Disconnect
+        } else {
+            FloodVerdict::Refuse
+        }
+    }
+}
+
+// Per-client message counter for enforcing `RoomPolicy::rate_per_sec`. This is a simple fixed
+// window: the count resets one second after the first message counted in the current window.
+// (Rooms count their messages per minute the same way; see `RoomRegistry`.)
+pub struct RateWindow {
+    start: Instant,
+    count: u32,
+}
+
+impl RateWindow {
+    pub fn new(now: Instant) -> RateWindow {
+        RateWindow {
+            start: now,
+            count: 0,
+        }
+    }
+
+    // Count a message sent at `now`, returning whether it fits within `rate_per_sec`. Messages
+    // that don't fit aren't counted.
+    pub fn allow(&mut self, now: Instant, rate_per_sec: u32) -> bool {
+        self.allow_per(now, rate_per_sec, Duration::from_secs(1))
+    }
+
+    // Like `allow`, but with windows of `period` rather than a second.
+    pub fn allow_per(&mut self, now: Instant, limit: u32, period: Duration) -> bool {
+        if limit == 0 {
+            return true;
+        }
+        if now.duration_since(self.start) >= period {
+            self.start = now;
+            self.count = 0;
+        }
+        if self.count >= limit {
+            return false;
+        }
+        self.count += 1;
+        true
+    }
+}
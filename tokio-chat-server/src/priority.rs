@@ -0,0 +1,63 @@
+use futures::{Async, Poll, Stream};
+
+// `Prioritized` merges two streams of the same item type, always preferring the `high` stream.
+// An item from `low` is only produced when `high` has nothing ready, so anything queued on `high`
+// jumps ahead of a backlog sitting in `low`. The combined stream finishes once both halves have
+// finished.
+//
+// This is how the server keeps control messages (connects, disconnects, errors) from getting
+// stuck behind a pile of chat messages headed to a slow client.
+pub struct Prioritized<H, L> {
+    high: H,
+    low: L,
+    high_done: bool,
+    low_done: bool,
+}
+
+impl<H, L> Prioritized<H, L>
+    where H: Stream,
+          L: Stream<Item = H::Item, Error = H::Error>
+{
+    pub fn new(high: H, low: L) -> Prioritized<H, L> {
+        Prioritized {
+            high: high,
+            low: low,
+            high_done: false,
+            low_done: false,
+        }
+    }
+}
+
+impl<H, L> Stream for Prioritized<H, L>
+    where H: Stream,
+          L: Stream<Item = H::Item, Error = H::Error>
+{
+    type Item = H::Item;
+    type Error = H::Error;
+
+    fn poll(&mut self) -> Poll<Option<H::Item>, H::Error> {
+        // Always check `high` first. Note that if it isn't ready, polling it has registered our
+        // task to be woken when it is, so it's safe to fall through to `low`.
+        if !self.high_done {
+            match self.high.poll()? {
+                Async::Ready(Some(item)) => return Ok(Async::Ready(Some(item))),
+                Async::Ready(None) => self.high_done = true,
+                Async::NotReady => {}
+            }
+        }
+
+        if !self.low_done {
+            match self.low.poll()? {
+                Async::Ready(Some(item)) => return Ok(Async::Ready(Some(item))),
+                Async::Ready(None) => self.low_done = true,
+                Async::NotReady => {}
+            }
+        }
+
+        if self.high_done && self.low_done {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
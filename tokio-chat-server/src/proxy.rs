@@ -0,0 +1,72 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str;
+
+use futures::future::{self, Loop};
+use futures::Future;
+use tokio_core::io::read_exact;
+use tokio_core::net::TcpStream;
+
+use IoFuture;
+
+// The longest a PROXY protocol v1 header can be, `\r\n` included.
+const MAX_HEADER_LEN: usize = 107;
+
+// Read the PROXY protocol v1 header a load balancer put at the start of `socket` (which it
+// accepted from a client and came to us from `peer`), and return the socket along with the
+// client's address, as the header gives it. A header that says the source is `UNKNOWN` leaves us
+// with `peer`. Anything that isn't a header at all is an error, so the connection is dropped.
+//
+// The header is read a byte at a time, so we stop right where it ends and whatever follows, the
+// handshake, is left for the codecs.
+pub fn read_header(socket: TcpStream, peer: SocketAddr) -> IoFuture<(TcpStream, SocketAddr)> {
+    Box::new(future::loop_fn((socket, Vec::new()), |(socket, mut header)| {
+        read_exact(socket, [0; 1]).and_then(|(socket, byte)| {
+            header.push(byte[0]);
+            if !header.ends_with(b"\r\n") {
+                if header.len() >= MAX_HEADER_LEN {
+                    return Err(malformed());
+                }
+                return Ok(Loop::Continue((socket, header)));
+            }
+            Ok(Loop::Break((socket, header)))
+        })
+    }).and_then(move |(socket, header)| {
+        let header = str::from_utf8(&header[..header.len() - 2]).map_err(|_| malformed())?;
+        parse(header, peer).map(|addr| (socket, addr)).ok_or_else(malformed)
+    }))
+}
+
+// The client address in `header` (without its `\r\n`), if it's a well-formed header.
+fn parse(header: &str, peer: SocketAddr) -> Option<SocketAddr> {
+    let mut fields = header.split(' ');
+    if fields.next() != Some("PROXY") {
+        return None;
+    }
+    let v4 = match fields.next()? {
+        "TCP4" => true,
+        "TCP6" => false,
+        "UNKNOWN" => return Some(peer),
+        _ => return None,
+    };
+    let source = fields.next()?.parse::<IpAddr>().ok()?;
+    let destination = fields.next()?.parse::<IpAddr>().ok()?;
+    let source_port = port(fields.next()?)?;
+    port(fields.next()?)?;
+    if fields.next().is_some() || source.is_ipv4() != v4 || destination.is_ipv4() != v4 {
+        return None;
+    }
+    Some(SocketAddr::new(source, source_port))
+}
+
+// A port number, written the way the header has them: in decimal, without leading zeros.
+fn port(field: &str) -> Option<u16> {
+    if field.len() > 1 && field.starts_with('0') {
+        return None;
+    }
+    field.parse().ok()
+}
+
+fn malformed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed PROXY protocol header")
+}
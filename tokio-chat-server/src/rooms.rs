@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use tokio_chat_common::RoomConfig;
+
+use policy::{RateWindow, RoomPolicy};
+
+// The rooms created with `ClientMessage::CreateRoom`, with the settings each was created with.
+// They stay here until the server stops, whether or not anybody's in them.
+//
+// A room's `messages_per_minute` is a limit on the room as a whole, however many members share
+// it, so its count is kept here, apart from each client's `RateWindow`: a message has to fit
+// within both.
+pub struct RoomRegistry {
+    rooms: HashMap<String, CreatedRoom>,
+}
+
+struct CreatedRoom {
+    config: RoomConfig,
+    rate: RateWindow,
+}
+
+impl RoomRegistry {
+    pub fn new() -> RoomRegistry {
+        RoomRegistry { rooms: HashMap::new() }
+    }
+
+    // Whether `room` has been created.
+    pub fn contains(&self, room: &str) -> bool {
+        self.rooms.contains_key(room)
+    }
+
+    // Remember `room`, created at `now` with `config`.
+    pub fn create(&mut self, room: String, config: RoomConfig, now: Instant) {
+        self.rooms.insert(room,
+                          CreatedRoom {
+                              config: config,
+                              rate: RateWindow::new(now),
+                          });
+    }
+
+    // The settings `room` was created with, if it was.
+    pub fn config(&self, room: &str) -> Option<&RoomConfig> {
+        self.rooms.get(room).map(|created| &created.config)
+    }
+
+    // Whether `room` was created private.
+    pub fn is_private(&self, room: &str) -> bool {
+        self.config(room).is_some_and(|config| config.private)
+    }
+
+    // `policy`, the one `room` would have anyway, with any limit on members the room was created
+    // with in place of its own.
+    pub fn policy(&self, room: &str, policy: RoomPolicy) -> RoomPolicy {
+        match self.config(room).and_then(|config| config.max_members) {
+            Some(max_members) => RoomPolicy { max_members: max_members as usize, ..policy },
+            None => policy,
+        }
+    }
+
+    // Count a message sent in `room` at `now`, as long as it fits within the room's
+    // `messages_per_minute`; if it doesn't, returns that limit. Rooms that weren't created with a
+    // limit (or at all) take any number of messages.
+    pub fn allow(&mut self, room: &str, now: Instant) -> Result<(), u32> {
+        let created = match self.rooms.get_mut(room) {
+            Some(created) => created,
+            None => return Ok(()),
+        };
+        match created.config.messages_per_minute {
+            Some(limit) if !created.rate.allow_per(now, limit, Duration::from_secs(60)) => {
+                Err(limit)
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,179 @@
+// Cleaning up HTML from clients before anyone else's client renders it. This works from an
+// allowlist: only the tags in `ALLOWED_TAGS` survive, with no attributes at all besides an `href`
+// on a link, and then only to a web or mail address. Scripts and styles go along with everything
+// in them, as do comments; any other tag is dropped but its contents kept. Whatever's left that
+// could still be taken for markup, like a `<` that never gets closed, is escaped so it shows as
+// the text it is.
+
+// Simple formatting, lists and links, but nothing that can run code, pull in other content, or
+// change the layout around the message.
+const ALLOWED_TAGS: &[&str] = &["a", "b", "blockquote", "br", "code", "del", "em", "i", "li",
+                                "ol", "p", "pre", "s", "strong", "u", "ul"];
+
+// Tags whose contents aren't text to show, so they're dropped whole.
+const DROPPED_WITH_CONTENTS: &[&str] = &["script", "style"];
+
+// The schemes links may have.
+const ALLOWED_SCHEMES: &[&str] = &["http:", "https:", "mailto:"];
+
+// Make `html` safe to render, as described above.
+pub fn html(html: &str) -> String {
+    let mut clean = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        clean.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("<!--") {
+            rest = after.find("-->").map_or("", |end| &after[end + 3..]);
+            continue;
+        }
+        let tag = match Tag::parse(rest) {
+            Some(tag) => tag,
+            None => {
+                clean.push_str("&lt;");
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        rest = &rest[tag.len..];
+        if !tag.closing && DROPPED_WITH_CONTENTS.contains(&tag.name.as_str()) {
+            rest = skip_past_closing(rest, &tag.name);
+        } else if ALLOWED_TAGS.contains(&tag.name.as_str()) {
+            tag.write(&mut clean);
+        }
+    }
+    clean.push_str(rest);
+    clean
+}
+
+// Everything in `html` after the tag closing the `name` that it follows, or nothing, if it's never
+// closed.
+fn skip_past_closing<'a>(html: &'a str, name: &str) -> &'a str {
+    let closing = format!("</{}", name);
+    let lower = html.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(found) = lower[from..].find(&closing) {
+        let start = from + found;
+        if let Some(tag) = Tag::parse(&html[start..]) {
+            if tag.closing && tag.name == name {
+                return &html[start + tag.len..];
+            }
+        }
+        from = start + closing.len();
+    }
+    ""
+}
+
+// An opening or closing tag, with only its name and the attributes we might keep.
+struct Tag {
+    name: String,
+    closing: bool,
+    href: Option<String>,
+
+    // How many bytes of the input it took up, from its `<` to its `>`.
+    len: usize,
+}
+
+impl Tag {
+    // Read the tag `html` starts with, if it's something a browser would take for one: a `<`,
+    // maybe a `/`, a name starting with a letter, then attributes up to the `>`.
+    fn parse(html: &str) -> Option<Tag> {
+        let bytes = html.as_bytes();
+        let mut i = 1;
+        let closing = bytes.get(i) == Some(&b'/');
+        if closing {
+            i += 1;
+        }
+        if !bytes.get(i).is_some_and(|b| b.is_ascii_alphabetic()) {
+            return None;
+        }
+        let name_start = i;
+        while bytes.get(i).is_some_and(|b| b.is_ascii_alphanumeric()) {
+            i += 1;
+        }
+        let name = html[name_start..i].to_ascii_lowercase();
+
+        let mut href = None;
+        loop {
+            while bytes.get(i).is_some_and(|&b| b.is_ascii_whitespace() || b == b'/') {
+                i += 1;
+            }
+            match bytes.get(i) {
+                None => return None,
+                Some(&b'>') => break,
+                Some(_) => {}
+            }
+            let attr_start = i;
+            while bytes.get(i).is_some_and(|&b| !b.is_ascii_whitespace() && !b"/>=".contains(&b)) {
+                i += 1;
+            }
+            let attr = html[attr_start..i].to_ascii_lowercase();
+            while bytes.get(i).is_some_and(|b| b.is_ascii_whitespace()) {
+                i += 1;
+            }
+            if bytes.get(i) != Some(&b'=') {
+                continue;
+            }
+            i += 1;
+            while bytes.get(i).is_some_and(|b| b.is_ascii_whitespace()) {
+                i += 1;
+            }
+            let value = match bytes.get(i) {
+                Some(&quote) if quote == b'"' || quote == b'\'' => {
+                    let end = html[i + 1..].find(quote as char)? + i + 1;
+                    let value = &html[i + 1..end];
+                    i = end + 1;
+                    value
+                }
+                _ => {
+                    let value_start = i;
+                    while bytes.get(i).is_some_and(|&b| !b.is_ascii_whitespace() && b != b'>') {
+                        i += 1;
+                    }
+                    &html[value_start..i]
+                }
+            };
+            if name == "a" && attr == "href" && is_safe_link(value) {
+                href = Some(value.to_string());
+            }
+        }
+        Some(Tag {
+            name: name,
+            closing: closing,
+            href: href,
+            len: i + 1,
+        })
+    }
+
+    fn write(&self, out: &mut String) {
+        out.push('<');
+        if self.closing {
+            out.push('/');
+        }
+        out.push_str(&self.name);
+        if let (false, Some(href)) = (self.closing, self.href.as_ref()) {
+            out.push_str(" href=\"");
+            for c in href.chars() {
+                match c {
+                    '"' => out.push_str("&quot;"),
+                    '<' => out.push_str("&lt;"),
+                    '>' => out.push_str("&gt;"),
+                    c => out.push(c),
+                }
+            }
+            out.push('"');
+        }
+        out.push('>');
+    }
+}
+
+// Whether `href` is a link to a web or mail address. Browsers ignore control characters and
+// whitespace in schemes, so `java\tscript:` is still JavaScript; a link with any of those in it
+// isn't taken.
+fn is_safe_link(href: &str) -> bool {
+    if href.chars().any(|c| c.is_control() || c.is_whitespace()) {
+        return false;
+    }
+    let href = href.to_ascii_lowercase();
+    ALLOWED_SCHEMES.iter().any(|scheme| href.starts_with(scheme))
+}
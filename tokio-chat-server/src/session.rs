@@ -0,0 +1,219 @@
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use rand::{self, Rng};
+use tokio_chat_common::{ChatMessage, ServerMessage};
+
+use clock::SharedClock;
+
+// A recent chat message, kept so that clients resuming a session can catch up on it.
+struct Entry {
+    seq: u64,
+    room: String,
+    message: ServerMessage,
+}
+
+// The last `capacity` chat messages broadcast in any room, in order. Each message is numbered so
+// we can tell which ones were sent after a given client went away, and so clients can refer to
+// them; a `ServerMessage::Message` carries its number as its `MessageId`. Some rooms may be held
+// to fewer; see `limit_room`.
+pub struct History {
+    capacity: usize,
+    room_capacities: HashMap<String, usize>,
+    next_seq: u64,
+    entries: VecDeque<Entry>,
+}
+
+impl History {
+    // Remember up to `capacity` messages, numbering them from `next_seq` on.
+    pub fn new(capacity: usize, next_seq: u64) -> History {
+        History {
+            capacity: capacity,
+            room_capacities: HashMap::new(),
+            next_seq: next_seq,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    // From now on, remember at most `capacity` of the messages broadcast in `room` (edits count as
+    // messages here, as they do towards the overall capacity), forgetting its oldest first.
+    pub fn limit_room(&mut self, room: &str, capacity: usize) {
+        self.room_capacities.insert(room.to_string(), capacity);
+    }
+
+    // The number the next recorded message will get.
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    // Carry on numbering from `seq`, if that's further on than we've got.
+    pub fn skip_to(&mut self, seq: u64) {
+        self.next_seq = cmp::max(self.next_seq, seq);
+    }
+
+    // Set aside the next number for a message that's to be recorded later (with `record_as`), so
+    // that nothing recorded in the meantime gets it.
+    pub fn reserve(&mut self) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        seq
+    }
+
+    // Remember `message`, broadcast in `room`, as number `next_seq`. Numbers are used up even when
+    // there's no room to keep anything, so they stay unique.
+    pub fn record(&mut self, room: &str, message: ServerMessage) {
+        let seq = self.next_seq;
+        self.record_as(seq, room, message);
+    }
+
+    // Like `record`, but as number `seq`: one that was reserved for it, or that it had before
+    // (when it's replayed from somewhere). Numbering carries on after it, if it hasn't already.
+    pub fn record_as(&mut self, seq: u64, room: &str, message: ServerMessage) {
+        self.next_seq = cmp::max(self.next_seq, seq + 1);
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(&room_capacity) = self.room_capacities.get(room) {
+            if room_capacity == 0 {
+                return;
+            }
+            let mut in_room = self.entries.iter().enumerate().filter(|&(_, e)| e.room == room);
+            if let Some((oldest, _)) = in_room.next() {
+                if in_room.count() + 1 >= room_capacity {
+                    self.entries.remove(oldest);
+                }
+            }
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(Entry {
+            seq: seq,
+            room: room.to_string(),
+            message: message,
+        });
+    }
+
+    // The message numbered `seq` and the room it was broadcast in, if we still remember it.
+    pub fn get(&self, seq: u64) -> Option<(&str, &ServerMessage)> {
+        self.entries
+            .iter()
+            .find(|entry| entry.seq == seq)
+            .map(|entry| (entry.room.as_str(), &entry.message))
+    }
+
+    // Whether we still remember every message numbered `seq` or later.
+    pub fn covers(&self, seq: u64) -> bool {
+        seq >= self.entries.front().map_or(self.next_seq, |entry| entry.seq)
+    }
+
+    // The messages broadcast in `room` numbered `seq` or later, as far back as we remember.
+    pub fn since(&self, seq: u64, room: &str) -> Vec<ServerMessage> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.seq >= seq && entry.room == room)
+            .map(|entry| entry.message.clone())
+            .collect()
+    }
+
+    // The chat in `room` numbered `seq` or later, as far back as we remember, as last edited.
+    pub fn chat(&self, seq: u64, room: &str) -> Vec<ChatMessage> {
+        // Edits are recorded alongside the messages they're to, so apply them as we go.
+        let mut chat: Vec<ChatMessage> = Vec::new();
+        for msg in self.since(seq, room) {
+            match msg {
+                ServerMessage::Message(id, from, body) |
+                ServerMessage::FormattedMessage { id, from, body, .. } => {
+                    chat.push(ChatMessage {
+                        id: id,
+                        from: from,
+                        body: body,
+                    })
+                }
+                ServerMessage::MessageEdited { id, new_body } => {
+                    if let Some(edited) = chat.iter_mut().find(|msg| msg.id == id) {
+                        edited.body = new_body;
+                    }
+                }
+                _ => {}
+            }
+        }
+        chat
+    }
+}
+
+// What we remember about a client that disconnected, so it can pick up where it left off if it
+// comes back with its resume token in time.
+pub struct Session {
+    pub name: String,
+    pub room: String,
+    pub status: Option<String>,
+
+    // `History::next_seq` as of the disconnect: everything from here on was missed.
+    pub missed_from: u64,
+
+    expires: Instant,
+}
+
+// Sessions of recently disconnected clients, by resume token. Sessions are good for `grace`
+// after their client disconnects.
+pub struct Sessions {
+    grace: Duration,
+    clock: SharedClock,
+    sessions: HashMap<String, Session>,
+}
+
+impl Sessions {
+    pub fn new(grace: Duration, clock: SharedClock) -> Sessions {
+        Sessions {
+            grace: grace,
+            clock: clock,
+            sessions: HashMap::new(),
+        }
+    }
+
+    // Remember a session that just ended, to be resumed with `token`.
+    pub fn suspend(&mut self,
+                   token: String,
+                   name: String,
+                   room: String,
+                   status: Option<String>,
+                   missed_from: u64) {
+        let now = self.clock.now();
+        self.expire(now);
+        self.sessions.insert(token,
+                             Session {
+                                 name: name,
+                                 room: room,
+                                 status: status,
+                                 missed_from: missed_from,
+                                 expires: now + self.grace,
+                             });
+    }
+
+    // Claim the session belonging to `token`, if it hasn't expired. Tokens only work once.
+    pub fn resume(&mut self, token: &str) -> Option<Session> {
+        let now = self.clock.now();
+        self.expire(now);
+        self.sessions.remove(token)
+    }
+
+    // Whether `token` would resume a session right now, without claiming it.
+    pub fn is_live(&self, token: &str) -> bool {
+        self.sessions.get(token).map_or(false, |session| session.expires > self.clock.now())
+    }
+
+    // There's no timer cleaning up after expired sessions; instead, we sweep them out whenever
+    // the map is touched.
+    fn expire(&mut self, now: Instant) {
+        self.sessions.retain(|_, session| session.expires > now);
+    }
+}
+
+// Make up a new resume token. These are bearer credentials for a session, so they need to be
+// unguessable.
+pub fn new_token() -> String {
+    let mut rng = rand::thread_rng();
+    format!("{:016x}{:016x}", rng.gen::<u64>(), rng.gen::<u64>())
+}
@@ -0,0 +1,193 @@
+use std::str;
+use std::time::Duration;
+
+use futures::{Future, Stream};
+use futures::sync::mpsc;
+use futures_cpupool::CpuPool;
+use ring::hmac;
+use serde_json::{self, Value};
+use serde_json::builder::ObjectBuilder;
+use tokio_core::reactor::Handle;
+use url::Url;
+
+use webhooks;
+use IoFuture;
+
+// What Slack users are called in the room: `slack/USER_ID`. That's also how we know not to send
+// what they say back to Slack.
+pub const NAME_PREFIX: &str = "slack/";
+
+// Requests signed longer ago than this (or this far in the future) are turned away, so that one
+// overheard can't be replayed later.
+const MAX_CLOCK_SKEW_SECS: u64 = 5 * 60;
+
+// How to mirror a room to a Slack channel: chat goes out through an Incoming Webhook, and comes
+// back as Events API requests to the HTTP API's `POST /slack/events`; see `api::serve`.
+#[derive(Debug, Clone)]
+pub struct SlackConfig {
+    // The Incoming Webhook to post the room's chat to. As with `WebhookRegistry`, only plain
+    // `http` URLs are supported, so this is normally a TLS-terminating proxy in front of
+    // `https://hooks.slack.com/services/...`.
+    pub webhook: String,
+
+    // The secret Slack signs its requests to us with, and the room to mirror the channel to.
+    pub signing_secret: String,
+    pub room: String,
+}
+
+// Where what's said in the mirrored room goes on its way to Slack, and how to tell the requests
+// that come back really are from Slack. `Chat::say` passes everything said anywhere through here;
+// what's for the mirrored room, and didn't come from Slack in the first place, is queued up for
+// `forward` to post.
+pub struct SlackBridge {
+    room: String,
+    signing_key: hmac::Key,
+
+    // The JSON to post to the webhook; see `forward`.
+    outbox: mpsc::UnboundedSender<String>,
+}
+
+impl SlackBridge {
+    pub fn new(slack: &SlackConfig, outbox: mpsc::UnboundedSender<String>) -> SlackBridge {
+        SlackBridge {
+            room: slack.room.clone(),
+            signing_key: hmac::Key::new(hmac::HMAC_SHA256, slack.signing_secret.as_bytes()),
+            outbox: outbox,
+        }
+    }
+
+    // The room the channel is mirrored to.
+    pub fn room(&self) -> &str {
+        &self.room
+    }
+
+    // Post `body`, said by `from` in `room`, to the channel, as `*from*: body`.
+    pub fn relay(&self, room: &str, from: &str, body: &str) {
+        if room != self.room || from.starts_with(NAME_PREFIX) {
+            return;
+        }
+        let text = format!("*{}*: {}", escape(from), escape(body));
+        let post = ObjectBuilder::new().insert("text", text).build();
+        let post = serde_json::to_string(&post).expect("JSON values always serialize");
+        // If the bridge is down, there's no one to tell.
+        let _ = self.outbox.unbounded_send(post);
+    }
+
+    // Whether `body` is signed with our secret, as `signature` (an `X-Slack-Signature` header:
+    // `v0=` and the hex of an HMAC-SHA256) says it is, at `timestamp` (an
+    // `X-Slack-Request-Timestamp`, in seconds), and that was recently enough, it being `now`.
+    pub fn verify(&self,
+                  timestamp: Option<&str>,
+                  signature: Option<&str>,
+                  body: &[u8],
+                  now: u64)
+                  -> bool {
+        let (timestamp, signature) = match (timestamp, signature) {
+            (Some(timestamp), Some(signature)) => (timestamp, signature),
+            _ => return false,
+        };
+        let signed_at = match timestamp.parse::<u64>() {
+            Ok(signed_at) => signed_at,
+            Err(_) => return false,
+        };
+        let skew = if now > signed_at { now - signed_at } else { signed_at - now };
+        if skew > MAX_CLOCK_SKEW_SECS {
+            return false;
+        }
+        let tag = match signature.strip_prefix("v0=").and_then(unhex) {
+            Some(tag) => tag,
+            None => return false,
+        };
+        let mut signed = format!("v0:{}:", timestamp).into_bytes();
+        signed.extend_from_slice(body);
+        hmac::verify(&self.signing_key, &signed, &tag).is_ok()
+    }
+}
+
+// What an Events API request asks of us.
+pub enum Event {
+    // Slack checking the URL is ours, when it's first set up: we answer with the challenge.
+    Challenge(String),
+
+    // A user (the first String, their id) said something (the second) in the channel.
+    Message(String, String),
+
+    // Anything else, which includes messages from bots (our own posts among them), edits, joins
+    // and the like.
+    Ignored,
+}
+
+// Make sense of the body of an Events API request, if it's JSON at all.
+pub fn parse_event(body: &[u8]) -> Option<Event> {
+    let request = serde_json::from_slice::<Value>(body).ok()?;
+    let field = |value: &Value, name: &str| value.find(name).and_then(Value::as_str)
+        .map(|field| field.to_string());
+    match field(&request, "type").as_ref().map(|kind| kind.as_str()) {
+        Some("url_verification") => Some(field(&request, "challenge").map_or(Event::Ignored,
+                                                                             Event::Challenge)),
+        Some("event_callback") => {
+            let event = match request.find("event") {
+                Some(event) => event,
+                None => return Some(Event::Ignored),
+            };
+            let plain = event.find("subtype").is_none() && event.find("bot_id").is_none();
+            Some(match (field(event, "type"), field(event, "user"), field(event, "text")) {
+                (Some(ref kind), Some(user), Some(text)) if kind == "message" && plain => {
+                    Event::Message(user, unescape(&text))
+                }
+                _ => Event::Ignored,
+            })
+        }
+        _ => Some(Event::Ignored),
+    }
+}
+
+// Post what `outbox` carries to `slack.webhook` in order, giving each post the same time and
+// retries a webhook gets (see `Webhooks`). A post that fails anyway is logged and skipped. The
+// returned future fails straight away if the webhook URL won't do, and otherwise runs for as long
+// as the bridge is up.
+pub fn forward(slack: &SlackConfig,
+               outbox: mpsc::UnboundedReceiver<String>,
+               timeout: Duration,
+               handle: Handle)
+               -> IoFuture<()> {
+    let url = match Url::parse(&slack.webhook) {
+        Ok(ref url) if url.scheme() == "http" && url.host_str().is_some() => url.clone(),
+        _ => {
+            let reason = format!("the Slack webhook must be http://HOST/..., not {}",
+                                 slack.webhook);
+            return Box::new(::futures::future::err(::std::io::Error::new(
+                ::std::io::ErrorKind::InvalidInput, reason)));
+        }
+    };
+    let resolver = CpuPool::new(1);
+    Box::new(outbox.map_err(|()| unreachable!("rx can't fail")).for_each(move |post| {
+        webhooks::deliver(url.clone(), post, timeout, handle.clone(), resolver.clone())
+            .then(|result| {
+                if let Err(err) = result {
+                    println!("SLACK post failed: {}", err);
+                }
+                Ok(())
+            })
+    }))
+}
+
+// Slack's text has `&`, `<` and `>` escaped, since it uses `<...>` for links and mentions.
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&")
+}
+
+// The bytes spelled out by the hex digits `s`, if that's what it is.
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
@@ -0,0 +1,358 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use futures::{future, Future};
+use futures_cpupool::CpuPool;
+use rusqlite::{Connection, OptionalExtension, Row};
+use tokio_chat_common::MessageId;
+
+// Everything we keep about a user between connections (and, with a database, between restarts).
+// Times are in seconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredUser {
+    pub username: String,
+
+    // The bcrypt hash of the user's password, or empty if they never set one; names without a
+    // password can be used by anybody.
+    pub password_hash: String,
+
+    // Whether the user has connected as an operator. Only honored for users with a password.
+    pub is_admin: bool,
+
+    pub created_at: u64,
+    pub last_seen: u64,
+}
+
+pub type StoreFuture<T> = Box<Future<Item = T, Error = io::Error>>;
+
+// Somewhere to keep `StoredUser`s. Lookups may have to wait on a database, so everything
+// returns a future.
+pub trait UserStore {
+    fn get_user(&self, username: &str) -> StoreFuture<Option<StoredUser>>;
+
+    // Save `user`, replacing whatever was stored under their name.
+    fn upsert_user(&self, user: StoredUser) -> StoreFuture<()>;
+
+    fn list_users(&self) -> StoreFuture<Vec<StoredUser>>;
+}
+
+// A chat message as it's kept in a `MessageStore`. `seq` is the message's `MessageId`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredMessage {
+    pub seq: MessageId,
+    pub room: String,
+    pub from_user: String,
+    pub body: String,
+
+    // When the message was sent, in milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+
+    // The message this one answers, if any. Nothing sets this yet.
+    pub reply_to: Option<MessageId>,
+
+    // What the author changed the message to say, if they edited it.
+    pub edited_body: Option<String>,
+
+    // Whether the message was taken back. Nothing sets this yet.
+    pub deleted: bool,
+}
+
+impl StoredMessage {
+    // What the message says now, taking edits into account.
+    pub fn current_body(&self) -> &str {
+        self.edited_body.as_ref().unwrap_or(&self.body)
+    }
+}
+
+// Somewhere to keep chat messages for longer than the server's in-memory history does (see
+// `History`), and across restarts.
+pub trait MessageStore {
+    fn append(&self, msg: &StoredMessage) -> StoreFuture<()>;
+
+    // Note that the message numbered `seq` now says `new_body`.
+    fn edit(&self, seq: MessageId, new_body: &str) -> StoreFuture<()>;
+
+    // Up to `limit` messages sent in `room`, numbered `seq` or later, oldest first.
+    fn get_since(&self,
+                 room: &str,
+                 seq: MessageId,
+                 limit: usize)
+                 -> StoreFuture<Vec<StoredMessage>>;
+
+    // The number after the highest one stored when the store was opened, so a restarted server
+    // can carry on numbering where it left off.
+    fn next_seq(&self) -> MessageId;
+}
+
+// Open the store at `url`: a SQLite database for `sqlite://path` (or `sqlite::memory:`), or, with
+// no URL, a `MemoryUserStore` that's forgotten when the server exits.
+pub fn open(url: Option<&str>) -> io::Result<Rc<UserStore>> {
+    match url {
+        Some(url) => Ok(Rc::new(SqliteUserStore::open(url)?)),
+        None => Ok(Rc::new(MemoryUserStore::new())),
+    }
+}
+
+// Open the message store at `url`, if any: a SQLite database, as for `open`. Without one, the
+// server only has its in-memory history.
+pub fn open_messages(url: Option<&str>) -> io::Result<Option<Rc<MessageStore>>> {
+    match url {
+        Some(url) => Ok(Some(Rc::new(SqliteMessageStore::open(url)?))),
+        None => Ok(None),
+    }
+}
+
+// Note that `username` was around at `now`, if we know them.
+pub fn seen(store: Rc<UserStore>, username: &str, now: u64) -> StoreFuture<()> {
+    Box::new(store.get_user(username).and_then(move |user| -> StoreFuture<()> {
+        match user {
+            Some(user) => store.upsert_user(StoredUser { last_seen: now, ..user }),
+            None => Box::new(future::ok(())),
+        }
+    }))
+}
+
+// Users kept in a `HashMap`, for servers that weren't given a database.
+#[derive(Default)]
+pub struct MemoryUserStore {
+    users: RefCell<HashMap<String, StoredUser>>,
+}
+
+impl MemoryUserStore {
+    pub fn new() -> MemoryUserStore {
+        MemoryUserStore::default()
+    }
+}
+
+impl UserStore for MemoryUserStore {
+    fn get_user(&self, username: &str) -> StoreFuture<Option<StoredUser>> {
+        Box::new(future::ok(self.users.borrow().get(username).cloned()))
+    }
+
+    fn upsert_user(&self, user: StoredUser) -> StoreFuture<()> {
+        self.users.borrow_mut().insert(user.username.clone(), user);
+        Box::new(future::ok(()))
+    }
+
+    fn list_users(&self) -> StoreFuture<Vec<StoredUser>> {
+        let mut users = self.users.borrow().values().cloned().collect::<Vec<_>>();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        Box::new(future::ok(users))
+    }
+}
+
+// A connection to a SQLite database. SQLite blocks, so queries run on a thread of their own rather
+// than on the event loop.
+struct Database {
+    db: Arc<Mutex<Connection>>,
+    pool: CpuPool,
+}
+
+impl Database {
+    // Open (creating if need be) the database at `url`, which is `sqlite://` followed by a path,
+    // or `sqlite::memory:` for a database that lasts only as long as the connection, and make
+    // sure it has a table per `schema`.
+    fn open(url: &str, schema: &str) -> io::Result<Database> {
+        let db = if url == "sqlite::memory:" {
+            Connection::open_in_memory()
+        } else if let Some(path) = url.strip_prefix("sqlite://") {
+            Connection::open(path)
+        } else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("unsupported database URL {}", url)));
+        };
+        let db = db.map_err(sql_error)?;
+        db.execute(schema, []).map_err(sql_error)?;
+
+        Ok(Database {
+            db: Arc::new(Mutex::new(db)),
+            pool: CpuPool::new(1),
+        })
+    }
+
+    // Run `query` against the database on its thread.
+    fn run<T, F>(&self, query: F) -> StoreFuture<T>
+        where T: Send + 'static,
+              F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static
+    {
+        let db = self.db.clone();
+        Box::new(self.pool.spawn_fn(move || {
+            let db = db.lock().expect("a query panicked while holding the database");
+            query(&db).map_err(sql_error)
+        }))
+    }
+}
+
+// Users kept in the `users` table of a SQLite database.
+pub struct SqliteUserStore {
+    db: Database,
+}
+
+impl SqliteUserStore {
+    // Open the database at `url`; see `Database::open`.
+    pub fn open(url: &str) -> io::Result<SqliteUserStore> {
+        let db = Database::open(url,
+                                "CREATE TABLE IF NOT EXISTS users (
+                                     username TEXT PRIMARY KEY,
+                                     password_hash TEXT NOT NULL,
+                                     is_admin INTEGER NOT NULL,
+                                     created_at INTEGER NOT NULL,
+                                     last_seen INTEGER NOT NULL
+                                 )")?;
+        Ok(SqliteUserStore { db: db })
+    }
+}
+
+impl UserStore for SqliteUserStore {
+    fn get_user(&self, username: &str) -> StoreFuture<Option<StoredUser>> {
+        let username = username.to_string();
+        self.db.run(move |db| {
+            db.query_row("SELECT username, password_hash, is_admin, created_at, last_seen
+                          FROM users WHERE username = ?1",
+                         [&username],
+                         stored_user)
+                .optional()
+        })
+    }
+
+    fn upsert_user(&self, user: StoredUser) -> StoreFuture<()> {
+        self.db.run(move |db| {
+            db.execute("INSERT OR REPLACE INTO users
+                            (username, password_hash, is_admin, created_at, last_seen)
+                        VALUES (?1, ?2, ?3, ?4, ?5)",
+                       (&user.username,
+                        &user.password_hash,
+                        user.is_admin,
+                        user.created_at as i64,
+                        user.last_seen as i64))
+                .map(|_| ())
+        })
+    }
+
+    fn list_users(&self) -> StoreFuture<Vec<StoredUser>> {
+        self.db.run(|db| {
+            let mut query = db.prepare("SELECT username, password_hash, is_admin, created_at,
+                                               last_seen
+                                        FROM users ORDER BY username")?;
+            let users = query.query_map([], stored_user)?.collect();
+            users
+        })
+    }
+}
+
+fn stored_user(row: &Row) -> rusqlite::Result<StoredUser> {
+    Ok(StoredUser {
+        username: row.get(0)?,
+        password_hash: row.get(1)?,
+        is_admin: row.get(2)?,
+        created_at: row.get::<_, i64>(3)? as u64,
+        last_seen: row.get::<_, i64>(4)? as u64,
+    })
+}
+
+// Chat messages kept in the `messages` table of a SQLite database.
+pub struct SqliteMessageStore {
+    db: Database,
+    next_seq: MessageId,
+}
+
+impl SqliteMessageStore {
+    // Open the database at `url`; see `Database::open`.
+    pub fn open(url: &str) -> io::Result<SqliteMessageStore> {
+        let db = Database::open(url,
+                                "CREATE TABLE IF NOT EXISTS messages (
+                                     seq INTEGER PRIMARY KEY,
+                                     room TEXT NOT NULL,
+                                     from_user TEXT NOT NULL,
+                                     body TEXT NOT NULL,
+                                     timestamp_ms INTEGER NOT NULL,
+                                     reply_to INTEGER,
+                                     edited_body TEXT,
+                                     deleted INTEGER NOT NULL
+                                 )")?;
+        // Opening happens at startup, before there's anything to block, so this can wait.
+        let next_seq = db.db
+            .lock()
+            .expect("a query panicked while holding the database")
+            .query_row("SELECT MAX(seq) FROM messages",
+                       [],
+                       |row| row.get::<_, Option<i64>>(0))
+            .map_err(sql_error)?
+            .map_or(0, |seq| seq as u64 + 1);
+        Ok(SqliteMessageStore {
+            db: db,
+            next_seq: next_seq,
+        })
+    }
+}
+
+impl MessageStore for SqliteMessageStore {
+    fn append(&self, msg: &StoredMessage) -> StoreFuture<()> {
+        let msg = msg.clone();
+        self.db.run(move |db| {
+            db.execute("INSERT INTO messages
+                            (seq, room, from_user, body, timestamp_ms, reply_to, edited_body,
+                             deleted)
+                        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                       (msg.seq as i64,
+                        &msg.room,
+                        &msg.from_user,
+                        &msg.body,
+                        msg.timestamp_ms as i64,
+                        msg.reply_to.map(|seq| seq as i64),
+                        &msg.edited_body,
+                        msg.deleted))
+                .map(|_| ())
+        })
+    }
+
+    fn edit(&self, seq: MessageId, new_body: &str) -> StoreFuture<()> {
+        let new_body = new_body.to_string();
+        self.db.run(move |db| {
+            db.execute("UPDATE messages SET edited_body = ?1 WHERE seq = ?2",
+                       (&new_body, seq as i64))
+                .map(|_| ())
+        })
+    }
+
+    fn get_since(&self,
+                 room: &str,
+                 seq: MessageId,
+                 limit: usize)
+                 -> StoreFuture<Vec<StoredMessage>> {
+        let room = room.to_string();
+        self.db.run(move |db| {
+            let mut query = db.prepare("SELECT seq, room, from_user, body, timestamp_ms, reply_to,
+                                               edited_body, deleted
+                                        FROM messages WHERE room = ?1 AND seq >= ?2
+                                        ORDER BY seq LIMIT ?3")?;
+            let messages = query.query_map((&room, seq as i64, limit as i64), stored_message)?
+                .collect();
+            messages
+        })
+    }
+
+    fn next_seq(&self) -> MessageId {
+        self.next_seq
+    }
+}
+
+fn stored_message(row: &Row) -> rusqlite::Result<StoredMessage> {
+    Ok(StoredMessage {
+        seq: row.get::<_, i64>(0)? as u64,
+        room: row.get(1)?,
+        from_user: row.get(2)?,
+        body: row.get(3)?,
+        timestamp_ms: row.get::<_, i64>(4)? as u64,
+        reply_to: row.get::<_, Option<i64>>(5)?.map(|seq| seq as u64),
+        edited_body: row.get(6)?,
+        deleted: row.get(7)?,
+    })
+}
+
+fn sql_error(err: rusqlite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
@@ -0,0 +1,223 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use clock::SharedClock;
+
+// How many failed logins an address gets before it's locked out, and for how long.
+#[derive(Debug, Clone, Copy)]
+pub struct ThrottlePolicy {
+    // How many failed logins within `window` of the first of them lock the address out. Zero
+    // means it never is.
+    pub max_failures: u32,
+    pub window: Duration,
+
+    // How long the first lockout lasts. Each one after that lasts twice as long as the one
+    // before, up to `max_lockout`; an address whose last lockout ended `max_lockout` ago starts
+    // again from the beginning.
+    pub lockout: Duration,
+    pub max_lockout: Duration,
+}
+
+impl Default for ThrottlePolicy {
+    fn default() -> ThrottlePolicy {
+        ThrottlePolicy {
+            max_failures: 5,
+            window: Duration::from_secs(60),
+            lockout: Duration::from_secs(30),
+            max_lockout: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+// Failed logins from each IP address, so that no single host can go through passwords (or
+// tokens) as fast as it can send them. A host that fails too often is locked out for a while,
+// and for longer each time it carries on.
+//
+// Given a file, the throttler keeps it up to date with every change, and starts from it, so a
+// restart doesn't let anyone off. Each line is a host that's failed lately or been locked out,
+// with its times as milliseconds since the Unix epoch:
+//
+// ```text
+// failures 203.0.113.7 3 1489504166535
+// lockout 198.51.100.23 2 1489504226535
+// ```
+//
+// giving the failures since the first of them, and the lockouts so far and when the last one
+// ends.
+pub struct LoginThrottler {
+    policy: ThrottlePolicy,
+    failures: HashMap<IpAddr, (u32, Instant)>,
+    lockouts: HashMap<IpAddr, (u32, Instant)>,
+    path: Option<PathBuf>,
+    clock: SharedClock,
+}
+
+impl LoginThrottler {
+    pub fn new(policy: ThrottlePolicy, clock: SharedClock) -> LoginThrottler {
+        LoginThrottler {
+            policy: policy,
+            failures: HashMap::new(),
+            lockouts: HashMap::new(),
+            path: None,
+            clock: clock,
+        }
+    }
+
+    // A throttler kept in the file at `path`, starting from what's in it if it's there already.
+    // Lines that can't be read are skipped.
+    pub fn open<P: AsRef<Path>>(path: P,
+                                policy: ThrottlePolicy,
+                                clock: SharedClock)
+                                -> io::Result<LoginThrottler> {
+        let mut throttler = LoginThrottler::new(policy, clock);
+        let path = path.as_ref().to_path_buf();
+        match File::open(&path) {
+            Ok(file) => {
+                let (now, now_ms) = (throttler.clock.now(), throttler.clock.unix_time_ms());
+                for (i, line) in BufReader::new(file).lines().enumerate() {
+                    let line = line?;
+                    match parse_line(&line, now, now_ms) {
+                        Some((true, ip, entry)) => throttler.lockouts.insert(ip, entry),
+                        Some((false, ip, entry)) => throttler.failures.insert(ip, entry),
+                        None => {
+                            println!("THROTTLE FILE skipping line {}: {:?}", i + 1, line);
+                            continue;
+                        }
+                    };
+                }
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        throttler.path = Some(path);
+        Ok(throttler)
+    }
+
+    // How much longer `ip` is locked out for, if it is.
+    pub fn locked_out(&self, ip: IpAddr) -> Option<Duration> {
+        let now = self.clock.now();
+        self.lockouts
+            .get(&ip)
+            .and_then(|&(_, until)| until.checked_duration_since(now))
+            .filter(|left| *left > Duration::from_secs(0))
+    }
+
+    // Count a failed login from `ip`. If that's one too many, `ip` is locked out, for as long as
+    // is returned.
+    pub fn failed(&mut self, ip: IpAddr) -> Option<Duration> {
+        if self.policy.max_failures == 0 {
+            return None;
+        }
+        let now = self.clock.now();
+        self.forget_stale(now);
+
+        let failures = {
+            let entry = self.failures.entry(ip).or_insert((0, now));
+            entry.0 += 1;
+            entry.0
+        };
+        if failures < self.policy.max_failures {
+            self.save();
+            return None;
+        }
+
+        // Each lockout lasts twice as long as the last, up to `max_lockout`, which any sensible
+        // policy reaches long before the doubling is capped.
+        self.failures.remove(&ip);
+        let lockouts = self.lockouts.get(&ip).map_or(0, |&(lockouts, _)| lockouts);
+        let max_lockout = self.policy.max_lockout;
+        let lockout = self.policy
+            .lockout
+            .checked_mul(1 << cmp::min(lockouts, 16))
+            .map_or(max_lockout, |lockout| cmp::min(lockout, max_lockout));
+        self.lockouts.insert(ip, (lockouts.saturating_add(1), now + lockout));
+        self.save();
+        println!("LOCKED OUT {} for {} seconds", ip, lockout.as_secs());
+        Some(lockout)
+    }
+
+    // Forget the failed logins from `ip`, which has just logged in. Its lockouts still count
+    // towards how long the next one is.
+    pub fn succeeded(&mut self, ip: IpAddr) {
+        if self.failures.remove(&ip).is_some() {
+            self.save();
+        }
+    }
+
+    // Forget failures that are too old to count towards a lockout, and lockouts too long over to
+    // count towards the next one, so the maps don't grow with every host that ever got a
+    // password wrong.
+    fn forget_stale(&mut self, now: Instant) {
+        let (window, max_lockout) = (self.policy.window, self.policy.max_lockout);
+        self.failures.retain(|_, &mut (_, since)| now.saturating_duration_since(since) < window);
+        self.lockouts
+            .retain(|_, &mut (_, until)| now.saturating_duration_since(until) < max_lockout);
+    }
+
+    // Write everything out to the file, if there is one, replacing it all at once so a server
+    // that stops partway through leaves the last version behind.
+    fn save(&self) {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return,
+        };
+        let (now, now_ms) = (self.clock.now(), self.clock.unix_time_ms());
+        let mut contents = String::new();
+        for (kind, entries) in &[("failures", &self.failures), ("lockout", &self.lockouts)] {
+            for (ip, &(count, at)) in entries.iter() {
+                let at_ms = to_ms(at, now, now_ms);
+                contents.push_str(&format!("{} {} {} {}\n", kind, ip, count, at_ms));
+            }
+        }
+        let new_path = path.with_extension("new");
+        let saved = File::create(&new_path)
+            .and_then(|mut file| file.write_all(contents.as_bytes()))
+            .and_then(|()| fs::rename(&new_path, path));
+        if let Err(err) = saved {
+            println!("THROTTLE FILE failed: {}", err);
+        }
+    }
+}
+
+// What a client that's `locked_out` is told: how long it has to wait, in whole seconds, rounded
+// up so that trying again then works.
+pub fn too_many_attempts(retry_after: Duration) -> String {
+    let secs = retry_after.as_secs() + if retry_after.subsec_nanos() > 0 { 1 } else { 0 };
+    format!("too many attempts, retry after {} seconds", secs)
+}
+
+// Read a line written by `save`, as whether it's a lockout, whose it is, and the count and time
+// it gives.
+fn parse_line(line: &str, now: Instant, now_ms: u64) -> Option<(bool, IpAddr, (u32, Instant))> {
+    let fields = line.split_whitespace().collect::<Vec<_>>();
+    if fields.len() != 4 {
+        return None;
+    }
+    let lockout = match fields[0] {
+        "failures" => false,
+        "lockout" => true,
+        _ => return None,
+    };
+    let ip = fields[1].parse().ok()?;
+    let count = fields[2].parse().ok()?;
+    let at_ms: u64 = fields[3].parse().ok()?;
+    let at = if at_ms >= now_ms {
+        now + Duration::from_millis(at_ms - now_ms)
+    } else {
+        now.checked_sub(Duration::from_millis(now_ms - at_ms)).unwrap_or(now)
+    };
+    Some((lockout, ip, (count, at)))
+}
+
+fn to_ms(at: Instant, now: Instant, now_ms: u64) -> u64 {
+    if at >= now {
+        now_ms + (at - now).as_millis() as u64
+    } else {
+        now_ms.saturating_sub((now - at).as_millis() as u64)
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+
+use jsonwebtoken::{self, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde_derive::{Deserialize, Serialize};
+
+use clock::SharedClock;
+use session;
+
+// What a login token says: who it's for (`sub`), when it was issued and when it expires (`iat`
+// and `exp`, in seconds since the Unix epoch), and a unique id (`jti`) it can be revoked by.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Claims {
+    pub sub: String,
+    pub iat: u64,
+    pub exp: u64,
+    pub jti: String,
+}
+
+impl Claims {
+    // Whether less than a tenth of the token's lifetime is left, so it's time to hand out a new
+    // one.
+    pub fn needs_refresh(&self, now: u64) -> bool {
+        self.exp.saturating_sub(now) * 10 < self.exp.saturating_sub(self.iat)
+    }
+}
+
+// Issues and checks the signed tokens (JWTs) registered users can log in with instead of their
+// password; see `ServerMessage::Token`. Tokens carry everything needed to check them, so the only
+// thing we keep is the ids of tokens that were revoked before they expired, and only until then.
+pub struct Tokens {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    lifetime: u64,
+    clock: SharedClock,
+    revoked: HashMap<String, u64>,
+}
+
+impl Tokens {
+    // Sign tokens with `secret`; they're good for `lifetime` seconds, as told by `clock`.
+    pub fn new(secret: &[u8], lifetime: u64, clock: SharedClock) -> Tokens {
+        Tokens {
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+            lifetime: lifetime,
+            clock: clock,
+            revoked: HashMap::new(),
+        }
+    }
+
+    // A new token for `username`, and how many seconds it's good for.
+    pub fn issue(&self, username: &str) -> (String, u64) {
+        let now = self.clock.unix_time();
+        let claims = Claims {
+            sub: username.to_string(),
+            iat: now,
+            exp: now + self.lifetime,
+            jti: session::new_token(),
+        };
+        let token = jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &self.encoding)
+            .expect("claims always serialize");
+        (token, self.lifetime)
+    }
+
+    // The claims of `token`, as long as we signed it and it hasn't expired or been revoked.
+    pub fn verify(&mut self, token: &str) -> Option<Claims> {
+        // jsonwebtoken would check the expiry against the system's time, not our clock's, so we
+        // do that ourselves.
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.validate_exp = false;
+        let claims = jsonwebtoken::decode::<Claims>(token, &self.decoding, &validation)
+            .ok()?
+            .claims;
+
+        let now = self.clock.unix_time();
+        self.expire(now);
+        if claims.exp <= now || self.revoked.contains_key(&claims.jti) {
+            return None;
+        }
+        Some(claims)
+    }
+
+    // Stop accepting the token `claims` came from.
+    pub fn revoke(&mut self, claims: &Claims) {
+        let now = self.clock.unix_time();
+        self.expire(now);
+        self.revoked.insert(claims.jti.clone(), claims.exp);
+    }
+
+    // Revoked tokens that have expired would be turned away anyway, so there's no need to keep
+    // them; as with `Sessions`, they're swept out whenever the list is touched.
+    fn expire(&mut self, now: u64) {
+        self.revoked.retain(|_, exp| *exp > now);
+    }
+}
@@ -0,0 +1,78 @@
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio_chat_common::ClientMessage;
+
+// Structured events for each connection, so that what happened to one client can be picked out of
+// everything a busy server is doing at once. Each connection gets a `Span`, and everything that
+// happens to it, from being accepted to disconnecting, is reported to the `Subscriber` (if the
+// server was given one; otherwise nothing is recorded at all) as a `ConnectionEvent` within that
+// span.
+//
+// This plays the part the `tracing` crate would, which this server doesn't depend on; a
+// `Subscriber` passing events on to it, or to a log, is only a few lines.
+pub trait Subscriber {
+    fn event(&self, span: &Span, event: &ConnectionEvent);
+}
+
+pub type SharedSubscriber = Arc<Subscriber + Send + Sync>;
+
+// One connection: a number no other connection to this server gets, the client's address, and,
+// once it's handshaked, its name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub id: u64,
+    pub addr: SocketAddr,
+    pub name: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ConnectionEvent<'a> {
+    Accepted,
+
+    // The client was welcomed, under the name that's now in the span.
+    Handshake,
+
+    // The client is now in this room, either because it just arrived or because it asked to move.
+    Joined(&'a str),
+
+    // A message from the client, before anything's been done with it.
+    Message(&'a ClientMessage),
+
+    Disconnected,
+}
+
+// A connection's `Span`, along with where its events go. Clones report to the same span.
+#[derive(Clone)]
+pub struct Tracer {
+    span: Rc<RefCell<Span>>,
+    subscriber: Option<SharedSubscriber>,
+}
+
+impl Tracer {
+    pub fn new(addr: SocketAddr, subscriber: Option<SharedSubscriber>) -> Tracer {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        Tracer {
+            span: Rc::new(RefCell::new(Span {
+                id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+                addr: addr,
+                name: None,
+            })),
+            subscriber: subscriber,
+        }
+    }
+
+    pub fn named(&self, name: &str) {
+        self.span.borrow_mut().name = Some(name.to_string());
+    }
+
+    pub fn event(&self, event: ConnectionEvent) {
+        if let Some(ref subscriber) = self.subscriber {
+            subscriber.event(&self.span.borrow(), &event);
+        }
+    }
+}
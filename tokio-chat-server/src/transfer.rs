@@ -0,0 +1,38 @@
+// A file a client has offered and is partway through sending. The server doesn't keep any of the
+// file's contents; it just relays chunks to the room and keeps track of enough to turn away chunks
+// that don't fit the offer.
+pub struct Transfer {
+    // The room the file was offered to. Chunks go there even if the sender moves on mid-transfer.
+    pub room: String,
+    size: u64,
+    chunk_count: u32,
+    chunks_received: u32,
+    bytes_received: u64,
+}
+
+impl Transfer {
+    pub fn new(room: String, size: u64, chunk_count: u32) -> Transfer {
+        Transfer {
+            room: room,
+            size: size,
+            chunk_count: chunk_count,
+            chunks_received: 0,
+            bytes_received: 0,
+        }
+    }
+
+    // Account for chunk `index` carrying `len` bytes. Returns whether that was the last chunk.
+    pub fn receive(&mut self, index: u32, len: usize) -> Result<bool, String> {
+        if index >= self.chunk_count {
+            return Err(format!("chunk {} is out of range; the file has {} chunks",
+                               index,
+                               self.chunk_count));
+        }
+        if self.bytes_received + len as u64 > self.size {
+            return Err(format!("chunk {} runs past the end of the {} byte file", index, self.size));
+        }
+        self.chunks_received += 1;
+        self.bytes_received += len as u64;
+        Ok(self.chunks_received == self.chunk_count)
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::Duration;
+
+use futures::{future, Future};
+use futures::future::Loop;
+use futures_cpupool::CpuPool;
+use serde_json::{self, Value};
+use tokio_core::io::{read_to_end, write_all};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Timeout};
+use toml;
+use url::Url;
+
+// A failed delivery is tried again this many times, waiting twice as long before each retry as
+// before the last, starting from `FIRST_RETRY_DELAY_MS`.
+const RETRIES: u32 = 3;
+const FIRST_RETRY_DELAY_MS: u64 = 100;
+
+// The kinds of room event a webhook can ask to hear about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventFilter {
+    // Chat messages, as they're broadcast.
+    Messages,
+
+    // Edits to chat messages.
+    Edits,
+}
+
+impl EventFilter {
+    // What the event is called in the JSON webhooks are sent, and in their configuration.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            EventFilter::Messages => "message",
+            EventFilter::Edits => "edit",
+        }
+    }
+}
+
+impl FromStr for EventFilter {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<EventFilter, ()> {
+        match s {
+            "message" => Ok(EventFilter::Messages),
+            "edit" => Ok(EventFilter::Edits),
+            _ => Err(()),
+        }
+    }
+}
+
+// Which URLs want to hear about which events in which rooms. Each event is `POST`ed to them as
+// JSON; see `Webhooks`. Only plain `http` URLs are supported.
+#[derive(Debug, Clone, Default)]
+pub struct WebhookRegistry {
+    hooks: HashMap<(String, EventFilter), Vec<Url>>,
+}
+
+impl WebhookRegistry {
+    pub fn new() -> WebhookRegistry {
+        WebhookRegistry::default()
+    }
+
+    // Have `url` told about `filter` events in `room`. On failure, returns why `url` won't do.
+    pub fn register(&mut self, room: &str, filter: EventFilter, url: &str) -> Result<(), String> {
+        let url = Url::parse(url).map_err(|err| format!("bad webhook URL {}: {}", url, err))?;
+        if url.scheme() != "http" || url.host_str().is_none() {
+            return Err(format!("webhook URLs must be http://HOST/..., not {}", url));
+        }
+        self.hooks.entry((room.to_string(), filter)).or_default().push(url);
+        Ok(())
+    }
+
+    // Read webhooks from TOML made up of `[[webhook]]` tables like
+    //
+    //     [[webhook]]
+    //     room = "ops"
+    //     url = "http://ci.example.com/chat-events"
+    //     events = ["message", "edit"]
+    //
+    // where `events` can be left out to hear about messages only.
+    pub fn from_toml(text: &str) -> Result<WebhookRegistry, String> {
+        let mut parser = toml::Parser::new(text);
+        let table = match parser.parse() {
+            Some(table) => table,
+            None => {
+                let errors = parser.errors.iter().map(|err| err.to_string()).collect::<Vec<_>>();
+                return Err(errors.join("; "));
+            }
+        };
+
+        let mut registry = WebhookRegistry::new();
+        let webhooks = match table.get("webhook") {
+            Some(webhooks) => webhooks.as_slice().ok_or("[[webhook]] must be a list of tables")?,
+            None => return Ok(registry),
+        };
+        for webhook in webhooks {
+            let field = |name: &str| webhook.lookup(name).and_then(toml::Value::as_str);
+            let (room, url) = match (field("room"), field("url")) {
+                (Some(room), Some(url)) => (room, url),
+                _ => return Err("each [[webhook]] needs a room and a url".to_string()),
+            };
+            let events = match webhook.lookup("events") {
+                Some(events) => {
+                    let names = events.as_slice().ok_or("webhook events must be a list")?;
+                    names.iter()
+                        .map(|name| name.as_str().and_then(|name| name.parse().ok()))
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or("webhook events must be \"message\" or \"edit\"")?
+                }
+                None => vec![EventFilter::Messages],
+            };
+            for filter in events {
+                registry.register(room, filter, url)?;
+            }
+        }
+        Ok(registry)
+    }
+
+    fn urls(&self, room: &str, filter: EventFilter) -> &[Url] {
+        self.hooks.get(&(room.to_string(), filter)).map_or(&[], |urls| urls.as_slice())
+    }
+}
+
+// Sends room events to the webhooks that asked for them. Deliveries run in the background, each
+// giving up after `timeout` per attempt and `RETRIES` retries; a failure is only logged.
+pub struct Webhooks {
+    registry: WebhookRegistry,
+    timeout: Duration,
+    handle: Handle,
+
+    // Looking up hosts' addresses blocks, so it happens here instead of on the event loop.
+    resolver: CpuPool,
+}
+
+impl Webhooks {
+    pub fn new(registry: WebhookRegistry, timeout: Duration, handle: Handle) -> Webhooks {
+        Webhooks {
+            registry: registry,
+            timeout: timeout,
+            handle: handle,
+            resolver: CpuPool::new(1),
+        }
+    }
+
+    // Tell the webhooks listening for `filter` events in `room` about one, described by `fields`
+    // (to which the event's name and room are added).
+    pub fn notify(&self, room: &str, filter: EventFilter, fields: Vec<(&str, Value)>) {
+        let urls = self.registry.urls(room, filter);
+        if urls.is_empty() {
+            return;
+        }
+
+        let mut event = serde_json::Map::new();
+        event.insert("event".to_string(), Value::String(filter.name().to_string()));
+        event.insert("room".to_string(), Value::String(room.to_string()));
+        for (name, value) in fields {
+            event.insert(name.to_string(), value);
+        }
+        let body = serde_json::to_string(&Value::Object(event))
+            .expect("JSON values always serialize");
+
+        for url in urls {
+            let url_inner = url.clone();
+            let delivery = deliver(url.clone(),
+                                   body.clone(),
+                                   self.timeout,
+                                   self.handle.clone(),
+                                   self.resolver.clone());
+            self.handle.spawn(delivery.map_err(move |err| {
+                println!("WEBHOOK delivery to {} failed: {}", url_inner, err)
+            }));
+        }
+    }
+}
+
+// `POST` `body` to `url`, retrying with backoff until it succeeds or we run out of retries.
+pub fn deliver(url: Url,
+               body: String,
+               timeout: Duration,
+               handle: Handle,
+               resolver: CpuPool)
+               -> Box<Future<Item = (), Error = io::Error>> {
+    Box::new(future::loop_fn(0, move |retries| {
+        let handle = handle.clone();
+        attempt(&url, &body, timeout, &handle, &resolver).then(move |result| {
+            let err = match result {
+                Ok(()) => return future::Either::A(future::ok(Loop::Break(()))),
+                Err(err) => err,
+            };
+            if retries == RETRIES {
+                return future::Either::A(future::err(err));
+            }
+            let delay = Duration::from_millis(FIRST_RETRY_DELAY_MS << retries);
+            let wait = future::result(Timeout::new(delay, &handle)).flatten();
+            future::Either::B(wait.map(move |()| Loop::Continue(retries + 1)))
+        })
+    }))
+}
+
+// Make one attempt at `POST`ing `body` to `url`, which succeeds if the receiver answers with a 2xx
+// status within `timeout`.
+fn attempt(url: &Url,
+           body: &str,
+           timeout: Duration,
+           handle: &Handle,
+           resolver: &CpuPool)
+           -> Box<Future<Item = (), Error = io::Error>> {
+    let host = url.host_str().expect("webhook URLs always have a host").to_string();
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addr = resolver.spawn_fn(move || -> io::Result<SocketAddr> {
+        (host.as_str(), port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host has no addresses"))
+    });
+
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let request = format!("POST {} HTTP/1.1\r\n\
+                           Host: {}\r\n\
+                           Content-Type: application/json\r\n\
+                           Content-Length: {}\r\n\
+                           Connection: close\r\n\
+                           \r\n\
+                           {}",
+                          path,
+                          url.host_str().expect("webhook URLs always have a host"),
+                          body.len(),
+                          body);
+
+    let connect_handle = handle.clone();
+    let exchange = addr.and_then(move |addr| TcpStream::connect(&addr, &connect_handle))
+        .and_then(move |socket| write_all(socket, request.into_bytes()))
+        .and_then(|(socket, _)| read_to_end(socket, Vec::new()))
+        .and_then(|(_, response)| {
+            // All we want from the response is its status line: "HTTP/1.1 200 OK", say.
+            let response = String::from_utf8_lossy(&response);
+            let status = response.split(' ').nth(1).unwrap_or("");
+            if status.starts_with('2') && status.len() == 3 {
+                return Ok(());
+            }
+            let status_line = response.lines().next().unwrap_or("no response").to_string();
+            Err(io::Error::new(io::ErrorKind::Other, status_line))
+        });
+
+    let timed_out = future::result(Timeout::new(timeout, handle))
+        .flatten()
+        .and_then(|()| Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")));
+    Box::new(exchange.select(timed_out).map(|_| ()).map_err(|(err, _)| err))
+}
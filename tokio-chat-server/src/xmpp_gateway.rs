@@ -0,0 +1,718 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::rc::Rc;
+use std::str;
+
+use futures::{future, Future, Sink, Stream};
+use futures::sync::mpsc;
+use tokio_core::io::{Codec, EasyBuf, Io};
+use tokio_core::net::TcpStream;
+use tokio_chat_common::{validation, ClientMessage, ServerMessage};
+
+use config::Config;
+use connection::ConnectionMetadata;
+use middleware::{self, ConnectionContext, MessageMiddleware, MiddlewareAction};
+use {Chat, IoFuture};
+
+// Stanzas longer than this are refused, along with the connection they came on.
+const MAX_STANZA_LEN: usize = 64 * 1024;
+
+// Nor do we follow elements nested any deeper than this.
+const MAX_DEPTH: usize = 32;
+
+const MUC_USER_NS: &str = "http://jabber.org/protocol/muc#user";
+const STANZAS_NS: &str = "urn:ietf:params:xml:ns:xmpp-stanzas";
+
+// How to bridge rooms to XMPP multi-user chat (MUC), as a component of an XMPP server (see
+// XEP-0114). Each room then appears as the MUC `ROOM@DOMAIN`.
+#[derive(Debug, Clone)]
+pub struct XmppConfig {
+    // Where the XMPP server takes component connections: `localhost:5347`, say.
+    pub server: String,
+
+    // The domain the XMPP server expects the component to serve, `conference.example.com`, say,
+    // and the secret they share.
+    pub domain: String,
+    pub secret: String,
+}
+
+// The XMPP users in each room, and how to get stanzas to them. `Chat::say` passes everything
+// said in a room on to its XMPP occupants through here, whoever said it (an XMPP user included,
+// since MUC echoes messages back to their senders).
+pub struct XmppBridge {
+    domain: String,
+
+    // By room: each occupant's nick in the room, and their real JID.
+    occupants: RefCell<HashMap<String, Vec<(String, String)>>>,
+
+    // Stanzas for the XMPP server; see `connect`.
+    outbox: mpsc::UnboundedSender<String>,
+}
+
+impl XmppBridge {
+    pub fn new(domain: String, outbox: mpsc::UnboundedSender<String>) -> XmppBridge {
+        XmppBridge {
+            domain: domain,
+            occupants: RefCell::new(HashMap::new()),
+            outbox: outbox,
+        }
+    }
+
+    // Send `body`, said by `from` in `room`, to the room's XMPP occupants.
+    pub fn relay(&self, room: &str, from: &str, body: &str) {
+        if let Some(occupants) = self.occupants.borrow().get(room) {
+            for &(_, ref jid) in occupants {
+                self.send(format!("<message type='groupchat' from='{}' to='{}'><body>{}</body>\
+                                   </message>",
+                                  escape(&self.occupant_jid(room, from)),
+                                  escape(jid),
+                                  escape(body)));
+            }
+        }
+    }
+
+    // The JID `nick` has in `room`: `ROOM@DOMAIN/NICK`.
+    fn occupant_jid(&self, room: &str, nick: &str) -> String {
+        format!("{}@{}/{}", room, self.domain, nick)
+    }
+
+    fn send(&self, stanza: String) {
+        // If the connection's gone, there's no one to tell.
+        let _ = self.outbox.unbounded_send(stanza);
+    }
+
+    // Tell `to` (a real JID) that `nick` is (or, if `available` is false, no longer is) in
+    // `room`. Occupants are told about themselves with status code 110.
+    fn presence(&self, room: &str, nick: &str, to: &str, available: bool, own: bool) {
+        self.send(format!("<presence from='{}' to='{}'{}><x xmlns='{}'><item affiliation='none' \
+                           role='{}'/>{}</x></presence>",
+                          escape(&self.occupant_jid(room, nick)),
+                          escape(to),
+                          if available { "" } else { " type='unavailable'" },
+                          MUC_USER_NS,
+                          if available { "participant" } else { "none" },
+                          if own { "<status code='110'/>" } else { "" }));
+    }
+
+    // Refuse `stanza` from `to`, sent to `from`, for the reason `condition` (one of RFC 6120's
+    // stanza error conditions) of type `kind`.
+    fn refuse(&self, stanza: &str, from: &str, to: &str, kind: &str, condition: &str) {
+        self.send(format!("<{0} type='error' from='{1}' to='{2}'><error type='{3}'><{4} \
+                           xmlns='{5}'/></error></{0}>",
+                          stanza,
+                          escape(from),
+                          escape(to),
+                          kind,
+                          condition,
+                          STANZAS_NS));
+    }
+
+    // `jid` asked to be `nick` in `room`. On success, returns whether it's new there.
+    fn enter(&self, room: &str, nick: &str, jid: &str) -> Result<bool, ()> {
+        let mut occupants = self.occupants.borrow_mut();
+        let occupants = occupants.entry(room.to_string()).or_default();
+        if occupants.iter().any(|&(_, ref occupant)| occupant == jid) {
+            return Ok(false);
+        }
+        if occupants.iter().any(|&(ref taken, _)| taken == nick) {
+            return Err(());
+        }
+
+        // The newcomer hears who's there already, then everyone, newcomer included, hears about
+        // the newcomer.
+        for &(ref other, _) in occupants.iter() {
+            self.presence(room, other, jid, true, false);
+        }
+        occupants.push((nick.to_string(), jid.to_string()));
+        for &(_, ref occupant) in occupants.iter() {
+            self.presence(room, nick, occupant, true, occupant == jid);
+        }
+        Ok(true)
+    }
+
+    // `jid` left `room`. Returns the nick it had there, if it was there at all.
+    fn leave(&self, room: &str, jid: &str) -> Option<String> {
+        let mut occupants = self.occupants.borrow_mut();
+        let occupants = occupants.get_mut(room)?;
+        let index = occupants.iter().position(|&(_, ref occupant)| occupant == jid)?;
+        let (nick, _) = occupants.remove(index);
+        self.presence(room, &nick, jid, false, true);
+        for &(_, ref occupant) in occupants.iter() {
+            self.presence(room, &nick, occupant, false, false);
+        }
+        Some(nick)
+    }
+
+    // The nick `jid` has in `room`, if it's there.
+    fn nick(&self, room: &str, jid: &str) -> Option<String> {
+        self.occupants
+            .borrow()
+            .get(room)?
+            .iter()
+            .find(|&&(_, ref occupant)| occupant == jid)
+            .map(|&(ref nick, _)| nick.clone())
+    }
+}
+
+// Connect to the XMPP server `xmpp` names as its component, and bridge `chat`'s rooms to MUC
+// through it until the connection closes, sending what's in `outbox` (see `XmppBridge`). XMPP
+// users entering and leaving a room are announced to its native members with
+// `ServerMessage::UserJoined` and `ServerMessage::UserLeft`, and what they say goes through the
+// middleware and the room's length limit, as a post to the HTTP API would. This is only as much of
+// XEP-0045 as that takes: native users don't appear in rooms' XMPP occupant lists, and there are
+// no private messages, subjects or room configuration.
+pub fn connect(xmpp: &XmppConfig,
+               chat: Chat,
+               config: Rc<Config>,
+               middleware: Rc<Vec<Box<MessageMiddleware>>>,
+               outbox: mpsc::UnboundedReceiver<String>)
+               -> IoFuture<()> {
+    let addr = match xmpp.server.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(addr)) => addr,
+        Ok(None) => {
+            let err = io::Error::new(io::ErrorKind::NotFound, "XMPP server has no addresses");
+            return Box::new(future::err(err));
+        }
+        Err(err) => return Box::new(future::err(err)),
+    };
+    let bridge = chat.xmpp.clone().expect("the XMPP gateway needs a bridge");
+    let secret = xmpp.secret.clone();
+    let header = format!("<stream:stream xmlns='jabber:component:accept' \
+                          xmlns:stream='http://etherx.jabber.org/streams' to='{}'>",
+                         escape(&xmpp.domain));
+
+    // Open a stream, then prove we're the component the server's expecting by hashing the id it
+    // gives its end of the stream with our shared secret.
+    let stream = TcpStream::connect(&addr, &chat.handle)
+        .and_then(|socket| socket.framed(XmppCodec).send(header))
+        .and_then(|stream| stream.into_future().map_err(|(err, _)| err))
+        .and_then(move |(event, stream)| {
+            let id = match event {
+                Some(Event::Start(ref attrs)) => attr(attrs, "id").unwrap_or("").to_string(),
+                _ => return future::Either::A(future::err(refused("no stream header"))),
+            };
+            let handshake = format!("<handshake>{}</handshake>", sha1_hex(id + &secret));
+            future::Either::B(stream.send(handshake))
+        })
+        .and_then(|stream| stream.into_future().map_err(|(err, _)| err))
+        .and_then(move |(event, stream)| {
+            match event {
+                Some(Event::Stanza(ref element)) if element.name == "handshake" => {
+                    println!("XMPP GATEWAY connected to {:?}", addr);
+                    Ok(stream)
+                }
+                _ => Err(refused("the server refused our secret")),
+            }
+        });
+
+    Box::new(stream.and_then(move |stream| {
+        let (to_server, from_server) = stream.split();
+        let gateway = Gateway {
+            chat: chat,
+            bridge: bridge,
+            config: config,
+            middleware: middleware,
+            addr: addr,
+        };
+        let reader = from_server.take_while(|event| Ok(!event.is_end()))
+            .for_each(move |event| {
+                match event {
+                    Event::Stanza(stanza) => gateway.handle(stanza),
+                    _ => Box::new(future::ok(())),
+                }
+            });
+        let writer = outbox.map_err(|()| unreachable!("rx can't fail"))
+            .forward(to_server)
+            .map(|_| ());
+        reader.select(writer).map(|_| ()).map_err(|(err, _)| err)
+    }))
+}
+
+fn refused(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, reason.to_string())
+}
+
+struct Gateway {
+    chat: Chat,
+    bridge: Rc<XmppBridge>,
+    config: Rc<Config>,
+    middleware: Rc<Vec<Box<MessageMiddleware>>>,
+
+    // The XMPP server's address, which stands in for any one user's in the middleware's
+    // `ConnectionContext`.
+    addr: SocketAddr,
+}
+
+impl Gateway {
+    // Act on `stanza`, sent to a room by an XMPP user. Anything that isn't to one of our rooms,
+    // or that we've no use for, is ignored.
+    fn handle(&self, stanza: Element) -> IoFuture<()> {
+        let (from, to) = match (stanza.attr("from"), stanza.attr("to")) {
+            (Some(from), Some(to)) => (from, to),
+            _ => return Box::new(future::ok(())),
+        };
+        let (room, nick) = match self.room(to) {
+            Some(room) => room,
+            None => return Box::new(future::ok(())),
+        };
+        match (stanza.name.as_str(), stanza.attr("type")) {
+            ("presence", Some("unavailable")) => {
+                match self.bridge.leave(&room, from) {
+                    Some(nick) => {
+                        let left = ServerMessage::UserLeft(nick, room.clone());
+                        self.chat.clients.broadcast_room(&room, left)
+                    }
+                    None => Box::new(future::ok(())),
+                }
+            }
+            ("presence", None) => {
+                // Nicks are names like any other, and have to be in their normal form to be
+                // checked against the ones that are taken.
+                let nick = match nick.as_ref().map(|nick| validation::normalize_username(nick)) {
+                    Some(Ok(nick)) => nick,
+                    Some(Err(_)) => {
+                        self.bridge.refuse("presence", to, from, "modify", "not-acceptable");
+                        return Box::new(future::ok(()));
+                    }
+                    None => {
+                        self.bridge.refuse("presence", to, from, "modify", "jid-malformed");
+                        return Box::new(future::ok(()));
+                    }
+                };
+                match self.bridge.enter(&room, &nick, from) {
+                    Ok(true) => {
+                        let joined = ServerMessage::UserJoined(nick, room.clone(), None);
+                        self.chat.clients.broadcast_room(&room, joined)
+                    }
+                    Ok(false) => Box::new(future::ok(())),
+                    Err(()) => {
+                        self.bridge.refuse("presence", to, from, "cancel", "conflict");
+                        Box::new(future::ok(()))
+                    }
+                }
+            }
+            ("message", Some("groupchat")) => {
+                let body = match stanza.child("body") {
+                    Some(body) => body.text.clone(),
+                    None => return Box::new(future::ok(())),
+                };
+                match self.bridge.nick(&room, from) {
+                    Some(nick) => self.say(&room, &nick, from, body),
+                    None => {
+                        self.bridge.refuse("message", to, from, "modify", "not-acceptable");
+                        Box::new(future::ok(()))
+                    }
+                }
+            }
+            _ => Box::new(future::ok(())),
+        }
+    }
+
+    // The room `jid` (`ROOM@DOMAIN`, or `ROOM@DOMAIN/NICK`) is, and the nick, if there is one.
+    fn room(&self, jid: &str) -> Option<(String, Option<String>)> {
+        let (bare, nick) = match jid.split_once('/') {
+            Some((bare, nick)) => (bare, Some(nick.to_string()).filter(|nick| !nick.is_empty())),
+            None => (jid, None),
+        };
+        let (room, domain) = bare.split_once('@')?;
+        if room.is_empty() || domain != self.bridge.domain {
+            return None;
+        }
+        Some((room.to_string(), nick))
+    }
+
+    // Say `body` in `room` as `nick`, whose real JID is `jid`, if the middleware and the room's
+    // policy let us.
+    fn say(&self, room: &str, nick: &str, jid: &str, body: String) -> IoFuture<()> {
+        let occupant = self.bridge.occupant_jid(room, nick);
+        if body.len() > self.config.policies.for_room(room).max_body_len {
+            self.bridge.refuse("message", &occupant, jid, "modify", "not-acceptable");
+            return Box::new(future::ok(()));
+        }
+
+        let mut msg = ClientMessage::Message(body);
+        let metadata = ConnectionMetadata::new();
+        let ctx = ConnectionContext {
+            addr: self.addr,
+            name: nick,
+            room: room,
+            metadata: &metadata,
+        };
+        match middleware::run(&self.middleware, &mut msg, &ctx) {
+            MiddlewareAction::Allow | MiddlewareAction::Modify => {}
+            MiddlewareAction::Drop => return Box::new(future::ok(())),
+            MiddlewareAction::Error(_) => {
+                self.bridge.refuse("message", &occupant, jid, "modify", "not-acceptable");
+                return Box::new(future::ok(()));
+            }
+        }
+        match msg {
+            ClientMessage::Message(body) => Box::new(self.chat.say(room, nick, body).map(|_| ())),
+            _ => Box::new(future::ok(())),
+        }
+    }
+}
+
+// What the XMPP server sends: its end of the stream opening (with its attributes), stanzas, and
+// finally the stream closing.
+enum Event {
+    Start(Vec<(String, String)>),
+    Stanza(Element),
+    End,
+}
+
+impl Event {
+    fn is_end(&self) -> bool {
+        match *self {
+            Event::End => true,
+            _ => false,
+        }
+    }
+}
+
+// An XML element, as much of one as we look at: its name, attributes, child elements and the
+// text directly inside it.
+#[derive(Debug, Clone, Default)]
+struct Element {
+    name: String,
+    attrs: Vec<(String, String)>,
+    children: Vec<Element>,
+    text: String,
+}
+
+impl Element {
+    fn attr(&self, name: &str) -> Option<&str> {
+        attr(&self.attrs, name)
+    }
+
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.name == name)
+    }
+}
+
+fn attr<'a>(attrs: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    attrs.iter().find(|&&(ref attr, _)| attr == name).map(|&(_, ref value)| value.as_str())
+}
+
+// An opening tag: its name and attributes, and whether it's empty (`<name/>`), closing itself.
+struct Tag {
+    name: String,
+    attrs: Vec<(String, String)>,
+    empty: bool,
+}
+
+// How parsing something at the start of a buffer went: it's not all there yet, it's not XML we
+// understand, or here it is, followed by the offset just past it.
+enum Parsed<T> {
+    Incomplete,
+    Malformed,
+    Done(T, usize),
+}
+
+impl<T> Parsed<T> {
+    fn map<U, F: FnOnce(T) -> U>(self, f: F) -> Parsed<U> {
+        match self {
+            Parsed::Incomplete => Parsed::Incomplete,
+            Parsed::Malformed => Parsed::Malformed,
+            Parsed::Done(parsed, end) => Parsed::Done(f(parsed), end),
+        }
+    }
+}
+
+// Parse the tag starting at `start` in `s`, which must be a `<`.
+fn parse_tag(s: &str, start: usize) -> Parsed<Tag> {
+    let bytes = s.as_bytes();
+    let mut i = start + 1;
+    let name_end = match s[i..].find(|c: char| c.is_whitespace() || c == '/' || c == '>') {
+        Some(len) => i + len,
+        None => return Parsed::Incomplete,
+    };
+    let name = s[i..name_end].to_string();
+    if name.is_empty() {
+        return Parsed::Malformed;
+    }
+    i = name_end;
+
+    let mut attrs = Vec::new();
+    loop {
+        while bytes.get(i).is_some_and(|b| b.is_ascii_whitespace()) {
+            i += 1;
+        }
+        match bytes.get(i) {
+            None => return Parsed::Incomplete,
+            Some(b'>') => {
+                let tag = Tag {
+                    name: name,
+                    attrs: attrs,
+                    empty: false,
+                };
+                return Parsed::Done(tag, i + 1);
+            }
+            Some(b'/') => {
+                return match bytes.get(i + 1) {
+                    None => Parsed::Incomplete,
+                    Some(b'>') => {
+                        let tag = Tag {
+                            name: name,
+                            attrs: attrs,
+                            empty: true,
+                        };
+                        Parsed::Done(tag, i + 2)
+                    }
+                    Some(_) => Parsed::Malformed,
+                };
+            }
+            Some(_) => {
+                let eq = match s[i..].find('=') {
+                    Some(len) => i + len,
+                    None => return Parsed::Incomplete,
+                };
+                let attr_name = s[i..eq].trim().to_string();
+                let mut j = eq + 1;
+                while bytes.get(j).is_some_and(|b| b.is_ascii_whitespace()) {
+                    j += 1;
+                }
+                let quote = match bytes.get(j) {
+                    None => return Parsed::Incomplete,
+                    Some(&quote) if quote == b'\'' || quote == b'"' => quote as char,
+                    Some(_) => return Parsed::Malformed,
+                };
+                let value_end = match s[j + 1..].find(quote) {
+                    Some(len) => j + 1 + len,
+                    None => return Parsed::Incomplete,
+                };
+                attrs.push((attr_name, unescape(&s[j + 1..value_end])));
+                i = value_end + 1;
+            }
+        }
+    }
+}
+
+// Parse the element starting at `start` in `s`, which must be a `<`, `depth` elements deep.
+fn parse_element(s: &str, start: usize, depth: usize) -> Parsed<Element> {
+    if depth > MAX_DEPTH {
+        return Parsed::Malformed;
+    }
+    let (tag, mut i) = match parse_tag(s, start) {
+        Parsed::Done(tag, end) => (tag, end),
+        Parsed::Incomplete => return Parsed::Incomplete,
+        Parsed::Malformed => return Parsed::Malformed,
+    };
+    let mut element = Element {
+        name: tag.name,
+        attrs: tag.attrs,
+        ..Element::default()
+    };
+    if tag.empty {
+        return Parsed::Done(element, i);
+    }
+
+    loop {
+        let lt = match s[i..].find('<') {
+            Some(len) => i + len,
+            None => return Parsed::Incomplete,
+        };
+        element.text.push_str(&unescape(&s[i..lt]));
+        let rest = &s[lt..];
+        if rest.starts_with("</") {
+            let gt = match rest.find('>') {
+                Some(len) => lt + len,
+                None => return Parsed::Incomplete,
+            };
+            if s[lt + 2..gt].trim() != element.name {
+                return Parsed::Malformed;
+            }
+            return Parsed::Done(element, gt + 1);
+        } else if rest.starts_with("<!--") {
+            i = match rest.find("-->") {
+                Some(len) => lt + len + 3,
+                None => return Parsed::Incomplete,
+            };
+        } else if rest.starts_with("<![CDATA[") {
+            let end = match rest.find("]]>") {
+                Some(len) => len,
+                None => return Parsed::Incomplete,
+            };
+            element.text.push_str(&rest[9..end]);
+            i = lt + end + 3;
+        } else {
+            match parse_element(s, lt, depth + 1) {
+                Parsed::Done(child, end) => {
+                    element.children.push(child);
+                    i = end;
+                }
+                Parsed::Incomplete => return Parsed::Incomplete,
+                Parsed::Malformed => return Parsed::Malformed,
+            }
+        }
+    }
+}
+
+// The next thing in `bytes`, if it's all there, with how many bytes it took up. Whitespace
+// between stanzas and XML declarations take up bytes but aren't anything.
+fn next_event(bytes: &[u8]) -> io::Result<(usize, Option<Event>)> {
+    // The end of the buffer may be partway through a character.
+    let s = match str::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(err) if err.error_len().is_none() => {
+            str::from_utf8(&bytes[..err.valid_up_to()]).expect("valid up to here")
+        }
+        Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+    };
+    let start = s.len() - s.trim_start().len();
+    let rest = &s[start..];
+    let parsed = if rest.is_empty() {
+        return Ok((start, None));
+    } else if rest.starts_with("<?") {
+        return Ok(match rest.find("?>") {
+            Some(len) => (start + len + 2, None),
+            None => (start, None),
+        });
+    } else if rest.starts_with("</stream:stream") {
+        return Ok((s.len(), Some(Event::End)));
+    } else if rest.starts_with("<stream:stream") {
+        parse_tag(s, start).map(|tag| Event::Start(tag.attrs))
+    } else {
+        parse_element(s, start, 0).map(Event::Stanza)
+    };
+    match parsed {
+        Parsed::Done(event, end) => Ok((end, Some(event))),
+        Parsed::Incomplete => Ok((start, None)),
+        Parsed::Malformed => Err(io::Error::new(io::ErrorKind::InvalidData, "malformed XML")),
+    }
+}
+
+// Frames a component's XML stream: `Event`s in, and stanzas (already serialized) out.
+struct XmppCodec;
+
+impl Codec for XmppCodec {
+    type In = Event;
+    type Out = String;
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Event>> {
+        loop {
+            let (len, event) = next_event(buf.as_slice())?;
+            buf.drain_to(len);
+            if event.is_some() {
+                return Ok(event);
+            }
+            if len == 0 {
+                if buf.len() > MAX_STANZA_LEN {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "stanza too long"));
+                }
+                return Ok(None);
+            }
+        }
+    }
+
+    fn encode(&mut self, stanza: String, buf: &mut Vec<u8>) -> io::Result<()> {
+        buf.extend_from_slice(stanza.as_bytes());
+        Ok(())
+    }
+}
+
+// Escape `s` for use in XML text or (quoted) attribute values.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Undo `escape`, along with character references (`&#65;`, `&#x41;`). Anything else that looks
+// like an entity is left as it is.
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(amp) = rest.find('&') {
+        unescaped.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let semi = match rest.find(';') {
+            Some(semi) => semi,
+            None => break,
+        };
+        let entity = &rest[1..semi];
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => {
+                entity.strip_prefix("#x")
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                    .and_then(char::from_u32)
+            }
+        };
+        match c {
+            Some(c) => {
+                unescaped.push(c);
+                rest = &rest[semi + 1..];
+            }
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+// The SHA-1 of `data`, in lower-case hex, which is what the component handshake takes. It's all
+// we need SHA-1 for, so it isn't worth a dependency.
+fn sha1_hex(data: String) -> String {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.into_bytes();
+    let bit_len = (message.len() as u64).wrapping_mul(8);
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&bit_len.to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (word, bytes) in w.iter_mut().zip(block.chunks(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a.rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, v) in h.iter_mut().zip(&[a, b, c, d, e]) {
+            *h = h.wrapping_add(*v);
+        }
+    }
+    h.iter().map(|word| format!("{:08x}", word)).collect()
+}
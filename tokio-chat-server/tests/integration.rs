@@ -0,0 +1,2619 @@
+// End-to-end tests: each one starts a real server on an ephemeral port, in-process, and talks to
+// it over TCP with the same codecs the real client uses.
+
+extern crate futures;
+extern crate jsonwebtoken;
+extern crate ring;
+extern crate serde_json;
+extern crate tokio_core;
+extern crate tokio_chat_bot;
+extern crate tokio_chat_common;
+extern crate tokio_chat_server;
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener as StdTcpListener, TcpStream};
+use std::process;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::{Future, Stream};
+use tokio_core::io::{Codec, EasyBuf};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::{Core, Timeout};
+use tokio_chat_bot::MessageHandler;
+use tokio_chat_common::client::{Client, ClientBuilder, ClientError, PingTimer,
+                                ReconnectConfig};
+use tokio_chat_common::testing::MockServer;
+use tokio_chat_common::{Handshake, HandshakeCodec, ClientMessage, ServerMessage,
+                        ClientToServerCodec, ChatMessage, ContentType, ErrorCode, UserInfo,
+                        RoomConfig, FileAssembly, DEFAULT_ROOM, capability, offer_file, rfc3339};
+use tokio_chat_server::{AuditLogger, BlockMode, Claims, Clock, Config, ConnectionEvent, LocalBus,
+                        MockClock, Span,
+                        SqliteUserStore, Subscriber, UserStore, WebhookRegistry, DiscordConfig,
+                        SlackConfig, XmppConfig};
+
+// The settings most tests want: the defaults, but letting in clients that haven't registered.
+fn guest_config() -> Config {
+    Config { allow_guests: true, ..Config::default() }
+}
+
+// Start a server with `config` on its own thread and return the address it's listening on. The
+// server runs until the test process exits.
+fn start_server(config: Config) -> SocketAddr {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        tx.send(listener.local_addr().unwrap()).unwrap();
+        core.run(tokio_chat_server::serve(listener, config, handle)).unwrap();
+    });
+    rx.recv().unwrap()
+}
+
+// A client that blocks on its socket, which keeps the tests straightforward.
+struct TestClient {
+    name: String,
+    stream: TcpStream,
+    codec: ClientToServerCodec,
+    buf: EasyBuf,
+    resume_token: String,
+    capabilities: Vec<String>,
+}
+
+impl TestClient {
+    // Connect to `addr` without handshaking.
+    fn open<S: Into<String>>(addr: &SocketAddr, name: S) -> TestClient {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        TestClient {
+            name: name.into(),
+            stream: stream,
+            codec: ClientToServerCodec::new(),
+            buf: EasyBuf::new(),
+            resume_token: String::new(),
+            capabilities: Vec::new(),
+        }
+    }
+
+    // Connect to `addr` and send `handshake`, without waiting to hear back.
+    fn handshake(addr: &SocketAddr, handshake: Handshake) -> TestClient {
+        let mut client = TestClient::open(addr, handshake.name.clone());
+        client.send_handshake(handshake);
+        client
+    }
+
+    // Connect to `addr` and handshake, waiting for the server's `Welcome` before returning.
+    fn connect(addr: &SocketAddr, handshake: Handshake) -> TestClient {
+        let mut client = TestClient::handshake(addr, handshake);
+        client.welcome();
+        client
+    }
+
+    fn send_handshake(&mut self, handshake: Handshake) {
+        let mut frame = Vec::new();
+        HandshakeCodec::new().encode(handshake, &mut frame).unwrap();
+        self.stream.write_all(&frame).unwrap();
+    }
+
+    // Wait for the server's `Welcome`, and remember what it says.
+    fn welcome(&mut self) {
+        match self.recv() {
+            ServerMessage::Welcome { resume_token, capabilities } => {
+                self.resume_token = resume_token;
+                self.capabilities = capabilities;
+            }
+            msg => panic!("{} expected a welcome, got {:?}", self.name, msg),
+        }
+    }
+
+    fn send(&mut self, msg: ClientMessage) {
+        let mut frame = Vec::new();
+        self.codec.encode(msg, &mut frame).unwrap();
+        self.stream.write_all(&frame).unwrap();
+    }
+
+    // Send `payload` as a frame of its own, whether or not it's a valid message.
+    fn send_raw(&mut self, payload: &[u8]) {
+        let mut frame = vec![(payload.len() >> 8) as u8, payload.len() as u8];
+        frame.extend_from_slice(payload);
+        self.stream.write_all(&frame).unwrap();
+    }
+
+    // The next message from the server, or `None` if the server sends nothing for `wait`.
+    fn recv_within(&mut self, wait: Duration) -> Option<ServerMessage> {
+        self.stream.set_read_timeout(Some(wait)).unwrap();
+        let msg = loop {
+            if let Some(msg) = self.codec.decode(&mut self.buf).unwrap() {
+                break Some(msg);
+            }
+            let mut chunk = [0; 4096];
+            match self.stream.read(&mut chunk) {
+                Ok(n) => {
+                    assert!(n > 0, "server closed the connection to {}", self.name);
+                    self.buf.get_mut().extend_from_slice(&chunk[..n]);
+                }
+                Err(_) => break None,
+            }
+        };
+        self.stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        msg
+    }
+
+    // The next message from the server, failing the test if none shows up in time.
+    fn recv(&mut self) -> ServerMessage {
+        loop {
+            if let Some(msg) = self.codec.decode(&mut self.buf).unwrap() {
+                return msg;
+            }
+            let mut chunk = [0; 4096];
+            let n = self.stream
+                .read(&mut chunk)
+                .unwrap_or_else(|err| panic!("{} is still waiting: {}", self.name, err));
+            assert!(n > 0, "server closed the connection to {}", self.name);
+            self.buf.get_mut().extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    // Skip over messages until one that `want` picks out arrives, and return that.
+    fn recv_until<T, F>(&mut self, mut want: F) -> T
+        where F: FnMut(ServerMessage) -> Option<T>
+    {
+        loop {
+            if let Some(found) = want(self.recv()) {
+                return found;
+            }
+        }
+    }
+
+    // The next chat message, as (from, body).
+    fn recv_chat(&mut self) -> (String, String) {
+        self.recv_until(|msg| match msg {
+            ServerMessage::Message(_, from, body) => Some((from, body)),
+            _ => None,
+        })
+    }
+
+    // Move to `room`, waiting until the server confirms it.
+    fn join(&mut self, room: &str) {
+        self.send(ClientMessage::Join(room.to_string()));
+        let name = self.name.clone();
+        self.recv_until(|msg| match msg {
+            ServerMessage::UserJoined(ref user, ref joined, _) if *user == name &&
+                                                                  joined == room => Some(()),
+            _ => None,
+        })
+    }
+
+    // Ask who's in our room.
+    fn who(&mut self) -> (String, Vec<UserInfo>) {
+        self.send(ClientMessage::Who);
+        self.recv_until(|msg| match msg {
+            ServerMessage::Users(room, users) => Some((room, users)),
+            _ => None,
+        })
+    }
+
+    // Register `username`, returning the server's answer: `Registered` or an error.
+    fn register(&mut self, username: &str, password: &str) -> ServerMessage {
+        self.send(ClientMessage::Register {
+            username: username.to_string(),
+            password: password.to_string(),
+        });
+        self.recv_until(|msg| match msg {
+            msg @ ServerMessage::Registered(_) |
+            msg @ ServerMessage::Error(..) => Some(msg),
+            _ => None,
+        })
+    }
+}
+
+// The next message `client` receives, running `core` until it does, and failing the test if none
+// shows up in time (or the connection's given up on).
+fn recv_from(core: &mut Core, client: &mut Client) -> ServerMessage {
+    let timeout = Timeout::new(Duration::from_secs(5), &core.handle()).unwrap();
+    let next = client.recv().into_future().map(|(msg, _)| msg).map_err(|(err, _)| err);
+    match core.run(next.map(Some).select(timeout.map(|()| None).map_err(ClientError::from))) {
+        Ok((Some(Some(msg)), _)) => msg,
+        Ok((Some(None), _)) => panic!("the client was closed"),
+        Ok((None, _)) => panic!("the client is still waiting"),
+        Err((err, _)) => panic!("the client gave up: {}", err),
+    }
+}
+
+// The next chat message `client` receives, as (from, body).
+fn recv_chat_from(core: &mut Core, client: &mut Client) -> (String, String) {
+    loop {
+        if let ServerMessage::Message(_, from, body) = recv_from(core, client) {
+            return (from, body);
+        }
+    }
+}
+
+// Handshake with `addr`, expecting to be told we're not welcome and then hung up on.
+fn assert_unauthorized(addr: &SocketAddr, handshake: Handshake) {
+    let mut client = TestClient::handshake(addr, handshake);
+    match client.recv() {
+        ServerMessage::Error(ErrorCode::Unauthorized, _) => {}
+        msg => panic!("{} expected to be refused, got {:?}", client.name, msg),
+    }
+    let mut rest = Vec::new();
+    assert_eq!(client.stream.read_to_end(&mut rest).unwrap(), 0);
+}
+
+// Make a request of the HTTP API at `addr`, with `token` as the bearer token (if any), and return
+// the response's status code and JSON.
+fn http(addr: &SocketAddr,
+        method: &str,
+        path: &str,
+        token: Option<&str>,
+        body: &str)
+        -> (u16, serde_json::Value) {
+    let auth = token.map_or(String::new(), |token| format!("Authorization: Bearer {}\r\n", token));
+    http_with_headers(addr, method, path, &auth, body)
+}
+
+// Likewise, but with `headers` (each ending in CRLF) instead of a bearer token.
+fn http_with_headers(addr: &SocketAddr,
+                     method: &str,
+                     path: &str,
+                     headers: &str,
+                     body: &str)
+                     -> (u16, serde_json::Value) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    write!(stream,
+           "{} {} HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: {}\r\n\r\n{}",
+           method,
+           path,
+           headers,
+           body.len(),
+           body)
+        .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response.split(' ').nth(1).unwrap().parse().unwrap();
+    let json = response.split("\r\n\r\n").nth(1).unwrap();
+    (status, serde_json::from_str(json).unwrap())
+}
+
+// A client of the IRC gateway, which, like `TestClient`, blocks on its socket.
+struct IrcClient {
+    reader: BufReader<TcpStream>,
+    stream: TcpStream,
+}
+
+impl IrcClient {
+    // Connect to `addr` and register as `nick`, waiting to be welcomed.
+    fn connect(addr: &SocketAddr, nick: &str) -> IrcClient {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut client = IrcClient {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            stream: stream,
+        };
+        client.send(&format!("NICK {}", nick));
+        client.send(&format!("USER {} 0 * :{}", nick, nick));
+        client.recv_until(&format!(" 001 {} ", nick));
+        client
+    }
+
+    fn send(&mut self, line: &str) {
+        write!(self.stream, "{}\r\n", line).unwrap();
+    }
+
+    // Skip over lines until one containing `wanted` arrives, and return that.
+    fn recv_until(&mut self, wanted: &str) -> String {
+        loop {
+            let mut line = String::new();
+            let n = self.reader
+                .read_line(&mut line)
+                .unwrap_or_else(|err| panic!("still waiting for {:?}: {}", wanted, err));
+            assert!(n > 0, "the gateway hung up while we waited for {:?}", wanted);
+            if line.contains(wanted) {
+                return line.trim_end().to_string();
+            }
+        }
+    }
+}
+
+// The XMPP server end of an XMPP gateway's component connection.
+struct XmppServer {
+    stream: TcpStream,
+    received: String,
+}
+
+impl XmppServer {
+    // Wait for the gateway to connect to `listener`.
+    fn accept(listener: &StdTcpListener) -> XmppServer {
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        XmppServer {
+            stream: stream,
+            received: String::new(),
+        }
+    }
+
+    fn send(&mut self, xml: &str) {
+        self.stream.write_all(xml.as_bytes()).unwrap();
+    }
+
+    // Read until the gateway has sent `wanted`, and forget everything up to the end of it.
+    fn expect(&mut self, wanted: &str) {
+        loop {
+            if let Some(start) = self.received.find(wanted) {
+                self.received.drain(..start + wanted.len());
+                return;
+            }
+            let mut chunk = [0; 4096];
+            let n = self.stream
+                .read(&mut chunk)
+                .unwrap_or_else(|err| panic!("still waiting for {:?}: {}", wanted, err));
+            assert!(n > 0, "the gateway hung up while we waited for {:?}", wanted);
+            self.received.push_str(&String::from_utf8_lossy(&chunk[..n]));
+        }
+    }
+}
+
+// Read an HTTP request off of `stream` and return its body.
+fn read_http_body(stream: &mut TcpStream) -> String {
+    read_http_request(stream).1
+}
+
+// Likewise, but return its head (the request line and headers) as well.
+fn read_http_request(stream: &mut TcpStream) -> (String, String) {
+    let mut request = Vec::new();
+    let mut chunk = [0; 4096];
+    loop {
+        let n = stream.read(&mut chunk).unwrap();
+        assert!(n > 0, "the request was cut short");
+        request.extend_from_slice(&chunk[..n]);
+        let request = String::from_utf8_lossy(&request).into_owned();
+        if let Some(head_len) = request.find("\r\n\r\n").map(|end| end + 4) {
+            let content_len = request[..head_len]
+                .lines()
+                .filter_map(|line| line.strip_prefix("Content-Length: "))
+                .next()
+                .map_or(0, |len| len.parse().unwrap());
+            if request.len() >= head_len + content_len {
+                let body = request[head_len..head_len + content_len].to_string();
+                return (request[..head_len].to_string(), body);
+            }
+        }
+    }
+}
+
+#[test]
+fn messages_reach_everyone_in_the_room_in_order() {
+    let addr = start_server(guest_config());
+
+    let mut clients = (1..11)
+        .map(|i| TestClient::connect(&addr, Handshake::new(format!("client{}", i))))
+        .collect::<Vec<_>>();
+    for client in &mut clients {
+        client.join("ops");
+    }
+    let mut outsider = TestClient::connect(&addr, Handshake::new("outsider"));
+
+    for i in 0..5 {
+        clients[0].send(ClientMessage::new(format!("message {}", i)));
+    }
+
+    // Everyone in the room, sender included, gets every message, in the order it was sent.
+    for client in &mut clients {
+        for i in 0..5 {
+            assert_eq!(client.recv_chat(),
+                       ("client1".to_string(), format!("message {}", i)),
+                       "as seen by {}",
+                       client.name);
+        }
+    }
+
+    // Nobody outside the room does: the first chat the outsider sees is its own.
+    outsider.send(ClientMessage::new("anyone here?"));
+    assert_eq!(outsider.recv_chat(),
+               ("outsider".to_string(), "anyone here?".to_string()));
+}
+
+#[test]
+fn disconnects_are_announced() {
+    let addr = start_server(guest_config());
+
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+    let bob = TestClient::connect(&addr, Handshake::new("bob"));
+    drop(bob);
+
+    alice.recv_until(|msg| match msg {
+        ServerMessage::UserDisconnected(ref user) if user == "bob" => Some(()),
+        _ => None,
+    });
+}
+
+#[test]
+fn clients_are_greeted_with_the_motd() {
+    let motd = "be excellent to each other";
+    let addr = start_server(Config { motd: Some(motd.to_string()), ..guest_config() });
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+    assert_eq!(alice.recv(), ServerMessage::Motd(motd.to_string()));
+
+    // Without one, the welcome is followed straight away by the usual announcement.
+    let addr = start_server(guest_config());
+    let mut bob = TestClient::connect(&addr, Handshake::new("bob"));
+    assert_eq!(bob.recv(), ServerMessage::UserConnected("bob".to_string()));
+}
+
+#[test]
+fn resumed_sessions_catch_up_on_missed_messages() {
+    let addr = start_server(guest_config());
+
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+    let mut bob = TestClient::connect(&addr,
+                                      Handshake::new("bob").with_capabilities(capability::ALL));
+    alice.join("ops");
+    bob.join("ops");
+    bob.send(ClientMessage::SetStatus(Some("busy".to_string())));
+    bob.recv_until(|msg| match msg {
+        ServerMessage::StatusChanged(..) => Some(()),
+        _ => None,
+    });
+
+    let token = bob.resume_token.clone();
+    drop(bob);
+    alice.recv_until(|msg| match msg {
+        ServerMessage::UserDisconnected(ref user) if user == "bob" => Some(()),
+        _ => None,
+    });
+    alice.send(ClientMessage::new("while you were out"));
+    alice.send(ClientMessage::new("one more thing"));
+    alice.recv_chat();
+    alice.recv_chat();
+
+    // Bob comes back under a different name, and gets the old session back, messages included.
+    let mut bob = TestClient::connect(&addr, Handshake::new("bob2").with_resume_token(token));
+    assert_eq!(bob.recv_chat(),
+               ("alice".to_string(), "while you were out".to_string()));
+    assert_eq!(bob.recv_chat(), ("alice".to_string(), "one more thing".to_string()));
+
+    let (room, users) = bob.who();
+    assert_eq!(room, "ops");
+    assert_eq!(users,
+               vec![UserInfo {
+                        name: "alice".to_string(),
+                        status: None,
+                    },
+                    UserInfo {
+                        name: "bob".to_string(),
+                        status: Some("busy".to_string()),
+                    }]);
+
+    // And from here on it's business as usual.
+    alice.send(ClientMessage::new("welcome back"));
+    assert_eq!(bob.recv_chat(), ("alice".to_string(), "welcome back".to_string()));
+}
+
+#[test]
+fn resume_tokens_only_work_once() {
+    let addr = start_server(guest_config());
+
+    let mut watcher = TestClient::connect(&addr, Handshake::new("watcher"));
+    let alice = TestClient::connect(&addr, Handshake::new("alice"));
+    let token = alice.resume_token.clone();
+    drop(alice);
+
+    // Once the watcher hears alice leave, her session has been suspended.
+    watcher.recv_until(|msg| match msg {
+        ServerMessage::UserDisconnected(ref user) if user == "alice" => Some(()),
+        _ => None,
+    });
+
+    // The first client to present her token gets her session; the second is just a new client.
+    let mut first = TestClient::connect(&addr,
+                                        Handshake::new("one").with_resume_token(token.clone()));
+    let _second = TestClient::connect(&addr, Handshake::new("two").with_resume_token(token));
+    let (_, users) = first.who();
+    let mut names = users.into_iter().map(|user| user.name).collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, vec!["alice", "two", "watcher"]);
+}
+
+#[test]
+fn connections_per_ip_are_limited() {
+    let mut config = guest_config();
+    config.max_connections_per_ip = 2;
+    let addr = start_server(config);
+
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+    let bob = TestClient::connect(&addr, Handshake::new("bob"));
+
+    // A third connection from loopback is turned away without a chance to handshake.
+    let mut extra = TestClient::open(&addr, "extra");
+    match extra.recv() {
+        ServerMessage::Error(ErrorCode::TooManyConnections, _) => {}
+        msg => panic!("expected a connection limit error, got {:?}", msg),
+    }
+    let mut rest = Vec::new();
+    assert_eq!(extra.stream.read_to_end(&mut rest).unwrap(), 0);
+
+    // Once someone leaves, there's room again.
+    drop(bob);
+    alice.recv_until(|msg| match msg {
+        ServerMessage::UserDisconnected(ref user) if user == "bob" => Some(()),
+        _ => None,
+    });
+    TestClient::connect(&addr, Handshake::new("carol"));
+}
+
+#[test]
+fn proxied_connections_count_against_the_client_address() {
+    let mut config = guest_config();
+    config.max_connections_per_ip = 1;
+    config.proxy_protocol = true;
+    let addr = start_server(config);
+
+    // Connect as `name`, by way of a load balancer that sends `header` first.
+    let proxied = |name: &str, header: &str| {
+        let mut client = TestClient::open(&addr, name);
+        client.stream.write_all(header.as_bytes()).unwrap();
+        client.send_handshake(Handshake::new(name));
+        client
+    };
+
+    // Everyone arrives from loopback, but only the addresses in the headers count...
+    let mut alice = proxied("alice", "PROXY TCP4 203.0.113.7 192.0.2.1 51000 4000\r\n");
+    alice.welcome();
+    let mut bob = proxied("bob", "PROXY TCP6 2001:db8::1 2001:db8::2 51000 4000\r\n");
+    bob.welcome();
+
+    // ... so it's a second connection from alice's address that's one too many.
+    let mut extra = proxied("extra", "PROXY TCP4 203.0.113.7 192.0.2.1 51001 4000\r\n");
+    match extra.recv() {
+        ServerMessage::Error(ErrorCode::TooManyConnections, _) => {}
+        msg => panic!("expected a connection limit error, got {:?}", msg),
+    }
+
+    // A connection that doesn't start with a header is hung up on.
+    for header in &["GET / HTTP/1.0\r\n",
+                    "PROXY TCP4 203.0.113.8\r\n",
+                    "PROXY TCP4 2001:db8::1 ::1 1 2\r\n"] {
+        let mut bad = TestClient::open(&addr, "carol");
+        bad.stream.write_all(header.as_bytes()).unwrap();
+        let mut rest = Vec::new();
+        assert_eq!(bad.stream.read_to_end(&mut rest).unwrap(), 0, "{:?}", header);
+    }
+}
+
+#[test]
+fn idle_clients_are_timed_out() {
+    let clock = Arc::new(MockClock::new());
+    let mut config = guest_config();
+    config.idle_timeout = Some(Duration::from_secs(60));
+    config.clock = clock.clone();
+    let addr = start_server(config);
+
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+    let mut bob = TestClient::connect(&addr, Handshake::new("bob"));
+
+    // Half a minute in, bob says something; alice doesn't.
+    clock.advance(Duration::from_secs(30));
+    bob.send(ClientMessage::new("anyone?"));
+    assert_eq!(bob.recv_chat(), ("bob".to_string(), "anyone?".to_string()));
+
+    // Three quarters of a minute after that, alice has been quiet too long, but bob hasn't.
+    clock.advance(Duration::from_secs(45));
+    alice.recv_until(|msg| match msg {
+        ServerMessage::Error(ErrorCode::IdleTimeout, _) => Some(()),
+        _ => None,
+    });
+    let mut rest = Vec::new();
+    assert_eq!(alice.stream.read_to_end(&mut rest).unwrap(), 0);
+
+    bob.recv_until(|msg| match msg {
+        ServerMessage::UserDisconnected(ref user) if user == "alice" => Some(()),
+        _ => None,
+    });
+    let (_, users) = bob.who();
+    assert_eq!(users.into_iter().map(|user| user.name).collect::<Vec<_>>(), vec!["bob"]);
+}
+
+#[test]
+fn flooders_are_warned_then_disconnected() {
+    let clock = Arc::new(MockClock::new());
+    let mut config = guest_config();
+    config.policies.default.rate_per_sec = 1;
+    config.policies.flood.warn_after = 3;
+    config.policies.flood.disconnect_after = 3;
+    config.policies.flood.grace = Duration::from_secs(10);
+    config.clock = clock.clone();
+    let addr = start_server(config);
+
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+    let mut say = |body: &str| {
+        alice.send(ClientMessage::new(body));
+        alice.recv_until(|msg| match msg {
+            ServerMessage::Message(_, _, body) => Some(Ok(body)),
+            ServerMessage::Error(code, detail) => Some(Err((code, detail))),
+            _ => None,
+        })
+    };
+    let refused = |said: Result<String, (ErrorCode, String)>| match said {
+        Err((ErrorCode::RateLimited, detail)) => detail.contains("disconnected"),
+        said => panic!("expected to be rate limited, got {:?}", said),
+    };
+
+    // A burst gets the third message over the limit a warning instead of a plain refusal.
+    assert_eq!(say("one"), Ok("one".to_string()));
+    assert_eq!(["two", "three", "four"].iter().map(|body| refused(say(body))).collect::<Vec<_>>(),
+               vec![false, false, true]);
+
+    // Backing off for long enough forgets the warning, and the burst before it.
+    clock.advance(Duration::from_secs(11));
+    assert_eq!(say("five"), Ok("five".to_string()));
+    assert!(!refused(say("six")));
+
+    // Carrying on over the limit after another warning is the end of the connection.
+    assert_eq!(["seven", "eight"].iter().map(|body| refused(say(body))).collect::<Vec<_>>(),
+               vec![false, true]);
+    assert!(!refused(say("nine")));
+    assert!(!refused(say("ten")));
+    match say("eleven") {
+        Err((ErrorCode::Flooding, _)) => {}
+        said => panic!("expected to be disconnected for flooding, got {:?}", said),
+    }
+    let mut rest = Vec::new();
+    assert_eq!(alice.stream.read_to_end(&mut rest).unwrap(), 0);
+}
+
+#[test]
+fn rooms_have_their_own_limits() {
+    let clock = Arc::new(MockClock::new());
+    let mut config = guest_config();
+    let mut terse = config.policies.default;
+    terse.max_body_len = 10;
+    terse.rate_per_sec = 1;
+    config.policies.rooms.insert("terse".to_string(), terse);
+    config.clock = clock.clone();
+    let addr = start_server(config);
+
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+    let mut bob = TestClient::connect(&addr, Handshake::new("bob"));
+    bob.join("terse");
+    let say = |client: &mut TestClient, body: &str| {
+        client.send(ClientMessage::new(body));
+        client.recv_until(|msg| match msg {
+            ServerMessage::Message(_, _, body) => Some(Ok(body)),
+            ServerMessage::Error(code, detail) => Some(Err((code, detail))),
+            _ => None,
+        })
+    };
+
+    // The same message, at the same pace, is fine in the lobby but not in a room with stricter
+    // rules.
+    let long = "twenty bytes of talk";
+    assert_eq!(say(&mut alice, long), Ok(long.to_string()));
+    assert_eq!(say(&mut bob, long),
+               Err((ErrorCode::InvalidMessage,
+                    "messages in terse are limited to 10 bytes".to_string())));
+    for body in &["one", "two"] {
+        assert_eq!(say(&mut alice, body), Ok(body.to_string()));
+    }
+    assert_eq!(say(&mut bob, "one"), Ok("one".to_string()));
+    assert_eq!(say(&mut bob, "two"),
+               Err((ErrorCode::RateLimited, "terse allows 1 messages per second".to_string())));
+
+    // A second later there's room for another.
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(say(&mut bob, "two"), Ok("two".to_string()));
+}
+
+// Remembers every connection event, as its span's id and name and the event, written out.
+#[derive(Default)]
+struct Recorder(Mutex<Vec<(u64, Option<String>, String)>>);
+
+impl Subscriber for Recorder {
+    fn event(&self, span: &Span, event: &ConnectionEvent) {
+        let event = (span.id, span.name.clone(), format!("{:?}", event));
+        self.0.lock().unwrap().push(event);
+    }
+}
+
+#[test]
+fn connections_are_traced() {
+    let recorder = Arc::new(Recorder::default());
+    let mut config = guest_config();
+    config.subscriber = Some(recorder.clone());
+    let addr = start_server(config);
+
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+    let mut bob = TestClient::connect(&addr, Handshake::new("bob"));
+    alice.join("den");
+    alice.send(ClientMessage::new("hi"));
+    alice.recv_chat();
+    drop(alice);
+    bob.recv_until(|msg| match msg {
+        ServerMessage::UserDisconnected(ref user) if user == "alice" => Some(()),
+        _ => None,
+    });
+
+    // Everything that happened to alice happened in the one span, and nothing else did.
+    let events = recorder.0.lock().unwrap().clone();
+    let span_of = |name: &str| {
+        events.iter()
+            .find(|&&(_, ref named, ref event)| {
+                named.as_ref().map(String::as_str) == Some(name) && event == "Handshake"
+            })
+            .map(|&(id, _, _)| id)
+            .expect("no handshake was traced")
+    };
+    let alice = span_of("alice");
+    assert!(span_of("bob") != alice);
+    let traced = events.iter()
+        .filter(|&&(id, _, _)| id == alice)
+        .map(|&(_, _, ref event)| event.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(traced,
+               vec!["Accepted",
+                    "Handshake",
+                    "Joined(\"lobby\")",
+                    "Message(Join(\"den\"))",
+                    "Joined(\"den\")",
+                    "Message(Message(\"hi\"))",
+                    "Disconnected"]);
+}
+
+#[test]
+fn lifecycle_hooks_hear_about_logins_and_disconnects() {
+    let log = env::temp_dir().join(format!("tokio-chat-audit-{}.log", process::id()));
+    let _ = fs::remove_file(&log);
+    let mut config = guest_config();
+    let clock = Arc::new(MockClock::new());
+    config.clock = clock.clone();
+    config.token = Some("sesame".to_string());
+    let logger = AuditLogger::new(fs::File::create(&log).unwrap(), clock.clone());
+    config.lifecycle = Some(Arc::new(logger));
+    let addr = start_server(config);
+
+    // Alice comes and goes; carol never gets in.
+    let alice = TestClient::connect(&addr, Handshake::new("alice").with_token("sesame"));
+    let alice_addr = alice.stream.local_addr().unwrap();
+    drop(alice);
+    let carol = TestClient::open(&addr, "carol");
+    let carol_addr = carol.stream.local_addr().unwrap();
+    drop(carol);
+    assert_unauthorized(&addr, Handshake::new("dave"));
+
+    // Each line is stamped with the server's time, which stands still here, and is then left off.
+    let now = UNIX_EPOCH + Duration::from_millis(clock.unix_time_ms());
+    let stamp = rfc3339::format(now).unwrap();
+    let mut logged = Vec::new();
+    for _ in 0..50 {
+        logged = fs::read_to_string(&log)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let (at, line) = line.split_once(' ').unwrap();
+                assert_eq!(at, stamp);
+                line.to_string()
+            })
+            .collect::<Vec<_>>();
+        if logged.iter().any(|line| line.ends_with(" refused")) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    // Each connection's lines are in order, though the two connections' may be interleaved.
+    let lines_of = |addr: SocketAddr| {
+        logged.iter().filter(|line| line.contains(&addr.to_string())).cloned().collect::<Vec<_>>()
+    };
+    assert_eq!(lines_of(alice_addr),
+               vec![format!("CONNECT {}", alice_addr),
+                    format!("LOGIN alice {}", alice_addr),
+                    format!("DISCONNECT alice {} closed", alice_addr)]);
+    assert_eq!(lines_of(carol_addr),
+               vec![format!("CONNECT {}", carol_addr),
+                    format!("DISCONNECT - {} closed", carol_addr)]);
+    assert_eq!(logged.iter().filter(|line| line.ends_with(" refused")).count(), 1);
+}
+
+#[test]
+fn clients_that_never_handshake_are_timed_out() {
+    let mut config = guest_config();
+    config.handshake_timeout = Some(Duration::from_millis(200));
+    let addr = start_server(config);
+
+    // Carol connects and says nothing; alice, who handshakes straight away, has nothing to fear.
+    let mut carol = TestClient::open(&addr, "carol");
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+    match carol.recv() {
+        ServerMessage::Error(ErrorCode::IdleTimeout, _) => {}
+        msg => panic!("carol expected to be timed out, got {:?}", msg),
+    }
+    let mut rest = Vec::new();
+    assert_eq!(carol.stream.read_to_end(&mut rest).unwrap(), 0);
+
+    thread::sleep(Duration::from_millis(300));
+    alice.send(ClientMessage::new("still here"));
+    assert_eq!(alice.recv_chat(), ("alice".to_string(), "still here".to_string()));
+}
+
+#[test]
+fn clients_that_stop_reading_are_dropped() {
+    let mut config = guest_config();
+    config.policies.default.max_body_len = 30000;
+    config.policies.default.rate_per_sec = 0;
+    config.write_timeout = Some(Duration::from_secs(1));
+    let addr = start_server(config);
+
+    // Alice never reads anything, so once the socket's buffers are full, the server can't write to
+    // her any more.
+    let alice = TestClient::connect(&addr, Handshake::new("alice"));
+    let mut bob = TestClient::connect(&addr, Handshake::new("bob"));
+    let big = "x".repeat(30000);
+    let mut dropped = false;
+    for _ in 0..10000 {
+        bob.send(ClientMessage::new(big.as_str()));
+        dropped = bob.recv_until(|msg| match msg {
+            ServerMessage::Message(..) => Some(false),
+            ServerMessage::UserDisconnected(ref user) if user == "alice" => Some(true),
+            _ => None,
+        });
+        if dropped {
+            break;
+        }
+    }
+    assert!(dropped, "alice was never dropped");
+
+    let (_, users) = bob.who();
+    assert_eq!(users.into_iter().map(|user| user.name).collect::<Vec<_>>(), vec!["bob"]);
+    drop(alice);
+}
+
+#[test]
+fn control_messages_overtake_queued_chat() {
+    let mut config = guest_config();
+    config.policies.default.max_body_len = 30000;
+    config.policies.default.rate_per_sec = 0;
+    config.write_timeout = None;
+    let addr = start_server(config);
+
+    // Alice stops reading, so once the socket's buffers are full, bob's chat piles up in the
+    // server's queue for her, and then the server stops taking any more from him until she
+    // catches up. He keeps sending regardless, from another thread.
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+    let mut bob = TestClient::connect(&addr, Handshake::new("bob"));
+    let mut frame = Vec::new();
+    ClientToServerCodec::new().encode(ClientMessage::new("x".repeat(30000)), &mut frame).unwrap();
+    let mut sending = bob.stream.try_clone().unwrap();
+    thread::spawn(move || {
+        for _ in 0..1000 {
+            if sending.write_all(&frame).is_err() {
+                break;
+            }
+        }
+    });
+    let mut echoed = 0;
+    while let Some(msg) = bob.recv_within(Duration::from_millis(500)) {
+        if let ServerMessage::Message(..) = msg {
+            echoed += 1;
+        }
+    }
+
+    // Somebody connecting meanwhile is news that goes out ahead of the queue, so when alice gets
+    // around to reading, she hears about it before all the chat that was already waiting for her.
+    let _carol = TestClient::connect(&addr, Handshake::new("carol"));
+    bob.recv_until(|msg| match msg {
+        ServerMessage::UserConnected(ref user) if user == "carol" => Some(()),
+        _ => None,
+    });
+    let mut before = 0;
+    loop {
+        match alice.recv() {
+            ServerMessage::Message(..) => before += 1,
+            ServerMessage::UserConnected(ref user) if user == "carol" => break,
+            _ => {}
+        }
+    }
+    assert!(before < echoed,
+            "carol's arrival waited behind all {} messages queued before it",
+            echoed);
+}
+
+#[test]
+fn cluster_nodes_share_chat() {
+    let bus = LocalBus::new();
+    let mut config = guest_config();
+    config.cluster = Some(Arc::new(bus.another_node()));
+    let first = start_server(config);
+    let mut config = guest_config();
+    config.cluster = Some(Arc::new(bus));
+    let second = start_server(config);
+
+    let mut alice = TestClient::connect(&first, Handshake::new("alice"));
+    let mut bob = TestClient::connect(&second, Handshake::new("bob"));
+    let mut carol = TestClient::connect(&second, Handshake::new("carol"));
+    carol.join("elsewhere");
+
+    // Chat crosses over to the other node's members of the room, and only once...
+    alice.send(ClientMessage::new("hello from the first node"));
+    assert_eq!(alice.recv_chat(),
+               ("alice".to_string(), "hello from the first node".to_string()));
+    assert_eq!(bob.recv_chat(),
+               ("alice".to_string(), "hello from the first node".to_string()));
+    bob.send(ClientMessage::new("hello back"));
+    assert_eq!(bob.recv_chat(), ("bob".to_string(), "hello back".to_string()));
+    assert_eq!(alice.recv_chat(), ("bob".to_string(), "hello back".to_string()));
+
+    // ... and not to anyone in another room. (Carol would have heard alice's first message by now
+    // if she were going to, since bob, on her node, heard it.)
+    carol.send(ClientMessage::new("anyone here?"));
+    assert_eq!(carol.recv_chat(), ("carol".to_string(), "anyone here?".to_string()));
+}
+
+#[test]
+fn bots_can_chat_over_http() {
+    // Find a free port for the API, since we can't ask the server which one it got.
+    let http_addr = StdTcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut config = guest_config();
+    config.http_addr = Some(http_addr);
+    config.http_token = Some("bot-secret".to_string());
+    let addr = start_server(config);
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+    let token = Some("bot-secret");
+
+    // Requests need the token.
+    assert_eq!(http(&http_addr, "GET", "/rooms", None, "").0, 401);
+    assert_eq!(http(&http_addr, "GET", "/rooms", Some("guess"), "").0, 401);
+
+    let (status, rooms) = http(&http_addr, "GET", "/rooms", token, "");
+    assert_eq!(status, 200);
+    assert_eq!(serde_json::to_string(&rooms).unwrap(), r#"{"rooms":["lobby"]}"#);
+
+    // A bot's message reaches the room like anyone else's...
+    let (status, posted) = http(&http_addr,
+                                "POST",
+                                "/rooms/lobby/messages",
+                                token,
+                                r#"{"from": "ci", "body": "build passed"}"#);
+    assert_eq!(status, 201);
+    let seq = posted.find("seq").and_then(|seq| seq.as_u64()).unwrap();
+    assert_eq!(alice.recv_chat(), ("ci".to_string(), "build passed".to_string()));
+    alice.send(ClientMessage::new("nice"));
+    alice.recv_chat();
+
+    // ... and the history can be read back, from the start or after a given message.
+    let listed = |path: &str| {
+        let (status, listed) = http(&http_addr, "GET", path, token, "");
+        assert_eq!(status, 200);
+        serde_json::to_string(listed.find("messages").unwrap()).unwrap()
+    };
+    let bot = format!(r#"{{"body":"build passed","from":"ci","seq":{}}}"#, seq);
+    let alice = format!(r#"{{"body":"nice","from":"alice","seq":{}}}"#, seq + 1);
+    assert_eq!(listed("/rooms/lobby/messages"), format!("[{},{}]", bot, alice));
+    assert_eq!(listed(&format!("/rooms/lobby/messages?after_seq={}", seq)),
+               format!("[{}]", alice));
+    assert_eq!(listed("/rooms/elsewhere/messages"), "[]");
+
+    assert_eq!(http(&http_addr, "POST", "/rooms/lobby/messages", token, "{}").0, 400);
+    assert_eq!(http(&http_addr, "GET", "/users", token, "").0, 404);
+}
+
+#[test]
+fn http_requests_that_stall_are_timed_out() {
+    let http_addr = StdTcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut config = guest_config();
+    config.http_addr = Some(http_addr);
+    config.http_token = Some("bot-secret".to_string());
+    config.read_timeout = Some(Duration::from_millis(200));
+    let addr = start_server(config);
+    TestClient::connect(&addr, Handshake::new("alice"));
+
+    // A request that never finishes its headers gets a 408 rather than holding on to the socket,
+    // while one that's sent in full is still answered.
+    let mut stalled = TcpStream::connect(&http_addr).unwrap();
+    stalled.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    write!(stalled, "GET /rooms HTTP/1.1\r\nHost: localhost\r\n").unwrap();
+    let mut response = String::new();
+    stalled.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.0 408 "), "got {:?}", response);
+
+    assert_eq!(http(&http_addr, "GET", "/rooms", Some("bot-secret"), "").0, 200);
+}
+
+#[test]
+fn irc_clients_chat_with_native_ones() {
+    // As with the HTTP API, find a free port for the gateway.
+    let irc_addr = StdTcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut config = guest_config();
+    config.irc_addr = Some(irc_addr);
+    let addr = start_server(config);
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+    alice.join("ops");
+
+    // The IRC client starts out in the default room's channel, and can move to another.
+    let mut bob = IrcClient::connect(&irc_addr, "bob");
+    bob.recv_until(&format!("JOIN #{}", DEFAULT_ROOM));
+    bob.send("JOIN #ops");
+    bob.recv_until(&format!(":bob!bob@tokio-chat PART #{}", DEFAULT_ROOM));
+    bob.recv_until(":bob!bob@tokio-chat JOIN #ops");
+    assert!(bob.recv_until(" 353 bob = #ops :").ends_with(":alice bob"));
+    alice.recv_until(|msg| match msg {
+        ServerMessage::UserJoined(ref user, ref room, _) if user == "bob" && room == "ops" => {
+            Some(())
+        }
+        _ => None,
+    });
+
+    // Chat goes both ways.
+    alice.send(ClientMessage::new("hi bob"));
+    assert_eq!(alice.recv_chat(), ("alice".to_string(), "hi bob".to_string()));
+    bob.recv_until(":alice!alice@tokio-chat PRIVMSG #ops :hi bob");
+    bob.send("PRIVMSG #ops :hi alice");
+    assert_eq!(alice.recv_chat(), ("bob".to_string(), "hi alice".to_string()));
+
+    // Only in the channel bob's in, though.
+    bob.send("PRIVMSG #elsewhere :anyone?");
+    bob.recv_until(" 404 bob #elsewhere :");
+
+    bob.send("PING :are-you-there");
+    bob.recv_until("PONG tokio-chat :are-you-there");
+
+    bob.send("QUIT :bye");
+    alice.recv_until(|msg| match msg {
+        ServerMessage::UserDisconnected(ref user) if user == "bob" => Some(()),
+        _ => None,
+    });
+}
+
+#[test]
+fn rooms_are_bridged_to_xmpp() {
+    let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+    let mut config = guest_config();
+    config.xmpp = Some(XmppConfig {
+        server: listener.local_addr().unwrap().to_string(),
+        domain: "conference.example.com".to_string(),
+        secret: "s3cr3t".to_string(),
+    });
+    let addr = start_server(config);
+
+    // The gateway proves it knows the secret with SHA-1("abc123" + "s3cr3t").
+    let mut xmpp = XmppServer::accept(&listener);
+    xmpp.expect("to='conference.example.com'>");
+    xmpp.send("<?xml version='1.0'?><stream:stream xmlns='jabber:component:accept' \
+               xmlns:stream='http://etherx.jabber.org/streams' id='abc123' \
+               from='conference.example.com'>");
+    xmpp.expect("<handshake>49fc1ea83a54123ae5a273341bed522fe7d4b91c</handshake>");
+    xmpp.send("<handshake/>");
+
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+    alice.join("ops");
+
+    // Entering the MUC is joining the room...
+    xmpp.send("<presence from='juliet@example.com/balcony' \
+               to='ops@conference.example.com/juliet'><x xmlns='http://jabber.org/protocol/muc'/>\
+               </presence>");
+    xmpp.expect("<presence from='ops@conference.example.com/juliet' \
+                 to='juliet@example.com/balcony'>");
+    xmpp.expect("<status code='110'/>");
+    alice.recv_until(|msg| match msg {
+        ServerMessage::UserJoined(ref user, ref room, _) if user == "juliet" && room == "ops" => {
+            Some(())
+        }
+        _ => None,
+    });
+
+    // ... and chat goes both ways, echoed back to XMPP senders as MUC does.
+    xmpp.send("<message type='groupchat' from='juliet@example.com/balcony' \
+               to='ops@conference.example.com'><body>hi &amp; hello</body></message>");
+    assert_eq!(alice.recv_chat(), ("juliet".to_string(), "hi & hello".to_string()));
+    xmpp.expect("from='ops@conference.example.com/juliet' to='juliet@example.com/balcony'>\
+                 <body>hi &amp; hello</body>");
+    alice.send(ClientMessage::new("hi <juliet>"));
+    xmpp.expect("from='ops@conference.example.com/alice' to='juliet@example.com/balcony'>\
+                 <body>hi &lt;juliet&gt;</body>");
+
+    xmpp.send("<presence type='unavailable' from='juliet@example.com/balcony' \
+               to='ops@conference.example.com/juliet'/>");
+    alice.recv_until(|msg| match msg {
+        ServerMessage::UserLeft(ref user, ref room) if user == "juliet" && room == "ops" => {
+            Some(())
+        }
+        _ => None,
+    });
+}
+
+#[test]
+fn rooms_are_mirrored_to_discord() {
+    // A stand-in for Discord's API, where we're user 1 and channel 42 has some history. Once
+    // `said` is set, there's something new in it, along with one of our own posts; what we post
+    // is passed on to `rx`.
+    let api = StdTcpListener::bind("127.0.0.1:0").unwrap();
+    let api_addr = api.local_addr().unwrap();
+    let said = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = mpsc::channel();
+    thread::spawn({
+        let said = said.clone();
+        move || {
+            for stream in api.incoming() {
+                let mut stream = stream.unwrap();
+                let (head, body) = read_http_request(&mut stream);
+                assert!(head.contains("\r\nAuthorization: Bot t0ken\r\n"), "{}", head);
+                let request_line = head.lines().next().unwrap().to_string();
+                let answer = match request_line.as_str() {
+                    "GET /api/users/@me HTTP/1.0" => r#"{"id": "1", "username": "bridge"}"#,
+                    "GET /api/channels/42/messages?limit=1 HTTP/1.0" => {
+                        r#"[{"id": "10", "content": "old news",
+                             "author": {"id": "7", "username": "dana"}}]"#
+                    }
+                    "GET /api/channels/42/messages?after=10 HTTP/1.0" if
+                        said.load(Ordering::SeqCst) => {
+                        r#"[{"id": "12", "content": "hi from discord",
+                             "author": {"id": "7", "username": "dana"}},
+                            {"id": "11", "content": "**alice**: an old post",
+                             "author": {"id": "1", "username": "bridge"}}]"#
+                    }
+                    "POST /api/channels/42/messages HTTP/1.0" => {
+                        tx.send(body).unwrap();
+                        r#"{"id": "13"}"#
+                    }
+                    _ => "[]",
+                };
+                write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                       answer.len(),
+                       answer)
+                    .unwrap();
+            }
+        }
+    });
+
+    let mut config = guest_config();
+    config.discord = Some(DiscordConfig {
+        api: format!("http://{}/api", api_addr),
+        token: "t0ken".to_string(),
+        channel_id: "42".to_string(),
+        room: DEFAULT_ROOM.to_string(),
+    });
+    let addr = start_server(config);
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+
+    // Only what's new in the channel reaches the room, and not what we posted ourselves...
+    said.store(true, Ordering::SeqCst);
+    assert_eq!(alice.recv_chat(),
+               ("discord/dana".to_string(), "hi from discord".to_string()));
+
+    // ... while what's said in the room is posted to the channel.
+    alice.send(ClientMessage::new("hello discord"));
+    let posted: serde_json::Value =
+        serde_json::from_str(&rx.recv_timeout(Duration::from_secs(5)).unwrap()).unwrap();
+    assert_eq!(posted.find("content").and_then(|content| content.as_str()),
+               Some("**alice**: hello discord"));
+}
+
+#[test]
+fn rooms_are_mirrored_to_slack() {
+    // A stand-in for Slack's Incoming Webhook, passing on what's posted to it to `rx`.
+    let webhook = StdTcpListener::bind("127.0.0.1:0").unwrap();
+    let webhook_addr = webhook.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in webhook.incoming() {
+            let mut stream = stream.unwrap();
+            let body = read_http_body(&mut stream);
+            write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").unwrap();
+            tx.send(body).unwrap();
+        }
+    });
+
+    let http_addr = StdTcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut config = guest_config();
+    config.http_addr = Some(http_addr);
+    config.http_token = Some("bot-secret".to_string());
+    config.slack = Some(SlackConfig {
+        webhook: format!("http://{}/services/T0/B0/x", webhook_addr),
+        signing_secret: "shh".to_string(),
+        room: DEFAULT_ROOM.to_string(),
+    });
+    let addr = start_server(config);
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+
+    // Send `body` to the events endpoint as Slack would, signed with `secret` at `timestamp`,
+    // along with any `extra` headers.
+    let signed = |secret: &str, timestamp: u64, extra: &str, body: &str| {
+        let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes());
+        let tag = ring::hmac::sign(&key, format!("v0:{}:{}", timestamp, body).as_bytes());
+        let hex = tag.as_ref().iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+        let headers = format!("X-Slack-Request-Timestamp: {}\r\nX-Slack-Signature: v0={}\r\n{}",
+                              timestamp,
+                              hex,
+                              extra);
+        http_with_headers(&http_addr, "POST", "/slack/events", &headers, body)
+    };
+    let event = |secret: &str, timestamp: u64, body: &str| signed(secret, timestamp, "", body);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+    // Slack checks the URL is ours by having us repeat a challenge, but only we can answer it.
+    let challenge = r#"{"type": "url_verification", "challenge": "3eZbrw1aBm2rZgRNFdxV"}"#;
+    let (status, answer) = event("shh", now, challenge);
+    assert_eq!(status, 200);
+    assert_eq!(answer.find("challenge").and_then(|challenge| challenge.as_str()),
+               Some("3eZbrw1aBm2rZgRNFdxV"));
+    assert_eq!(event("guess", now, challenge).0, 401);
+    assert_eq!(event("shh", now - 600, challenge).0, 401);
+
+    // What's said in the channel reaches the room, except the bridge's own posts...
+    let message = |extra: &str, text: &str| {
+        format!(r#"{{"type": "event_callback",
+                     "event": {{"type": "message", "user": "U123", {} "text": "{}"}}}}"#,
+                extra,
+                text)
+    };
+    assert_eq!(event("shh", now, &message(r#""bot_id": "B1","#, "*alice*: echo")).0, 200);
+    assert_eq!(event("shh", now, &message("", "hi from slack &amp; co")).0, 200);
+    assert_eq!(alice.recv_chat(),
+               ("slack/U123".to_string(), "hi from slack & co".to_string()));
+
+    // Slack's retries of an event are acknowledged but not said again.
+    let retry = "X-Slack-Retry-Num: 1\r\nX-Slack-Retry-Reason: http_timeout\r\n";
+    assert_eq!(signed("shh", now, retry, &message("", "hi from slack &amp; co")).0, 200);
+    assert_eq!(event("shh", now, &message("", "anyone?")).0, 200);
+    assert_eq!(alice.recv_chat(), ("slack/U123".to_string(), "anyone?".to_string()));
+
+    // ... while what's said in the room is posted to the channel.
+    alice.send(ClientMessage::new("hello <slack>"));
+    let posted: serde_json::Value =
+        serde_json::from_str(&rx.recv_timeout(Duration::from_secs(5)).unwrap()).unwrap();
+    assert_eq!(posted.find("text").and_then(|text| text.as_str()),
+               Some("*alice*: hello &lt;slack&gt;"));
+}
+
+#[test]
+fn webhooks_hear_about_room_events() {
+    // A webhook receiver that turns down the first delivery, to see that it's tried again.
+    let receiver = StdTcpListener::bind("127.0.0.1:0").unwrap();
+    let hook_addr = receiver.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for (i, stream) in receiver.incoming().enumerate() {
+            let mut stream = stream.unwrap();
+            let body = read_http_body(&mut stream);
+            let status = if i == 0 { "500 Internal Server Error" } else { "200 OK" };
+            write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+            tx.send(body).unwrap();
+        }
+    });
+    let event = || -> serde_json::Value {
+        serde_json::from_str(&rx.recv_timeout(Duration::from_secs(5)).unwrap()).unwrap()
+    };
+    let field = |event: &serde_json::Value, name: &str| event.find(name).unwrap().clone();
+
+    let webhooks = format!("[[webhook]]\n\
+                            room = \"lobby\"\n\
+                            url = \"http://{}/chat-events\"\n\
+                            events = [\"message\", \"edit\"]\n",
+                           hook_addr);
+    let mut config = guest_config();
+    config.webhooks = WebhookRegistry::from_toml(&webhooks).unwrap();
+    let addr = start_server(config);
+    let mut alice = TestClient::connect(&addr,
+                                        Handshake::new("alice").with_capabilities(capability::ALL));
+
+    // Nothing's listening to other rooms.
+    alice.join("elsewhere");
+    alice.send(ClientMessage::new("nobody's listening"));
+    alice.recv_chat();
+    alice.join("lobby");
+
+    alice.send(ClientMessage::new("deploying"));
+    let id = alice.recv_until(|msg| match msg {
+        ServerMessage::Message(id, ..) => Some(id),
+        _ => None,
+    });
+    let first = event();
+    assert_eq!(event(), first);
+    assert_eq!(field(&first, "event").as_str(), Some("message"));
+    assert_eq!(field(&first, "room").as_str(), Some("lobby"));
+    assert_eq!(field(&first, "seq").as_u64(), Some(id));
+    assert_eq!(field(&first, "from").as_str(), Some("alice"));
+    assert_eq!(field(&first, "body").as_str(), Some("deploying"));
+
+    alice.send(ClientMessage::EditMessage {
+        id: id,
+        new_body: "deployed".to_string(),
+    });
+    let edit = event();
+    assert_eq!(field(&edit, "event").as_str(), Some("edit"));
+    assert_eq!(field(&edit, "seq").as_u64(), Some(id));
+    assert_eq!(field(&edit, "body").as_str(), Some("deployed"));
+
+    assert!(WebhookRegistry::from_toml("[[webhook]]\nroom = \"lobby\"\n").is_err());
+    assert!(WebhookRegistry::from_toml("[[webhook]]\nroom = \"a\"\nurl = \"https://b/\"\n")
+        .is_err());
+}
+
+#[test]
+fn messages_too_big_to_send_are_skipped() {
+    let addr = start_server(guest_config());
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+
+    // Two names that fit in a frame on their own, but not together.
+    let long_names = ["b", "c"].iter().map(|c| c.repeat(40_000)).collect::<Vec<_>>();
+    let mut others = long_names.iter()
+        .map(|name| TestClient::connect(&addr, Handshake::new(name.clone())))
+        .collect::<Vec<_>>();
+
+    // The list of who's here is too big to send alice, so she doesn't get it...
+    alice.send(ClientMessage::Who);
+
+    // ... but she's still connected, and chat still reaches her and everyone else.
+    alice.send(ClientMessage::new("still here"));
+    assert_eq!(alice.recv_chat(), ("alice".to_string(), "still here".to_string()));
+    for other in &mut others {
+        assert_eq!(other.recv_chat(), ("alice".to_string(), "still here".to_string()));
+    }
+}
+
+#[test]
+fn unknown_message_types_are_reported() {
+    let addr = start_server(guest_config());
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+
+    // A message of a type from some future version of the protocol is refused as just that, as
+    // many times as it's sent, without costing the client its connection...
+    for _ in 0..5 {
+        alice.send_raw(br#"{"Shout":"hello"}"#);
+        let reason = alice.recv_until(|msg| match msg {
+            ServerMessage::Error(ErrorCode::UnsupportedMessageType, reason) => Some(reason),
+            _ => None,
+        });
+        assert_eq!(reason, "unsupported message type Shout");
+    }
+    alice.send(ClientMessage::new("still here"));
+    assert_eq!(alice.recv_chat(), ("alice".to_string(), "still here".to_string()));
+
+    // ... which isn't the same as a message that's garbled.
+    alice.send_raw(br#"{"Message":4}"#);
+    let reason = alice.recv_until(|msg| match msg {
+        ServerMessage::Error(ErrorCode::InvalidMessage, reason) => Some(reason),
+        _ => None,
+    });
+    assert!(reason.starts_with("couldn't decode message"), "{}", reason);
+}
+
+#[test]
+fn unknown_fields_are_reported_with_strict_protocol() {
+    let mut config = guest_config();
+    config.max_bad_frames = 0;
+    config.strict_protocol = true;
+    let addr = start_server(config);
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+
+    // Fields from some future version of the protocol are refused as just that, however many
+    // times they're sent...
+    for _ in 0..3 {
+        alice.send_raw(br#"{"EditMessage":{"id":0,"new_body":"hi","urgent":true}}"#);
+        let reason = alice.recv_until(|msg| match msg {
+            ServerMessage::Error(ErrorCode::InvalidMessage, reason) => Some(reason),
+            _ => None,
+        });
+        assert_eq!(reason, "unknown fields in message: urgent");
+    }
+    alice.send(ClientMessage::new("still here"));
+    assert_eq!(alice.recv_chat(), ("alice".to_string(), "still here".to_string()));
+
+    // ... but without `strict_protocol` they're as bad as any other malformed message.
+    let mut config = guest_config();
+    config.max_bad_frames = 0;
+    let addr = start_server(config);
+    let mut bob = TestClient::connect(&addr, Handshake::new("bob"));
+    bob.send_raw(br#"{"EditMessage":{"id":0,"new_body":"hi","urgent":true}}"#);
+    // (Reading to the end only works once the server has hung up.)
+    let mut rest = Vec::new();
+    bob.stream.read_to_end(&mut rest).unwrap();
+}
+
+#[test]
+fn blocked_words_are_censored() {
+    let mut config = guest_config();
+    config.blocked = vec!["darn".to_string(), "heck no".to_string()];
+    let addr = start_server(config);
+
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+    alice.send(ClientMessage::new("well DARN it"));
+    assert_eq!(alice.recv_chat().1, "well **** it");
+
+    // Punctuation, spacing and surrounding letters don't get a phrase past the filter.
+    alice.send(ClientMessage::new("oh h.e.c.k-NO, undarnable"));
+    assert_eq!(alice.recv_chat().1, "oh *.*.*.*-**, un****able");
+
+    alice.send(ClientMessage::new("nothing to see here"));
+    assert_eq!(alice.recv_chat().1, "nothing to see here");
+}
+
+#[test]
+fn blocked_words_can_be_rejected() {
+    let mut config = guest_config();
+    config.blocked = vec!["darn".to_string()];
+    config.block_mode = BlockMode::Reject;
+    let addr = start_server(config);
+
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+    let mut bob = TestClient::connect(&addr, Handshake::new("bob"));
+    alice.send(ClientMessage::new("well darn it"));
+    alice.recv_until(|msg| match msg {
+        ServerMessage::Error(ErrorCode::InvalidMessage, _) => Some(()),
+        ServerMessage::Message(..) => panic!("a blocked message got through"),
+        _ => None,
+    });
+
+    // Nobody else saw it: the next chat bob gets is the one after.
+    alice.send(ClientMessage::new("sorry"));
+    assert_eq!(bob.recv_chat(), ("alice".to_string(), "sorry".to_string()));
+}
+
+#[test]
+fn observers_watch_but_cannot_talk() {
+    let addr = start_server(guest_config());
+
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+    let mut screen = TestClient::connect(&addr, Handshake::new("screen").as_observer());
+
+    alice.send(ClientMessage::new("hello, room"));
+    assert_eq!(screen.recv_chat(), ("alice".to_string(), "hello, room".to_string()));
+
+    screen.send(ClientMessage::new("can anyone hear me?"));
+    screen.recv_until(|msg| match msg {
+        ServerMessage::Error(ErrorCode::Unauthorized, _) => Some(()),
+        ServerMessage::Message(..) => panic!("an observer's message got through"),
+        _ => None,
+    });
+
+    // Nobody can tell the observer is there.
+    let (_, users) = alice.who();
+    let names = users.into_iter().map(|user| user.name).collect::<Vec<_>>();
+    assert_eq!(names, vec!["alice"]);
+
+    // Observers can still follow other rooms.
+    screen.send(ClientMessage::Join("ops".to_string()));
+    assert_eq!(screen.who().0, "ops");
+    alice.join("ops");
+    alice.send(ClientMessage::new("over here now"));
+    assert_eq!(screen.recv_chat(), ("alice".to_string(), "over here now".to_string()));
+}
+
+#[test]
+fn capabilities_are_negotiated() {
+    let mut config = guest_config();
+    config.capabilities = vec![capability::FILE_TRANSFER.to_string(),
+                               capability::STATUS.to_string()];
+    let addr = start_server(config);
+
+    // Only what both sides support is agreed on.
+    let handshake = Handshake::new("alice")
+        .with_capabilities(&[capability::ANNOUNCEMENTS, capability::STATUS]);
+    let mut alice = TestClient::connect(&addr, handshake);
+    assert_eq!(alice.capabilities, vec![capability::STATUS]);
+
+    // Messages needing anything else are refused...
+    alice.send(ClientMessage::FileOffer {
+        transfer_id: 1,
+        name: "notes.txt".to_string(),
+        size: 0,
+        chunk_count: 0,
+    });
+    alice.recv_until(|msg| match msg {
+        ServerMessage::Error(ErrorCode::InvalidMessage, ref reason) if reason.contains("file") => {
+            Some(())
+        }
+        _ => None,
+    });
+
+    // ... and only clients that agreed to them receive them.
+    let handshake = Handshake::new("bob").with_capabilities(capability::ALL);
+    let mut bob = TestClient::connect(&addr, handshake);
+    assert_eq!(bob.capabilities, vec![capability::FILE_TRANSFER, capability::STATUS]);
+    let mut carol = TestClient::connect(&addr, Handshake::new("carol"));
+    assert!(carol.capabilities.is_empty());
+
+    alice.send(ClientMessage::SetStatus(Some("busy".to_string())));
+    alice.send(ClientMessage::new("done"));
+    let status = bob.recv_until(|msg| match msg {
+        ServerMessage::StatusChanged(user, status) => Some((user, status)),
+        _ => None,
+    });
+    assert_eq!(status, ("alice".to_string(), Some("busy".to_string())));
+    carol.recv_until(|msg| match msg {
+        ServerMessage::StatusChanged(..) => panic!("carol didn't negotiate statuses"),
+        ServerMessage::Message(..) => Some(()),
+        _ => None,
+    });
+}
+
+#[test]
+fn statuses_are_shown_to_the_room() {
+    let addr = start_server(guest_config());
+
+    let handshake = |name| Handshake::new(name).with_capabilities(capability::ALL);
+    let mut alice = TestClient::connect(&addr, handshake("alice"));
+    let mut bob = TestClient::connect(&addr, handshake("bob"));
+    let set_status = |client: &mut TestClient, status: Option<&str>| {
+        let status = status.map(|status| status.to_string());
+        client.send(ClientMessage::SetStatus(status.clone()));
+        let name = client.name.clone();
+        client.recv_until(|msg| match msg {
+            ServerMessage::StatusChanged(ref user, ref changed) if *user == name => {
+                assert_eq!(*changed, status);
+                Some(())
+            }
+            _ => None,
+        });
+    };
+    let status_of = |client: &mut TestClient, name: &str| {
+        client.who().1.into_iter().find(|user| user.name == name).unwrap().status
+    };
+
+    set_status(&mut alice, Some("busy"));
+    assert_eq!(status_of(&mut bob, "alice"), Some("busy".to_string()));
+
+    // It goes with alice into other rooms.
+    bob.join("ops");
+    alice.send(ClientMessage::Join("ops".to_string()));
+    let joined = bob.recv_until(|msg| match msg {
+        ServerMessage::UserJoined(ref user, _, ref status) if user == "alice" => {
+            Some(status.clone())
+        }
+        _ => None,
+    });
+    assert_eq!(joined, Some("busy".to_string()));
+
+    // Until it's cleared.
+    set_status(&mut alice, None);
+    assert_eq!(status_of(&mut bob, "alice"), None);
+}
+
+#[test]
+fn files_are_relayed_in_chunks() {
+    let mut config = guest_config();
+    config.max_file_size = 20000;
+    let addr = start_server(config);
+
+    let handshake = |name| Handshake::new(name).with_capabilities(capability::ALL);
+    let mut alice = TestClient::connect(&addr, handshake("alice"));
+    let mut bob = TestClient::connect(&addr, handshake("bob"));
+
+    // Bob puts the file back together from its chunks as they come.
+    let data = (0..20000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let msgs = offer_file(1, "notes.txt", &data);
+    assert_eq!(msgs.len(), 4);
+    for msg in msgs {
+        alice.send(msg);
+    }
+    let mut file = bob.recv_until(|msg| match msg {
+        ServerMessage::FileOffer { from, transfer_id: 1, name, size, chunk_count } => {
+            assert_eq!(from, "alice");
+            Some(FileAssembly::new(name, size, chunk_count).unwrap())
+        }
+        _ => None,
+    });
+    while !file.is_complete() {
+        let (index, data) = bob.recv_until(|msg| match msg {
+            ServerMessage::FileChunk { transfer_id: 1, index, data, .. } => Some((index, data)),
+            _ => None,
+        });
+        file.add_chunk(index, data).unwrap();
+    }
+    assert_eq!(file.name(), "notes.txt");
+    assert_eq!(file.into_bytes(), data);
+
+    // A chunk past the end of its file is refused, and calls the whole transfer off.
+    let refused = |alice: &mut TestClient, msg| {
+        alice.send(msg);
+        alice.recv_until(|msg| match msg {
+            ServerMessage::Error(ErrorCode::InvalidMessage, reason) => Some(reason),
+            _ => None,
+        })
+    };
+    alice.send(ClientMessage::FileOffer {
+        transfer_id: 2,
+        name: "short.txt".to_string(),
+        size: 10,
+        chunk_count: 1,
+    });
+    let chunk = |index| {
+        ClientMessage::FileChunk {
+            transfer_id: 2,
+            index: index,
+            data: vec![0; 10],
+        }
+    };
+    assert_eq!(refused(&mut alice, chunk(1)),
+               "chunk 1 is out of range; the file has 1 chunks");
+    assert_eq!(refused(&mut alice, chunk(0)), "no file transfer 2 is in progress");
+
+    // So is a file bigger than the server allows.
+    let offer = ClientMessage::FileOffer {
+        transfer_id: 3,
+        name: "big.bin".to_string(),
+        size: 20001,
+        chunk_count: 3,
+    };
+    assert_eq!(refused(&mut alice, offer), "files are limited to 20000 bytes");
+
+    // Bob heard about the short file, but got none of it, and nothing of the big one.
+    alice.send(ClientMessage::new("never mind"));
+    bob.recv_until(|msg| match msg {
+        ServerMessage::FileOffer { transfer_id: 3, .. } |
+        ServerMessage::FileChunk { .. } => panic!("bob got part of a refused file: {:?}", msg),
+        ServerMessage::Message(..) => Some(()),
+        _ => None,
+    });
+}
+
+#[test]
+fn registered_names_need_their_password() {
+    let addr = start_server(guest_config());
+
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+    match alice.register("alice", "short") {
+        ServerMessage::Error(ErrorCode::InvalidMessage, _) => {}
+        msg => panic!("expected a short password to be refused, got {:?}", msg),
+    }
+    assert_eq!(alice.register("alice", "hunter22"),
+               ServerMessage::Registered("alice".to_string()));
+    match alice.register("alice", "hunter23") {
+        ServerMessage::Error(ErrorCode::InvalidMessage, _) => {}
+        msg => panic!("expected a second registration to be refused, got {:?}", msg),
+    }
+    drop(alice);
+
+    assert_unauthorized(&addr, Handshake::new("alice"));
+    assert_unauthorized(&addr, Handshake::new("alice").with_password("hunter23"));
+    TestClient::connect(&addr, Handshake::new("alice").with_password("hunter22"));
+
+    // Passwords are only for registered names.
+    assert_unauthorized(&addr, Handshake::new("bob").with_password("hunter22"));
+
+    // Names that only look different are the same name: a registered one can't be had by
+    // writing it another way, or registered again that way.
+    let mut first = TestClient::connect(&addr, Handshake::new("caf\u{e9}"));
+    assert_eq!(first.register("caf\u{e9}", "hunter22"),
+               ServerMessage::Registered("caf\u{e9}".to_string()));
+    match first.register("cafe\u{301}", "hunter23") {
+        ServerMessage::Error(ErrorCode::InvalidMessage, _) => {}
+        msg => panic!("expected a second registration to be refused, got {:?}", msg),
+    }
+    drop(first);
+    assert_unauthorized(&addr, Handshake::new("cafe\u{301}"));
+    let mut cafe = TestClient::connect(&addr,
+                                       Handshake::new("cafe\u{301}").with_password("hunter22"));
+    let (_, users) = cafe.who();
+    assert!(users.iter().any(|user| user.name == "caf\u{e9}"));
+
+    // Names that mix scripts are turned away, registered or not.
+    match cafe.register("\u{430}lice", "hunter22") {
+        ServerMessage::Error(ErrorCode::InvalidMessage, _) => {}
+        msg => panic!("expected a name mixing scripts to be refused, got {:?}", msg),
+    }
+    let mut mixed = TestClient::handshake(&addr, Handshake::new("\u{430}lice"));
+    match mixed.recv() {
+        ServerMessage::Error(ErrorCode::InvalidMessage, reason) => {
+            assert!(reason.contains("Cyrillic"), "{}", reason)
+        }
+        msg => panic!("expected a name mixing scripts to be refused, got {:?}", msg),
+    }
+}
+
+#[test]
+fn failed_logins_lock_out_their_address() {
+    let file = env::temp_dir().join(format!("tokio-chat-throttle-{}", process::id()));
+    let _ = fs::remove_file(&file);
+    let clock = Arc::new(MockClock::new());
+    let config = || {
+        let mut config = guest_config();
+        config.login_throttle.max_failures = 3;
+        config.login_throttle_file = Some(file.display().to_string());
+        config.clock = clock.clone();
+        config
+    };
+    let addr = start_server(config());
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+    assert_eq!(alice.register("alice", "hunter22"),
+               ServerMessage::Registered("alice".to_string()));
+    drop(alice);
+
+    let assert_locked_out = |addr: &SocketAddr, retry_after: u64| {
+        let password = Handshake::new("alice").with_password("hunter22");
+        let mut client = TestClient::handshake(addr, password);
+        match client.recv() {
+            ServerMessage::Error(ErrorCode::TooManyAttempts, reason) => {
+                assert_eq!(reason,
+                           format!("too many attempts, retry after {} seconds", retry_after))
+            }
+            msg => panic!("expected to be locked out, got {:?}", msg),
+        }
+        client.stream.read_to_end(&mut Vec::new()).unwrap();
+    };
+    let fail = |addr: &SocketAddr| {
+        assert_unauthorized(addr, Handshake::new("alice").with_password("hunter23"))
+    };
+
+    // Enough wrong passwords lock the address out, even for the right one.
+    for _ in 0..3 {
+        fail(&addr);
+    }
+    assert_locked_out(&addr, 30);
+    clock.advance(Duration::from_secs(10));
+    assert_locked_out(&addr, 20);
+
+    // Once the lockout's over, the right password works again.
+    clock.advance(Duration::from_secs(20));
+    TestClient::connect(&addr, Handshake::new("alice").with_password("hunter22"));
+
+    // The next lockout lasts twice as long, and outlasts a restart.
+    for _ in 0..3 {
+        fail(&addr);
+    }
+    let addr = start_server(config());
+    assert_locked_out(&addr, 60);
+    let _ = fs::remove_file(&file);
+}
+
+#[test]
+fn shared_secret_tokens_are_required() {
+    let config = Config { token: Some("sesame".to_string()), ..guest_config() };
+    let addr = start_server(config);
+    assert_unauthorized(&addr, Handshake::new("alice").with_token("open sesame"));
+    assert_unauthorized(&addr, Handshake::new("alice"));
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice").with_token("sesame"));
+    let (_, users) = alice.who();
+    assert_eq!(users.into_iter().map(|user| user.name).collect::<Vec<_>>(), vec!["alice"]);
+
+    // Without a token of its own, the server lets anyone in, and ignores tokens it's given.
+    let addr = start_server(guest_config());
+    TestClient::connect(&addr, Handshake::new("alice"));
+    TestClient::connect(&addr, Handshake::new("bob").with_token("sesame"));
+}
+
+#[test]
+fn guests_need_permission() {
+    let config = Config { admin_token: Some("sesame".to_string()), ..Config::default() };
+    let addr = start_server(config);
+    assert_unauthorized(&addr, Handshake::new("bob"));
+
+    // Operators get in regardless, and can let other people in by registering them.
+    let mut root = TestClient::connect(&addr, Handshake::new("root").with_token("sesame"));
+    assert_eq!(root.register("bob", "hunter22"), ServerMessage::Registered("bob".to_string()));
+    TestClient::connect(&addr, Handshake::new("bob").with_password("hunter22"));
+}
+
+#[test]
+fn resumed_sessions_catch_up_from_the_database() {
+    let db = env::temp_dir().join(format!("tokio-chat-messages-{}.db", process::id()));
+    let _ = fs::remove_file(&db);
+    let url = format!("sqlite://{}", db.display());
+    let config = || {
+        Config {
+            history_len: 2,
+            db_url: Some(url.clone()),
+            ..guest_config()
+        }
+    };
+    let addr = start_server(config());
+
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+    let bob = TestClient::connect(&addr, Handshake::new("bob"));
+    let token = bob.resume_token.clone();
+    drop(bob);
+    alice.recv_until(|msg| match msg {
+        ServerMessage::UserDisconnected(ref user) if user == "bob" => Some(()),
+        _ => None,
+    });
+
+    // More goes on while bob's away than the server keeps in memory...
+    let said = ["one", "two", "three", "four"];
+    for text in &said {
+        alice.send(ClientMessage::new(*text));
+        alice.recv_chat();
+    }
+
+    // ... but he still hears all of it.
+    let recv_numbered = |client: &mut TestClient| {
+        client.recv_until(|msg| match msg {
+            ServerMessage::Message(id, _, body) => Some((id, body)),
+            _ => None,
+        })
+    };
+    let mut bob = TestClient::connect(&addr, Handshake::new("bob").with_resume_token(token));
+    let caught_up = (0..said.len()).map(|_| recv_numbered(&mut bob)).collect::<Vec<_>>();
+    assert_eq!(caught_up.iter().map(|&(_, ref body)| body.as_str()).collect::<Vec<_>>(), said);
+
+    // After a restart, messages are numbered after the ones from before.
+    let addr = start_server(config());
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+    alice.send(ClientMessage::new("after"));
+    assert!(recv_numbered(&mut alice).0 > caught_up[said.len() - 1].0);
+    let _ = fs::remove_file(&db);
+}
+
+#[test]
+fn registered_users_survive_restarts() {
+    let db = env::temp_dir().join(format!("tokio-chat-users-{}.db", process::id()));
+    let _ = fs::remove_file(&db);
+    let url = format!("sqlite://{}", db.display());
+    let config = || {
+        Config {
+            admin_token: Some("sesame".to_string()),
+            db_url: Some(url.clone()),
+            ..Config::default()
+        }
+    };
+
+    // alice registers, then logs in as an operator, which she'll stay.
+    let addr = start_server(config());
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice").with_token("sesame"));
+    assert_eq!(alice.register("alice", "hunter22"),
+               ServerMessage::Registered("alice".to_string()));
+    drop(alice);
+    let handshake = Handshake::new("alice").with_token("sesame").with_password("hunter22");
+    drop(TestClient::connect(&addr, handshake));
+
+    // A second server on the same database stands in for the first one after a restart.
+    let addr = start_server(config());
+    assert_unauthorized(&addr, Handshake::new("alice"));
+    drop(TestClient::connect(&addr, Handshake::new("alice").with_password("hunter22")));
+
+    let store = SqliteUserStore::open(&url).unwrap();
+    let users = Core::new().unwrap().run(store.list_users()).unwrap();
+    assert_eq!(users.len(), 1);
+    assert_eq!(users[0].username, "alice");
+    assert!(users[0].password_hash.starts_with("$2"));
+    assert!(users[0].is_admin);
+    assert!(users[0].created_at <= users[0].last_seen);
+    let _ = fs::remove_file(&db);
+}
+
+#[test]
+fn authors_can_edit_their_messages() {
+    let mut config = guest_config();
+    config.policies.default.max_body_len = 16;
+    let addr = start_server(config);
+
+    let mut alice = TestClient::connect(&addr,
+                                        Handshake::new("alice").with_capabilities(capability::ALL));
+    let mut bob = TestClient::connect(&addr,
+                                      Handshake::new("bob").with_capabilities(capability::ALL));
+
+    alice.send(ClientMessage::new("helo"));
+    let id = bob.recv_until(|msg| match msg {
+        ServerMessage::Message(id, ref from, ref body) if from == "alice" && body == "helo" => {
+            Some(id)
+        }
+        _ => None,
+    });
+
+    alice.send(ClientMessage::EditMessage {
+        id: id,
+        new_body: "hello".to_string(),
+    });
+    let edit = bob.recv_until(|msg| match msg {
+        ServerMessage::MessageEdited { id, new_body } => Some((id, new_body)),
+        _ => None,
+    });
+    assert_eq!(edit, (id, "hello".to_string()));
+
+    // Only the author can edit a message...
+    bob.send(ClientMessage::EditMessage {
+        id: id,
+        new_body: "goodbye".to_string(),
+    });
+    match bob.recv() {
+        ServerMessage::Error(ErrorCode::Unauthorized, _) => {}
+        msg => panic!("expected bob's edit to be refused, got {:?}", msg),
+    }
+
+    // ... only messages the server knows about can be edited...
+    alice.send(ClientMessage::EditMessage {
+        id: id + 1000,
+        new_body: "hello?".to_string(),
+    });
+    alice.recv_until(|msg| match msg {
+        ServerMessage::Error(ErrorCode::InvalidMessage, _) => Some(()),
+        _ => None,
+    });
+
+    // ... and edits follow the same rules as new messages.
+    alice.send(ClientMessage::EditMessage {
+        id: id,
+        new_body: "x".repeat(17),
+    });
+    alice.recv_until(|msg| match msg {
+        ServerMessage::Error(ErrorCode::InvalidMessage, _) => Some(()),
+        ServerMessage::MessageEdited { ref new_body, .. } if new_body.len() > 16 => {
+            panic!("an over-long edit got through")
+        }
+        _ => None,
+    });
+}
+
+#[test]
+fn formatted_messages_are_sanitized() {
+    let addr = start_server(guest_config());
+    let mut alice = TestClient::connect(&addr,
+                                        Handshake::new("alice")
+                                            .with_capabilities(&[capability::FORMATTING]));
+    let mut bob = TestClient::connect(&addr, Handshake::new("bob"));
+    let formatted = |alice: &mut TestClient, body: &str, content_type: ContentType| {
+        alice.send(ClientMessage::FormattedMessage {
+            body: body.to_string(),
+            content_type: content_type,
+        });
+        alice.recv_until(|msg| match msg {
+            ServerMessage::FormattedMessage { body, content_type, .. } => {
+                Some((body, content_type))
+            }
+            _ => None,
+        })
+    };
+
+    // HTML loses anything that could run, or send the reader anywhere but a web page...
+    let html = "<p><b onclick=\"steal()\">hi</b><script>alert(1)</script> \
+                <A HREF='javascript:x'>x</a> <a href=https://example.com>y</a><!-- <img src=x> --> \
+                1 < 2</p>";
+    let clean = "<p><b>hi</b> <a>x</a> <a href=\"https://example.com\">y</a> 1 &lt; 2</p>";
+    assert_eq!(formatted(&mut alice, html, ContentType::Html),
+               (clean.to_string(), ContentType::Html));
+
+    // ... and clients that don't do formatting get it as a plain message.
+    assert_eq!(bob.recv_chat(), ("alice".to_string(), clean.to_string()));
+
+    // Markdown is left as it is.
+    let markdown = "**hi** <b>";
+    assert_eq!(formatted(&mut alice, markdown, ContentType::Markdown),
+               (markdown.to_string(), ContentType::Markdown));
+    assert_eq!(bob.recv_chat(), ("alice".to_string(), markdown.to_string()));
+
+    // Formats the server hasn't heard of are refused.
+    alice.send_raw(br#"{"FormattedMessage":{"body":"hi","content_type":"Rtf"}}"#);
+    alice.recv_until(|msg| match msg {
+        ServerMessage::Error(ErrorCode::UnsupportedContentType, _) => Some(()),
+        ServerMessage::FormattedMessage { .. } => panic!("an unsupported message got through"),
+        _ => None,
+    });
+}
+
+#[test]
+fn operators_can_announce_to_every_room() {
+    let mut config = guest_config();
+    config.admin_token = Some("sesame".to_string());
+    let addr = start_server(config);
+    let handshake = |name| Handshake::new(name).with_capabilities(capability::ALL);
+    let mut ops = TestClient::connect(&addr, handshake("ops").with_token("sesame"));
+    let mut alice = TestClient::connect(&addr, handshake("alice"));
+    let mut bob = TestClient::connect(&addr, handshake("bob"));
+    bob.join("elsewhere");
+
+    let announcement = |client: &mut TestClient| {
+        client.recv_until(|msg| match msg {
+            ServerMessage::ServerAnnouncement(text) => Some(text),
+            _ => None,
+        })
+    };
+    ops.send(ClientMessage::AdminAnnounce("restarting at noon".to_string()));
+    assert_eq!(announcement(&mut alice), "restarting at noon");
+    assert_eq!(announcement(&mut bob), "restarting at noon");
+
+    // Anyone else is refused, and nobody hears it.
+    alice.send(ClientMessage::AdminAnnounce("free pizza".to_string()));
+    alice.recv_until(|msg| match msg {
+        ServerMessage::Error(ErrorCode::Unauthorized, _) => Some(()),
+        ServerMessage::ServerAnnouncement(..) => panic!("a non-operator's announcement went out"),
+        _ => None,
+    });
+    bob.join(DEFAULT_ROOM);
+    alice.send(ClientMessage::new("oh well"));
+    bob.recv_until(|msg| match msg {
+        ServerMessage::ServerAnnouncement(..) => panic!("bob heard a non-operator's announcement"),
+        ServerMessage::Message(..) => Some(()),
+        _ => None,
+    });
+}
+
+#[test]
+fn operators_can_export_rooms() {
+    let mut config = guest_config();
+    config.admin_token = Some("sesame".to_string());
+    let addr = start_server(config);
+    let mut alice = TestClient::connect(&addr,
+                                        Handshake::new("alice").with_capabilities(capability::ALL));
+    let handshake = Handshake::new("ops").with_token("sesame").with_capabilities(capability::ALL);
+    let mut ops = TestClient::connect(&addr, handshake);
+
+    // Some chat in the lobby, one message of it edited, and some elsewhere that isn't exported.
+    let mut ids = Vec::new();
+    for body in &["first", "secnod"] {
+        alice.send(ClientMessage::new(*body));
+        ids.push(ops.recv_until(|msg| match msg {
+            ServerMessage::Message(id, ..) => Some(id),
+            _ => None,
+        }));
+    }
+    alice.send(ClientMessage::EditMessage {
+        id: ids[1],
+        new_body: "second".to_string(),
+    });
+    ops.recv_until(|msg| match msg {
+        ServerMessage::MessageEdited { .. } => Some(()),
+        _ => None,
+    });
+    alice.join("elsewhere");
+    alice.send(ClientMessage::new("not for the lobby"));
+    alice.recv_chat();
+
+    ops.send(ClientMessage::AdminExport("lobby".to_string()));
+    let exported = ops.recv_until(|msg| match msg {
+        ServerMessage::ExportData { room, messages } => Some((room, messages)),
+        _ => None,
+    });
+    let chat = |id: u64, body: &str| {
+        ChatMessage {
+            id: id,
+            from: "alice".to_string(),
+            body: body.to_string(),
+        }
+    };
+    assert_eq!(exported,
+               ("lobby".to_string(), vec![chat(ids[0], "first"), chat(ids[1], "second")]));
+
+    // Only operators may.
+    alice.send(ClientMessage::AdminExport("lobby".to_string()));
+    match alice.recv() {
+        ServerMessage::Error(ErrorCode::Unauthorized, _) => {}
+        msg => panic!("expected alice's export to be refused, got {:?}", msg),
+    }
+}
+
+#[test]
+fn full_rooms_turn_joiners_away() {
+    let mut config = guest_config();
+    let mut ops = config.policies.default;
+    ops.max_members = 2;
+    config.policies.rooms.insert("ops".to_string(), ops);
+    let addr = start_server(config);
+
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+    let mut bob = TestClient::connect(&addr, Handshake::new("bob"));
+    let mut carol = TestClient::connect(&addr, Handshake::new("carol"));
+    alice.join("ops");
+    bob.join("ops");
+
+    // There's no room for carol, who stays put...
+    carol.send(ClientMessage::Join("ops".to_string()));
+    carol.recv_until(|msg| match msg {
+        ServerMessage::Error(ErrorCode::RoomFull, _) => Some(()),
+        ServerMessage::UserJoined(ref user, ..) if user == "carol" => {
+            panic!("carol got into a full room")
+        }
+        _ => None,
+    });
+    assert_eq!(carol.who().0, DEFAULT_ROOM);
+
+    // ... while the room carries on as before.
+    alice.send(ClientMessage::new("just us"));
+    assert_eq!(bob.recv_chat(), ("alice".to_string(), "just us".to_string()));
+    let (room, users) = bob.who();
+    assert_eq!(room, "ops");
+    assert_eq!(users.len(), 2);
+
+    // Once someone leaves, there's space again.
+    bob.join(DEFAULT_ROOM);
+    carol.join("ops");
+}
+
+#[test]
+fn rooms_keep_their_topics() {
+    let addr = start_server(guest_config());
+    let connect = |name: &str| {
+        TestClient::connect(&addr, Handshake::new(name).with_capabilities(capability::ALL))
+    };
+    let topic_of = |client: &mut TestClient| {
+        client.recv_until(|msg| match msg {
+            ServerMessage::TopicChanged { room, topic } => Some((room, topic)),
+            _ => None,
+        })
+    };
+    let ops_topic = ("ops".to_string(), "deploys at noon".to_string());
+
+    let mut alice = connect("alice");
+    let mut bob = connect("bob");
+    alice.join("ops");
+    bob.join("ops");
+
+    // Only the room you're in can be given a topic...
+    alice.send(ClientMessage::SetTopic {
+        room: DEFAULT_ROOM.to_string(),
+        topic: "welcome".to_string(),
+    });
+    alice.recv_until(|msg| match msg {
+        ServerMessage::Error(ErrorCode::InvalidMessage, _) => Some(()),
+        _ => None,
+    });
+
+    // ... where everyone hears about it...
+    alice.send(ClientMessage::SetTopic {
+        room: "ops".to_string(),
+        topic: "deploys at noon".to_string(),
+    });
+    assert_eq!(topic_of(&mut alice), ops_topic);
+    assert_eq!(topic_of(&mut bob), ops_topic);
+
+    // ... including whoever joins later.
+    let mut carol = connect("carol");
+    carol.join("ops");
+    assert_eq!(topic_of(&mut carol), ops_topic);
+}
+
+#[test]
+fn created_rooms_keep_their_own_settings() {
+    let mut config = guest_config();
+    config.admin_token = Some("sesame".to_string());
+    let addr = start_server(config);
+    let connect = |handshake: Handshake| {
+        TestClient::connect(&addr, handshake.with_capabilities(capability::ALL))
+    };
+    let mut alice = connect(Handshake::new("alice"));
+    let mut bob = connect(Handshake::new("bob"));
+    let mut ops = connect(Handshake::new("ops").with_token("sesame"));
+    let info_or_error = |client: &mut TestClient| {
+        client.recv_until(|msg| match msg {
+            ServerMessage::RoomInfo { name, config, member_count, topic } => {
+                Some(Ok((name, config, member_count, topic)))
+            }
+            ServerMessage::Error(code, _) => Some(Err(code)),
+            _ => None,
+        })
+    };
+
+    // A room with a member apiece, two messages a minute between them, and a topic to start with.
+    let quiet = RoomConfig {
+        max_members: Some(1),
+        messages_per_minute: Some(2),
+        topic: Some("shh".to_string()),
+        ..RoomConfig::default()
+    };
+    let create = |client: &mut TestClient, name: &str, config: &RoomConfig| {
+        client.send(ClientMessage::CreateRoom {
+            name: name.to_string(),
+            config: config.clone(),
+        });
+        info_or_error(client)
+    };
+    assert_eq!(create(&mut alice, "quiet", &quiet),
+               Ok(("quiet".to_string(), quiet.clone(), 0, Some("shh".to_string()))));
+    assert_eq!(create(&mut bob, "quiet", &quiet), Err(ErrorCode::InvalidMessage));
+    assert_eq!(create(&mut bob, DEFAULT_ROOM, &quiet), Err(ErrorCode::InvalidMessage));
+
+    // Joining tells alice about the room...
+    alice.join("quiet");
+    assert_eq!(info_or_error(&mut alice),
+               Ok(("quiet".to_string(), quiet.clone(), 1, Some("shh".to_string()))));
+
+    // ... which has no space for bob...
+    bob.send(ClientMessage::Join("quiet".to_string()));
+    assert_eq!(info_or_error(&mut bob), Err(ErrorCode::RoomFull));
+
+    // ... and, though alice could say more on her own, the room only takes two messages.
+    for body in &["one", "two"] {
+        alice.send(ClientMessage::new(*body));
+        assert_eq!(alice.recv_chat(), ("alice".to_string(), body.to_string()));
+    }
+    alice.send(ClientMessage::new("three"));
+    assert_eq!(info_or_error(&mut alice), Err(ErrorCode::RateLimited));
+
+    // Only operators may create private rooms, and then only they can get in or ask about them.
+    let secret = RoomConfig {
+        private: true,
+        ..RoomConfig::default()
+    };
+    assert_eq!(create(&mut alice, "secret", &secret), Err(ErrorCode::Unauthorized));
+    assert_eq!(create(&mut ops, "secret", &secret),
+               Ok(("secret".to_string(), secret.clone(), 0, None)));
+    bob.send(ClientMessage::GetRoomInfo("secret".to_string()));
+    assert_eq!(info_or_error(&mut bob), Err(ErrorCode::Unauthorized));
+    bob.send(ClientMessage::Join("secret".to_string()));
+    assert_eq!(info_or_error(&mut bob), Err(ErrorCode::Unauthorized));
+    ops.join("secret");
+    assert_eq!(info_or_error(&mut ops),
+               Ok(("secret".to_string(), secret.clone(), 1, None)));
+}
+
+#[test]
+fn rooms_are_restored_from_the_event_log() {
+    let log = env::temp_dir().join(format!("tokio-chat-events-{}.jsonl", process::id()));
+    let _ = fs::remove_file(&log);
+    let config = || {
+        Config {
+            admin_token: Some("sesame".to_string()),
+            event_log: Some(log.display().to_string()),
+            ..guest_config()
+        }
+    };
+    let connect = |addr: &SocketAddr, handshake: Handshake| {
+        TestClient::connect(addr, handshake.with_capabilities(capability::ALL))
+    };
+    let recv_info = |client: &mut TestClient| {
+        client.recv_until(|msg| match msg {
+            ServerMessage::RoomInfo { config, topic, .. } => Some((config, topic)),
+            _ => None,
+        })
+    };
+
+    let addr = start_server(config());
+    let mut alice = connect(&addr, Handshake::new("alice"));
+    let quiet = RoomConfig {
+        messages_per_minute: Some(10),
+        topic: Some("shh".to_string()),
+        ..RoomConfig::default()
+    };
+    alice.send(ClientMessage::CreateRoom {
+        name: "quiet".to_string(),
+        config: quiet.clone(),
+    });
+    recv_info(&mut alice);
+    alice.join("quiet");
+    alice.send(ClientMessage::new("frist"));
+    let id = alice.recv_until(|msg| match msg {
+        ServerMessage::Message(id, _, _) => Some(id),
+        _ => None,
+    });
+    alice.send(ClientMessage::EditMessage {
+        id: id,
+        new_body: "first".to_string(),
+    });
+    alice.send(ClientMessage::SetTopic {
+        room: "quiet".to_string(),
+        topic: "hush".to_string(),
+    });
+    alice.recv_until(|msg| match msg {
+        ServerMessage::TopicChanged { .. } => Some(()),
+        _ => None,
+    });
+
+    // Alice only hears about each change once it's in the log, so a server started afterwards on
+    // the same log has the room as it was left: its settings, its new topic, and its chat, edits
+    // and all.
+    let addr = start_server(config());
+    let mut ops = connect(&addr, Handshake::new("ops").with_token("sesame"));
+    ops.send(ClientMessage::GetRoomInfo("quiet".to_string()));
+    assert_eq!(recv_info(&mut ops), (quiet, Some("hush".to_string())));
+    ops.send(ClientMessage::AdminExport("quiet".to_string()));
+    let exported = ops.recv_until(|msg| match msg {
+        ServerMessage::ExportData { messages, .. } => Some(messages),
+        _ => None,
+    });
+    assert_eq!(exported,
+               vec![ChatMessage {
+                        id: id,
+                        from: "alice".to_string(),
+                        body: "first".to_string(),
+                    }]);
+    let _ = fs::remove_file(&log);
+}
+
+#[test]
+fn login_tokens_stand_in_for_passwords() {
+    let addr = start_server(guest_config());
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+    assert_eq!(alice.register("alice", "hunter22"),
+               ServerMessage::Registered("alice".to_string()));
+    drop(alice);
+
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice").with_password("hunter22"));
+    let token = match alice.recv() {
+        ServerMessage::Token { token, expires_in_secs } => {
+            assert_eq!(expires_in_secs, 24 * 60 * 60);
+            token
+        }
+        msg => panic!("expected a login token, got {:?}", msg),
+    };
+
+    // The token gets alice in without her password (and, being fresh, isn't replaced)...
+    let mut again = TestClient::connect(&addr,
+                                        Handshake::new("alice").with_auth_token(token.clone()));
+    assert_eq!(again.recv(), ServerMessage::UserConnected("alice".to_string()));
+
+    // ... but nobody else, and not once she's revoked it.
+    assert_unauthorized(&addr, Handshake::new("bob").with_auth_token(token.clone()));
+    assert_unauthorized(&addr, Handshake::new("alice").with_auth_token("not a token"));
+    alice.send(ClientMessage::RevokeToken { token: token.clone() });
+    alice.who();
+    assert_unauthorized(&addr, Handshake::new("alice").with_auth_token(token));
+}
+
+#[test]
+fn old_login_tokens_are_refreshed() {
+    let mut config = guest_config();
+    config.jwt_secret = Some("test secret".to_string());
+    let addr = start_server(config);
+    let mut alice = TestClient::connect(&addr, Handshake::new("alice"));
+    assert_eq!(alice.register("alice", "hunter22"),
+               ServerMessage::Registered("alice".to_string()));
+    drop(alice);
+
+    // Sign tokens the way the server does, so we can pick their age.
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let token = |iat, exp| {
+        let claims = Claims {
+            sub: "alice".to_string(),
+            iat: iat,
+            exp: exp,
+            jti: format!("{}-{}", iat, exp),
+        };
+        let key = jsonwebtoken::EncodingKey::from_secret(b"test secret");
+        jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, &key).unwrap()
+    };
+
+    // With a twentieth of its lifetime left, a token still works, but gets replaced.
+    let handshake = Handshake::new("alice").with_auth_token(token(now - 950, now + 50));
+    let mut alice = TestClient::connect(&addr, handshake);
+    match alice.recv() {
+        ServerMessage::Token { .. } => {}
+        msg => panic!("expected a new login token, got {:?}", msg),
+    }
+
+    // Once it's expired, it doesn't.
+    assert_unauthorized(&addr, Handshake::new("alice").with_auth_token(token(now - 1000, now - 1)));
+}
+
+#[test]
+fn built_clients_chat() {
+    let addr = start_server(guest_config());
+    let mut core = Core::new().unwrap();
+    let mut connect = |name: &str| {
+        let connecting = ClientBuilder::new(Handshake::new(name))
+            .server(addr)
+            .connect(&core.handle());
+        let mut client = core.run(connecting).unwrap();
+        match recv_from(&mut core, &mut client) {
+            ServerMessage::Welcome { .. } => client,
+            msg => panic!("{} expected a welcome, got {:?}", name, msg),
+        }
+    };
+    let mut alice = connect("alice");
+    let mut bob = connect("bob");
+
+    alice.send(ClientMessage::new("hi bob")).unwrap();
+    assert_eq!(recv_chat_from(&mut core, &mut bob), ("alice".to_string(), "hi bob".to_string()));
+    bob.send(ClientMessage::new("hi alice")).unwrap();
+    assert_eq!(recv_chat_from(&mut core, &mut alice), ("alice".to_string(), "hi bob".to_string()));
+    assert_eq!(recv_chat_from(&mut core, &mut alice), ("bob".to_string(), "hi alice".to_string()));
+
+    alice.close();
+    assert!(alice.send(ClientMessage::new("still there?")).is_err());
+    loop {
+        match recv_from(&mut core, &mut bob) {
+            ServerMessage::UserDisconnected(ref user) if user == "alice" => break,
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn clients_can_be_driven_without_a_terminal() {
+    let addr = start_server(guest_config());
+    let mut core = Core::new().unwrap();
+    let alice = core.run(Client::connect(addr, "alice", &core.handle())).unwrap();
+    let mut bob = core.run(Client::connect(addr, "bob", &core.handle())).unwrap();
+
+    // Alice's halves go their separate ways, as a bot's might; her welcome is already waiting.
+    let (to_server, from_server) = alice.split();
+    match core.run(from_server.into_future().map_err(|(err, _)| err)).unwrap() {
+        (Some(ServerMessage::Welcome { .. }), _) => {}
+        (msg, _) => panic!("alice expected a welcome, got {:?}", msg),
+    }
+    to_server.send(ClientMessage::new("beep boop")).unwrap();
+    assert_eq!(recv_chat_from(&mut core, &mut bob),
+               ("alice".to_string(), "beep boop".to_string()));
+}
+
+// Answers anything mentioning "ping", and nothing else.
+struct PingBot;
+
+impl MessageHandler for PingBot {
+    fn on_message(&self, msg: &ServerMessage) -> Option<ClientMessage> {
+        match *msg {
+            ServerMessage::Message(_, _, ref body) if body.contains("ping") => {
+                Some(ClientMessage::new("pong"))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn message_handlers_reply() {
+    let addr = start_server(guest_config());
+    let mut core = Core::new().unwrap();
+    let mut alice = core.run(Client::connect(addr, "alice", &core.handle())).unwrap();
+    let bot = tokio_chat_bot::run(PingBot, &addr, Handshake::new("pingbot"), &[DEFAULT_ROOM],
+                                  &core.handle());
+    core.handle().spawn(bot.map_err(|err| panic!("the bot failed: {}", err)));
+    loop {
+        match recv_from(&mut core, &mut alice) {
+            ServerMessage::UserConnected(ref user) if user == "pingbot" => break,
+            _ => {}
+        }
+    }
+
+    // Only the second of these gets an answer...
+    alice.send(ClientMessage::new("hello")).unwrap();
+    alice.send(ClientMessage::new("anyone up for ping pong?")).unwrap();
+    let said = (0..3).map(|_| recv_chat_from(&mut core, &mut alice)).collect::<Vec<_>>();
+    assert_eq!(said,
+               vec![("alice".to_string(), "hello".to_string()),
+                    ("alice".to_string(), "anyone up for ping pong?".to_string()),
+                    ("pingbot".to_string(), "pong".to_string())]);
+
+    // ... and nothing else does, or it would turn up before this.
+    alice.send(ClientMessage::new("bye")).unwrap();
+    assert_eq!(recv_chat_from(&mut core, &mut alice), ("alice".to_string(), "bye".to_string()));
+}
+
+#[test]
+fn built_clients_can_be_refused() {
+    let mut config = guest_config();
+    config.token = Some("sesame".to_string());
+    let addr = start_server(config);
+
+    let mut core = Core::new().unwrap();
+    let connecting = ClientBuilder::new(Handshake::new("alice"))
+        .server(addr)
+        .connect(&core.handle());
+    match core.run(connecting) {
+        Err(ClientError::Refused(ErrorCode::Unauthorized, _)) => {}
+        Err(err) => panic!("expected to be refused, got {}", err),
+        Ok(_) => panic!("expected to be refused, got in"),
+    }
+}
+
+#[test]
+fn built_clients_reconnect_and_catch_up() {
+    let clock = Arc::new(MockClock::new());
+    let mut config = guest_config();
+    config.idle_timeout = Some(Duration::from_secs(60));
+    config.clock = clock.clone();
+    let addr = start_server(config);
+
+    let mut core = Core::new().unwrap();
+    let connecting = ClientBuilder::new(Handshake::new("bob")).server(addr).connect(&core.handle());
+    let mut bob = core.run(connecting).unwrap();
+    let reconnect = ReconnectConfig {
+        attempts: 3,
+        delay: Duration::from_secs(1),
+    };
+    let connecting = ClientBuilder::new(Handshake::new("alice"))
+        .server(addr)
+        .reconnect(reconnect)
+        .connect(&core.handle());
+    let mut alice = core.run(connecting).unwrap();
+    bob.send(ClientMessage::new("anyone?")).unwrap();
+    assert_eq!(recv_chat_from(&mut core, &mut alice),
+               ("bob".to_string(), "anyone?".to_string()));
+
+    // Alice is timed out for being quiet, and misses what bob says while she's away...
+    clock.advance(Duration::from_secs(30));
+    bob.send(ClientMessage::new("still here")).unwrap();
+    recv_chat_from(&mut core, &mut bob);
+    clock.advance(Duration::from_secs(45));
+    loop {
+        match recv_from(&mut core, &mut bob) {
+            ServerMessage::UserDisconnected(ref user) if user == "alice" => break,
+            _ => {}
+        }
+    }
+    bob.send(ClientMessage::new("while you were out")).unwrap();
+
+    // ... but she's back a second later, and catches up.
+    let mut heard = Vec::new();
+    while heard.last().map(String::as_str) != Some("while you were out") {
+        match recv_from(&mut core, &mut alice) {
+            ServerMessage::Welcome { .. } => heard.push("welcome".to_string()),
+            ServerMessage::Error(ErrorCode::IdleTimeout, _) => heard.push("timed out".to_string()),
+            ServerMessage::Message(_, _, body) => heard.push(body),
+            _ => {}
+        }
+    }
+    assert_eq!(heard, vec!["still here", "timed out", "welcome", "while you were out"]);
+}
+
+#[test]
+fn built_clients_keep_transcripts() {
+    let addr = start_server(guest_config());
+    let log = env::temp_dir().join(format!("tokio-chat-transcript-{}.jsonl", process::id()));
+    let _ = fs::remove_file(&log);
+
+    let mut core = Core::new().unwrap();
+    let connecting = ClientBuilder::new(Handshake::new("bob")).server(addr).connect(&core.handle());
+    let mut bob = core.run(connecting).unwrap();
+    let connecting = ClientBuilder::new(Handshake::new("alice"))
+        .server(addr)
+        .log(&log)
+        .connect(&core.handle());
+    let mut alice = core.run(connecting).unwrap();
+    alice.send(ClientMessage::new("hi")).unwrap();
+    recv_chat_from(&mut core, &mut alice);
+    recv_chat_from(&mut core, &mut bob);
+    bob.send(ClientMessage::new("hello")).unwrap();
+    recv_chat_from(&mut core, &mut alice);
+
+    // The transcript is all written out once alice hangs up.
+    alice.close();
+    core.run(alice.recv().for_each(|_| Ok(()))).unwrap();
+    let mut sent = Vec::new();
+    let mut received = Vec::new();
+    for line in BufReader::new(fs::File::open(&log).unwrap()).lines() {
+        let line: serde_json::Value = serde_json::from_str(&line.unwrap()).unwrap();
+        assert!(line.find("at").and_then(|at| at.as_str()).is_some());
+        if let Some(msg) = line.find("sent") {
+            sent.push(serde_json::from_value::<ClientMessage>(msg.clone()).unwrap());
+        }
+        if let Some(msg) = line.find("received") {
+            received.push(serde_json::from_value::<ServerMessage>(msg.clone()).unwrap());
+        }
+    }
+    assert_eq!(sent, vec![ClientMessage::new("hi")]);
+    match received.first() {
+        Some(&ServerMessage::Welcome { .. }) => {}
+        msg => panic!("expected the transcript to start with a welcome, got {:?}", msg),
+    }
+    let chat = received.into_iter()
+        .filter_map(|msg| match msg {
+            ServerMessage::Message(_, from, body) => Some((from, body)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(chat,
+               vec![("alice".to_string(), "hi".to_string()),
+                    ("bob".to_string(), "hello".to_string())]);
+    let _ = fs::remove_file(&log);
+}
+
+#[test]
+fn pings_are_timed() {
+    let handshake = Handshake::new("alice").with_capabilities(&[capability::HEARTBEAT]);
+    let (mut server, mut client) = MockServer::new(handshake);
+    client.recv();
+    let mut timer = PingTimer::new();
+
+    // Two pings are out at once, and come back the other way round.
+    let first = timer.ping();
+    thread::sleep(Duration::from_millis(50));
+    let second = timer.ping();
+    let ids = match (&first, &second) {
+        (&ClientMessage::Ping(first), &ClientMessage::Ping(second)) => [first, second],
+        pings => panic!("expected pings, got {:?}", pings),
+    };
+    assert!(ids[0] != ids[1]);
+    client.send(first.clone());
+    client.send(second.clone());
+    server.expect_message(first);
+    server.expect_message(second);
+    server.send_response(ServerMessage::Pong(ids[1]));
+    server.send_response(ServerMessage::Pong(ids[0]));
+
+    let mut latencies = Vec::new();
+    for _ in 0..2 {
+        match client.recv() {
+            ServerMessage::Pong(id) => {
+                latencies.push(timer.pong(id).expect("the pong answers one of our pings"));
+                assert_eq!(timer.pong(id), None);
+            }
+            msg => panic!("expected a pong, got {:?}", msg),
+        }
+    }
+    // The second ping, answered first, took less time than the first, which waited for it.
+    assert!(latencies[0] < Duration::from_secs(5));
+    assert!(latencies[1] >= latencies[0] + Duration::from_millis(50));
+    assert_eq!(timer.pong(12345), None);
+}
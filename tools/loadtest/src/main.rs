@@ -0,0 +1,235 @@
+//! A load generator for tokio-chat-server.
+//!
+//! This connects a number of simulated users to the server, each of which sends a number of chat
+//! messages at a steady rate, and measures how long each message takes to come back to its sender
+//! in the server's broadcast. It talks to the server with tokio-chat-common's codec and message
+//! types, just like the real client. Run
+//!
+//!     cargo run --release -- --clients 100 --messages 50 --rate 2
+//!
+//! against a running server, and it prints a latency summary once every message is accounted for.
+//! The server's default rate limit is 5 messages per second per client, so start it with
+//! `--rate-limit 0` (or something above `--rate`) to measure the server rather than the limiter.
+//! Every simulated user connects from the same address, so the server's
+//! `--max-connections-per-ip` (16 by default) needs to be at least `--clients` as well. The
+//! simulated users are guests, so the server needs `--allow-guests` too.
+
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_chat_common;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::net::SocketAddr;
+use std::process;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio_core::io::Io;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Core, Handle, Interval};
+use futures::{future, Future, Sink, Stream};
+use tokio_chat_common::{Handshake, HandshakeCodec, ClientMessage, ServerMessage,
+                        ClientToServerCodec};
+
+const USAGE: &str = "\
+usage: chat-loadtest [options]
+
+options:
+    --addr HOST:PORT    server to connect to (default 127.0.0.1:12345)
+    --clients N         number of simulated users (default 10)
+    --messages M        messages each user sends (default 100)
+    --size BYTES        length of each message (default 64)
+    --rate N            messages per second each user sends (default 1)";
+
+struct Options {
+    addr: SocketAddr,
+    clients: usize,
+    messages: usize,
+    size: usize,
+    rate: u32,
+}
+
+impl Options {
+    fn from_args() -> Options {
+        let mut options = Options {
+            addr: "127.0.0.1:12345".parse().unwrap(),
+            clients: 10,
+            messages: 100,
+            size: 64,
+            rate: 1,
+        };
+
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--addr" => options.addr = parse(&mut args),
+                "--clients" => options.clients = parse(&mut args),
+                "--messages" => options.messages = parse(&mut args),
+                "--size" => options.size = parse(&mut args),
+                "--rate" => options.rate = parse(&mut args),
+                _ => usage(),
+            }
+        }
+        if options.rate == 0 {
+            usage();
+        }
+
+        options
+    }
+}
+
+fn parse<T: FromStr, I: Iterator<Item = String>>(args: &mut I) -> T {
+    args.next().and_then(|value| value.parse().ok()).unwrap_or_else(|| usage())
+}
+
+fn usage() -> ! {
+    println!("{}", USAGE);
+    process::exit(1);
+}
+
+// How one simulated user's messages fared.
+struct ClientReport {
+    // Round trip time of each message that came back, from when we sent it to when the server's
+    // broadcast of it reached us.
+    latencies: Vec<Duration>,
+
+    // Messages the server refused (e.g., because of its rate limit) instead of broadcasting.
+    errors: usize,
+}
+
+// Run one simulated user from connecting to hearing back about its last message.
+fn run_client(id: usize,
+              options: &Options,
+              handle: &Handle)
+              -> Box<Future<Item = ClientReport, Error = io::Error>> {
+    let name = format!("load-{}", id);
+    let messages = options.messages;
+    let size = options.size;
+    let period = Duration::from_millis(1000 / options.rate as u64);
+    let handle = handle.clone();
+
+    let handshake = TcpStream::connect(&options.addr, &handle).and_then({
+        let name = name.clone();
+        move |stream| {
+            stream.framed(HandshakeCodec::new())
+                .send(Handshake::new(name))
+                .map(|handshake_io| handshake_io.into_inner())
+        }
+    });
+
+    // Wait for the server to announce our arrival before sending anything; until then, it's still
+    // reading our handshake and could lose anything we write.
+    let connected = handshake.and_then(|socket| {
+        let (to_server, from_server) = socket.framed(ClientToServerCodec::new()).split();
+        from_server.into_future()
+            .map_err(|(err, _)| err)
+            .map(|(_, from_server)| (to_server, from_server))
+    });
+
+    Box::new(connected.and_then(move |(to_server, from_server)| {
+        // When each message we haven't heard back about yet was sent, by sequence number. Every
+        // message starts with its sequence number so we can match up the echo.
+        let sent_at = Rc::new(RefCell::new(HashMap::new()));
+
+        let sent_at_writer = sent_at.clone();
+        let writer = future::result(Interval::new(period, &handle))
+            .and_then(move |interval| {
+                interval.take(messages as u64)
+                    .fold((to_server, 0), move |(to_server, seq), ()| {
+                        let mut body = format!("{} ", seq);
+                        while body.len() < size {
+                            body.push('x');
+                        }
+                        sent_at_writer.borrow_mut().insert(seq, Instant::now());
+                        to_server.send(ClientMessage::new(body))
+                            .map(move |to_server| (to_server, seq + 1))
+                    })
+            });
+
+        // Every message we send ends in either its own echo or an error; stop listening once
+        // we've seen one or the other for all of them.
+        let reader = from_server
+            .filter_map(move |msg| match msg {
+                ServerMessage::Message(_, ref from, ref body) if *from == name => {
+                    let seq = body.split(' ').next().and_then(|seq| seq.parse().ok());
+                    seq.and_then(|seq: usize| sent_at.borrow_mut().remove(&seq))
+                        .map(|sent| Some(sent.elapsed()))
+                }
+                ServerMessage::Error(..) => Some(None),
+                _ => None,
+            })
+            .take(messages as u64)
+            .fold(ClientReport { latencies: Vec::new(), errors: 0 }, |mut report, latency| {
+                match latency {
+                    Some(latency) => report.latencies.push(latency),
+                    None => report.errors += 1,
+                }
+                Ok::<_, io::Error>(report)
+            });
+
+        writer.join(reader).map(|(_, report)| report)
+    }))
+}
+
+// The `pct`th percentile of `sorted`, which must not be empty.
+fn percentile(sorted: &[Duration], pct: usize) -> Duration {
+    sorted[(sorted.len() - 1) * pct / 100]
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + d.subsec_nanos() as f64 / 1_000_000.0
+}
+
+fn print_report(options: &Options, reports: &[ClientReport], elapsed: Duration) {
+    let mut all = reports.iter().flat_map(|r| r.latencies.iter().cloned()).collect::<Vec<_>>();
+    all.sort();
+    let errors = reports.iter().map(|r| r.errors).sum::<usize>();
+
+    println!("clients:      {}", options.clients);
+    println!("sent:         {}", options.clients * options.messages);
+    println!("echoed:       {}", all.len());
+    println!("errors:       {}", errors);
+    println!("elapsed:      {:.1} s", millis(elapsed) / 1000.0);
+    if all.is_empty() {
+        return;
+    }
+    println!("latency p50:  {:.2} ms", millis(percentile(&all, 50)));
+    println!("latency p95:  {:.2} ms", millis(percentile(&all, 95)));
+    println!("latency p99:  {:.2} ms", millis(percentile(&all, 99)));
+    println!("latency max:  {:.2} ms", millis(all[all.len() - 1]));
+
+    // The spread between users says something about fairness, so also report how the best and
+    // worst off users did at the tail.
+    let mut client_p99s = reports.iter()
+        .filter(|r| !r.latencies.is_empty())
+        .map(|r| {
+            let mut latencies = r.latencies.clone();
+            latencies.sort();
+            percentile(&latencies, 99)
+        })
+        .collect::<Vec<_>>();
+    client_p99s.sort();
+    println!("per-client p99: best {:.2} ms, worst {:.2} ms",
+             millis(client_p99s[0]),
+             millis(client_p99s[client_p99s.len() - 1]));
+}
+
+fn main() {
+    let options = Options::from_args();
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let start = Instant::now();
+    let clients = (0..options.clients)
+        .map(|id| run_client(id, &options, &handle))
+        .collect::<Vec<_>>();
+    let reports = core.run(future::join_all(clients)).unwrap_or_else(|err| {
+        println!("load test failed: {}", err);
+        process::exit(1);
+    });
+
+    print_report(&options, &reports, start.elapsed());
+}